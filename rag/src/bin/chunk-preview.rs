@@ -0,0 +1,49 @@
+//! Prints the chunks a document is split into, to tune the split criteria without a server.
+//!
+//! Usage: `chunk-preview <file> <split_criteria> [tokenizer.json]`, e.g.
+//! `chunk-preview document.txt token_count:512:1 tokenizer.json`. A tokenizer is required
+//! by the `token_count` and `paragraph_bounded` criteria, and enables the token counts.
+//! Tokenizer files ending in `.tiktoken` are loaded as tiktoken encodings.
+
+use std::{env, fs, path::Path};
+
+use anyhow::{Error, Result};
+use rag::{
+    split_criteria::SplitCriteria,
+    tokens::{load_token_counter, TokenizerKind},
+};
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (file, criteria, tokenizer) = match args.as_slice() {
+        [file, criteria] => (file, criteria, None),
+        [file, criteria, tokenizer] => (file, criteria, Some(tokenizer)),
+        _ => {
+            return Err(Error::msg(
+                "Usage: chunk-preview <file> <split_criteria> [tokenizer.json]",
+            ))
+        }
+    };
+    let text = fs::read_to_string(file)?;
+    let criteria: SplitCriteria = criteria.parse()?;
+    let tokenizer = tokenizer
+        .map(|path| {
+            let kind = if path.ends_with(".tiktoken") {
+                TokenizerKind::Tiktoken
+            } else {
+                TokenizerKind::HuggingFace
+            };
+            load_token_counter(kind, Path::new(path))
+        })
+        .transpose()?;
+
+    let previews = criteria.preview(&text, tokenizer.as_deref())?;
+    for (index, preview) in previews.iter().enumerate() {
+        let tokens = preview
+            .tokens
+            .map_or_else(|| "-".to_string(), |tokens| tokens.to_string());
+        println!("[{}] ({} tokens) {}", index, tokens, preview.text);
+    }
+    println!("{} chunks, split with {}", previews.len(), criteria);
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! Circuit breaker failing calls to Pinecone fast during sustained outages.
+
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use crate::error::RagError;
+
+/// Default number of consecutive failures of Pinecone opening the circuit breaker.
+pub const DEFAULT_BREAKER_FAILURE_THRESHOLD: usize = 5;
+/// Default number of seconds the circuit breaker stays open before letting a call through.
+pub const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 30;
+
+/// The state of a `CircuitBreaker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Calls go through
+    Closed,
+    /// Calls fail fast, until the cooldown elapses
+    Open,
+    /// The cooldown elapsed, the next call goes through to test whether Pinecone recovered
+    HalfOpen,
+}
+
+/// Consecutive failures, and until when calls fail fast if the breaker is open.
+#[derive(Default)]
+struct Failures {
+    consecutive: usize,
+    open_until: Option<Instant>,
+}
+
+/// Fails calls fast once Pinecone failed `failure_threshold` times in a row, rather than
+/// having every request wait for its own calls to time out.
+///
+/// Once open, calls fail with a `RagError::Unavailable` for the `cooldown`, after which the
+/// breaker half-opens: a single call goes through, closing the breaker if it succeeds, and
+/// opening it for another cooldown if it fails. The other calls keep failing fast meanwhile.
+///
+/// The state is shared by every request going through the breaker. Errors typed as a
+/// `RagError`, e.g. invalid inputs or failures of the embedding service, are not failures of
+/// Pinecone and leave the breaker as is.
+pub struct CircuitBreaker {
+    failure_threshold: NonZeroUsize,
+    cooldown: Duration,
+    failures: Mutex<Failures>,
+}
+
+impl CircuitBreaker {
+    /// Creates a closed breaker, opening after `failure_threshold` consecutive failures for
+    /// `cooldown`.
+    pub fn new(failure_threshold: NonZeroUsize, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            failures: Mutex::new(Failures::default()),
+        }
+    }
+
+    /// Returns the current state of the breaker.
+    pub fn state(&self) -> BreakerState {
+        match self.failures.lock().unwrap().open_until {
+            None => BreakerState::Closed,
+            Some(open_until) if Instant::now() < open_until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Runs a call unless the breaker is open, recording whether it failed.
+    ///
+    /// # Errors
+    ///
+    /// Fails with a `RagError::Unavailable`, without running the call, if the breaker is open,
+    /// and otherwise with the error of the call.
+    pub async fn call<T>(&self, call: impl Future<Output = Result<T>>) -> Result<T> {
+        self.acquire()?;
+        let result = call.await;
+        match &result {
+            Ok(_) => self.record_success(),
+            Err(e) if e.downcast_ref::<RagError>().is_none() => self.record_failure(),
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Lets a call through if the breaker is closed or half-open.
+    ///
+    /// A half-open breaker is opened again for the cooldown, so that a single call tests
+    /// whether Pinecone recovered, even if it never completes.
+    fn acquire(&self) -> Result<(), RagError> {
+        let mut failures = self.failures.lock().unwrap();
+        let Some(open_until) = failures.open_until else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < open_until {
+            return Err(RagError::Unavailable(format!(
+                "Pinecone failed {} times in a row, retry in {}s",
+                failures.consecutive,
+                (open_until - now).as_secs().max(1)
+            )));
+        }
+        failures.open_until = Some(now + self.cooldown);
+        info!("Circuit breaker half-open, testing whether Pinecone recovered");
+        Ok(())
+    }
+
+    fn record_success(&self) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.open_until.is_some() {
+            info!("Pinecone recovered, closing the circuit breaker");
+        }
+        *failures = Failures::default();
+    }
+
+    fn record_failure(&self) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.consecutive += 1;
+        if failures.consecutive >= self.failure_threshold.get() {
+            warn!(
+                "Pinecone failed {} times in a row, failing calls fast for {:?}",
+                failures.consecutive, self.cooldown
+            );
+            failures.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_breaker_opens_fails_fast_and_recovers() {
+        let breaker = CircuitBreaker::new(NonZeroUsize::new(2).unwrap(), Duration::from_millis(50));
+        let calls = AtomicUsize::new(0);
+        let failing = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(anyhow!("Pinecone is down"))
+        };
+
+        // Typed errors are not failures of Pinecone
+        let invalid = breaker
+            .call(async { Err::<(), _>(RagError::InvalidInput("bad".to_string()).into()) })
+            .await;
+        assert!(invalid.is_err());
+        assert!(breaker.call(failing()).await.is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.call(failing()).await.is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // Open, calls fail fast without reaching Pinecone
+        let error = breaker.call(failing()).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RagError>(),
+            Some(RagError::Unavailable(_))
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Half-open, a failing probe opens the breaker again
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.call(failing()).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // A succeeding probe closes it
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(breaker.call(async { Ok(42) }).await.unwrap(), 42);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.call(failing()).await.is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
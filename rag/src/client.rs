@@ -1,17 +1,127 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::DateTime;
+use futures::future::{join_all, try_join_all};
+use lru::LruCache;
 use pinecone_sdk::{
-    models::{Cloud, DeletionProtection, Kind, Metadata, Metric, Value, Vector, WaitPolicy},
+    models::{
+        Cloud, DeletionProtection, DescribeIndexStatsResponse, FetchResponse, Kind, Metadata,
+        Metric, Namespace, Value, Vector, WaitPolicy,
+    },
     pinecone::{PineconeClient, PineconeClientConfig},
+    utils::errors::PineconeError,
 };
+use prost_types::ListValue;
 use reqwest::Client;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
-use tracing::{debug, error, info, info_span, instrument, Span};
+use tracing::{debug, error, info, info_span, instrument, warn, Span};
+
+use crate::{
+    circuit_breaker::CircuitBreaker,
+    error::RagError,
+    math::l2_norm,
+    metadata::{from_sdk_metadata, to_sdk_metadata, MetadataFields, MetadataFilter, MetadataValue},
+    model_guard::MODEL_MARKER_NAMESPACE,
+    rank::{bm25_scores, reciprocal_rank_fusion},
+    request_id::{current_request_id, REQUEST_ID_HEADER},
+    split_criteria::{Chunk, SplitCriteria},
+    tokens::TokenCounter,
+    types::{QueryResponse, TextToEmbed},
+};
+
+/// Default Pinecone namespace embeddings are stored in and queried from.
+pub const CURRENT_NAME_SPACE: &str = "atoma-alpha-namespace";
+/// Maximum number of characters of a response body included in embedding errors.
+const MAX_BODY_SNIPPET_CHARS: usize = 200;
+/// Default number of results returned by queries.
+pub const DEFAULT_TOP_K: u32 = 10;
+/// Maximum number of results Pinecone returns for a single query.
+pub const MAX_TOP_K: u32 = 10_000;
+/// Default name of the JSON field holding the text in embedding requests, as expected by TEI.
+pub const DEFAULT_INPUT_FIELD: &str = "inputs";
+/// Default name of the JSON field holding the image URL in multimodal embedding requests.
+pub const DEFAULT_IMAGE_FIELD: &str = "image_url";
+/// Maximum dimension of a Pinecone index.
+pub const MAX_INDEX_DIMENSION: i32 = 20_000;
+/// Interval between two readiness checks when waiting for an index to be ready.
+const INDEX_READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Maximum length of a Pinecone index name.
+pub const MAX_INDEX_NAME_LENGTH: usize = 45;
+/// Default reciprocal rank fusion constant, as in the original RRF paper.
+pub const DEFAULT_RRF_K: f32 = 60.0;
+/// Number of dense candidates fetched per requested result by hybrid queries, to be rescored with BM25.
+const RRF_CANDIDATES_PER_RESULT: u32 = 4;
+/// Default maximum number of vectors sent to Pinecone in a single upsert request.
+pub const DEFAULT_UPSERT_BATCH_SIZE: usize = 100;
+/// Number of vector ids listed per page by `list_chunks`, the maximum Pinecone allows.
+const LIST_PAGE_SIZE: u32 = 100;
+/// Suffix of the namespace the raw documents of a namespace are stored in, see `documents_namespace`.
+pub const DOCUMENTS_NAMESPACE_SUFFIX: &str = "__documents";
+/// Maximum size in bytes of a raw document, within the 40KB of metadata Pinecone stores per
+/// vector along with the other fields of the document.
+pub const MAX_RAW_DOCUMENT_BYTES: usize = 32 * 1024;
+
+/// The kind of text being embedded, which determines the prefix applied to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmbeddingKind {
+    /// A document chunk, to be stored in the index.
+    Document,
+    /// A query text, to be searched for in the index.
+    Query,
+}
+
+/// What to do with texts exceeding the maximum sequence length of the embedding model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowMode {
+    /// Truncate the text to the maximum sequence length, logging a warning.
+    Truncate,
+    /// Reject the text with a `RagError::InvalidInput`.
+    Error,
+}
 
-use crate::types::QueryResponse;
+/// Guards the embedding model against texts longer than its maximum sequence length.
+///
+/// Embedding models silently truncate longer texts, producing misleading embeddings. This
+/// limit is distinct from the split `max_tokens`, so documents can be split in larger chunks
+/// while still being guarded at the model limit.
+#[derive(Clone)]
+pub struct SequenceGuard {
+    /// Tokenizer of the embedding model.
+    pub tokenizer: Arc<dyn TokenCounter>,
+    /// Maximum number of tokens the embedding model accepts, including special tokens.
+    pub max_sequence_tokens: usize,
+    /// What to do with texts exceeding `max_sequence_tokens`.
+    pub mode: OverflowMode,
+}
+
+/// Hook applied to the results of `EmbeddingClient::query` before they are returned, to
+/// apply custom business logic, e.g. boosting results by their metadata or dropping the
+/// results of blocked authors.
+///
+/// Closures taking and returning the results implement it.
+pub trait ResultPostProcessor: Send + Sync {
+    /// Returns the results to return in place of `results`, in their new order.
+    fn process(&self, results: Vec<QueryResponse>) -> Vec<QueryResponse>;
+}
 
-const CURRENT_NAME_SPACE: &str = "atoma-alpha-namespace";
+impl<F> ResultPostProcessor for F
+where
+    F: Fn(Vec<QueryResponse>) -> Vec<QueryResponse> + Send + Sync,
+{
+    fn process(&self, results: Vec<QueryResponse>) -> Vec<QueryResponse> {
+        self(results)
+    }
+}
 
 /// A client for managing embeddings and interacting with Pinecone vector database.
 ///
@@ -20,6 +130,10 @@ const CURRENT_NAME_SPACE: &str = "atoma-alpha-namespace";
 pub struct EmbeddingClient {
     /// Counter for generating unique IDs for stored embeddings.
     pub counter: usize,
+    /// Optional path of the file the counter is persisted to, so ids survive restarts.
+    pub counter_file: Option<PathBuf>,
+    /// Optional prefix prepended to generated ids, e.g. `"tweet-"`, unless the document sets its own.
+    pub id_prefix: Option<String>,
     /// HTTP client for making requests to the embedding service.
     pub embedding_client: Client,
     /// Client for interacting with the Pinecone API.
@@ -30,6 +144,40 @@ pub struct EmbeddingClient {
     pub embedding_host: String,
     /// Port number of the embedding service.
     pub embedding_port: u16,
+    /// Optional base URL of the embedding service, e.g. `https://tei.example.com/v1`, used
+    /// instead of the host and port when set.
+    pub embedding_url: Option<String>,
+    /// Name of the JSON field holding the text in embedding requests, e.g. `inputs` for TEI.
+    pub input_field: String,
+    /// Name of the JSON field holding the image URL in multimodal embedding requests.
+    pub image_field: String,
+    /// Encoding of the embeddings requested from the embedding service.
+    pub embedding_encoding: EmbeddingEncoding,
+    /// Optional prefix prepended to document chunks before embedding them.
+    pub document_prefix: Option<String>,
+    /// Optional prefix prepended to query texts before embedding them.
+    pub query_prefix: Option<String>,
+    /// Cache of similarity metrics for indexes, keyed by index name, filled by `create_index`
+    /// and by the first `index_metric` lookup of other indexes.
+    pub index_metrics: Mutex<HashMap<String, Metric>>,
+    /// Cache of dimensions of indexes, keyed by index name, filled along with `index_metrics`.
+    pub index_dimensions: Mutex<HashMap<String, i32>>,
+    /// Names of the indexes known to exist, to avoid redundant `list_indexes` calls.
+    pub known_indexes: HashSet<String>,
+    /// Optional guard against texts longer than the maximum sequence length of the embedding model.
+    pub sequence_guard: Option<SequenceGuard>,
+    /// Optional LRU cache of embeddings, keyed by the prefixed text sent to the embedding service.
+    pub embedding_cache: Option<Mutex<LruCache<String, Vec<Vec<f32>>>>>,
+    /// Constant `k` of the reciprocal rank fusion used by hybrid queries.
+    pub rrf_k: f32,
+    /// Maximum number of vectors sent to Pinecone in a single upsert request.
+    pub upsert_batch_size: usize,
+    /// Pinecone namespace used by requests not naming one.
+    pub namespace: String,
+    /// Optional hook applied to the results of `query` and `query_with_vector`.
+    pub result_post_processor: Option<Arc<dyn ResultPostProcessor>>,
+    /// Optional circuit breaker the calls to Pinecone go through, see `EmbeddingStore`.
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
     /// Tracing span for logging and debugging.
     pub span: Span,
 }
@@ -66,15 +214,287 @@ impl EmbeddingClient {
                 return Err(anyhow::anyhow!("Failed to list indexes: {}", e));
             }
         };
-        Ok(Self {
+        Ok(Self::from_parts(
+            embedding_host,
+            embedding_port,
+            pinecone_client,
+            pinecone_host,
+            cloned_span,
+        ))
+    }
+
+    /// Constructor of a client without a Pinecone account, used as the embedder of an
+    /// `InMemoryStore`.
+    ///
+    /// No request is sent to Pinecone, and the client has no API key, so its Pinecone
+    /// methods fail.
+    pub fn without_pinecone(embedding_host: String, embedding_port: u16) -> Result<Self> {
+        let config = PineconeClientConfig {
+            api_key: Some(String::new()),
+            ..Default::default()
+        };
+        let pinecone_client = config.client()?;
+        Ok(Self::from_parts(
+            embedding_host,
+            embedding_port,
+            pinecone_client,
+            String::new(),
+            info_span!("embedding_client"),
+        ))
+    }
+
+    /// Builds a client with the default configuration.
+    fn from_parts(
+        embedding_host: String,
+        embedding_port: u16,
+        pinecone_client: PineconeClient,
+        pinecone_host: String,
+        span: Span,
+    ) -> Self {
+        Self {
             counter: 0,
+            counter_file: None,
+            id_prefix: None,
             embedding_client: Client::new(),
             pinecone_client,
             pinecone_host,
             embedding_host,
             embedding_port,
-            span: cloned_span,
-        })
+            embedding_url: None,
+            input_field: DEFAULT_INPUT_FIELD.to_string(),
+            image_field: DEFAULT_IMAGE_FIELD.to_string(),
+            embedding_encoding: EmbeddingEncoding::default(),
+            document_prefix: None,
+            query_prefix: None,
+            index_metrics: Mutex::new(HashMap::new()),
+            index_dimensions: Mutex::new(HashMap::new()),
+            known_indexes: HashSet::new(),
+            sequence_guard: None,
+            embedding_cache: None,
+            rrf_k: DEFAULT_RRF_K,
+            upsert_batch_size: DEFAULT_UPSERT_BATCH_SIZE,
+            namespace: CURRENT_NAME_SPACE.to_string(),
+            result_post_processor: None,
+            circuit_breaker: None,
+            span,
+        }
+    }
+
+    /// Sets the prefixes prepended to document chunks and query texts before embedding them.
+    ///
+    /// Instruction tuned embedding models expect such templates, e.g. `"search_document: "`
+    /// and `"search_query: "`. The prefixes are only sent to the embedding service, the
+    /// text stored in the index metadata is left untouched.
+    pub fn with_prefixes(
+        mut self,
+        document_prefix: Option<String>,
+        query_prefix: Option<String>,
+    ) -> Self {
+        self.document_prefix = document_prefix;
+        self.query_prefix = query_prefix;
+        self
+    }
+
+    /// Sets the base URL of the embedding service, to which `/embed` is appended.
+    ///
+    /// Unlike the host and port, which are always reached over plain HTTP at the root path,
+    /// a base URL can use `https://` and hold a path prefix. Passing `None` falls back to the
+    /// host and port.
+    pub fn with_embedding_url(mut self, embedding_url: Option<String>) -> Self {
+        self.embedding_url = embedding_url;
+        self
+    }
+
+    /// Returns the URL embedding requests are posted to.
+    pub fn embed_url(&self) -> String {
+        match &self.embedding_url {
+            Some(url) => format!("{}/embed", url.trim_end_matches('/')),
+            None => format!(
+                "http://{}:{}/embed",
+                self.embedding_host, self.embedding_port
+            ),
+        }
+    }
+
+    /// Sets the name of the JSON field holding the text in embedding requests.
+    ///
+    /// Defaults to `DEFAULT_INPUT_FIELD`, as expected by text-embeddings-inference. Other
+    /// embedding servers, e.g. OpenAI compatible ones, expect `input` instead.
+    pub fn with_input_field(mut self, input_field: impl Into<String>) -> Self {
+        self.input_field = input_field.into();
+        self
+    }
+
+    /// Sets the name of the JSON field holding the image URL in multimodal embedding requests.
+    ///
+    /// Defaults to `DEFAULT_IMAGE_FIELD`, multimodal backends expecting another field name
+    /// can be configured to receive it there.
+    pub fn with_image_field(mut self, image_field: impl Into<String>) -> Self {
+        self.image_field = image_field.into();
+        self
+    }
+
+    /// Sets the encoding of the embeddings requested from the embedding service.
+    ///
+    /// Defaults to `EmbeddingEncoding::Float`. Base64 encoded embeddings are about half the
+    /// size of JSON floats, but are only returned by servers supporting `encoding_format`.
+    pub fn with_embedding_encoding(mut self, embedding_encoding: EmbeddingEncoding) -> Self {
+        self.embedding_encoding = embedding_encoding;
+        self
+    }
+
+    /// Builds the JSON body of an embedding request.
+    ///
+    /// The text is sent in the `input_field`, and the image URL, if any, in the `image_field`,
+    /// so text-only requests keep the shape expected by text embedding servers. Base64
+    /// encoded embeddings are requested with an `encoding_format` field.
+    pub fn embedding_request(
+        &self,
+        input_text: &str,
+        image_url: Option<&str>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        self.request_body(json!(input_text), image_url)
+    }
+
+    /// Builds the JSON body of an embedding request, whose `input_field` holds either a text
+    /// or an array of texts.
+    fn request_body(
+        &self,
+        input_texts: serde_json::Value,
+        image_url: Option<&str>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let mut input = serde_json::Map::new();
+        input.insert(self.input_field.clone(), input_texts);
+        if let Some(image_url) = image_url {
+            input.insert(self.image_field.clone(), json!(image_url));
+        }
+        if self.embedding_encoding == EmbeddingEncoding::Base64 {
+            input.insert("encoding_format".to_string(), json!("base64"));
+        }
+        input
+    }
+
+    /// Sets the guard against texts longer than the maximum sequence length of the embedding model.
+    pub fn with_sequence_guard(mut self, sequence_guard: Option<SequenceGuard>) -> Self {
+        self.sequence_guard = sequence_guard;
+        self
+    }
+
+    /// Enables an in-memory LRU cache of embeddings holding up to `capacity` entries.
+    ///
+    /// Re-indexing overlapping documents embeds the same chunks repeatedly, a cache hit
+    /// skips the request to the embedding service. Passing `None` disables the cache.
+    pub fn with_embedding_cache(mut self, capacity: Option<NonZeroUsize>) -> Self {
+        self.embedding_cache = capacity.map(|capacity| Mutex::new(LruCache::new(capacity)));
+        self
+    }
+
+    /// Sets the constant `k` of the reciprocal rank fusion used by `query_rrf`.
+    pub fn with_rrf_k(mut self, rrf_k: f32) -> Self {
+        self.rrf_k = rrf_k;
+        self
+    }
+
+    /// Sets the hook applied to the results of `query` and `query_with_vector` before they
+    /// are returned, after the score threshold.
+    ///
+    /// The hook also sees the dense candidates of `query_rrf`, before their fusion. Passing
+    /// `None` removes it.
+    pub fn with_result_post_processor(
+        mut self,
+        result_post_processor: Option<Arc<dyn ResultPostProcessor>>,
+    ) -> Self {
+        self.result_post_processor = result_post_processor;
+        self
+    }
+
+    /// Applies the `result_post_processor`, if any, to query results.
+    pub fn post_process(&self, results: Vec<QueryResponse>) -> Vec<QueryResponse> {
+        match &self.result_post_processor {
+            Some(post_processor) => post_processor.process(results),
+            None => results,
+        }
+    }
+
+    /// Sets the circuit breaker failing the calls to Pinecone fast during outages, disabled
+    /// by default.
+    ///
+    /// The breaker only guards the calls going through the `EmbeddingStore` implementation
+    /// of the client, the calls to the embedding service alone are never failed fast.
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Option<CircuitBreaker>) -> Self {
+        self.circuit_breaker = circuit_breaker.map(Arc::new);
+        self
+    }
+
+    /// Sets the maximum number of vectors sent to Pinecone in a single upsert request,
+    /// at least 1.
+    pub fn with_upsert_batch_size(mut self, upsert_batch_size: usize) -> Self {
+        self.upsert_batch_size = upsert_batch_size.max(1);
+        self
+    }
+
+    /// Rebuilds the HTTP client sending requests to the embedding service with the given
+    /// connection handling.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the HTTP client cannot be built, see `HttpOptions::build_client`.
+    pub fn with_http_options(mut self, options: &HttpOptions) -> Result<Self> {
+        self.embedding_client = options.build_client()?;
+        Ok(self)
+    }
+
+    /// Sets the Pinecone namespace used by requests not naming one, `CURRENT_NAME_SPACE` by default.
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    /// Returns the given namespace, or else the default namespace of the client.
+    pub fn namespace_or_default<'a>(&'a self, namespace: Option<&'a str>) -> &'a str {
+        namespace.unwrap_or(&self.namespace)
+    }
+
+    /// Sets the file the id counter is persisted to, and loads the counter from it.
+    ///
+    /// Without a counter file, the counter starts at 0 on every start, so new embeddings
+    /// silently overwrite the ones stored by a previous run. If the file does not exist
+    /// yet, the counter starts at 0 and the file is created on the first upsert.
+    pub fn with_counter_file(mut self, counter_file: Option<PathBuf>) -> Self {
+        if let Some(path) = &counter_file {
+            match fs::read_to_string(path) {
+                Ok(contents) => match contents.trim().parse() {
+                    Ok(counter) => {
+                        info!("Loaded counter {} from {}", counter, path.display());
+                        self.counter = counter;
+                    }
+                    Err(e) => warn!("Invalid counter file {}: {}", path.display(), e),
+                },
+                Err(e) => info!("No counter loaded from {}: {}", path.display(), e),
+            }
+        }
+        self.counter_file = counter_file;
+        self
+    }
+
+    /// Sets the prefix prepended to generated ids, e.g. `"tweet-"` or `"doc-"`.
+    ///
+    /// When several sources write to the same index, the prefix tells their vectors apart
+    /// and keeps their ids from colliding. Documents may override it with their own `id_prefix`.
+    pub fn with_id_prefix(mut self, id_prefix: Option<String>) -> Self {
+        self.id_prefix = id_prefix;
+        self
+    }
+
+    /// Writes the current counter to the counter file, if any.
+    ///
+    /// This is best-effort: a failed write is logged and otherwise ignored.
+    fn persist_counter(&self) {
+        if let Some(path) = &self.counter_file {
+            if let Err(e) = fs::write(path, self.counter.to_string()) {
+                warn!("Failed to persist counter to {}: {}", path.display(), e);
+            }
+        }
     }
 
     /// Creates an embedding for the given text using the embedding service.
@@ -82,6 +502,8 @@ impl EmbeddingClient {
     /// # Arguments
     ///
     /// * `text` - The input text to be embedded.
+    /// * `kind` - Whether the text is a document chunk or a query, which selects the
+    ///   prefix prepended to the text, if any.
     ///
     /// # Returns
     ///
@@ -91,23 +513,145 @@ impl EmbeddingClient {
     /// # Errors
     ///
     /// This function will return an error if:
+    /// - The text exceeds the maximum sequence length of the sequence guard, in `OverflowMode::Error`.
     /// - The HTTP request to the embedding service fails.
+    /// - The embedding service responds with an error status, or a body which is not an
+    ///   embedding (`RagError::Embedding`, holding a snippet of the body).
     /// - The response cannot be parsed as a vector of f32 values.
     #[instrument(skip_all)]
-    pub async fn create_embedding(&self, text: &str) -> Result<Vec<Vec<f32>>> {
+    pub async fn create_embedding(&self, text: &str, kind: EmbeddingKind) -> Result<Vec<Vec<f32>>> {
+        self.create_embedding_with_image(text, None, kind).await
+    }
+
+    /// Creates an embedding for the given text and optional image, using a multimodal
+    /// embedding service.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The input text to be embedded.
+    /// * `image_url` - Optional URL of an image embedded along with the text.
+    /// * `kind` - Whether the text is a document chunk or a query.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error as `create_embedding`.
+    ///
+    /// # Notes
+    ///
+    /// Without an image, this is `create_embedding`. Embeddings of requests with an image are
+    /// not cached, as the cache is keyed by text only.
+    #[instrument(skip_all)]
+    pub async fn create_embedding_with_image(
+        &self,
+        text: &str,
+        image_url: Option<&str>,
+        kind: EmbeddingKind,
+    ) -> Result<Vec<Vec<f32>>> {
+        let _enter = self.span.enter();
+        let input_text = self.prepare_input(text, kind)?;
+        // The cache is keyed by the prefixed text, so document and query embeddings never collide
+        let cache = self
+            .embedding_cache
+            .as_ref()
+            .filter(|_| image_url.is_none());
+        if let Some(cache) = cache {
+            if let Some(embedding) = cache.lock().unwrap().get(&input_text) {
+                info!("Embedding cache hit");
+                return Ok(embedding.clone());
+            }
+        }
+        let input = self.embedding_request(&input_text, image_url);
+        let embedding = self.post_embedding_request(&input, text).await?;
+        info!("Embedding: {:?}", embedding);
+        if let Some(cache) = cache {
+            cache.lock().unwrap().put(input_text, embedding.clone());
+        }
+        Ok(embedding)
+    }
+
+    /// Creates the embeddings of several texts with a single request to the embedding service.
+    ///
+    /// # Arguments
+    ///
+    /// * `texts` - The input texts to be embedded, sent as an array in the `input_field`.
+    /// * `kind` - Whether the texts are document chunks or queries.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing one embedding per text, in the order of the texts.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error as `create_embedding`, or if the embedding service
+    /// does not return one embedding per text.
+    ///
+    /// # Notes
+    ///
+    /// The embedding cache is bypassed, as it holds the embeddings of single texts.
+    #[instrument(skip_all)]
+    pub async fn create_embeddings(
+        &self,
+        texts: &[String],
+        kind: EmbeddingKind,
+    ) -> Result<Vec<Vec<f32>>> {
         let _enter = self.span.enter();
-        let input = json!({ "inputs": text });
+        let input_texts = texts
+            .iter()
+            .map(|text| self.prepare_input(text, kind))
+            .collect::<Result<Vec<_>>>()?;
+        let input = self.request_body(json!(input_texts), None);
+        let embeddings = self
+            .post_embedding_request(&input, &format!("{} texts", texts.len()))
+            .await?;
+        if embeddings.len() != texts.len() {
+            error!(
+                "Embedding service returned {} embeddings for {} texts",
+                embeddings.len(),
+                texts.len()
+            );
+            return Err(RagError::Embedding {
+                message: format!(
+                    "expected {} embeddings, got {}",
+                    texts.len(),
+                    embeddings.len()
+                ),
+                body: String::new(),
+            }
+            .into());
+        }
+        Ok(embeddings)
+    }
+
+    /// Prepends the prefix of the kind of text, if any, and applies the sequence guard.
+    fn prepare_input(&self, text: &str, kind: EmbeddingKind) -> Result<String> {
+        let prefix = match kind {
+            EmbeddingKind::Document => self.document_prefix.as_deref(),
+            EmbeddingKind::Query => self.query_prefix.as_deref(),
+        };
+        let input_text = match prefix {
+            Some(prefix) => format!("{}{}", prefix, text),
+            None => text.to_string(),
+        };
+        match &self.sequence_guard {
+            Some(guard) => guard_sequence(input_text, guard),
+            None => Ok(input_text),
+        }
+    }
+
+    /// Posts an embedding request, returning the batch of embeddings of the response.
+    ///
+    /// `text` only describes the request in the logs.
+    async fn post_embedding_request(
+        &self,
+        input: &serde_json::Map<String, serde_json::Value>,
+        text: &str,
+    ) -> Result<Vec<Vec<f32>>> {
         info!("Posting to embedding client");
-        let response = match self
-            .embedding_client
-            .post(format!(
-                "http://{}:{}/embed",
-                self.embedding_host, self.embedding_port
-            ))
-            .json(&input)
-            .send()
-            .await
-        {
+        let mut request = self.embedding_client.post(self.embed_url()).json(input);
+        if let Some(request_id) = current_request_id() {
+            request = request.header(REQUEST_ID_HEADER, request_id);
+        }
+        let response = match request.send().await {
             Ok(res) => res,
             Err(e) => {
                 error!("Error posting to embedding client: {:?}", e);
@@ -118,15 +662,33 @@ impl EmbeddingClient {
             }
         };
         debug!("Response: {:?} for text = {}", response, text);
-        let embedding = match response.json::<Vec<Vec<f32>>>().await {
-            Ok(embedding) => embedding,
+        let status = response.status();
+        let body = match response.text().await {
+            Ok(body) => body,
             Err(e) => {
-                error!("Error parsing embedding: {:?}", e);
-                return Err(anyhow::anyhow!("Error parsing embedding: {:?}", e));
+                error!("Error reading embedding response: {:?}", e);
+                return Err(anyhow::anyhow!("Error reading embedding response: {:?}", e));
             }
         };
-        info!("Embedding: {:?}", embedding);
-        Ok(embedding)
+        if !status.is_success() {
+            error!("Embedding service responded with status {}", status);
+            return Err(RagError::Embedding {
+                message: format!("status {}", status),
+                body: body_snippet(&body),
+            }
+            .into());
+        }
+        match serde_json::from_str::<EmbeddingResponse>(&body) {
+            Ok(embedding) => Ok(embedding.into_embeddings()),
+            Err(e) => {
+                error!("Error parsing embedding: {:?}", e);
+                Err(RagError::Embedding {
+                    message: format!("invalid response: {}", e),
+                    body: body_snippet(&body),
+                }
+                .into())
+            }
+        }
     }
 
     /// Stores an embedding in the specified Pinecone index.
@@ -136,6 +698,8 @@ impl EmbeddingClient {
     /// * `original_text` - The original text associated with the embedding.
     /// * `embedding` - The vector representation of the text to be stored.
     /// * `index_name` - The name of the Pinecone index to store the embedding in.
+    /// * `document` - Optional document the embedding belongs to, whose fields are stored alongside the text.
+    /// * `split` - Optional criteria the document was split with, stored for reproducibility.
     ///
     /// # Returns
     ///
@@ -144,144 +708,219 @@ impl EmbeddingClient {
     /// # Errors
     ///
     /// This function will return an error if:
+    /// - The extra metadata holds values Pinecone cannot store (see `build_metadata`).
     /// - The Pinecone index cannot be retrieved.
     /// - The upsert operation to the Pinecone index fails.
     ///
     /// # Notes
     ///
-    /// This method increments an internal counter to generate unique IDs for each stored embedding.
-    /// The embedding is stored with metadata containing the original text.
+    /// This method increments an internal counter to generate unique IDs for each stored embedding,
+    /// prefixed with the `id_prefix` of the document or else of the client (see `vector_id`).
+    /// The counter is persisted to the counter file, if any, after each successful upsert.
+    /// The embedding is stored with metadata built by `build_metadata` from the original text and the document.
     #[instrument(skip_all)]
     pub async fn store_embedding(
         &mut self,
         host: &str,
-        original_text: String,
+        index_name: &str,
+        chunk: Chunk,
         embedding: Vec<Vec<f32>>,
+        document: Option<&TextToEmbed>,
+        split: Option<&SplitCriteria>,
     ) -> Result<()> {
-        let _enter = self.span.enter();
-        info!("Storing embedding");
-        let mut index = self.pinecone_client.index(host).await?;
-        let metadata: Metadata = Metadata {
-            fields: BTreeMap::from_iter(vec![(
-                "text".to_string(),
-                Value {
-                    kind: Some(Kind::StringValue(original_text)),
-                },
-            )]),
-        };
-        let vector = Vector {
-            id: format!("{}", self.counter),
-            values: embedding.into_iter().flatten().collect(),
-            sparse_values: None,
-            metadata: Some(metadata),
-        };
-        match index.upsert(&[vector], &"".into()).await {
-            Ok(result) => {
-                info!(
-                    "Response successful, with insertions: {:?}",
-                    result.upserted_count
-                );
-                self.counter += 1;
-                Ok(())
-            }
-            Err(e) => {
-                error!("Error storing embedding: {:?}", e);
-                Err(anyhow::anyhow!("Error storing embedding: {:?}", e))
-            }
-        }
+        self.store_embeddings(host, index_name, vec![(chunk, embedding)], document, split)
+            .await
+            .map(|_| ())
     }
 
-    /// Creates a new serverless index in Pinecone.
+    /// Stores several embeddings in the specified Pinecone index, in batches.
     ///
     /// # Arguments
     ///
-    /// * `index_name` - The name of the index to create.
-    /// * `dimension` - The dimension of the vectors to be stored in the index.
-    /// * `metric` - Optional similarity metric to use. Defaults to Cosine similarity if not provided.
+    /// * `host` - The host of the Pinecone index.
+    /// * `index_name` - The name of the Pinecone index behind the host, described again if an
+    ///   upsert fails as if the index was recreated.
+    /// * `embeddings` - The chunk and vector representation of each embedding.
+    /// * `document` - Optional document the embeddings belong to, whose fields are stored alongside
+    ///   the texts. Its `namespace`, if any, overrides the default namespace of the client.
+    /// * `split` - Optional criteria the document was split with, stored for reproducibility.
     ///
     /// # Returns
     ///
-    /// Returns `Ok(())` if the index is successfully created, or an `Err` if an error occurs.
+    /// Returns the number of vectors upserted, summed over the batches.
     ///
     /// # Errors
     ///
     /// This function will return an error if:
-    /// - The Pinecone API request fails.
-    /// - There's an issue with creating the serverless index.
+    /// - The extra metadata holds values Pinecone cannot store (see `build_metadata`).
+    /// - The Pinecone index cannot be retrieved.
+    /// - An upsert operation fails, reporting how many embeddings were stored before it.
+    /// - An upsert fails as if the index was recreated, and the refreshed dimension of the
+    ///   index still does not match the vectors (`RagError::InvalidInput`).
     ///
     /// # Notes
     ///
-    /// - The index is created in the AWS us-east-1 region.
-    /// - Deletion protection is enabled for the created index.
-    /// - The function uses a no-wait policy, meaning it returns immediately after initiating index creation.
+    /// An upsert failing with a dimension mismatch or a not found error may come from an index
+    /// deleted and recreated behind the client's back, so the cached description of the index
+    /// is refreshed once with `describe_index`, and the upsert retried.
+    ///
+    /// Pinecone limits the number of vectors and the size of upsert requests, so the
+    /// embeddings are sent in sequential batches of at most `upsert_batch_size` vectors
+    /// (see `upsert_batches`). Ids are generated from the counter as by `store_embedding`,
+    /// and the counter is persisted after each successful batch, so the embeddings stored
+    /// before a failed batch keep their ids.
     #[instrument(skip_all)]
-    pub async fn create_index(
+    pub async fn store_embeddings(
         &mut self,
+        host: &str,
         index_name: &str,
-        dimension: i32,
-        metric: Option<Metric>,
+        embeddings: Vec<(Chunk, Vec<Vec<f32>>)>,
+        document: Option<&TextToEmbed>,
+        split: Option<&SplitCriteria>,
+    ) -> Result<usize> {
+        // NOTE: Entered on a clone, as the caches may be refreshed while storing
+        let span = self.span.clone();
+        let _enter = span.enter();
+        info!("Storing {} embeddings", embeddings.len());
+        let id_prefix = document
+            .and_then(|document| document.id_prefix.as_deref())
+            .or(self.id_prefix.as_deref());
+        let mut vectors = Vec::with_capacity(embeddings.len());
+        for (i, (chunk, embedding)) in embeddings.into_iter().enumerate() {
+            vectors.push(Vector {
+                id: vector_id(id_prefix, self.counter + i),
+                values: embedding.into_iter().flatten().collect(),
+                sparse_values: None,
+                metadata: Some(to_sdk_metadata(chunk_metadata(&chunk, document, split)?)),
+            });
+        }
+        let total = vectors.len();
+        let namespace = self
+            .namespace_or_default(document.and_then(|document| document.namespace.as_deref()))
+            .to_string();
+        let dimension = vectors.first().map(|vector| vector.values.len());
+        let mut index = self.pinecone_client.index(host).await?;
+        let mut stored = 0;
+        let mut upserted = 0;
+        let mut refreshed = false;
+        for batch in upsert_batches(vectors, self.upsert_batch_size) {
+            let result = match index.upsert(&batch, &namespace.as_str().into()).await {
+                // NOTE: Only retried once, so that a persistent mismatch is not masked
+                Err(e) if !refreshed && is_stale_index_error(&e) => {
+                    warn!(
+                        "Index {} may have been recreated, retrying: {:?}",
+                        index_name, e
+                    );
+                    refreshed = true;
+                    if let Some(dimension) = dimension {
+                        self.refresh_dimension(index_name, dimension).await?;
+                    }
+                    index = self.pinecone_client.index(host).await?;
+                    index.upsert(&batch, &namespace.as_str().into()).await
+                }
+                result => result,
+            };
+            match result {
+                Ok(result) => {
+                    info!(
+                        "Response successful, with insertions: {:?}",
+                        result.upserted_count
+                    );
+                    stored += batch.len();
+                    upserted += result.upserted_count as usize;
+                    self.counter += batch.len();
+                    self.persist_counter();
+                }
+                Err(e) => {
+                    error!(
+                        "Error storing embeddings, after storing {} of {}: {:?}",
+                        stored, total, e
+                    );
+                    return Err(anyhow::anyhow!(
+                        "Error storing embeddings, after storing {} of {}: {:?}",
+                        stored,
+                        total,
+                        e
+                    ));
+                }
+            }
+        }
+        Ok(upserted)
+    }
+
+    /// Deletes all the embeddings stored for the given query id.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The host of the Pinecone index.
+    /// * `query_id` - The id of the document whose embeddings are deleted.
+    /// * `namespace` - Optional namespace to delete from, instead of the default one of the client.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The Pinecone index cannot be retrieved.
+    /// - The delete operation on the Pinecone index fails.
+    ///
+    /// # Notes
+    ///
+    /// This relies on the `query_id` metadata field, so embeddings stored before it was
+    /// added to the metadata are not deleted.
+    #[instrument(skip_all)]
+    pub async fn delete_by_query_id(
+        &self,
+        host: &str,
+        query_id: &str,
+        namespace: Option<&str>,
     ) -> Result<()> {
         let _enter = self.span.enter();
-        info!("Creating index");
-        let region = "us-east-1";
-        let metric = metric.unwrap_or(Metric::Cosine);
-        match self
-            .pinecone_client
-            .create_serverless_index(
-                index_name,
-                dimension,
-                metric,
-                Cloud::Aws,
-                region,
-                DeletionProtection::Enabled,
-                WaitPolicy::NoWait,
-            )
+        info!("Deleting embeddings for query with id: {}", query_id);
+        let namespace = self.namespace_or_default(namespace);
+        let mut index = self.pinecone_client.index(host).await?;
+        match index
+            .delete_by_filter(sdk_filter(query_id_filter(query_id)), &namespace.into())
             .await
         {
-            Ok(result) => {
-                info!("Index created: {:?}", result);
-                Ok(())
-            }
+            Ok(()) => Ok(()),
             Err(e) => {
-                error!("Error creating index: {:?}", e);
-                Err(anyhow::anyhow!("Error creating index: {:?}", e))
+                error!("Error deleting embeddings: {:?}", e);
+                Err(anyhow::anyhow!("Error deleting embeddings: {:?}", e))
             }
         }
     }
 
-    /// Queries the Pinecone index with a given input and returns the most similar results.
+    /// Deletes all the embeddings matching a Pinecone metadata filter.
     ///
     /// # Arguments
     ///
-    /// * `query` - The input text to query against the index.
-    /// * `index_name` - The name of the Pinecone index to query.
-    /// * `top_k` - Optional number of top results to return. Defaults to 10 if not specified.
-    ///
-    /// # Returns
-    ///
-    /// Returns a `Result` containing a vector of `QueryResponse` structs if successful.
-    /// Each `QueryResponse` contains the similarity score, embedding vector, and original text.
+    /// * `index_name` - The name of the Pinecone index to delete from.
+    /// * `filter` - The metadata filter, e.g. `{"author": {"$eq": "atoma"}}`.
+    /// * `confirm` - Whether deleting everything is intended, required for an empty filter.
+    /// * `namespace` - Optional namespace to delete from, instead of the default one of the client.
     ///
     /// # Errors
     ///
     /// This function will return an error if:
+    /// - The filter is not a JSON object, or is empty without `confirm` (`RagError::InvalidInput`).
     /// - The Pinecone index cannot be retrieved.
-    /// - Creating an embedding for the query fails.
-    /// - Querying the Pinecone index fails.
-    /// - The metadata in the response doesn't contain the expected text field.
+    /// - The delete operation on the Pinecone index fails.
     ///
-    /// # Panics
+    /// # Notes
     ///
-    /// This function will panic if the metadata in the response doesn't contain the expected text field.
+    /// An empty filter matches every embedding, so a confirmed empty filter deletes all the
+    /// embeddings of the namespace.
     #[instrument(skip_all)]
-    pub async fn query(
+    pub async fn delete_by_filter(
         &self,
-        query: &str,
         index_name: &str,
-        top_k: Option<u32>,
-    ) -> Result<Vec<QueryResponse>> {
+        filter: serde_json::Value,
+        confirm: bool,
+        namespace: Option<&str>,
+    ) -> Result<()> {
         let _enter = self.span.enter();
-        info!("Retrieving index");
+        let filter = delete_filter(&filter, confirm)?;
+        let namespace: Namespace = self.namespace_or_default(namespace).into();
+        info!("Deleting embeddings matching filter: {:?}", filter);
         let mut index = match self.pinecone_client.index(index_name).await {
             Ok(index) => index,
             Err(e) => {
@@ -289,50 +928,3651 @@ impl EmbeddingClient {
                 return Err(anyhow::anyhow!("Error retrieving index: {:?}", e));
             }
         };
-        let top_k = top_k.unwrap_or(10);
-        let query_vector = match self.create_embedding(query).await {
-            Ok(embedding) => embedding,
+        let result = match filter {
+            Some(filter) => index.delete_by_filter(sdk_filter(filter), &namespace).await,
+            None => index.delete_all(&namespace).await,
+        };
+        match result {
+            Ok(()) => Ok(()),
             Err(e) => {
-                error!("Error creating embedding: {:?}", e);
-                return Err(anyhow::anyhow!("Error creating embedding: {:?}", e));
+                error!("Error deleting embeddings: {:?}", e);
+                Err(anyhow::anyhow!("Error deleting embeddings: {:?}", e))
             }
-        };
-        let response = match index
-            .query_by_value(
-                query_vector.into_iter().flatten().collect(),
-                None,
-                top_k,
-                &CURRENT_NAME_SPACE.into(),
-                None,
-                None,
-                Some(true),
+        }
+    }
+
+    /// Creates a new serverless index in Pinecone.
+    ///
+    /// # Arguments
+    ///
+    /// * `index_name` - The name of the index to create.
+    /// * `dimension` - The dimension of the vectors to be stored in the index.
+    /// * `metric` - Optional similarity metric to use. Defaults to Cosine similarity if not provided.
+    /// * `wait_policy` - Whether to wait for the index to be ready before returning.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the index is successfully created, or an `Err` if an error occurs.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The dimension is not in `1..=MAX_INDEX_DIMENSION` (`RagError::InvalidInput`).
+    /// - An index with the same name already exists (`RagError::AlreadyExists`).
+    /// - The Pinecone API request fails.
+    /// - There's an issue with creating the serverless index.
+    /// - The wait policy is `WaitFor`, and the index is not ready before the timeout elapses.
+    ///
+    /// # Notes
+    ///
+    /// - The index is created in the AWS us-east-1 region.
+    /// - Deletion protection is enabled for the created index.
+    /// - With `WaitPolicy::NoWait`, the function returns immediately after initiating index creation,
+    ///   and the index may not be queryable yet.
+    #[instrument(skip_all)]
+    pub async fn create_index(
+        &mut self,
+        index_name: &str,
+        dimension: i32,
+        metric: Option<Metric>,
+        wait_policy: WaitPolicy,
+    ) -> Result<()> {
+        validate_index_name(index_name)?;
+        validate_dimension(dimension)?;
+        if self.index_exists(index_name).await? {
+            return Err(
+                RagError::AlreadyExists(format!("index {} already exists", index_name)).into(),
+            );
+        }
+        self.create_serverless_index(index_name, dimension, metric, wait_policy)
+            .await
+    }
+
+    /// Creates a new serverless index in Pinecone, without checking if it already exists.
+    async fn create_serverless_index(
+        &mut self,
+        index_name: &str,
+        dimension: i32,
+        metric: Option<Metric>,
+        wait_policy: WaitPolicy,
+    ) -> Result<()> {
+        let _enter = self.span.enter();
+        info!("Creating index");
+        let region = "us-east-1";
+        let metric = metric.unwrap_or(Metric::Cosine);
+        match self
+            .pinecone_client
+            .create_serverless_index(
+                index_name,
+                dimension,
+                metric,
+                Cloud::Aws,
+                region,
+                DeletionProtection::Enabled,
+                WaitPolicy::NoWait,
             )
             .await
         {
-            Ok(response) => response,
+            Ok(result) => {
+                info!("Index created: {:?}", result);
+                self.index_metrics
+                    .lock()
+                    .unwrap()
+                    .insert(index_name.to_string(), result.metric);
+                self.index_dimensions
+                    .lock()
+                    .unwrap()
+                    .insert(index_name.to_string(), result.dimension);
+            }
             Err(e) => {
-                error!("Error querying index: {:?}", e);
-                return Err(anyhow::anyhow!("Error querying index: {:?}", e));
+                error!("Error creating index: {:?}", e);
+                return Err(anyhow::anyhow!("Error creating index: {:?}", e));
             }
-        };
-        let query_response = response
-            .matches
-            .iter()
-            .map(|match_| {
-                let text = match match_.metadata.as_ref().unwrap().fields.get("text") {
-                    Some(Value {
-                        kind: Some(Kind::StringValue(text)),
-                        ..
-                    }) => text.to_string(),
-                    _ => panic!("No text found in metadata"),
-                };
-                QueryResponse {
-                    score: match_.score,
-                    embedding: match_.values.clone(),
-                    text,
+        }
+        if let WaitPolicy::WaitFor(timeout) = wait_policy {
+            self.wait_until_ready(index_name, timeout).await?;
+        }
+        Ok(())
+    }
+
+    /// Polls `describe_index` until the index is ready, or the timeout elapses.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the index cannot be described, or is not
+    /// ready before the timeout elapses.
+    async fn wait_until_ready(&self, index_name: &str, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            match self.pinecone_client.describe_index(index_name).await {
+                Ok(index) if index.status.ready => {
+                    info!("Index {} is ready", index_name);
+                    return Ok(());
                 }
-            })
-            .collect::<Vec<_>>();
-        Ok(query_response)
+                Ok(index) => debug!("Index {} is not ready: {:?}", index_name, index.status),
+                Err(e) => {
+                    error!("Error describing index: {:?}", e);
+                    return Err(anyhow::anyhow!("Error describing index: {:?}", e));
+                }
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                error!("Index {} not ready after {:?}", index_name, timeout);
+                return Err(anyhow::anyhow!(
+                    "Index {} not ready after {:?}",
+                    index_name,
+                    timeout
+                ));
+            }
+            tokio::time::sleep(INDEX_READY_POLL_INTERVAL.min(timeout - elapsed)).await;
+        }
+    }
+
+    /// Ensures the given index exists with the given dimension, creating it if it is missing.
+    ///
+    /// # Arguments
+    ///
+    /// * `index_name` - The name of the index to check.
+    /// * `dimension` - The dimension to use if the index has to be created.
+    /// * `metric` - Optional similarity metric to use if the index has to be created.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(true)` if the index was created, `Ok(false)` if it already existed with the
+    /// same dimension, so that repeated calls are idempotent.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if listing, describing or creating the index fails,
+    /// and a `RagError::AlreadyExists` if the index exists with another dimension.
+    ///
+    /// # Notes
+    ///
+    /// Existing indexes are remembered, so `list_indexes` is only called once per index, and
+    /// their dimension is cached as by `index_dimension`.
+    /// Since this method takes `&mut self`, callers sharing the client behind a mutex
+    /// are serialized, which prevents concurrent requests from both creating the index.
+    #[instrument(skip_all)]
+    pub async fn ensure_index(
+        &mut self,
+        index_name: &str,
+        dimension: i32,
+        metric: Option<Metric>,
+    ) -> Result<bool> {
+        let exists =
+            self.known_indexes.contains(index_name) || self.index_exists(index_name).await?;
+        if exists {
+            check_existing_dimension(
+                index_name,
+                self.index_dimension(index_name).await?,
+                dimension,
+            )?;
+        } else {
+            validate_index_name(index_name)?;
+            validate_dimension(dimension)?;
+            self.create_serverless_index(index_name, dimension, metric, WaitPolicy::NoWait)
+                .await?;
+        }
+        self.known_indexes.insert(index_name.to_string());
+        Ok(!exists)
+    }
+
+    /// Checks whether an index with the given name exists, with `list_indexes`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if listing the indexes fails.
+    async fn index_exists(&self, index_name: &str) -> Result<bool> {
+        let _enter = self.span.enter();
+        info!("Checking if index {} exists", index_name);
+        match self.pinecone_client.list_indexes().await {
+            Ok(indexes) => Ok(indexes
+                .indexes
+                .unwrap_or_default()
+                .iter()
+                .any(|index| index.name == index_name)),
+            Err(e) => {
+                error!("Failed to list indexes: {}", e);
+                Err(anyhow::anyhow!("Failed to list indexes: {}", e))
+            }
+        }
+    }
+
+    /// Retrieves the similarity metric of the given index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index_name` - The name of the Pinecone index.
+    ///
+    /// # Returns
+    ///
+    /// Returns the `Metric` the index was created with. The result is cached, by
+    /// `create_index` or else on the first lookup, so `describe_index` is called at most
+    /// once for each index, until the cache is refreshed (see `store_embeddings`). Queries
+    /// share the cache, e.g. to tell the direction of their score threshold.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the index cannot be described.
+    #[instrument(skip_all)]
+    pub async fn index_metric(&self, index_name: &str) -> Result<Metric> {
+        if let Some(metric) = self.index_metrics.lock().unwrap().get(index_name) {
+            return Ok(metric.clone());
+        }
+        self.describe_and_cache(index_name)
+            .await
+            .map(|(metric, _)| metric)
+    }
+
+    /// Retrieves the dimension of the given index, cached as by `index_metric`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the index cannot be described.
+    #[instrument(skip_all)]
+    pub async fn index_dimension(&self, index_name: &str) -> Result<i32> {
+        if let Some(dimension) = self.index_dimensions.lock().unwrap().get(index_name) {
+            return Ok(*dimension);
+        }
+        self.describe_and_cache(index_name)
+            .await
+            .map(|(_, dimension)| dimension)
+    }
+
+    /// Describes the given index, caching its metric and dimension.
+    async fn describe_and_cache(&self, index_name: &str) -> Result<(Metric, i32)> {
+        let _enter = self.span.enter();
+        info!("Describing index: {}", index_name);
+        // NOTE: The caches are not locked while describing, concurrent first lookups may both describe the index
+        let index = match self.pinecone_client.describe_index(index_name).await {
+            Ok(index) => index,
+            Err(e) => {
+                error!("Error describing index: {:?}", e);
+                return Err(anyhow::anyhow!("Error describing index: {:?}", e));
+            }
+        };
+        self.index_metrics
+            .lock()
+            .unwrap()
+            .insert(index_name.to_string(), index.metric.clone());
+        self.index_dimensions
+            .lock()
+            .unwrap()
+            .insert(index_name.to_string(), index.dimension);
+        Ok((index.metric, index.dimension))
+    }
+
+    /// Forgets everything cached about the given index, e.g. once it may have been recreated.
+    fn forget_index(&mut self, index_name: &str) {
+        self.index_metrics.lock().unwrap().remove(index_name);
+        self.index_dimensions.lock().unwrap().remove(index_name);
+        self.known_indexes.remove(index_name);
+    }
+
+    /// Refreshes the cached description of the given index, e.g. once it may have been
+    /// recreated, and checks that vectors of the given dimension can be stored in it.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RagError::InvalidInput` if the refreshed dimension does not match, or an error
+    /// if the index cannot be described.
+    async fn refresh_dimension(&mut self, index_name: &str, dimension: usize) -> Result<()> {
+        self.forget_index(index_name);
+        let index_dimension = self.index_dimension(index_name).await?;
+        if index_dimension as usize != dimension {
+            return Err(RagError::InvalidInput(format!(
+                "vector dimension {} does not match the dimension {} of index {}",
+                dimension, index_dimension, index_name
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Lists the namespaces of the given index holding embeddings.
+    ///
+    /// # Arguments
+    ///
+    /// * `index_name` - The name of the Pinecone index.
+    ///
+    /// # Returns
+    ///
+    /// Returns the names of the namespaces, sorted (see `namespace_names`).
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The Pinecone index cannot be retrieved.
+    /// - The statistics of the index cannot be described.
+    #[instrument(skip_all)]
+    pub async fn list_namespaces(&self, index_name: &str) -> Result<Vec<String>> {
+        let _enter = self.span.enter();
+        info!("Listing namespaces of index: {}", index_name);
+        let mut index = match self.pinecone_client.index(index_name).await {
+            Ok(index) => index,
+            Err(e) => {
+                error!("Error retrieving index: {:?}", e);
+                return Err(anyhow::anyhow!("Error retrieving index: {:?}", e));
+            }
+        };
+        match index.describe_index_stats(None).await {
+            Ok(stats) => Ok(namespace_names(&stats)),
+            Err(e) => {
+                error!("Error describing index stats: {:?}", e);
+                Err(anyhow::anyhow!("Error describing index stats: {:?}", e))
+            }
+        }
+    }
+
+    /// Queries the Pinecone index with a given input and returns the most similar results.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The input text to query against the index.
+    /// * `index_name` - The name of the Pinecone index to query.
+    /// * `options` - The number of results, filter, threshold and namespace of the query.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing a vector of `QueryResponse` structs if successful.
+    /// Each `QueryResponse` contains the similarity score, embedding vector, and original text.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - A threshold is set, and the metric of the index cannot be retrieved.
+    /// - The Pinecone index cannot be retrieved.
+    /// - Creating an embedding for the query fails.
+    /// - Querying the Pinecone index fails.
+    ///
+    /// # Notes
+    ///
+    /// Matches without a text in their metadata, e.g. the model marker, are skipped.
+    #[instrument(skip_all)]
+    pub async fn query(
+        &self,
+        query: &str,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>> {
+        // NOTE: The metric is looked up first, so that a missing index fails before embedding the query
+        if options.score_threshold.is_some() {
+            self.index_metric(index_name).await?;
+        }
+        let query_vector = self.create_query_vector(query).await?;
+        self.query_with_vector(query_vector, index_name, options)
+            .await
+    }
+
+    /// Queries the Pinecone index with an already embedded query, as `query` does once the
+    /// query text is embedded, e.g. for queries embedded in a batch.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error as `query`, besides embedding the query.
+    #[instrument(skip_all)]
+    pub async fn query_with_vector(
+        &self,
+        query_vector: Vec<f32>,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>> {
+        let QueryOptions {
+            top_k,
+            filter,
+            include_values,
+            score_threshold,
+            namespace,
+        } = options;
+        let metric = match score_threshold {
+            Some(_) => Some(self.index_metric(index_name).await?),
+            None => None,
+        };
+        let mut query_response = self
+            .query_by_vector(
+                query_vector,
+                index_name,
+                top_k,
+                filter,
+                include_values,
+                namespace.as_deref(),
+            )
+            .await?;
+        if let (Some(score_threshold), Some(metric)) = (score_threshold, &metric) {
+            apply_score_threshold(&mut query_response, score_threshold, metric);
+        }
+        Ok(self.post_process(query_response))
+    }
+
+    /// Queries the Pinecone index with a hybrid of dense and keyword (BM25) retrieval.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The input text to query against the index.
+    /// * `index_name` - The name of the Pinecone index to query.
+    /// * `top_k` - Optional number of top results to return. Defaults to `DEFAULT_TOP_K` if not specified.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing at most `top_k` results, sorted by descending fused
+    /// score. The `score` of each result is its reciprocal rank fusion score.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - Creating an embedding for the query fails.
+    /// - Querying the Pinecone index fails.
+    ///
+    /// # Notes
+    ///
+    /// Dense retrieval underperforms on short keyword queries. Pinecone has no full text
+    /// search, so `RRF_CANDIDATES_PER_RESULT` dense candidates are fetched per requested
+    /// result, ranked by BM25 over their text, and both rankings are fused with reciprocal
+    /// rank fusion using the client `rrf_k`.
+    #[instrument(skip_all)]
+    pub async fn query_rrf(
+        &self,
+        query: &str,
+        index_name: &str,
+        top_k: Option<u32>,
+    ) -> Result<Vec<QueryResponse>> {
+        let top_k = top_k.unwrap_or(DEFAULT_TOP_K);
+        validate_top_k(top_k)?;
+        let candidates_count = top_k
+            .saturating_mul(RRF_CANDIDATES_PER_RESULT)
+            .min(MAX_TOP_K);
+        let candidates = self
+            .query(
+                query,
+                index_name,
+                QueryOptions {
+                    top_k: Some(candidates_count),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(fuse_dense_and_bm25(
+            query,
+            candidates,
+            self.rrf_k,
+            top_k as usize,
+        ))
+    }
+
+    /// Queries several Pinecone indexes with a given input and merges their results.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The input text to query against the indexes.
+    /// * `index_names` - The names of the Pinecone indexes to query.
+    /// * `top_k` - Optional number of top results to return. Defaults to `DEFAULT_TOP_K` if not specified.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing at most `top_k` results across all indexes, ordered best
+    /// first for their metric. Each `QueryResponse` is tagged with the index it comes from.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - `top_k` is out of bounds (`RagError::InvalidInput`).
+    /// - The indexes use different metrics (`RagError::InvalidInput`), as their scores cannot
+    ///   be compared.
+    /// - Creating an embedding for the query fails.
+    ///
+    /// # Notes
+    ///
+    /// The query is embedded once, and the indexes are queried concurrently. An index whose
+    /// metric cannot be retrieved, or that fails to be queried, is logged and skipped, rather
+    /// than failing the whole query.
+    #[instrument(skip_all)]
+    pub async fn query_multi(
+        &self,
+        query: &str,
+        index_names: &[String],
+        top_k: Option<u32>,
+    ) -> Result<Vec<QueryResponse>> {
+        let top_k = top_k.unwrap_or(DEFAULT_TOP_K);
+        validate_top_k(top_k)?;
+        let metrics = join_all(
+            index_names
+                .iter()
+                .map(|index_name| self.index_metric(index_name)),
+        )
+        .await;
+        let mut metric: Option<(&String, Metric)> = None;
+        let mut queried = Vec::with_capacity(index_names.len());
+        for (index_name, index_metric) in index_names.iter().zip(metrics) {
+            let index_metric = match index_metric {
+                Ok(index_metric) => index_metric,
+                Err(e) => {
+                    error!("Error describing index {}, skipping it: {}", index_name, e);
+                    continue;
+                }
+            };
+            match &metric {
+                Some((first, first_metric)) if *first_metric != index_metric => {
+                    return Err(RagError::InvalidInput(format!(
+                        "indexes {} and {} use different metrics ({:?} and {:?})",
+                        first, index_name, first_metric, index_metric
+                    ))
+                    .into());
+                }
+                Some(_) => {}
+                None => metric = Some((index_name, index_metric)),
+            }
+            queried.push(index_name);
+        }
+        let Some((_, metric)) = metric else {
+            return Ok(vec![]);
+        };
+        let query_vector = self.create_query_vector(query).await?;
+        let responses = join_all(queried.into_iter().map(|index_name| {
+            let query_vector = query_vector.clone();
+            async move {
+                let response = self
+                    .query_by_vector(query_vector, index_name, Some(top_k), None, false, None)
+                    .await;
+                (index_name.clone(), response)
+            }
+        }))
+        .await;
+        Ok(merge_results(responses, &metric, top_k as usize))
+    }
+
+    /// Queries the Pinecone index with several query texts, e.g. paraphrases of a query, and
+    /// merges their results.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - The input texts to query against the index.
+    /// * `index_name` - The name of the Pinecone index to query.
+    /// * `top_k` - Optional number of top results to return, and of results fetched for each
+    ///   query. Defaults to `DEFAULT_TOP_K` if not specified.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing at most `top_k` results, each vector appearing once with
+    /// the best score any query gave it, ordered best first for the metric of the index.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - No query text is given (`RagError::InvalidInput`).
+    /// - The metric of the index cannot be retrieved.
+    /// - Creating the embeddings of the queries fails.
+    /// - Querying the Pinecone index fails for any of the queries.
+    ///
+    /// # Notes
+    ///
+    /// The queries are embedded in a single batched request, then the index is queried
+    /// concurrently with each of their embeddings (see `merge_multi_vector_results`).
+    #[instrument(skip_all)]
+    pub async fn query_multi_text(
+        &self,
+        queries: Vec<String>,
+        index_name: &str,
+        top_k: Option<u32>,
+    ) -> Result<Vec<QueryResponse>> {
+        if queries.is_empty() {
+            return Err(RagError::InvalidInput("no query text given".to_string()).into());
+        }
+        let top_k = top_k.unwrap_or(DEFAULT_TOP_K);
+        validate_top_k(top_k)?;
+        let metric = self.index_metric(index_name).await?;
+        let query_vectors = self
+            .create_embeddings(&queries, EmbeddingKind::Query)
+            .await?;
+        let responses = try_join_all(query_vectors.into_iter().map(|query_vector| {
+            self.query_by_vector(query_vector, index_name, Some(top_k), None, false, None)
+        }))
+        .await?;
+        Ok(merge_multi_vector_results(
+            responses,
+            &metric,
+            top_k as usize,
+        ))
+    }
+
+    /// Queries the Pinecone index for results similar to a seed result ("more like this").
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - The seed to find similar results for, either a stored vector id or a
+    ///   previous query result.
+    /// * `query` - Optional original query text, whose embedding is blended with the seed vector.
+    /// * `index_name` - The name of the Pinecone index to query.
+    /// * `top_k` - Optional number of top results to return. Defaults to `DEFAULT_TOP_K` if not specified.
+    /// * `alpha` - Weight of the seed vector in the blend, between 0 and 1. With `alpha = 1.0`
+    ///   (or no `query`), the query is a pure "more like this" on the seed vector.
+    /// * `namespace` - Optional namespace the seed is fetched from and the query runs in,
+    ///   instead of the default one of the client.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing at most `top_k` results, never including the seed itself.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - `top_k` is out of bounds (`RagError::InvalidInput`).
+    /// - `alpha` is not between 0 and 1.
+    /// - The seed vector cannot be fetched from the index.
+    /// - Creating an embedding for the query fails.
+    /// - Querying the Pinecone index fails.
+    ///
+    /// # Notes
+    ///
+    /// For cosine indexes the blended vector is renormalized to unit length.
+    #[instrument(skip_all)]
+    pub async fn query_similar_to(
+        &self,
+        seed: SimilarTo,
+        query: Option<&str>,
+        index_name: &str,
+        top_k: Option<u32>,
+        alpha: f32,
+        namespace: Option<&str>,
+    ) -> Result<Vec<QueryResponse>> {
+        let top_k = top_k.unwrap_or(DEFAULT_TOP_K);
+        validate_top_k(top_k)?;
+        if !(0.0..=1.0).contains(&alpha) {
+            return Err(RagError::InvalidInput(format!(
+                "alpha must be between 0 and 1, got {}",
+                alpha
+            ))
+            .into());
+        }
+        let (seed_id, seed_vector) = match seed {
+            SimilarTo::Result(result) if !result.embedding.is_empty() => {
+                (result.id, result.embedding)
+            }
+            SimilarTo::Result(result) => {
+                let vector = self.fetch_vector(index_name, &result.id, namespace).await?;
+                (result.id, vector)
+            }
+            SimilarTo::Id(id) => {
+                let vector = self.fetch_vector(index_name, &id, namespace).await?;
+                (id, vector)
+            }
+        };
+        let query_vector = match query {
+            Some(query) if alpha < 1.0 => {
+                let query_vector = self.create_query_vector(query).await?;
+                let normalize = self.index_metric(index_name).await? == Metric::Cosine;
+                blend_vectors(&seed_vector, &query_vector, alpha, normalize)?
+            }
+            _ => seed_vector,
+        };
+        // Query one extra result, as the seed is usually its own closest match, within the bound
+        let results = self
+            .query_by_vector(
+                query_vector,
+                index_name,
+                Some((top_k + 1).min(MAX_TOP_K)),
+                None,
+                false,
+                namespace,
+            )
+            .await?;
+        Ok(exclude_seed(results, &seed_id, top_k as usize))
+    }
+
+    /// Fetches the values of a stored vector by its full id, including its prefix, in the given
+    /// namespace or else the default one.
+    async fn fetch_vector(
+        &self,
+        index_name: &str,
+        id: &str,
+        namespace: Option<&str>,
+    ) -> Result<Vec<f32>> {
+        let _enter = self.span.enter();
+        let mut index = match self.pinecone_client.index(index_name).await {
+            Ok(index) => index,
+            Err(e) => {
+                error!("Error retrieving index: {:?}", e);
+                return Err(anyhow::anyhow!("Error retrieving index: {:?}", e));
+            }
+        };
+        let namespace = self.namespace_or_default(namespace);
+        let response = match index.fetch(&[id], &namespace.into()).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Error fetching vector: {:?}", e);
+                return Err(anyhow::anyhow!("Error fetching vector: {:?}", e));
+            }
+        };
+        take_fetched_vector(response, id)
+    }
+
+    /// Fetches stored chunks by their full ids, in the given namespace or else the default one.
+    ///
+    /// Ids with no stored vector are left out of the returned chunks.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the Pinecone index cannot be retrieved, or if
+    /// the fetch request fails.
+    pub async fn fetch_chunks(
+        &self,
+        index_name: &str,
+        ids: &[String],
+        namespace: Option<&str>,
+    ) -> Result<Vec<StoredChunk>> {
+        let _enter = self.span.enter();
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut index = match self.pinecone_client.index(index_name).await {
+            Ok(index) => index,
+            Err(e) => {
+                error!("Error retrieving index: {:?}", e);
+                return Err(anyhow::anyhow!("Error retrieving index: {:?}", e));
+            }
+        };
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let namespace = self.namespace_or_default(namespace);
+        let response = match index.fetch(&ids, &namespace.into()).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Error fetching chunks: {:?}", e);
+                return Err(anyhow::anyhow!("Error fetching chunks: {:?}", e));
+            }
+        };
+        Ok(response
+            .vectors
+            .into_iter()
+            .map(|(id, vector)| {
+                let metadata = from_sdk_metadata(vector.metadata.unwrap_or_default());
+                StoredChunk {
+                    id,
+                    text: string_field(&metadata, "text").unwrap_or_default(),
+                    query_id: string_field(&metadata, "query_id"),
+                    chunk_index: chunk_index_field(&metadata),
+                    embedding: vector.values,
+                    metadata,
+                }
+            })
+            .collect())
+    }
+
+    /// Lists every chunk stored in the given namespace, or else the default one, with its
+    /// embedding and metadata.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the Pinecone index cannot be retrieved, or if a
+    /// list or fetch request fails.
+    ///
+    /// # Notes
+    ///
+    /// Vector ids can only be listed in serverless indexes. They are listed in pages of
+    /// `LIST_PAGE_SIZE`, each fetched before listing the next one, and all the chunks are
+    /// held in memory.
+    #[instrument(skip_all)]
+    pub async fn list_chunks(
+        &self,
+        index_name: &str,
+        namespace: Option<&str>,
+    ) -> Result<Vec<StoredChunk>> {
+        let _enter = self.span.enter();
+        let mut index = match self.pinecone_client.index(index_name).await {
+            Ok(index) => index,
+            Err(e) => {
+                error!("Error retrieving index: {:?}", e);
+                return Err(anyhow::anyhow!("Error retrieving index: {:?}", e));
+            }
+        };
+        let namespace = self.namespace_or_default(namespace);
+        let mut chunks = Vec::new();
+        let mut pagination_token: Option<String> = None;
+        loop {
+            let page = match index
+                .list(
+                    &namespace.into(),
+                    None,
+                    Some(LIST_PAGE_SIZE),
+                    pagination_token.as_deref(),
+                )
+                .await
+            {
+                Ok(page) => page,
+                Err(e) => {
+                    error!("Error listing vectors: {:?}", e);
+                    return Err(anyhow::anyhow!("Error listing vectors: {:?}", e));
+                }
+            };
+            let ids: Vec<String> = page.vectors.into_iter().map(|item| item.id).collect();
+            chunks.extend(self.fetch_chunks(index_name, &ids, Some(namespace)).await?);
+            pagination_token = page
+                .pagination
+                .map(|pagination| pagination.next)
+                .filter(|next| !next.is_empty());
+            if pagination_token.is_none() {
+                break;
+            }
+        }
+        info!("Listed {} chunks", chunks.len());
+        Ok(chunks)
+    }
+
+    /// Replaces stored chunks: upserts the given chunks, keyed by their id, with their
+    /// embedding and metadata, then deletes the vectors with the `stale_ids`.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The host of the Pinecone index.
+    /// * `chunks` - The chunks to upsert, overwriting those stored with the same ids.
+    /// * `stale_ids` - The full ids of the vectors to delete once the chunks are upserted.
+    /// * `namespace` - Optional namespace of the chunks, instead of the default one of the client.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the Pinecone index cannot be retrieved, or if an
+    /// upsert or the delete request fails.
+    ///
+    /// # Notes
+    ///
+    /// Pinecone has no transactions. Upserting first means a failure never loses a chunk,
+    /// at worst leaving the stale vectors stored alongside the new ones until the next run.
+    #[instrument(skip_all)]
+    pub async fn replace_chunks(
+        &self,
+        host: &str,
+        chunks: Vec<StoredChunk>,
+        stale_ids: &[String],
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        let _enter = self.span.enter();
+        info!(
+            "Upserting {} chunks, replacing {} stale ones",
+            chunks.len(),
+            stale_ids.len()
+        );
+        let namespace = self.namespace_or_default(namespace);
+        let mut index = self.pinecone_client.index(host).await?;
+        let vectors = chunks
+            .into_iter()
+            .map(|chunk| Vector {
+                id: chunk.id,
+                values: chunk.embedding,
+                sparse_values: None,
+                metadata: Some(to_sdk_metadata(chunk.metadata)),
+            })
+            .collect();
+        for batch in upsert_batches(vectors, self.upsert_batch_size) {
+            if let Err(e) = index.upsert(&batch, &namespace.into()).await {
+                error!("Error upserting chunks: {:?}", e);
+                return Err(anyhow::anyhow!("Error upserting chunks: {:?}", e));
+            }
+        }
+        if stale_ids.is_empty() {
+            return Ok(());
+        }
+        let stale_ids: Vec<&str> = stale_ids.iter().map(String::as_str).collect();
+        match index.delete_by_id(&stale_ids, &namespace.into()).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                error!("Error deleting stale chunks: {:?}", e);
+                Err(anyhow::anyhow!("Error deleting stale chunks: {:?}", e))
+            }
+        }
+    }
+
+    /// Stores the raw content of a document once, keyed by its `query_id`, in the documents
+    /// namespace of its namespace (see `documents_namespace`).
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The host of the Pinecone index.
+    /// * `document` - The document, whose content is stored as the `text` of its metadata.
+    /// * `values` - The vector stored with the document, Pinecone rejecting vectors of zeros.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The content is longer than `MAX_RAW_DOCUMENT_BYTES` (`RagError::InvalidInput`).
+    /// - The extra metadata holds values Pinecone cannot store (see `build_metadata`).
+    /// - The Pinecone index cannot be retrieved, or the upsert request fails.
+    ///
+    /// # Notes
+    ///
+    /// Storing a document with the same `query_id` again overwrites it.
+    #[instrument(skip_all)]
+    pub async fn store_document(
+        &self,
+        host: &str,
+        document: &TextToEmbed,
+        values: Vec<f32>,
+    ) -> Result<()> {
+        let _enter = self.span.enter();
+        info!(
+            "Storing raw document, for query with id: {}",
+            document.query_id
+        );
+        check_raw_document_size(document)?;
+        let stored = StoredDocument::new(document)?;
+        let namespace =
+            documents_namespace(self.namespace_or_default(document.namespace.as_deref()));
+        let mut index = self.pinecone_client.index(host).await?;
+        let vector = Vector {
+            id: stored.query_id,
+            values,
+            sparse_values: None,
+            metadata: Some(to_sdk_metadata(stored.metadata)),
+        };
+        match index.upsert(&[vector], &namespace.as_str().into()).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Error storing raw document: {:?}", e);
+                Err(anyhow::anyhow!("Error storing raw document: {:?}", e))
+            }
+        }
+    }
+
+    /// Fetches the raw document stored by `store_document` for a `query_id`, in the documents
+    /// namespace of the given namespace or else of the default one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no raw document is stored for the `query_id`
+    /// (`RagError::NotFound`), or if the fetch request fails.
+    pub async fn fetch_document(
+        &self,
+        index_name: &str,
+        query_id: &str,
+        namespace: Option<&str>,
+    ) -> Result<StoredDocument> {
+        let namespace = documents_namespace(self.namespace_or_default(namespace));
+        let chunks = self
+            .fetch_chunks(index_name, &[query_id.to_string()], Some(&namespace))
+            .await?;
+        match chunks.into_iter().next() {
+            Some(chunk) => Ok(StoredDocument {
+                query_id: query_id.to_string(),
+                content: chunk.text,
+                metadata: chunk.metadata,
+            }),
+            None => Err(RagError::NotFound(format!(
+                "no raw document stored for query with id {}",
+                query_id
+            ))
+            .into()),
+        }
+    }
+
+    /// Creates the embedding of a query text, flattened into a single vector.
+    async fn create_query_vector(&self, query: &str) -> Result<Vec<f32>> {
+        let _enter = self.span.enter();
+        match self.create_embedding(query, EmbeddingKind::Query).await {
+            Ok(embedding) => Ok(embedding.into_iter().flatten().collect()),
+            Err(e) => {
+                error!("Error creating embedding: {:?}", e);
+                Err(anyhow::anyhow!("Error creating embedding: {:?}", e))
+            }
+        }
+    }
+
+    /// Queries the Pinecone index with an embedding vector and returns the most similar results.
+    ///
+    /// # Arguments
+    ///
+    /// * `query_vector` - The embedding vector to query against the index.
+    /// * `index_name` - The name of the Pinecone index to query.
+    /// * `top_k` - Optional number of top results to return. Defaults to `DEFAULT_TOP_K` if not specified.
+    /// * `filter` - Optional metadata filter the results must match.
+    /// * `include_values` - Whether to return the stored vector of each result. Vectors make up
+    ///   most of the response payload, e.g. 100 results of 768 dimensions, so they are best left out.
+    /// * `namespace` - Optional namespace to query, instead of the default one of the client.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The Pinecone index cannot be retrieved.
+    /// - Querying the Pinecone index fails.
+    ///
+    /// # Notes
+    ///
+    /// Matches without a text in their metadata, e.g. the model marker, are skipped with a
+    /// warning.
+    #[instrument(skip_all)]
+    pub async fn query_by_vector(
+        &self,
+        query_vector: Vec<f32>,
+        index_name: &str,
+        top_k: Option<u32>,
+        filter: Option<MetadataFilter>,
+        include_values: bool,
+        namespace: Option<&str>,
+    ) -> Result<Vec<QueryResponse>> {
+        let _enter = self.span.enter();
+        info!("Retrieving index");
+        let mut index = match self.pinecone_client.index(index_name).await {
+            Ok(index) => index,
+            Err(e) => {
+                error!("Error retrieving index: {:?}", e);
+                return Err(anyhow::anyhow!("Error retrieving index: {:?}", e));
+            }
+        };
+        let top_k = top_k.unwrap_or(DEFAULT_TOP_K);
+        validate_top_k(top_k)?;
+        let response = match index
+            .query_by_value(
+                query_vector,
+                None,
+                top_k,
+                &self.namespace_or_default(namespace).into(),
+                filter.map(sdk_filter),
+                Some(include_values),
+                Some(true),
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Error querying index: {:?}", e);
+                return Err(anyhow::anyhow!("Error querying index: {:?}", e));
+            }
+        };
+        let query_response = response
+            .matches
+            .iter()
+            .filter_map(|match_| {
+                let metadata = from_sdk_metadata(match_.metadata.clone().unwrap_or_default());
+                let Some(text) = string_field(&metadata, "text") else {
+                    warn!("Skipping match {} without text in its metadata", match_.id);
+                    return None;
+                };
+                Some(QueryResponse {
+                    id: match_.id.clone(),
+                    score: match_.score,
+                    raw_score: None,
+                    embedding: match_.values.clone(),
+                    text,
+                    index_name: Some(index_name.to_string()),
+                    query_id: string_field(&metadata, "query_id"),
+                    chunk_index: chunk_index_field(&metadata),
+                    context: None,
+                    timestamp: timestamp_field(&metadata),
+                    author: string_field(&metadata, "author"),
+                    norm: None,
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(query_response)
+    }
+}
+
+/// Applies the sequence guard to a text about to be embedded.
+///
+/// # Errors
+///
+/// Returns a `RagError::InvalidInput` if the text exceeds the maximum sequence length and
+/// the guard mode is `OverflowMode::Error`, or an error if the text cannot be tokenized.
+fn guard_sequence(text: String, guard: &SequenceGuard) -> Result<String> {
+    let tokens = guard.tokenizer.count(&text)?;
+    if tokens <= guard.max_sequence_tokens {
+        return Ok(text);
+    }
+    match guard.mode {
+        OverflowMode::Truncate => {
+            warn!(
+                "Truncating text of {} tokens to the maximum sequence length of {} tokens",
+                tokens, guard.max_sequence_tokens
+            );
+            Ok(guard
+                .tokenizer
+                .truncate(&text, guard.max_sequence_tokens)?
+                .to_string())
+        }
+        OverflowMode::Error => Err(RagError::InvalidInput(format!(
+            "text has {} tokens, more than the maximum sequence length of {} tokens",
+            tokens, guard.max_sequence_tokens
+        ))
+        .into()),
+    }
+}
+
+/// Truncates a response body to `MAX_BODY_SNIPPET_CHARS` characters, to be included in errors.
+fn body_snippet(body: &str) -> String {
+    match body.char_indices().nth(MAX_BODY_SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+/// Connection handling of the HTTP client sending requests to the embedding service.
+///
+/// Unset options keep the defaults of `reqwest`. Under high throughput, a bounded pool of
+/// long lived connections avoids reconnecting to the embedding server for each request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpOptions {
+    /// Maximum number of idle connections kept open per host, unlimited by default
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long idle connections are kept open, 90 seconds by default
+    pub pool_idle_timeout: Option<Duration>,
+    /// Interval of the TCP keep-alive probes sent on open connections, disabled by default
+    pub tcp_keepalive: Option<Duration>,
+    /// Whether to speak HTTP/2 without negotiating it, for self-hosted backends serving
+    /// HTTP/2 over plain connections
+    pub http2_prior_knowledge: bool,
+}
+
+impl HttpOptions {
+    /// Builds an HTTP client with these options.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the HTTP client cannot be built, e.g. if its TLS backend fails
+    /// to initialize.
+    pub fn build_client(&self) -> Result<Client> {
+        let mut builder = Client::builder().tcp_keepalive(self.tcp_keepalive);
+        if let Some(max_idle) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(idle_timeout);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        builder
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to build the HTTP client: {}", e))
+    }
+}
+
+/// Encoding of the embeddings returned by the embedding service.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmbeddingEncoding {
+    /// Embeddings as JSON arrays of floats
+    #[default]
+    Float,
+    /// Embeddings as base64 encoded little-endian `f32` bytes, as returned by OpenAI
+    /// compatible servers for `"encoding_format": "base64"`
+    Base64,
+}
+
+/// Decodes a base64 encoded embedding of little-endian `f32` bytes.
+///
+/// # Errors
+///
+/// Returns an error if the text is not valid base64, or if the decoded bytes do not hold a
+/// whole number of `f32`.
+pub fn decode_base64_embedding(encoded: &str) -> Result<Vec<f32>> {
+    let bytes = STANDARD.decode(encoded)?;
+    if bytes.len() % 4 != 0 {
+        return Err(anyhow::anyhow!(
+            "{} bytes do not hold a whole number of f32",
+            bytes.len()
+        ));
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect())
+}
+
+/// Deserializes an embedding given either as an array of floats or as a base64 string.
+fn deserialize_embedding<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Embedding {
+        Float(Vec<f32>),
+        Base64(String),
+    }
+    match Embedding::deserialize(deserializer)? {
+        Embedding::Float(embedding) => Ok(embedding),
+        Embedding::Base64(encoded) => {
+            decode_base64_embedding(&encoded).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+/// Response of the embedding service, either a bare batch of embeddings or a single one,
+/// or one of the common shapes wrapping them in an object.
+///
+/// The shapes are tried in the order of the variants, so bare arrays are parsed first. The
+/// embeddings wrapped in objects can also be base64 encoded (see `EmbeddingEncoding`).
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingResponse {
+    /// A batch of embeddings, e.g. `[[0.1, 0.2]]` as returned by TEI.
+    Batch(Vec<Vec<f32>>),
+    /// A single bare embedding, e.g. `[0.1, 0.2]`.
+    Single(Vec<f32>),
+    /// A single embedding wrapped in an object, e.g. `{"embedding": [0.1, 0.2]}`.
+    Wrapped {
+        #[serde(deserialize_with = "deserialize_embedding")]
+        embedding: Vec<f32>,
+    },
+    /// A list of embedding objects, e.g. `{"data": [{"embedding": [0.1, 0.2]}]}` as returned
+    /// by OpenAI compatible servers.
+    Data { data: Vec<EmbeddingData> },
+}
+
+/// An embedding object of an `EmbeddingResponse::Data` response.
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingData {
+    /// The embedding
+    #[serde(deserialize_with = "deserialize_embedding")]
+    pub embedding: Vec<f32>,
+}
+
+impl EmbeddingResponse {
+    /// Returns the embeddings of the response, as a batch.
+    pub fn into_embeddings(self) -> Vec<Vec<f32>> {
+        match self {
+            EmbeddingResponse::Batch(embeddings) => embeddings,
+            EmbeddingResponse::Single(embedding) | EmbeddingResponse::Wrapped { embedding } => {
+                vec![embedding]
+            }
+            EmbeddingResponse::Data { data } => data.into_iter().map(|d| d.embedding).collect(),
+        }
+    }
+}
+
+/// Options of a query, besides its text and index.
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// Optional number of top results to return. Defaults to `DEFAULT_TOP_K` if not specified.
+    pub top_k: Option<u32>,
+    /// Optional metadata filter the results must match (see `query_filter`).
+    pub filter: Option<MetadataFilter>,
+    /// Whether to return the stored vector of each result, left empty otherwise.
+    pub include_values: bool,
+    /// Optional minimum score of the results, or maximum distance for Euclidean indexes
+    /// (see `passes_score_threshold`).
+    pub score_threshold: Option<f32>,
+    /// Optional namespace to query, instead of the default one of the client.
+    pub namespace: Option<String>,
+}
+
+/// The seed of a "more like this" query.
+#[derive(Debug, Clone)]
+pub enum SimilarTo {
+    /// The full id of a vector stored in the index, including its prefix, as in `QueryResponse::id`.
+    Id(String),
+    /// A previous query result. Its embedding is fetched from the index if it is empty.
+    Result(QueryResponse),
+}
+
+/// Returns the sorted names of the namespaces reported by the statistics of an index.
+///
+/// Pinecone only reports namespaces holding vectors, and reports the default namespace as `""`.
+/// Internal namespaces are left out, see `is_internal_namespace`.
+pub fn namespace_names(stats: &DescribeIndexStatsResponse) -> Vec<String> {
+    let mut names: Vec<String> = stats
+        .namespaces
+        .keys()
+        .filter(|name| !is_internal_namespace(name))
+        .cloned()
+        .collect();
+    names.sort();
+    names
+}
+
+/// A chunk fetched from an index by id, with the fields of its metadata locating it in its
+/// document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredChunk {
+    /// The full id of the stored vector, including its prefix
+    pub id: String,
+    /// The text stored alongside the embedding
+    pub text: String,
+    /// The `query_id` of the document the chunk belongs to, if stored
+    pub query_id: Option<String>,
+    /// The position of the chunk among the chunks of its document, if stored
+    pub chunk_index: Option<usize>,
+    /// The stored embedding of the chunk
+    pub embedding: Vec<f32>,
+    /// The whole metadata stored alongside the embedding, including the fields above
+    pub metadata: MetadataFields,
+}
+
+/// The raw content of a document, stored once per `query_id` alongside its chunks.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StoredDocument {
+    /// The `query_id` of the document
+    pub query_id: String,
+    /// The full, unsplit content of the document
+    pub content: String,
+    /// The metadata stored with the document, as built by `build_metadata` with its content
+    /// as `text`
+    pub metadata: MetadataFields,
+}
+
+impl StoredDocument {
+    /// Builds the raw document of a `TextToEmbed`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the extra metadata of the document cannot be stored
+    /// (see `build_metadata`).
+    pub fn new(document: &TextToEmbed) -> Result<Self> {
+        Ok(Self {
+            query_id: document.query_id.clone(),
+            content: document.content.clone(),
+            metadata: build_metadata(document.content.clone(), Some(document), None)?,
+        })
+    }
+}
+
+/// Returns the namespace the raw documents of a namespace are stored in, so that they are
+/// never returned by the queries of its chunks.
+pub fn documents_namespace(namespace: &str) -> String {
+    format!("{}{}", namespace, DOCUMENTS_NAMESPACE_SUFFIX)
+}
+
+/// Checks whether a namespace is used internally rather than holding chunks, i.e. the namespace
+/// of the embedding model marker or the raw documents namespace of another one.
+pub fn is_internal_namespace(namespace: &str) -> bool {
+    namespace == MODEL_MARKER_NAMESPACE || namespace.ends_with(DOCUMENTS_NAMESPACE_SUFFIX)
+}
+
+/// Validates that a namespace requested by a query, delete or compaction holds chunks.
+///
+/// # Errors
+///
+/// Returns a `RagError::InvalidInput` if the namespace is an internal one, see
+/// `is_internal_namespace`.
+pub fn validate_namespace(namespace: Option<&str>) -> Result<()> {
+    match namespace {
+        Some(namespace) if is_internal_namespace(namespace) => Err(RagError::InvalidInput(
+            format!("namespace {} is reserved for internal use", namespace),
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the ids of the chunks within `window` chunks before and after a query result,
+/// leaving out the result itself.
+///
+/// The chunks of a document are stored with consecutive ids (see `vector_id`), so the ids
+/// are derived from the counter ending the id of the result and its `chunk_index`. No ids
+/// are returned for results without a `chunk_index`, or whose id does not end with a counter.
+pub fn context_window_ids(result: &QueryResponse, window: usize) -> Vec<String> {
+    let Some(chunk_index) = result.chunk_index else {
+        return vec![];
+    };
+    let prefix = result.id.trim_end_matches(|c: char| c.is_ascii_digit());
+    let Some(counter) = result.id[prefix.len()..]
+        .parse::<usize>()
+        .ok()
+        .and_then(|counter| counter.checked_sub(chunk_index))
+    else {
+        return vec![];
+    };
+    (chunk_index.saturating_sub(window)..=chunk_index.saturating_add(window))
+        .filter(|i| *i != chunk_index)
+        .map(|i| vector_id(Some(prefix), counter + i))
+        .collect()
+}
+
+/// Concatenates the text of a query result with the texts of its neighboring chunks, in the
+/// order of the document, separated by newlines.
+///
+/// Neighbors are only kept if they belong to the same document as the result and lie
+/// within `window` chunks of it, so that ids reused by another document are never mixed in.
+pub fn assemble_context(
+    result: &QueryResponse,
+    neighbors: Vec<StoredChunk>,
+    window: usize,
+) -> String {
+    let Some(chunk_index) = result.chunk_index else {
+        return result.text.clone();
+    };
+    let mut chunks: Vec<(usize, String)> = neighbors
+        .into_iter()
+        .filter(|neighbor| neighbor.query_id.is_some() && neighbor.query_id == result.query_id)
+        .filter_map(|neighbor| Some((neighbor.chunk_index?, neighbor.text)))
+        .filter(|(i, _)| *i != chunk_index && i.abs_diff(chunk_index) <= window)
+        .collect();
+    chunks.push((chunk_index, result.text.clone()));
+    chunks.sort_by_key(|(i, _)| *i);
+    chunks.dedup_by_key(|(i, _)| *i);
+    chunks
+        .into_iter()
+        .map(|(_, text)| text)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Generates the id of a stored vector from the id counter, prepending the optional prefix.
+pub fn vector_id(id_prefix: Option<&str>, counter: usize) -> String {
+    format!("{}{}", id_prefix.unwrap_or_default(), counter)
+}
+
+/// Takes the values of the vector with the given full id out of a fetch response.
+///
+/// # Errors
+///
+/// Returns a `RagError::InvalidInput` if no vector with this id was fetched.
+fn take_fetched_vector(mut response: FetchResponse, id: &str) -> Result<Vec<f32>> {
+    match response.vectors.remove(id) {
+        Some(vector) => Ok(vector.values),
+        None => Err(RagError::InvalidInput(format!("No vector found with id: {}", id)).into()),
+    }
+}
+
+/// Blends a seed vector with a query vector, weighting the seed by `alpha`.
+///
+/// If `normalize` is set, the blended vector is rescaled to unit length.
+///
+/// # Errors
+///
+/// Returns an error if the vectors have different lengths.
+pub fn blend_vectors(seed: &[f32], query: &[f32], alpha: f32, normalize: bool) -> Result<Vec<f32>> {
+    if seed.len() != query.len() {
+        return Err(anyhow::anyhow!(
+            "Vector length mismatch: {} vs {}",
+            seed.len(),
+            query.len()
+        ));
+    }
+    let mut blended: Vec<f32> = seed
+        .iter()
+        .zip(query.iter())
+        .map(|(s, q)| alpha * s + (1.0 - alpha) * q)
+        .collect();
+    if normalize {
+        let norm = l2_norm(&blended);
+        if norm > 0.0 {
+            blended.iter_mut().for_each(|x| *x /= norm);
+        }
+    }
+    Ok(blended)
+}
+
+/// Removes the seed from the results of a "more like this" query, keeping at most `top_k`.
+pub fn exclude_seed(
+    results: Vec<QueryResponse>,
+    seed_id: &str,
+    top_k: usize,
+) -> Vec<QueryResponse> {
+    results
+        .into_iter()
+        .filter(|result| result.id != seed_id)
+        .take(top_k)
+        .collect()
+}
+
+/// Merges the results of querying several indexes into the `top_k` best results.
+///
+/// Results are ordered best first for the metric all the indexes share, ties broken by index
+/// name and id. Indexes whose query failed are logged and skipped.
+pub fn merge_results(
+    responses: Vec<(String, Result<Vec<QueryResponse>>)>,
+    metric: &Metric,
+    top_k: usize,
+) -> Vec<QueryResponse> {
+    let mut merged = Vec::new();
+    for (index_name, response) in responses {
+        match response {
+            Ok(results) => merged.extend(results),
+            Err(e) => error!("Error querying index {}, skipping it: {}", index_name, e),
+        }
+    }
+    merged.sort_by(|a, b| {
+        match metric {
+            Metric::Euclidean => a.score.total_cmp(&b.score),
+            _ => b.score.total_cmp(&a.score),
+        }
+        .then_with(|| a.index_name.cmp(&b.index_name))
+        .then_with(|| a.id.cmp(&b.id))
+    });
+    merged.truncate(top_k);
+    merged
+}
+
+/// Keeps only the best ranked chunk of each document among query results.
+///
+/// Results are expected in the order returned by the index, best first, which is kept.
+/// Documents are told apart by their `query_id`; results without one are all kept.
+pub fn dedupe_by_document(results: Vec<QueryResponse>) -> Vec<QueryResponse> {
+    let mut seen = HashSet::new();
+    results
+        .into_iter()
+        .filter(|result| match &result.query_id {
+            Some(query_id) => seen.insert(query_id.clone()),
+            None => true,
+        })
+        .collect()
+}
+
+/// Reranks dense candidates by fusing their dense ranking with their BM25 ranking.
+///
+/// Candidates are expected in descending dense score order. Only candidates sharing a term
+/// with the query take part in the BM25 ranking. The `score` of the returned results is
+/// their reciprocal rank fusion score.
+pub fn fuse_dense_and_bm25(
+    query: &str,
+    candidates: Vec<QueryResponse>,
+    rrf_k: f32,
+    top_k: usize,
+) -> Vec<QueryResponse> {
+    let texts: Vec<&str> = candidates.iter().map(|c| c.text.as_str()).collect();
+    let scores = bm25_scores(query, &texts);
+    let dense_ranking: Vec<usize> = (0..candidates.len()).collect();
+    let mut sparse_ranking: Vec<usize> = dense_ranking
+        .iter()
+        .copied()
+        .filter(|&i| scores[i] > 0.0)
+        .collect();
+    sparse_ranking.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+    let mut candidates: Vec<Option<QueryResponse>> = candidates.into_iter().map(Some).collect();
+    reciprocal_rank_fusion(&[dense_ranking, sparse_ranking], rrf_k)
+        .into_iter()
+        .take(top_k)
+        .filter_map(|(i, score)| {
+            let mut result = candidates[i].take()?;
+            result.score = score;
+            Some(result)
+        })
+        .collect()
+}
+
+/// Checks that an existing index has the dimension it is ensured with, see `ensure_index`.
+///
+/// # Errors
+///
+/// Returns a `RagError::AlreadyExists` if the dimensions differ.
+pub fn check_existing_dimension(index_name: &str, existing: i32, dimension: i32) -> Result<()> {
+    if existing != dimension {
+        return Err(RagError::AlreadyExists(format!(
+            "index {} already exists with dimension {}, not {}",
+            index_name, existing, dimension
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Validates that an index dimension is in `1..=MAX_INDEX_DIMENSION`.
+///
+/// # Errors
+///
+/// Returns a `RagError::InvalidInput` if the dimension is out of range.
+pub fn validate_dimension(dimension: i32) -> Result<()> {
+    if dimension <= 0 {
+        return Err(RagError::InvalidInput(format!(
+            "dimension must be positive, got {}",
+            dimension
+        ))
+        .into());
+    }
+    if dimension > MAX_INDEX_DIMENSION {
+        return Err(RagError::InvalidInput(format!(
+            "dimension must be at most {}, got {}",
+            MAX_INDEX_DIMENSION, dimension
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Validates that a number of results can be returned by a single Pinecone query.
+///
+/// # Errors
+///
+/// Returns a `RagError::InvalidInput` if `top_k` is not in `1..=MAX_TOP_K`.
+pub fn validate_top_k(top_k: u32) -> Result<()> {
+    if top_k == 0 || top_k > MAX_TOP_K {
+        return Err(RagError::InvalidInput(format!(
+            "top_k must be between 1 and {}, got {}",
+            MAX_TOP_K, top_k
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Validates that an index name follows the Pinecone naming rules.
+///
+/// Index names must be 1 to `MAX_INDEX_NAME_LENGTH` characters long, made of lowercase
+/// alphanumeric characters or hyphens, and must start and end with an alphanumeric character.
+///
+/// # Errors
+///
+/// Returns a `RagError::InvalidInput` describing the first rule the name violates.
+pub fn validate_index_name(index_name: &str) -> Result<()> {
+    if index_name.is_empty() {
+        return Err(RagError::InvalidInput("index name must not be empty".to_string()).into());
+    }
+    if index_name.len() > MAX_INDEX_NAME_LENGTH {
+        return Err(RagError::InvalidInput(format!(
+            "index name must be at most {} characters long, got {}",
+            MAX_INDEX_NAME_LENGTH,
+            index_name.len()
+        ))
+        .into());
+    }
+    if let Some(c) = index_name
+        .chars()
+        .find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-'))
+    {
+        return Err(RagError::InvalidInput(format!(
+            "index name must only contain lowercase alphanumeric characters or hyphens, found '{}' in '{}'",
+            c, index_name
+        ))
+        .into());
+    }
+    if index_name.starts_with('-') || index_name.ends_with('-') {
+        return Err(RagError::InvalidInput(format!(
+            "index name must start and end with an alphanumeric character, got '{}'",
+            index_name
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Turns an arbitrary string into a valid index name.
+///
+/// The name is lowercased, invalid characters are replaced with hyphens, leading and
+/// trailing hyphens are trimmed and the result is truncated to `MAX_INDEX_NAME_LENGTH`.
+/// The result may still be empty, which `validate_index_name` rejects.
+pub fn sanitize_index_name(index_name: &str) -> String {
+    let sanitized: String = index_name
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_lowercase() || c.is_ascii_digit() {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let sanitized: String = sanitized
+        .trim_matches('-')
+        .chars()
+        .take(MAX_INDEX_NAME_LENGTH)
+        .collect();
+    sanitized.trim_end_matches('-').to_string()
+}
+
+/// Metadata keys set by the client, which extra fields cannot override.
+pub const RESERVED_METADATA_KEYS: [&str; 15] = [
+    "text",
+    "query_id",
+    "topic",
+    "description",
+    "source",
+    "author",
+    "page",
+    "timestamp",
+    "split",
+    "image_url",
+    "lang",
+    "chunk_index",
+    "chunk_total",
+    "chunk_start",
+    "chunk_end",
+];
+
+/// Builds the metadata stored alongside an embedding.
+///
+/// The metadata holds the text that is embedded, e.g. the text of a chunk, as `text`, the `split`
+/// criteria if given (e.g. `token_count:512:1`, to compare chunking strategies within an index)
+/// and, if a document is given:
+/// - Its `query_id`.
+/// - Its `topic`, `description`, `source`, `author` and `page`, if any, so that queries can
+///   filter on them.
+/// - Its `date` as a `timestamp` number of seconds since the epoch, if it can be parsed
+///   (see `parse_date`), so that queries can filter on a date range.
+/// - Its `image_url`, if any, marking the embedding as derived from an image.
+/// - Its `detected_lang`, if any, as `lang`, so that queries can filter on the language.
+/// - Its extra fields.
+///
+/// # Errors
+///
+/// Pinecone metadata is flat, so this function returns an error if an extra field:
+/// - Is one of the `RESERVED_METADATA_KEYS`.
+/// - Holds a `null`, a nested object, or a list of anything but strings.
+pub fn build_metadata(
+    text: String,
+    document: Option<&TextToEmbed>,
+    split: Option<&SplitCriteria>,
+) -> Result<MetadataFields> {
+    let mut fields = MetadataFields::from_iter(vec![("text".to_string(), text.into())]);
+    if let Some(split) = split {
+        fields.insert("split".to_string(), split.to_string().into());
+    }
+    let Some(document) = document else {
+        return Ok(fields);
+    };
+    fields.insert("query_id".to_string(), document.query_id.clone().into());
+    for (key, value) in [
+        ("topic", &document.topic),
+        ("description", &document.description),
+        ("source", &document.source),
+        ("author", &document.author),
+    ] {
+        if let Some(value) = value {
+            fields.insert(key.to_string(), value.clone().into());
+        }
+    }
+    if let Some(page) = document.page {
+        fields.insert("page".to_string(), f64::from(page).into());
+    }
+    if let Some(date) = &document.date {
+        match parse_date(date) {
+            Ok(timestamp) => {
+                fields.insert("timestamp".to_string(), (timestamp as f64).into());
+            }
+            Err(e) => warn!("Not storing a timestamp: {}", e),
+        }
+    }
+    if let Some(image_url) = &document.image_url {
+        fields.insert("image_url".to_string(), image_url.clone().into());
+    }
+    if let Some(lang) = &document.detected_lang {
+        fields.insert("lang".to_string(), lang.clone().into());
+    }
+    for (key, value) in document.extra.iter().flatten() {
+        if RESERVED_METADATA_KEYS.contains(&key.as_str()) {
+            return Err(anyhow::anyhow!(
+                "Invalid metadata field '{}': the key is reserved",
+                key
+            ));
+        }
+        fields.insert(key.clone(), metadata_value(key, value)?);
+    }
+    Ok(fields)
+}
+
+/// Prepends the `topic` and `description` of a document, if any, to the text of one of its
+/// chunks, as lightweight context for the embedding model, e.g.
+/// `"Topic: Rust\nDescription: A systems language\n\nRust is fast."`.
+///
+/// The text is returned as is if the document has neither.
+pub fn prepend_document_context(
+    text: &str,
+    topic: Option<&str>,
+    description: Option<&str>,
+) -> String {
+    let mut context = String::new();
+    for (label, value) in [("Topic", topic), ("Description", description)] {
+        if let Some(value) = value.map(str::trim).filter(|value| !value.is_empty()) {
+            context.push_str(&format!("{}: {}\n", label, value));
+        }
+    }
+    if context.is_empty() {
+        return text.to_string();
+    }
+    format!("{}\n{}", context, text)
+}
+
+/// Builds the metadata stored alongside the embedding of a chunk, as `build_metadata` from the
+/// text of the chunk, adding:
+/// - The position of the chunk among the chunks of its document as `chunk_index`, and their
+///   number as `chunk_total`.
+/// - The byte range of the chunk in its document as `chunk_start` and `chunk_end`, if known.
+/// - The `query_id` of the chunk, if any, for chunks stored without their document.
+///
+/// # Errors
+///
+/// This function will return an error as `build_metadata`.
+pub fn chunk_metadata(
+    chunk: &Chunk,
+    document: Option<&TextToEmbed>,
+    split: Option<&SplitCriteria>,
+) -> Result<MetadataFields> {
+    let mut metadata = build_metadata(chunk.text.clone(), document, split)?;
+    let number = |value: usize| MetadataValue::Num(value as f64);
+    metadata.insert("chunk_index".to_string(), number(chunk.index));
+    metadata.insert("chunk_total".to_string(), number(chunk.total));
+    if let Some((start, end)) = chunk.source_offsets {
+        metadata.insert("chunk_start".to_string(), number(start));
+        metadata.insert("chunk_end".to_string(), number(end));
+    }
+    if let Some(query_id) = &chunk.query_id {
+        metadata.insert("query_id".to_string(), query_id.clone().into());
+    }
+    Ok(metadata)
+}
+
+/// Checks that the content of a document is small enough to be stored as a raw document,
+/// see `MAX_RAW_DOCUMENT_BYTES`.
+///
+/// # Errors
+///
+/// Returns a `RagError::InvalidInput` stating the limit if the content is larger.
+pub fn check_raw_document_size(document: &TextToEmbed) -> Result<()> {
+    if document.content.len() > MAX_RAW_DOCUMENT_BYTES {
+        return Err(RagError::InvalidInput(format!(
+            "content is {} bytes, more than the maximum of {} bytes of a raw document",
+            document.content.len(),
+            MAX_RAW_DOCUMENT_BYTES
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Returns the string value of a metadata field, if any.
+pub fn string_field(metadata: &MetadataFields, key: &str) -> Option<String> {
+    metadata.get(key)?.as_str().map(str::to_string)
+}
+
+/// Returns the `chunk_index` stored in the metadata of a chunk, if any.
+pub fn chunk_index_field(metadata: &MetadataFields) -> Option<usize> {
+    match metadata.get("chunk_index")?.as_f64()? {
+        chunk_index if chunk_index >= 0.0 => Some(chunk_index as usize),
+        _ => None,
+    }
+}
+
+/// Returns the `timestamp` stored in the metadata of a chunk, if any.
+pub fn timestamp_field(metadata: &MetadataFields) -> Option<i64> {
+    Some(metadata.get("timestamp")?.as_f64()? as i64)
+}
+
+/// Parses a date into a number of seconds since the epoch.
+///
+/// Both RFC 3339 dates (e.g. `2024-11-01T12:00:00.000Z`, as in note tweets) and the X
+/// archive format (e.g. `Fri Nov 01 12:00:00 +0000 2024`, as in tweets) are accepted.
+///
+/// # Errors
+///
+/// Returns a `RagError::InvalidInput` if the date matches neither format.
+pub fn parse_date(date: &str) -> Result<i64> {
+    DateTime::parse_from_rfc3339(date)
+        .or_else(|_| DateTime::parse_from_str(date, "%a %b %d %H:%M:%S %z %Y"))
+        .map(|date| date.timestamp())
+        .map_err(|e| RagError::InvalidInput(format!("Invalid date '{}': {}", date, e)).into())
+}
+
+/// Builds the metadata filter of a query, combining an optional user filter with an
+/// optional date range on the `timestamp` metadata field.
+///
+/// Both bounds of the date range are inclusive, and are parsed with `parse_date`. When
+/// both a user filter and a date range are given, they are combined with `$and`.
+///
+/// # Errors
+///
+/// Returns a `RagError::InvalidInput` if a date cannot be parsed.
+pub fn query_filter(
+    filter: Option<&serde_json::Map<String, serde_json::Value>>,
+    date_from: Option<&str>,
+    date_to: Option<&str>,
+) -> Result<Option<MetadataFilter>> {
+    let mut range = serde_json::Map::new();
+    if let Some(date_from) = date_from {
+        range.insert("$gte".to_string(), json!(parse_date(date_from)?));
+    }
+    if let Some(date_to) = date_to {
+        range.insert("$lte".to_string(), json!(parse_date(date_to)?));
+    }
+    let date_filter = (!range.is_empty()).then(|| json!({ "timestamp": range }));
+    let filter = match (filter, date_filter) {
+        (Some(filter), Some(date_filter)) => json!({ "$and": [filter, date_filter] }),
+        (Some(filter), None) => return Ok(Some(filter.clone())),
+        (None, Some(date_filter)) => date_filter,
+        (None, None) => return Ok(None),
+    };
+    match filter {
+        serde_json::Value::Object(filter) => Ok(Some(filter)),
+        _ => unreachable!("Filters are JSON objects"),
+    }
+}
+
+/// Builds the metadata filter of a deletion, `None` meaning that everything is deleted.
+///
+/// # Errors
+///
+/// Returns a `RagError::InvalidInput` if the filter is not a JSON object, or if it is empty,
+/// which would delete everything, without `confirm`.
+pub fn delete_filter(filter: &serde_json::Value, confirm: bool) -> Result<Option<MetadataFilter>> {
+    let fields = match filter {
+        serde_json::Value::Object(fields) => fields,
+        _ => {
+            return Err(RagError::InvalidInput(
+                "the delete filter must be a JSON object".to_string(),
+            )
+            .into())
+        }
+    };
+    if fields.is_empty() {
+        if !confirm {
+            return Err(RagError::InvalidInput(
+                "an empty delete filter deletes everything, set `confirm` to proceed".to_string(),
+            )
+            .into());
+        }
+        return Ok(None);
+    }
+    Ok(Some(fields.clone()))
+}
+
+/// Converts a metadata filter into its Pinecone counterpart.
+fn sdk_filter(filter: MetadataFilter) -> Metadata {
+    match json_value(&serde_json::Value::Object(filter)).kind {
+        Some(Kind::StructValue(filter)) => filter,
+        _ => unreachable!("The filter is a JSON object"),
+    }
+}
+
+/// Converts any JSON value into a Pinecone value, e.g. to build metadata filters.
+fn json_value(value: &serde_json::Value) -> Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(b) => Kind::BoolValue(*b),
+        serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or(f64::NAN)),
+        serde_json::Value::String(s) => Kind::StringValue(s.clone()),
+        serde_json::Value::Array(values) => Kind::ListValue(ListValue {
+            values: values.iter().map(json_value).collect(),
+        }),
+        serde_json::Value::Object(fields) => Kind::StructValue(Metadata {
+            fields: fields
+                .iter()
+                .map(|(key, value)| (key.clone(), json_value(value)))
+                .collect(),
+        }),
+    };
+    Value { kind: Some(kind) }
+}
+
+/// Builds the metadata filter matching the embeddings stored for the given query id.
+pub fn query_id_filter(query_id: &str) -> MetadataFilter {
+    match json!({ "query_id": { "$eq": query_id } }) {
+        serde_json::Value::Object(filter) => filter,
+        _ => unreachable!("The filter is a JSON object"),
+    }
+}
+
+/// Converts a JSON value into a metadata value.
+fn metadata_value(key: &str, value: &serde_json::Value) -> Result<MetadataValue> {
+    let value = match value {
+        serde_json::Value::String(s) => MetadataValue::Str(s.clone()),
+        serde_json::Value::Bool(b) => MetadataValue::Bool(*b),
+        serde_json::Value::Number(n) => match n.as_f64() {
+            Some(n) => MetadataValue::Num(n),
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Invalid metadata field '{}': number {} is out of range",
+                    key,
+                    n
+                ))
+            }
+        },
+        serde_json::Value::Array(values) => {
+            let values = values
+                .iter()
+                .map(|value| match value {
+                    serde_json::Value::String(s) => Ok(s.clone()),
+                    _ => Err(anyhow::anyhow!(
+                        "Invalid metadata field '{}': lists may only contain strings",
+                        key
+                    )),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            MetadataValue::List(values)
+        }
+        serde_json::Value::Object(_) => {
+            return Err(anyhow::anyhow!(
+                "Invalid metadata field '{}': nested objects are not supported",
+                key
+            ))
+        }
+        serde_json::Value::Null => {
+            return Err(anyhow::anyhow!(
+                "Invalid metadata field '{}': null values are not supported",
+                key
+            ))
+        }
+    };
+    Ok(value)
+}
+
+/// Checks whether a score satisfies the threshold for the given metric.
+///
+/// For `Cosine` and `Dotproduct` a higher score is a better match, so scores
+/// at or above the threshold are kept. For `Euclidean` the score is a distance,
+/// so scores at or below the threshold are kept.
+pub fn passes_score_threshold(score: f32, score_threshold: f32, metric: &Metric) -> bool {
+    match metric {
+        Metric::Cosine | Metric::Dotproduct => score >= score_threshold,
+        Metric::Euclidean => score <= score_threshold,
+    }
+}
+
+/// Retains only the query results whose score satisfies the threshold for the given metric.
+pub fn apply_score_threshold(
+    query_response: &mut Vec<QueryResponse>,
+    score_threshold: f32,
+    metric: &Metric,
+) {
+    query_response.retain(|result| passes_score_threshold(result.score, score_threshold, metric));
+}
+
+/// Rescales the scores of query results to [0, 1] for the given metric, keeping the
+/// original scores in `raw_score`. Higher rescaled scores are always better matches.
+///
+/// Cosine scores in [-1, 1] map to `(s + 1) / 2`, and Euclidean distances to
+/// `1 / (1 + d)`. Dot-product scores are unbounded, so they are min-max scaled over the
+/// given results, which all score `1.0` when their scores are equal.
+pub fn normalize_scores(query_response: &mut [QueryResponse], metric: &Metric) {
+    let (min, max) = query_response
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), result| {
+            (min.min(result.score), max.max(result.score))
+        });
+    for result in query_response.iter_mut() {
+        let score = result.score;
+        // NOTE: Scores already weighted by recency keep the score returned by the index
+        result.raw_score.get_or_insert(score);
+        result.score = match metric {
+            Metric::Cosine => ((score + 1.0) / 2.0).clamp(0.0, 1.0),
+            Metric::Euclidean => 1.0 / (1.0 + score.max(0.0)),
+            Metric::Dotproduct if max > min => (score - min) / (max - min),
+            Metric::Dotproduct => 1.0,
+        };
+    }
+}
+
+/// Number of seconds in a day, the unit of recency half-lives.
+const SECONDS_PER_DAY: f32 = 86_400.0;
+
+/// Returns the factor weighting the score of a result by its recency, halved every
+/// `half_life_days` of age. Results without a timestamp, or dated after `now`, get a neutral
+/// factor of 1.
+pub fn recency_factor(timestamp: Option<i64>, now: i64, half_life_days: f32) -> f32 {
+    let Some(timestamp) = timestamp else {
+        return 1.0;
+    };
+    let age_days = now.saturating_sub(timestamp).max(0) as f32 / SECONDS_PER_DAY;
+    0.5f32.powf(age_days / half_life_days)
+}
+
+/// Weights the scores of query results by their `recency_factor` at `now`, keeping the score
+/// returned by the index in `raw_score`, then sorts them best first again.
+///
+/// Similarity scores are multiplied by the factor, and Euclidean distances divided by it, so
+/// that older results rank lower with either metric.
+pub fn apply_recency(
+    query_response: &mut [QueryResponse],
+    half_life_days: f32,
+    now: i64,
+    metric: &Metric,
+) {
+    for result in query_response.iter_mut() {
+        let factor = recency_factor(result.timestamp, now, half_life_days);
+        let score = result.score;
+        result.raw_score = Some(score);
+        result.score = match metric {
+            Metric::Euclidean => score / factor.max(f32::MIN_POSITIVE),
+            _ => score * factor,
+        };
+    }
+    query_response.sort_by(|a, b| match metric {
+        Metric::Euclidean => a.score.total_cmp(&b.score),
+        _ => b.score.total_cmp(&a.score),
+    });
+}
+
+/// Merges the results of the queries of several vectors, e.g. the chunks of a long query,
+/// keeping the best score of each result, ordered best first for the given metric, and at most
+/// `top_k` of them.
+pub fn merge_multi_vector_results(
+    results: Vec<Vec<QueryResponse>>,
+    metric: &Metric,
+    top_k: usize,
+) -> Vec<QueryResponse> {
+    let better = |a: f32, b: f32| match metric {
+        Metric::Euclidean => a < b,
+        _ => a > b,
+    };
+    let mut best: HashMap<String, QueryResponse> = HashMap::new();
+    for result in results.into_iter().flatten() {
+        match best.get(&result.id) {
+            Some(kept) if !better(result.score, kept.score) => {}
+            _ => {
+                best.insert(result.id.clone(), result);
+            }
+        }
+    }
+    let mut merged: Vec<QueryResponse> = best.into_values().collect();
+    // NOTE: Ties are broken by id, so that the order does not depend on the hash map
+    merged.sort_by(|a, b| {
+        match metric {
+            Metric::Euclidean => a.score.total_cmp(&b.score),
+            _ => b.score.total_cmp(&a.score),
+        }
+        .then_with(|| a.id.cmp(&b.id))
+    });
+    merged.truncate(top_k);
+    merged
+}
+
+/// Partitions vectors into consecutive batches of at most `batch_size` vectors, each sent
+/// to Pinecone in its own upsert request.
+pub fn upsert_batches(vectors: Vec<Vector>, batch_size: usize) -> Vec<Vec<Vector>> {
+    let batch_size = batch_size.max(1);
+    let mut batches = Vec::with_capacity(vectors.len().div_ceil(batch_size));
+    let mut vectors = vectors.into_iter().peekable();
+    while vectors.peek().is_some() {
+        batches.push(vectors.by_ref().take(batch_size).collect());
+    }
+    batches
+}
+
+/// Whether an upsert error may come from a recreated index: the index is not found, or
+/// rejects the dimension of the vectors.
+fn is_stale_index_error(error: &PineconeError) -> bool {
+    match error {
+        PineconeError::IndexNotFoundError { .. } => true,
+        PineconeError::DataPlaneError { status } => match status.code() {
+            tonic::Code::NotFound => true,
+            tonic::Code::InvalidArgument => status.message().contains("dimension"),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Sets the `norm` of each query result to the L2 norm of its `embedding`, if returned.
+pub fn set_norms(query_response: &mut [QueryResponse]) {
+    for result in query_response.iter_mut() {
+        if !result.embedding.is_empty() {
+            result.norm = Some(l2_norm(&result.embedding));
+        }
+    }
+}
+
+/// Empties the `embedding` of each query result, to keep responses small.
+pub fn strip_embeddings(query_response: &mut [QueryResponse]) {
+    for result in query_response.iter_mut() {
+        result.embedding = Vec::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        chunk, mock_embedding, spawn_data_plane, spawn_server, test_client, text_to_embed,
+        word_level_tokenizer, MockControlPlane, MockDataPlane, MockEmbedder,
+    };
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn response(score: f32, text: &str) -> QueryResponse {
+        QueryResponse {
+            id: text.to_string(),
+            score,
+            raw_score: None,
+            embedding: vec![],
+            text: text.to_string(),
+            index_name: None,
+            query_id: None,
+            chunk_index: None,
+            context: None,
+            timestamp: None,
+            author: None,
+            norm: None,
+        }
+    }
+
+    fn index_response(score: f32, text: &str, index_name: &str) -> QueryResponse {
+        QueryResponse {
+            index_name: Some(index_name.to_string()),
+            ..response(score, text)
+        }
+    }
+
+    #[test]
+    fn test_upsert_batches_partitions_vectors() {
+        let vectors: Vec<Vector> = (0..250)
+            .map(|i| Vector {
+                id: vector_id(None, i),
+                values: vec![i as f32],
+                sparse_values: None,
+                metadata: None,
+            })
+            .collect();
+        let batches = upsert_batches(vectors, DEFAULT_UPSERT_BATCH_SIZE);
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![100, 100, 50]);
+        // Batches keep the order of the vectors
+        assert_eq!(batches[1][0].id, vector_id(None, 100));
+        assert_eq!(batches[2][49].id, vector_id(None, 249));
+
+        assert!(upsert_batches(vec![], DEFAULT_UPSERT_BATCH_SIZE).is_empty());
+    }
+
+    #[test]
+    fn test_strip_embeddings_shrinks_payload() {
+        let mut results: Vec<QueryResponse> = (0..10)
+            .map(|i| QueryResponse {
+                embedding: vec![0.123_456; 768],
+                ..response(1.0 - i as f32 / 10.0, "some text")
+            })
+            .collect();
+        let full_size = serde_json::to_string(&results).unwrap().len();
+        strip_embeddings(&mut results);
+        assert!(results.iter().all(|result| result.embedding.is_empty()));
+        assert!(serde_json::to_string(&results).unwrap().len() * 10 < full_size);
+    }
+
+    #[test]
+    fn test_set_norms_matches_manual_computation() {
+        let mut results = vec![
+            QueryResponse {
+                embedding: vec![3.0, 4.0],
+                ..response(0.9, "known")
+            },
+            QueryResponse {
+                embedding: vec![0.0, 0.0],
+                ..response(0.5, "degenerate")
+            },
+            response(0.1, "stripped"),
+        ];
+        set_norms(&mut results);
+        assert_eq!(results[0].norm, Some(5.0));
+        assert_eq!(results[1].norm, Some(0.0));
+        assert_eq!(results[2].norm, None);
+    }
+
+    #[test]
+    fn test_merge_multi_vector_results_keeps_best_scores() {
+        let results = vec![
+            vec![response(0.9, "a"), response(0.2, "b")],
+            vec![response(0.8, "b"), response(0.3, "a"), response(0.1, "c")],
+        ];
+        let merged = merge_multi_vector_results(results.clone(), &Metric::Cosine, 10);
+        let scores: Vec<(&str, f32)> = merged
+            .iter()
+            .map(|result| (result.id.as_str(), result.score))
+            .collect();
+        assert_eq!(scores, vec![("a", 0.9), ("b", 0.8), ("c", 0.1)]);
+
+        // Lower distances are better matches
+        let merged = merge_multi_vector_results(results, &Metric::Euclidean, 2);
+        let scores: Vec<(&str, f32)> = merged
+            .iter()
+            .map(|result| (result.id.as_str(), result.score))
+            .collect();
+        assert_eq!(scores, vec![("c", 0.1), ("b", 0.2)]);
+    }
+
+    #[test]
+    fn test_score_threshold_cosine_keeps_highest() {
+        let mut results = vec![
+            response(0.9, "close"),
+            response(0.6, "medium"),
+            response(0.2, "far"),
+        ];
+        apply_score_threshold(&mut results, 0.5, &Metric::Cosine);
+        let texts: Vec<_> = results.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, vec!["close", "medium"]);
+    }
+
+    #[test]
+    fn test_score_threshold_euclidean_keeps_closest() {
+        let mut results = vec![
+            response(0.1, "close"),
+            response(0.4, "medium"),
+            response(2.5, "far"),
+        ];
+        apply_score_threshold(&mut results, 0.5, &Metric::Euclidean);
+        let texts: Vec<_> = results.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, vec!["close", "medium"]);
+    }
+
+    fn normalized(scores: &[f32], metric: Metric) -> Vec<(f32, Option<f32>)> {
+        let mut results: Vec<QueryResponse> = scores
+            .iter()
+            .map(|score| response(*score, "text"))
+            .collect();
+        normalize_scores(&mut results, &metric);
+        results.iter().map(|r| (r.score, r.raw_score)).collect()
+    }
+
+    #[test]
+    fn test_normalize_scores_cosine() {
+        assert_eq!(
+            normalized(&[1.0, 0.0, -1.0], Metric::Cosine),
+            vec![(1.0, Some(1.0)), (0.5, Some(0.0)), (0.0, Some(-1.0))]
+        );
+    }
+
+    #[test]
+    fn test_normalize_scores_euclidean() {
+        // Distances are inverted, so the closest result scores highest
+        assert_eq!(
+            normalized(&[0.0, 1.0, 3.0], Metric::Euclidean),
+            vec![(1.0, Some(0.0)), (0.5, Some(1.0)), (0.25, Some(3.0))]
+        );
+    }
+
+    #[test]
+    fn test_normalize_scores_dotproduct() {
+        assert_eq!(
+            normalized(&[12.0, 7.0, 2.0], Metric::Dotproduct),
+            vec![(1.0, Some(12.0)), (0.5, Some(7.0)), (0.0, Some(2.0))]
+        );
+        // Equal scores cannot be min-max scaled
+        assert_eq!(
+            normalized(&[3.0, 3.0], Metric::Dotproduct),
+            vec![(1.0, Some(3.0)), (1.0, Some(3.0))]
+        );
+    }
+
+    #[test]
+    fn test_recency_reorders_old_high_scores_below_recent_ones() {
+        let now = 1_735_689_600;
+        let day = 86_400;
+        let dated = |score: f32, text: &str, timestamp: Option<i64>| QueryResponse {
+            timestamp,
+            ..response(score, text)
+        };
+        let mut results = vec![
+            dated(0.9, "old", Some(now - 60 * day)),
+            dated(0.7, "recent", Some(now - day)),
+            dated(0.5, "undated", None),
+        ];
+        apply_recency(&mut results, 30.0, now, &Metric::Cosine);
+        let texts: Vec<&str> = results.iter().map(|result| result.text.as_str()).collect();
+        assert_eq!(texts, vec!["recent", "undated", "old"]);
+        // Two half-lives quarter the score of the old result, undated results are not weighted
+        assert!((results[2].score - 0.225).abs() < 1e-6);
+        assert_eq!(results[2].raw_score, Some(0.9));
+        assert_eq!(results[1].score, 0.5);
+        assert_eq!(results[1].raw_score, Some(0.5));
+
+        // Distances grow with age instead
+        let mut results = vec![
+            dated(1.0, "old", Some(now - 60 * day)),
+            dated(2.0, "recent", Some(now)),
+        ];
+        apply_recency(&mut results, 30.0, now, &Metric::Euclidean);
+        assert_eq!(results[0].text, "recent");
+        assert_eq!(results[1].score, 4.0);
+        assert_eq!(recency_factor(Some(now + day), now, 30.0), 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_ensure_index_creates_missing_index_once() {
+        let control_plane = MockControlPlane::default().with_index("existing", 4, "cosine");
+        let addr = spawn_server(control_plane.router()).await;
+        let client = Arc::new(Mutex::new(test_client(addr)));
+
+        let created = client
+            .lock()
+            .await
+            .ensure_index("existing", 4, None)
+            .await
+            .unwrap();
+        assert!(!created);
+        assert_eq!(*control_plane.create_calls.lock().unwrap(), 0);
+
+        let handles = (0..2).map(|_| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                client
+                    .lock()
+                    .await
+                    .ensure_index("missing", 4, Some(Metric::Dotproduct))
+                    .await
+                    .unwrap()
+            })
+        });
+        let mut created = 0;
+        for handle in handles {
+            if handle.await.unwrap() {
+                created += 1;
+            }
+        }
+        assert_eq!(created, 1);
+        assert_eq!(*control_plane.create_calls.lock().unwrap(), 1);
+        assert!(control_plane
+            .indexes
+            .lock()
+            .unwrap()
+            .contains_key("missing"));
+    }
+
+    #[tokio::test]
+    async fn test_ensure_index_checks_the_dimension_of_existing_indexes() {
+        let control_plane = MockControlPlane::default().with_index("existing", 4, "cosine");
+        let addr = spawn_server(control_plane.router()).await;
+        let mut client = test_client(addr);
+
+        // Absent, the index is created, and its dimension cached
+        assert!(client.ensure_index("absent", 8, None).await.unwrap());
+        assert!(!client.ensure_index("absent", 8, None).await.unwrap());
+        assert_eq!(*control_plane.create_calls.lock().unwrap(), 1);
+        assert_eq!(*control_plane.describe_calls.lock().unwrap(), 0);
+
+        // Present with a matching dimension, ensuring it is idempotent
+        for _ in 0..2 {
+            assert!(!client.ensure_index("existing", 4, None).await.unwrap());
+        }
+        assert_eq!(*control_plane.describe_calls.lock().unwrap(), 1);
+
+        // Present with another dimension
+        let error = client
+            .ensure_index("existing", 768, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RagError>(),
+            Some(RagError::AlreadyExists(_))
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Already exists: index existing already exists with dimension 4, not 768"
+        );
+        assert_eq!(*control_plane.create_calls.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_prefix_by_default() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let client = test_client(addr);
+        client
+            .create_embedding("plain text", EmbeddingKind::Document)
+            .await
+            .unwrap();
+        assert_eq!(embedder.inputs(), vec!["plain text"]);
+    }
+
+    #[tokio::test]
+    async fn test_embedding_cache_hit_skips_request() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let client = test_client(addr)
+            .with_prefixes(None, Some("search_query: ".to_string()))
+            .with_embedding_cache(NonZeroUsize::new(8));
+
+        let first = client
+            .create_embedding("cached text", EmbeddingKind::Document)
+            .await
+            .unwrap();
+        let second = client
+            .create_embedding("cached text", EmbeddingKind::Document)
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(embedder.inputs(), vec!["cached text"]);
+
+        // The query prefix is part of the cache key
+        client
+            .create_embedding("cached text", EmbeddingKind::Query)
+            .await
+            .unwrap();
+        assert_eq!(
+            embedder.inputs(),
+            vec!["cached text", "search_query: cached text"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configurable_input_field() {
+        let embedder = MockEmbedder::new(4).with_input_field("input");
+        let addr = spawn_server(embedder.router()).await;
+        let client = test_client(addr).with_input_field("input");
+        client
+            .create_embedding("openai style", EmbeddingKind::Document)
+            .await
+            .unwrap();
+        assert_eq!(embedder.inputs(), vec!["openai style"]);
+        assert!(embedder.requests.lock().unwrap()[0].get("inputs").is_none());
+
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        test_client(addr)
+            .create_embedding("tei style", EmbeddingKind::Document)
+            .await
+            .unwrap();
+        assert_eq!(embedder.inputs(), vec!["tei style"]);
+    }
+
+    #[tokio::test]
+    async fn test_multimodal_request_includes_image_url() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let client = test_client(addr).with_image_field("image");
+        client
+            .create_embedding_with_image(
+                "a cat",
+                Some("https://example.com/cat.png"),
+                EmbeddingKind::Document,
+            )
+            .await
+            .unwrap();
+        client
+            .create_embedding("a dog", EmbeddingKind::Document)
+            .await
+            .unwrap();
+        let requests = embedder.requests.lock().unwrap();
+        assert_eq!(
+            requests[0],
+            json!({ "inputs": "a cat", "image": "https://example.com/cat.png" })
+        );
+        // Text-only requests keep their shape
+        assert_eq!(requests[1], json!({ "inputs": "a dog" }));
+    }
+
+    #[test]
+    fn test_build_metadata_notes_image_url() {
+        let document = TextToEmbed::builder("id", "index", "a cat")
+            .with_image_url("https://example.com/cat.png")
+            .build();
+        let metadata = build_metadata("a cat".to_string(), Some(&document), None).unwrap();
+        assert_eq!(
+            metadata["image_url"],
+            MetadataValue::from("https://example.com/cat.png".to_string())
+        );
+        let document = TextToEmbed::builder("id", "index", "a dog").build();
+        let metadata = build_metadata("a dog".to_string(), Some(&document), None).unwrap();
+        assert!(!metadata.contains_key("image_url"));
+    }
+
+    #[test]
+    fn test_embed_url_from_host_port_or_base_url() {
+        let addr: std::net::SocketAddr = "10.0.0.7:8080".parse().unwrap();
+        let client = test_client(addr);
+        assert_eq!(client.embed_url(), "http://10.0.0.7:8080/embed");
+
+        let client = client.with_embedding_url(Some("https://tei.example.com/v1".to_string()));
+        assert_eq!(client.embed_url(), "https://tei.example.com/v1/embed");
+        let client = client.with_embedding_url(Some("https://tei.example.com/".to_string()));
+        assert_eq!(client.embed_url(), "https://tei.example.com/embed");
+    }
+
+    #[tokio::test]
+    async fn test_embedding_url_with_path_prefix() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(axum::Router::new().nest("/tei", embedder.router())).await;
+        let client = test_client(addr).with_embedding_url(Some(format!("http://{}/tei", addr)));
+        client
+            .create_embedding("behind a prefix", EmbeddingKind::Document)
+            .await
+            .unwrap();
+        assert_eq!(embedder.inputs(), vec!["behind a prefix"]);
+    }
+
+    #[tokio::test]
+    async fn test_query_score_threshold_looks_up_metric_first() {
+        let embedder = MockEmbedder::new(4);
+        let control_plane = MockControlPlane::default();
+        let router = embedder.router().merge(control_plane.router());
+        let addr = spawn_server(router).await;
+        let client = test_client(addr);
+
+        // The metric of an unknown index cannot be described, so the query is not even embedded
+        let options = QueryOptions {
+            score_threshold: Some(0.5),
+            ..Default::default()
+        };
+        let result = client.query("query", "missing", options).await;
+        assert!(result.is_err());
+        assert!(embedder.inputs().is_empty());
+        assert_eq!(*control_plane.describe_calls.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_score_threshold_drops_matches() {
+        for (metric, matches, kept) in [
+            ("cosine", [("close", 0.9), ("far", 0.2)], "close"),
+            ("euclidean", [("close", 0.2), ("far", 0.9)], "close"),
+        ] {
+            let data_plane =
+                matches
+                    .iter()
+                    .fold(MockDataPlane::default(), |data_plane, (id, score)| {
+                        data_plane.with_match(id, *score, &format!("The {} match.", id))
+                    });
+            // NOTE: The data plane host stands for the index name, as the client queries the
+            // index it names
+            let index_name = format!("http://{}", spawn_data_plane(data_plane.clone()).await);
+            let embedder = MockEmbedder::new(4);
+            let control_plane = MockControlPlane::default().with_index(&index_name, 4, metric);
+            let router = embedder.router().merge(control_plane.router());
+            let client = test_client(spawn_server(router).await);
+
+            let options = QueryOptions {
+                score_threshold: Some(0.5),
+                ..Default::default()
+            };
+            let results = client.query("query", &index_name, options).await.unwrap();
+            let ids: Vec<&str> = results.iter().map(|result| result.id.as_str()).collect();
+            assert_eq!(ids, [kept], "{}", metric);
+            assert_eq!(results[0].text, "The close match.");
+
+            // Without a threshold, every match is kept
+            let results = client
+                .query("query", &index_name, QueryOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(results.len(), 2);
+            assert_eq!(data_plane.queries.lock().unwrap().len(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_multi_text_ranks_by_the_index_metric() {
+        let data_plane = MockDataPlane::default()
+            .with_match("near", 0.1, "The near match.")
+            .with_match("far", 0.9, "The far match.");
+        let index_name = format!("http://{}", spawn_data_plane(data_plane.clone()).await);
+        let embedder = MockEmbedder::new(4);
+        let control_plane = MockControlPlane::default().with_index(&index_name, 4, "euclidean");
+        let router = embedder.router().merge(control_plane.router());
+        let client = test_client(spawn_server(router).await);
+
+        // Lower distances are better matches, so the nearest match is ranked first
+        let queries = vec!["first query".to_string(), "second query".to_string()];
+        let results = client
+            .query_multi_text(queries, &index_name, Some(2))
+            .await
+            .unwrap();
+        let ids: Vec<&str> = results.iter().map(|result| result.id.as_str()).collect();
+        assert_eq!(ids, ["near", "far"]);
+    }
+
+    #[tokio::test]
+    async fn test_index_metric_is_cached_after_first_lookup() {
+        let control_plane = MockControlPlane::default().with_index("cached", 4, "dotproduct");
+        let addr = spawn_server(control_plane.router()).await;
+        let mut client = test_client(addr);
+
+        assert_eq!(
+            client.index_metric("cached").await.unwrap(),
+            Metric::Dotproduct
+        );
+        assert_eq!(
+            client.index_metric("cached").await.unwrap(),
+            Metric::Dotproduct
+        );
+        assert_eq!(*control_plane.describe_calls.lock().unwrap(), 1);
+
+        // Creating an index caches its metric without describing it again
+        client
+            .create_index("created", 4, Some(Metric::Euclidean), WaitPolicy::NoWait)
+            .await
+            .unwrap();
+        let describe_calls = *control_plane.describe_calls.lock().unwrap();
+        assert_eq!(
+            client.index_metric("created").await.unwrap(),
+            Metric::Euclidean
+        );
+        assert_eq!(
+            *control_plane.describe_calls.lock().unwrap(),
+            describe_calls
+        );
+    }
+
+    /// Drops the results of a blocked author and ranks those of a featured author first.
+    struct AuthorRules {
+        blocked: &'static str,
+        featured: &'static str,
+    }
+
+    impl ResultPostProcessor for AuthorRules {
+        fn process(&self, results: Vec<QueryResponse>) -> Vec<QueryResponse> {
+            let mut results: Vec<_> = results
+                .into_iter()
+                .filter(|result| result.author.as_deref() != Some(self.blocked))
+                .collect();
+            results.sort_by_key(|result| result.author.as_deref() != Some(self.featured));
+            results
+        }
+    }
+
+    #[test]
+    fn test_result_post_processor_filters_and_reorders_results() {
+        let by = |score: f32, text: &str, author: &str| QueryResponse {
+            author: Some(author.to_string()),
+            ..response(score, text)
+        };
+        let results = vec![
+            by(0.9, "spam", "spammer"),
+            by(0.8, "first", "atoma"),
+            by(0.7, "second", "rustacean"),
+            by(0.6, "third", "atoma"),
+        ];
+        let texts =
+            |results: Vec<QueryResponse>| results.into_iter().map(|r| r.text).collect::<Vec<_>>();
+
+        let client = test_client("127.0.0.1:1".parse().unwrap());
+        assert_eq!(
+            texts(client.post_process(results.clone())),
+            vec!["spam", "first", "second", "third"]
+        );
+
+        let client = client.with_result_post_processor(Some(Arc::new(AuthorRules {
+            blocked: "spammer",
+            featured: "rustacean",
+        })));
+        assert_eq!(
+            texts(client.post_process(results.clone())),
+            vec!["second", "first", "third"]
+        );
+
+        // Closures are post-processors too
+        let client =
+            client.with_result_post_processor(Some(Arc::new(|mut results: Vec<QueryResponse>| {
+                results.reverse();
+                results
+            })));
+        assert_eq!(
+            texts(client.post_process(results)),
+            vec!["third", "second", "first", "spam"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upsert_is_retried_once_after_index_recreation() {
+        let control_plane = MockControlPlane::default().with_index("recreated", 4, "cosine");
+        let addr = spawn_server(control_plane.router()).await;
+        let data_plane = MockDataPlane::default();
+        let mut client = test_client(addr);
+        let host = format!("http://{}", spawn_data_plane(data_plane.clone()).await);
+        assert_eq!(client.index_dimension("recreated").await.unwrap(), 4);
+
+        // The index is recreated with another dimension behind the client's back, so the
+        // first upsert fails, and is retried once the index is described again
+        let control_plane = control_plane.with_index("recreated", 8, "dotproduct");
+        data_plane.fail_next_upsert(
+            tonic::Code::InvalidArgument,
+            "Vector dimension 8 does not match the dimension of the index 4",
+        );
+        let stored = client
+            .store_embeddings(
+                &host,
+                "recreated",
+                vec![(chunk("Some text."), vec![vec![0.1; 8]])],
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(stored, 1);
+        assert_eq!(data_plane.upserts.lock().unwrap().len(), 2);
+        assert_eq!(*control_plane.describe_calls.lock().unwrap(), 2);
+        assert_eq!(client.index_dimension("recreated").await.unwrap(), 8);
+        assert_eq!(
+            client.index_metric("recreated").await.unwrap(),
+            Metric::Dotproduct
+        );
+        assert_eq!(*control_plane.describe_calls.lock().unwrap(), 2);
+
+        // A genuine mismatch fails after a single refresh, without retrying
+        data_plane.fail_next_upsert(
+            tonic::Code::InvalidArgument,
+            "Vector dimension 16 does not match the dimension of the index 8",
+        );
+        let error = client
+            .store_embeddings(
+                &host,
+                "recreated",
+                vec![(chunk("Some text."), vec![vec![0.1; 16]])],
+                None,
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RagError>(),
+            Some(RagError::InvalidInput(_))
+        ));
+        assert_eq!(data_plane.upserts.lock().unwrap().len(), 3);
+        assert_eq!(*control_plane.describe_calls.lock().unwrap(), 3);
+    }
+
+    async fn embedding_error(status: axum::http::StatusCode, body: &'static str) -> RagError {
+        let router = axum::Router::new().route(
+            "/embed",
+            axum::routing::post(move || async move { (status, body) }),
+        );
+        let addr = spawn_server(router).await;
+        let error = test_client(addr)
+            .create_embedding("some text", EmbeddingKind::Document)
+            .await
+            .unwrap_err();
+        error.downcast::<RagError>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_embedding_error_status_includes_body() {
+        let error = embedding_error(
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            "<html><body>Internal Server Error</body></html>",
+        )
+        .await;
+        let RagError::Embedding { message, body } = &error else {
+            panic!("Expected an embedding error, got {:?}", error);
+        };
+        assert!(message.contains("500"), "{}", message);
+        assert_eq!(body, "<html><body>Internal Server Error</body></html>");
+        assert_eq!(error.status_code(), axum::http::StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_embedding_garbage_body_includes_snippet() {
+        let error = embedding_error(axum::http::StatusCode::OK, "not json at all").await;
+        let RagError::Embedding { message, body } = &error else {
+            panic!("Expected an embedding error, got {:?}", error);
+        };
+        assert!(message.contains("invalid response"), "{}", message);
+        assert_eq!(body, "not json at all");
+    }
+
+    #[test]
+    fn test_body_snippet_is_truncated() {
+        let body = "é".repeat(MAX_BODY_SNIPPET_CHARS + 10);
+        let snippet = body_snippet(&body);
+        assert_eq!(snippet.chars().count(), MAX_BODY_SNIPPET_CHARS + 3);
+        assert!(snippet.ends_with("..."));
+        assert_eq!(body_snippet("short"), "short");
+    }
+
+    #[test]
+    fn test_embedding_response_shapes() {
+        let parse = |body: &str| {
+            serde_json::from_str::<EmbeddingResponse>(body).map(EmbeddingResponse::into_embeddings)
+        };
+        assert_eq!(parse("[0.1, 0.2]").unwrap(), vec![vec![0.1, 0.2]]);
+        assert_eq!(parse("[[0.1, 0.2]]").unwrap(), vec![vec![0.1, 0.2]]);
+        assert_eq!(
+            parse(r#"{"embedding": [0.1, 0.2]}"#).unwrap(),
+            vec![vec![0.1, 0.2]]
+        );
+        assert_eq!(
+            parse(r#"{"object": "list", "data": [{"index": 0, "embedding": [0.1, 0.2]}]}"#)
+                .unwrap(),
+            vec![vec![0.1, 0.2]]
+        );
+        assert!(parse(r#"{"vectors": [0.1, 0.2]}"#).is_err());
+    }
+
+    #[test]
+    fn test_decode_base64_embedding() {
+        // Little-endian bytes of [1.0, -2.0, 0.5, 0.25]
+        let encoded = "AACAPwAAAMAAAAA/AACAPg==";
+        let expected = vec![1.0, -2.0, 0.5, 0.25];
+        assert_eq!(decode_base64_embedding(encoded).unwrap(), expected);
+        assert!(decode_base64_embedding("not base64!").is_err());
+        // Six bytes are not a whole number of f32
+        assert!(decode_base64_embedding("AACAPwAA").is_err());
+
+        let body = format!(
+            r#"{{"data": [{{"index": 0, "embedding": "{}"}}]}}"#,
+            encoded
+        );
+        let response = serde_json::from_str::<EmbeddingResponse>(&body).unwrap();
+        assert_eq!(response.into_embeddings(), vec![expected]);
+
+        let client = EmbeddingClient::without_pinecone("localhost".to_string(), 8080)
+            .unwrap()
+            .with_embedding_encoding(EmbeddingEncoding::Base64);
+        assert_eq!(
+            client.embedding_request("text", None)["encoding_format"],
+            "base64"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_and_single_response_shapes() {
+        let text = "some text";
+        let expected = vec![mock_embedding(text, 4)];
+
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let batch = test_client(addr)
+            .create_embedding(text, EmbeddingKind::Document)
+            .await
+            .unwrap();
+        assert_eq!(batch, expected);
+
+        let embedder = MockEmbedder::new(4).with_single_response();
+        let addr = spawn_server(embedder.router()).await;
+        let single = test_client(addr)
+            .create_embedding(text, EmbeddingKind::Document)
+            .await
+            .unwrap();
+        assert_eq!(single, expected);
+    }
+
+    fn sequence_guard(max_sequence_tokens: usize, mode: OverflowMode) -> Option<SequenceGuard> {
+        Some(SequenceGuard {
+            tokenizer: Arc::new(word_level_tokenizer()),
+            max_sequence_tokens,
+            mode,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_sequence_guard_passes_short_texts_through() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let client = test_client(addr).with_sequence_guard(sequence_guard(4, OverflowMode::Error));
+        client
+            .create_embedding("one two three four", EmbeddingKind::Document)
+            .await
+            .unwrap();
+        assert_eq!(embedder.inputs(), vec!["one two three four"]);
+    }
+
+    #[tokio::test]
+    async fn test_sequence_guard_truncates_long_texts() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let client = test_client(addr)
+            .with_prefixes(Some("doc: ".to_string()), None)
+            .with_sequence_guard(sequence_guard(4, OverflowMode::Truncate));
+        client
+            .create_embedding("one two three four", EmbeddingKind::Document)
+            .await
+            .unwrap();
+        // The prefix counts towards the model limit
+        assert_eq!(embedder.inputs(), vec!["doc: one two"]);
+    }
+
+    #[tokio::test]
+    async fn test_sequence_guard_rejects_long_texts() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let client = test_client(addr).with_sequence_guard(sequence_guard(3, OverflowMode::Error));
+        let error = client
+            .create_embedding("one two three four", EmbeddingKind::Document)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RagError>(),
+            Some(RagError::InvalidInput(_))
+        ));
+        assert!(embedder.inputs().is_empty());
+    }
+
+    fn document_with_extra(extra: serde_json::Value) -> TextToEmbed {
+        TextToEmbed {
+            extra: extra.as_object().cloned(),
+            ..text_to_embed("some text")
+        }
+    }
+
+    #[test]
+    fn test_build_metadata_with_extra_fields() {
+        let extra = json!({ "likes": 42, "pinned": true, "tags": ["rust", "rag"] });
+        let document = document_with_extra(extra);
+        let metadata = build_metadata("some text".to_string(), Some(&document), None).unwrap();
+        assert_eq!(metadata.len(), 5);
+        assert_eq!(metadata["likes"], MetadataValue::Num(42.0));
+        assert_eq!(metadata["pinned"], MetadataValue::Bool(true));
+        assert!(matches!(
+            metadata["tags"],
+            MetadataValue::List(ref tags) if tags.len() == 2
+        ));
+        assert_eq!(
+            metadata["text"],
+            MetadataValue::from("some text".to_string())
+        );
+    }
+
+    #[test]
+    fn test_build_metadata_with_query_id() {
+        let document = text_to_embed("some text");
+        let metadata = build_metadata("some text".to_string(), Some(&document), None).unwrap();
+        assert_eq!(
+            metadata["query_id"],
+            MetadataValue::from(document.query_id.clone())
+        );
+        let document = document_with_extra(json!({ "query_id": "other" }));
+        assert!(build_metadata("some text".to_string(), Some(&document), None).is_err());
+    }
+
+    #[test]
+    fn test_prefixed_ids_round_trip_through_fetch() {
+        assert_eq!(vector_id(None, 7), "7");
+        let id = vector_id(Some("tweet-"), 7);
+        assert_eq!(id, "tweet-7");
+
+        let vector = Vector {
+            id: id.clone(),
+            values: vec![0.5, 1.5],
+            sparse_values: None,
+            metadata: None,
+        };
+        let response = FetchResponse {
+            vectors: HashMap::from([(id.clone(), vector)]),
+            namespace: CURRENT_NAME_SPACE.to_string(),
+            usage: None,
+        };
+        assert_eq!(
+            take_fetched_vector(response.clone(), &id).unwrap(),
+            vec![0.5, 1.5]
+        );
+        // The unprefixed id belongs to another source, and is not found
+        let error = take_fetched_vector(response, "7").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RagError>(),
+            Some(RagError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_context_window_ids_and_assembly() {
+        let result = QueryResponse {
+            id: "doc-12".to_string(),
+            query_id: Some("doc".to_string()),
+            chunk_index: Some(2),
+            ..response(0.9, "third")
+        };
+        assert_eq!(
+            context_window_ids(&result, 1),
+            vec!["doc-11".to_string(), "doc-13".to_string()]
+        );
+        // The first chunk of the document has the counter 10, no id is derived before it
+        assert_eq!(
+            context_window_ids(&result, 3),
+            vec!["doc-10", "doc-11", "doc-13", "doc-14", "doc-15"]
+        );
+        assert!(context_window_ids(&response(0.9, "text"), 1).is_empty());
+
+        let chunk = |id: &str, query_id: &str, chunk_index: usize, text: &str| StoredChunk {
+            id: id.to_string(),
+            text: text.to_string(),
+            query_id: Some(query_id.to_string()),
+            chunk_index: Some(chunk_index),
+            embedding: vec![],
+            metadata: MetadataFields::new(),
+        };
+        let neighbors = vec![
+            chunk("doc-13", "doc", 3, "fourth"),
+            chunk("doc-11", "doc", 1, "second"),
+            // The next document reuses the following ids
+            chunk("doc-14", "other", 0, "unrelated"),
+        ];
+        assert_eq!(
+            assemble_context(&result, neighbors, 2),
+            "second\nthird\nfourth"
+        );
+        assert_eq!(assemble_context(&result, vec![], 2), "third");
+    }
+
+    #[test]
+    fn test_namespace_names_from_index_stats() {
+        let mut stats = DescribeIndexStatsResponse::default();
+        assert!(namespace_names(&stats).is_empty());
+        let documents = documents_namespace("tweets");
+        for name in [
+            "tweets",
+            "",
+            CURRENT_NAME_SPACE,
+            MODEL_MARKER_NAMESPACE,
+            &documents,
+        ] {
+            stats
+                .namespaces
+                .insert(name.to_string(), Default::default());
+        }
+        assert_eq!(
+            namespace_names(&stats),
+            vec!["", CURRENT_NAME_SPACE, "tweets"]
+        );
+    }
+
+    #[test]
+    fn test_namespace_defaults_to_the_client_one() {
+        let client = test_client("127.0.0.1:1".parse().unwrap());
+        assert_eq!(client.namespace_or_default(None), CURRENT_NAME_SPACE);
+        let client = client.with_namespace("tweets");
+        assert_eq!(client.namespace_or_default(None), "tweets");
+        assert_eq!(client.namespace_or_default(Some("notes")), "notes");
+    }
+
+    #[test]
+    fn test_prepend_document_context() {
+        assert_eq!(
+            prepend_document_context("Rust is fast.", Some("rust"), Some("Notes on Rust")),
+            "Topic: rust\nDescription: Notes on Rust\n\nRust is fast."
+        );
+        assert_eq!(
+            prepend_document_context("Rust is fast.", None, Some("Notes")),
+            "Description: Notes\n\nRust is fast."
+        );
+        assert_eq!(
+            prepend_document_context("Rust is fast.", Some(" "), None),
+            "Rust is fast."
+        );
+    }
+
+    #[test]
+    fn test_build_metadata_with_split() {
+        let split = SplitCriteria::TokenCount {
+            max_tokens: 512,
+            context_sentences: 1,
+        };
+        let metadata = build_metadata("some text".to_string(), None, Some(&split)).unwrap();
+        assert_eq!(
+            metadata["split"],
+            MetadataValue::from("token_count:512:1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_build_metadata_with_timestamp() {
+        let mut document = text_to_embed("some text");
+        document.date = Some("2024-11-01T12:00:00.000Z".to_string());
+        let metadata = build_metadata("some text".to_string(), Some(&document), None).unwrap();
+        assert_eq!(metadata["timestamp"], MetadataValue::Num(1_730_462_400.0));
+
+        // Unparseable dates are not stored, rather than failing the whole document
+        document.date = Some("yesterday".to_string());
+        let metadata = build_metadata("some text".to_string(), Some(&document), None).unwrap();
+        assert!(!metadata.contains_key("timestamp"));
+    }
+
+    #[test]
+    fn test_chunk_metadata_holds_position() {
+        let chunk = Chunk {
+            text: "Second sentence.".to_string(),
+            query_id: Some("doc".to_string()),
+            index: 1,
+            total: 3,
+            source_offsets: Some((16, 32)),
+        };
+        let metadata = chunk_metadata(&chunk, None, None).unwrap();
+        assert_eq!(chunk_index_field(&metadata), Some(1));
+        assert_eq!(metadata["chunk_total"], MetadataValue::Num(3.0));
+        assert_eq!(metadata["chunk_start"], MetadataValue::Num(16.0));
+        assert_eq!(metadata["chunk_end"], MetadataValue::Num(32.0));
+        assert_eq!(string_field(&metadata, "query_id").as_deref(), Some("doc"));
+        assert_eq!(
+            string_field(&metadata, "text").as_deref(),
+            Some("Second sentence.")
+        );
+    }
+
+    #[test]
+    fn test_parse_date_formats() {
+        assert_eq!(parse_date("2024-11-01T12:00:00Z").unwrap(), 1_730_462_400);
+        assert_eq!(
+            parse_date("Fri Nov 01 12:00:00 +0000 2024").unwrap(),
+            1_730_462_400
+        );
+        let error = parse_date("2024-11-01").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RagError>(),
+            Some(RagError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_delete_filter_requires_confirmation_when_empty() {
+        let filter = delete_filter(&json!({ "author": { "$eq": "atoma" } }), false)
+            .unwrap()
+            .unwrap();
+        let Some(Kind::StructValue(condition)) = &sdk_filter(filter).fields["author"].kind else {
+            panic!("Expected a condition on the author");
+        };
+        assert_eq!(
+            condition.fields["$eq"].kind,
+            Some(Kind::StringValue("atoma".to_string()))
+        );
+
+        let error = delete_filter(&json!({}), false).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RagError>(),
+            Some(RagError::InvalidInput(_))
+        ));
+        assert!(delete_filter(&json!({}), true).unwrap().is_none());
+        assert!(delete_filter(&json!(["author"]), true).is_err());
+    }
+
+    #[test]
+    fn test_query_filter_combines_user_filter_and_date_range() {
+        let filter = json!({ "author": { "$eq": "atoma" } });
+        let combined = query_filter(
+            filter.as_object(),
+            Some("2024-01-01T00:00:00Z"),
+            Some("2024-12-31T23:59:59Z"),
+        )
+        .unwrap()
+        .unwrap();
+        let expected = json!({
+            "$and": [
+                { "author": { "$eq": "atoma" } },
+                { "timestamp": { "$gte": 1_704_067_200, "$lte": 1_735_689_599 } },
+            ]
+        });
+        assert_eq!(
+            Some(Kind::StructValue(sdk_filter(combined))),
+            json_value(&expected).kind
+        );
+
+        let date_only = query_filter(None, Some("2024-01-01T00:00:00Z"), None)
+            .unwrap()
+            .unwrap();
+        let expected = json!({ "timestamp": { "$gte": 1_704_067_200 } });
+        assert_eq!(
+            Some(Kind::StructValue(sdk_filter(date_only))),
+            json_value(&expected).kind
+        );
+
+        assert!(query_filter(None, None, None).unwrap().is_none());
+        assert!(query_filter(None, Some("not a date"), None).is_err());
+    }
+
+    #[test]
+    fn test_query_id_filter() {
+        let filter = sdk_filter(query_id_filter("doc-1"));
+        let Some(Kind::StructValue(condition)) = &filter.fields["query_id"].kind else {
+            panic!("Expected a struct condition");
+        };
+        assert_eq!(
+            condition.fields["$eq"].kind,
+            Some(Kind::StringValue("doc-1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_build_metadata_rejects_nested_object() {
+        let document = document_with_extra(json!({ "likes": 42, "profile": { "name": "atoma" } }));
+        let error = build_metadata("some text".to_string(), Some(&document), None).unwrap_err();
+        assert!(error.to_string().contains("'profile'"));
+        assert!(error.to_string().contains("nested objects"));
+    }
+
+    #[test]
+    fn test_validate_dimension() {
+        for dimension in [0, -1, -768, MAX_INDEX_DIMENSION + 1] {
+            let error = validate_dimension(dimension).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<RagError>(),
+                Some(RagError::InvalidInput(_))
+            ));
+        }
+        assert!(validate_dimension(1).is_ok());
+        assert!(validate_dimension(768).is_ok());
+        assert!(validate_dimension(MAX_INDEX_DIMENSION).is_ok());
+    }
+
+    #[test]
+    fn test_validate_top_k() {
+        assert!(validate_top_k(1).is_ok());
+        assert!(validate_top_k(MAX_TOP_K).is_ok());
+        for top_k in [0, MAX_TOP_K + 1] {
+            let error = validate_top_k(top_k).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<RagError>(),
+                Some(RagError::InvalidInput(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_validate_index_name() {
+        for name in [
+            "atoma-alpha-mistral",
+            "index1",
+            "a",
+            &"a".repeat(MAX_INDEX_NAME_LENGTH),
+        ] {
+            assert!(validate_index_name(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "My_Index",
+            "UPPER",
+            "",
+            "-leading",
+            "trailing-",
+            &"a".repeat(MAX_INDEX_NAME_LENGTH + 1),
+        ] {
+            let error = validate_index_name(name).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<RagError>(),
+                Some(RagError::InvalidInput(_))
+            ));
+        }
+        let error = validate_index_name("My_Index").unwrap_err().to_string();
+        assert!(error.contains("lowercase"), "{}", error);
+        let error = validate_index_name(&"a".repeat(50))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("at most 45"), "{}", error);
+    }
+
+    #[test]
+    fn test_sanitize_index_name() {
+        assert_eq!(sanitize_index_name("My_Index"), "my-index");
+        assert_eq!(sanitize_index_name("  Tweets 2024! "), "tweets-2024");
+        assert_eq!(sanitize_index_name("already-valid"), "already-valid");
+        let long = sanitize_index_name(&format!("{}_b", "a".repeat(44)));
+        assert_eq!(long, "a".repeat(44));
+        assert!(validate_index_name(&long).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_create_index_rejects_invalid_name() {
+        let control_plane = MockControlPlane::default();
+        let addr = spawn_server(control_plane.router()).await;
+        let mut client = test_client(addr);
+        let error = client
+            .create_index("My_Index", 4, None, WaitPolicy::NoWait)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<RagError>().is_some());
+        assert_eq!(*control_plane.create_calls.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_create_index_validates_dimension() {
+        let control_plane = MockControlPlane::default();
+        let addr = spawn_server(control_plane.router()).await;
+        let mut client = test_client(addr);
+
+        for dimension in [0, -4] {
+            let error = client
+                .create_index("invalid", dimension, None, WaitPolicy::NoWait)
+                .await
+                .unwrap_err();
+            assert!(error.downcast_ref::<RagError>().is_some());
+        }
+        assert_eq!(*control_plane.create_calls.lock().unwrap(), 0);
+
+        client
+            .create_index("valid", 4, None, WaitPolicy::NoWait)
+            .await
+            .unwrap();
+        assert_eq!(*control_plane.create_calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_fuse_dense_and_bm25_promotes_keyword_matches() {
+        let candidates = vec![
+            response(0.9, "Embeddings capture meaning."),
+            response(0.8, "Vector databases store embeddings."),
+            response(0.7, "Pinecone is a vector database."),
+        ];
+        let fused = fuse_dense_and_bm25("pinecone", candidates, DEFAULT_RRF_K, 2);
+        let texts: Vec<_> = fused.iter().map(|r| r.text.as_str()).collect();
+        // The only keyword match is ranked first by BM25, which outweighs its dense rank
+        assert_eq!(
+            texts,
+            vec![
+                "Pinecone is a vector database.",
+                "Embeddings capture meaning."
+            ]
+        );
+        assert!((fused[0].score - (1.0 / 63.0 + 1.0 / 61.0)).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_create_embeddings_batches_texts() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let client = test_client(addr).with_prefixes(None, Some("query: ".to_string()));
+        let texts = vec!["first".to_string(), "second".to_string()];
+        let embeddings = client
+            .create_embeddings(&texts, EmbeddingKind::Query)
+            .await
+            .unwrap();
+        assert_eq!(
+            embeddings,
+            vec![
+                mock_embedding("query: first", 4),
+                mock_embedding("query: second", 4)
+            ]
+        );
+        // A single request holds both prefixed texts
+        let requests = embedder.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0],
+            json!({ "inputs": ["query: first", "query: second"] })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_options_keep_requests_working() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        for http2_prior_knowledge in [false, true] {
+            let options = HttpOptions {
+                pool_max_idle_per_host: Some(2),
+                pool_idle_timeout: Some(Duration::from_secs(30)),
+                tcp_keepalive: Some(Duration::from_secs(15)),
+                http2_prior_knowledge,
+            };
+            let client = test_client(addr).with_http_options(&options).unwrap();
+            // Requests reuse the pooled connections
+            for text in ["first", "second"] {
+                let embedding = client
+                    .create_embedding(text, EmbeddingKind::Document)
+                    .await
+                    .unwrap();
+                assert_eq!(embedding, vec![mock_embedding(text, 4)]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_results_from_two_indexes() {
+        let responses = vec![
+            (
+                "tweets".to_string(),
+                Ok(vec![
+                    index_response(0.9, "tweet a", "tweets"),
+                    index_response(0.5, "tweet b", "tweets"),
+                ]),
+            ),
+            (
+                "docs".to_string(),
+                Ok(vec![
+                    index_response(0.8, "doc a", "docs"),
+                    index_response(0.7, "doc b", "docs"),
+                ]),
+            ),
+            (
+                "broken".to_string(),
+                Err(anyhow::anyhow!("index not found")),
+            ),
+        ];
+        let merged = merge_results(responses, &Metric::Cosine, 3);
+        let texts: Vec<_> = merged.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, vec!["tweet a", "doc a", "doc b"]);
+        let indexes: Vec<_> = merged
+            .iter()
+            .map(|r| r.index_name.as_deref().unwrap())
+            .collect();
+        assert_eq!(indexes, vec!["tweets", "docs", "docs"]);
+    }
+
+    #[tokio::test]
+    async fn test_query_similar_to_fetches_the_seed_from_its_namespace() {
+        let data_plane = MockDataPlane::default()
+            .with_vector("tweets", "seed", vec![1.0, 0.0, 0.0, 0.0])
+            .with_match("seed", 1.0, "The seed.")
+            .with_match("similar", 0.9, "A similar chunk.");
+        let index_name = format!("http://{}", spawn_data_plane(data_plane.clone()).await);
+        let control_plane = MockControlPlane::default().with_index(&index_name, 4, "cosine");
+        let client = test_client(spawn_server(control_plane.router()).await);
+        let seed = || SimilarTo::Id("seed".to_string());
+
+        // The largest legal `top_k` queries no more than `MAX_TOP_K` results
+        let results = client
+            .query_similar_to(
+                seed(),
+                None,
+                &index_name,
+                Some(MAX_TOP_K),
+                1.0,
+                Some("tweets"),
+            )
+            .await
+            .unwrap();
+        let ids: Vec<&str> = results.iter().map(|result| result.id.as_str()).collect();
+        assert_eq!(ids, ["similar"]);
+        assert_eq!(
+            *data_plane.queries.lock().unwrap(),
+            [("tweets".to_string(), MAX_TOP_K)]
+        );
+
+        // The seed is not stored in the default namespace
+        let result = client
+            .query_similar_to(seed(), None, &index_name, Some(1), 1.0, None)
+            .await;
+        assert!(result.is_err());
+
+        for top_k in [0, u32::MAX] {
+            let error = client
+                .query_similar_to(seed(), None, &index_name, Some(top_k), 1.0, Some("tweets"))
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<RagError>(),
+                Some(RagError::InvalidInput(_))
+            ));
+        }
+        assert_eq!(data_plane.queries.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_multi_validates_top_k_and_metrics() {
+        let mut index_names = vec![];
+        let mut control_plane = MockControlPlane::default();
+        for (metric, matches) in [
+            ("euclidean", [("tweet near", 0.1), ("tweet far", 0.9)]),
+            ("euclidean", [("doc near", 0.2), ("doc far", 0.8)]),
+            ("cosine", [("other close", 0.9), ("other far", 0.1)]),
+        ] {
+            let data_plane = matches
+                .iter()
+                .fold(MockDataPlane::default(), |data_plane, (id, score)| {
+                    data_plane.with_match(id, *score, id)
+                });
+            let index_name = format!("http://{}", spawn_data_plane(data_plane).await);
+            control_plane = control_plane.with_index(&index_name, 4, metric);
+            index_names.push(index_name);
+        }
+        let embedder = MockEmbedder::new(4);
+        let router = embedder.router().merge(control_plane.router());
+        let client = test_client(spawn_server(router).await);
+
+        // Out of bounds `top_k` are rejected before embedding the query
+        for top_k in [0, MAX_TOP_K + 1] {
+            let error = client
+                .query_multi("query", &index_names, Some(top_k))
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<RagError>(),
+                Some(RagError::InvalidInput(_))
+            ));
+        }
+        assert!(embedder.inputs().is_empty());
+
+        // Indexes using different metrics cannot be merged
+        let error = client
+            .query_multi("query", &index_names, Some(4))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RagError>(),
+            Some(RagError::InvalidInput(_))
+        ));
+        assert!(embedder.inputs().is_empty());
+
+        // Euclidean indexes are merged by ascending distance
+        let results = client
+            .query_multi("query", &index_names[..2], Some(3))
+            .await
+            .unwrap();
+        let ids: Vec<&str> = results.iter().map(|result| result.id.as_str()).collect();
+        assert_eq!(ids, ["tweet near", "doc near", "doc far"]);
+    }
+
+    #[test]
+    fn test_exclude_seed() {
+        let results = vec![
+            response(1.0, "seed"),
+            response(0.9, "a"),
+            response(0.8, "b"),
+            response(0.7, "c"),
+        ];
+        let texts: Vec<_> = exclude_seed(results, "seed", 2)
+            .into_iter()
+            .map(|r| r.text)
+            .collect();
+        assert_eq!(texts, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_blend_vectors() {
+        let seed = [3.0, 4.0];
+        let query = [1.0, 0.0];
+        // alpha = 1.0 is a pure "more like this" on the seed
+        assert_eq!(
+            blend_vectors(&seed, &query, 1.0, false).unwrap(),
+            vec![3.0, 4.0]
+        );
+        let normalized = blend_vectors(&seed, &query, 1.0, true).unwrap();
+        assert!((normalized[0] - 0.6).abs() < 1e-6);
+        assert!((normalized[1] - 0.8).abs() < 1e-6);
+        assert_eq!(
+            blend_vectors(&seed, &query, 0.5, false).unwrap(),
+            vec![2.0, 2.0]
+        );
+        assert!((l2_norm(&blend_vectors(&seed, &query, 0.5, true).unwrap()) - 1.0).abs() < 1e-6);
+        assert!(blend_vectors(&seed, &[1.0], 0.5, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_counter_is_loaded_from_counter_file() {
+        let addr = spawn_server(MockControlPlane::default().router()).await;
+        let path = std::env::temp_dir().join(format!("rag-counter-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut client = test_client(addr).with_counter_file(Some(path.clone()));
+        assert_eq!(client.counter, 0);
+        client.counter = 42;
+        client.persist_counter();
+
+        let client = test_client(addr).with_counter_file(Some(path.clone()));
+        assert_eq!(client.counter, 42);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_index_waits_until_ready() {
+        let control_plane = MockControlPlane::default().with_pending_describes(2);
+        let addr = spawn_server(control_plane.router()).await;
+        let mut client = test_client(addr);
+
+        client
+            .create_index(
+                "waited",
+                4,
+                None,
+                WaitPolicy::WaitFor(Duration::from_secs(10)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(*control_plane.describe_calls.lock().unwrap(), 3);
+        assert_eq!(
+            control_plane.indexes.lock().unwrap()["waited"]["status"]["ready"],
+            true
+        );
+
+        let control_plane = MockControlPlane::default().with_pending_describes(100);
+        let addr = spawn_server(control_plane.router()).await;
+        let mut client = test_client(addr);
+        assert!(client
+            .create_index(
+                "never-ready",
+                4,
+                None,
+                WaitPolicy::WaitFor(Duration::from_millis(100)),
+            )
+            .await
+            .is_err());
     }
 }
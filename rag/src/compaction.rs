@@ -0,0 +1,379 @@
+//! Offline compaction of the chunks of an index, merging the adjacent small chunks of each
+//! document, e.g. those left by splitting short paragraphs or sentences.
+
+use std::{collections::BTreeMap, ops::Range};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    client::{prepend_document_context as with_context, string_field, EmbeddingKind, StoredChunk},
+    error::RagError,
+    metadata::{MetadataFields, MetadataValue},
+    store::EmbeddingStore,
+    tokens::TokenCounter,
+};
+
+/// What a compaction did, returned by `/compact_index`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionSummary {
+    /// Number of documents found in the namespace, as told apart by `query_id`
+    pub documents: usize,
+    /// Number of documents whose chunks were merged
+    pub compacted_documents: usize,
+    /// Number of documents left as is, as their chunks cannot be merged safely
+    pub skipped_documents: usize,
+    /// Number of chunks in the namespace before the compaction
+    pub chunks_before: usize,
+    /// Number of chunks in the namespace after the compaction
+    pub chunks_after: usize,
+}
+
+/// Groups adjacent chunks, given their token counts in order, into runs of at most
+/// `target_tokens` tokens, returning the range of each run.
+///
+/// Chunks are packed greedily, a chunk over `target_tokens` on its own forming a run of its
+/// own, so chunks are never split and keep their order.
+pub fn plan_merges(token_counts: &[usize], target_tokens: usize) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let (mut start, mut tokens) = (0, 0);
+    for (i, &count) in token_counts.iter().enumerate() {
+        if i > start && tokens + count > target_tokens {
+            runs.push(start..i);
+            (start, tokens) = (i, 0);
+        }
+        tokens += count;
+    }
+    if start < token_counts.len() {
+        runs.push(start..token_counts.len());
+    }
+    runs
+}
+
+/// Returns the byte range of a chunk in its document, stored as `chunk_start` and
+/// `chunk_end`, if any.
+fn chunk_offsets(metadata: &MetadataFields) -> Option<(usize, usize)> {
+    let offset = |key: &str| metadata.get(key)?.as_f64().map(|offset| offset as usize);
+    Some((offset("chunk_start")?, offset("chunk_end")?))
+}
+
+/// Joins the texts of adjacent chunks of a document.
+///
+/// The overlap of chunks, e.g. their context sentences, is only kept once, as told by their
+/// stored byte ranges. The text between chunks is not stored, so chunks that neither overlap
+/// nor touch, or whose range is unknown, are joined by newlines.
+fn merge_texts(chunks: &[StoredChunk]) -> String {
+    let mut text = String::new();
+    let mut end: Option<usize> = None;
+    for chunk in chunks {
+        let offsets = chunk_offsets(&chunk.metadata);
+        let rest = match (end, offsets) {
+            (Some(end), Some((start, _))) if start <= end && !text.is_empty() => {
+                chunk.text.get((end - start).min(chunk.text.len())..)
+            }
+            _ => None,
+        };
+        match rest {
+            Some(rest) => text.push_str(rest),
+            None => {
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+                text.push_str(&chunk.text);
+            }
+        }
+        end = match (end, offsets) {
+            (end, Some((_, chunk_end))) => Some(end.map_or(chunk_end, |end| end.max(chunk_end))),
+            (_, None) => None,
+        };
+    }
+    text
+}
+
+/// Returns the chunks of a document ordered by `chunk_index`, unless they cannot be merged
+/// safely: some chunk has no `chunk_index`, the indexes are not exactly `0..n`, e.g. for
+/// partially deleted documents, or the embeddings are derived from an image, which is not
+/// re-embedded.
+fn mergeable(mut chunks: Vec<StoredChunk>) -> Option<Vec<StoredChunk>> {
+    chunks.sort_by_key(|chunk| chunk.chunk_index);
+    let contiguous = chunks
+        .iter()
+        .enumerate()
+        .all(|(i, chunk)| chunk.chunk_index == Some(i));
+    let with_image = chunks
+        .iter()
+        .any(|chunk| chunk.metadata.contains_key("image_url"));
+    (contiguous && !with_image).then_some(chunks)
+}
+
+/// Merges the adjacent small chunks of each document of an index, up to `target_tokens`
+/// tokens per chunk, and replaces the chunks of the compacted documents with the merged ones.
+///
+/// # Arguments
+///
+/// * `store` - The store holding the index.
+/// * `tokenizer` - The tokenizer counting the tokens of the chunks.
+/// * `index_name` - The name of the index to compact.
+/// * `target_tokens` - The maximum number of tokens of a merged chunk.
+/// * `namespace` - Optional namespace to compact, instead of the default one of the store.
+/// * `prepend_document_context` - Whether the stored `topic` and `description` of the
+///   documents are prepended to the re-embedded texts, as they were when the chunks were
+///   embedded (see `client::prepend_document_context`).
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - `target_tokens` is `0` (`RagError::InvalidInput`).
+/// - The chunks cannot be listed or their tokens counted.
+/// - Re-embedding or replacing the chunks of a document fails, reporting how many documents
+///   were compacted before it.
+///
+/// # Notes
+///
+/// Chunks are grouped by `query_id`, chunks without one being left as is, and merged
+/// following `plan_merges`. A merged chunk keeps the metadata of its first chunk, with its
+/// text, position and byte range updated, and the id of the chunk at its new position, so
+/// that the ids of a document stay consecutive (see `context_window_ids`). The chunks of
+/// each document are replaced at once, the merged ones being upserted before the remaining
+/// ones are deleted (see `EmbeddingClient::replace_chunks`).
+pub async fn compact_index<S: EmbeddingStore + ?Sized>(
+    store: &mut S,
+    tokenizer: &dyn TokenCounter,
+    index_name: &str,
+    target_tokens: usize,
+    namespace: Option<&str>,
+    prepend_document_context: bool,
+) -> Result<CompactionSummary> {
+    if target_tokens == 0 {
+        return Err(RagError::InvalidInput("target_tokens must be at least 1".to_string()).into());
+    }
+    let chunks = store.list_chunks(index_name, namespace).await?;
+    let mut summary = CompactionSummary {
+        chunks_before: chunks.len(),
+        chunks_after: chunks.len(),
+        ..Default::default()
+    };
+    let mut documents: BTreeMap<String, Vec<StoredChunk>> = BTreeMap::new();
+    for chunk in chunks {
+        if let Some(query_id) = chunk.query_id.clone() {
+            documents.entry(query_id).or_default().push(chunk);
+        }
+    }
+    summary.documents = documents.len();
+
+    for (query_id, chunks) in documents {
+        let Some(chunks) = mergeable(chunks) else {
+            warn!(
+                "Not compacting document {}, its chunks are not mergeable",
+                query_id
+            );
+            summary.skipped_documents += 1;
+            continue;
+        };
+        let token_counts = chunks
+            .iter()
+            .map(|chunk| tokenizer.count(&chunk.text))
+            .collect::<Result<Vec<_>>>()?;
+        let runs = plan_merges(&token_counts, target_tokens);
+        if runs.len() == chunks.len() {
+            continue;
+        }
+        let merged = merged_chunks(&chunks, &runs);
+        let texts: Vec<String> = merged
+            .iter()
+            .map(|chunk| match prepend_document_context {
+                true => with_context(
+                    &chunk.text,
+                    string_field(&chunk.metadata, "topic").as_deref(),
+                    string_field(&chunk.metadata, "description").as_deref(),
+                ),
+                false => chunk.text.clone(),
+            })
+            .collect();
+        let compacted = summary.compacted_documents;
+        let embeddings = store
+            .embed_batch(&texts, EmbeddingKind::Document)
+            .await
+            .with_context(|| {
+                format!(
+                    "Error compacting document {}, after compacting {} documents",
+                    query_id, compacted
+                )
+            })?;
+        let merged = merged
+            .into_iter()
+            .zip(embeddings)
+            .map(|(chunk, embedding)| StoredChunk { embedding, ..chunk })
+            .collect();
+        let stale_ids: Vec<String> = chunks[runs.len()..]
+            .iter()
+            .map(|chunk| chunk.id.clone())
+            .collect();
+        store
+            .replace_chunks(index_name, merged, &stale_ids, namespace)
+            .await
+            .with_context(|| {
+                format!(
+                    "Error compacting document {}, after compacting {} documents",
+                    query_id, compacted
+                )
+            })?;
+        info!(
+            "Compacted document {} from {} to {} chunks",
+            query_id,
+            chunks.len(),
+            runs.len()
+        );
+        summary.compacted_documents += 1;
+        summary.chunks_after -= stale_ids.len();
+    }
+    Ok(summary)
+}
+
+/// Builds the merged chunks of a document, one per run of its chunks, yet to be embedded.
+fn merged_chunks(chunks: &[StoredChunk], runs: &[Range<usize>]) -> Vec<StoredChunk> {
+    let number = |value: usize| MetadataValue::Num(value as f64);
+    runs.iter()
+        .enumerate()
+        .map(|(i, run)| {
+            let run_chunks = &chunks[run.clone()];
+            let text = merge_texts(run_chunks);
+            let mut metadata = run_chunks[0].metadata.clone();
+            metadata.insert("text".to_string(), text.clone().into());
+            metadata.insert("chunk_index".to_string(), number(i));
+            metadata.insert("chunk_total".to_string(), number(runs.len()));
+            let first = chunk_offsets(&run_chunks[0].metadata);
+            let last = run_chunks
+                .last()
+                .and_then(|chunk| chunk_offsets(&chunk.metadata));
+            match (first, last) {
+                (Some((start, _)), Some((_, end))) => {
+                    metadata.insert("chunk_start".to_string(), number(start));
+                    metadata.insert("chunk_end".to_string(), number(end));
+                }
+                _ => {
+                    metadata.remove("chunk_start");
+                    metadata.remove("chunk_end");
+                }
+            }
+            StoredChunk {
+                id: chunks[i].id.clone(),
+                text,
+                query_id: run_chunks[0].query_id.clone(),
+                chunk_index: Some(i),
+                embedding: Vec::new(),
+                metadata,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        split_criteria::Chunk,
+        test_utils::{mock_embedding, word_level_tokenizer, FakeStore},
+        types::TextToEmbed,
+    };
+
+    #[test]
+    fn test_plan_merges_packs_adjacent_chunks() {
+        assert_eq!(plan_merges(&[2, 2, 2, 2, 2], 5), vec![0..2, 2..4, 4..5]);
+        // Chunks over the target stand alone
+        assert_eq!(plan_merges(&[1, 9, 1, 1], 4), vec![0..1, 1..2, 2..4]);
+        assert_eq!(plan_merges(&[3, 3], 6), vec![0..2]);
+        assert!(plan_merges(&[], 6).is_empty());
+    }
+
+    async fn store_document(store: &mut FakeStore, query_id: &str, content: &str, texts: &[&str]) {
+        let document = TextToEmbed::builder(query_id, "test-index", content)
+            .with_author("atoma")
+            .build();
+        let texts = texts.iter().map(|text| text.to_string()).collect();
+        let embeddings = Chunk::from_texts(texts, content, Some(query_id))
+            .into_iter()
+            .map(|chunk| {
+                let embedding = vec![mock_embedding(&chunk.text, 8)];
+                (chunk, embedding)
+            })
+            .collect();
+        store
+            .store("test-index", embeddings, Some(&document), None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compaction_merges_fragmented_documents() {
+        let mut store = FakeStore::new(8);
+        // Sentences of 3 tokens each, split with a context sentence
+        let content = "One fish. Two fish. Red fish. Blue fish.";
+        store_document(
+            &mut store,
+            "fragmented",
+            content,
+            &[
+                "One fish. Two fish.",
+                "Two fish. Red fish.",
+                "Red fish. Blue fish.",
+                "Blue fish.",
+            ],
+        )
+        .await;
+        store_document(&mut store, "whole", "A single chunk.", &["A single chunk."]).await;
+        let tokenizer = word_level_tokenizer();
+
+        let summary = compact_index(&mut store, &tokenizer, "test-index", 12, None, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            CompactionSummary {
+                documents: 2,
+                compacted_documents: 1,
+                skipped_documents: 0,
+                chunks_before: 5,
+                chunks_after: 3,
+            }
+        );
+
+        let mut chunks = store.list_chunks("test-index", None).await.unwrap();
+        chunks.retain(|chunk| chunk.query_id.as_deref() == Some("fragmented"));
+        chunks.sort_by_key(|chunk| chunk.chunk_index);
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        // The overlapping context sentences are kept once
+        assert_eq!(
+            texts,
+            ["One fish. Two fish. Red fish.", "Red fish. Blue fish."]
+        );
+        // The merged chunks keep the ids of the first chunks, and the document metadata
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| chunk.id.as_str())
+                .collect::<Vec<_>>(),
+            ["0", "1"]
+        );
+        let merged = &chunks[0];
+        assert_eq!(merged.metadata["author"], MetadataValue::from("atoma"));
+        assert_eq!(merged.metadata["chunk_total"], MetadataValue::from(2.0));
+        assert_eq!(merged.metadata["chunk_end"], MetadataValue::from(29.0));
+        assert_eq!(chunks[1].metadata["chunk_start"], MetadataValue::from(20.0));
+        assert_eq!(merged.embedding, mock_embedding(texts[0], 8));
+        assert_eq!(store.batches.lock().unwrap().len(), 1);
+
+        // Compacting again finds nothing left to merge
+        let summary = compact_index(&mut store, &tokenizer, "test-index", 12, None, false)
+            .await
+            .unwrap();
+        assert_eq!(summary.compacted_documents, 0);
+        assert_eq!(summary.chunks_after, 3);
+        assert!(
+            compact_index(&mut store, &tokenizer, "test-index", 0, None, false)
+                .await
+                .is_err()
+        );
+    }
+}
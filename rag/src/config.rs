@@ -0,0 +1,429 @@
+//! Configuration of the server, read from the environment once at startup.
+
+use std::{env, fmt::Display, num::NonZeroUsize, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    circuit_breaker::{DEFAULT_BREAKER_COOLDOWN_SECS, DEFAULT_BREAKER_FAILURE_THRESHOLD},
+    client::{
+        EmbeddingEncoding, HttpOptions, CURRENT_NAME_SPACE, DEFAULT_IMAGE_FIELD,
+        DEFAULT_INPUT_FIELD, DEFAULT_TOP_K, DEFAULT_UPSERT_BATCH_SIZE,
+    },
+    idempotency::{DEFAULT_IDEMPOTENCY_CAPACITY, DEFAULT_IDEMPOTENCY_TTL_SECS},
+    model_guard::ModelMismatchMode,
+    pipeline::PipelineOptions,
+    server::{Limits, DEFAULT_EMBEDDING_CONCURRENCY, DEFAULT_MAX_QUERY_TOKENS},
+    split_criteria::SplitCriteria,
+    tokens::{TokenizerKind, DEFAULT_TOKENIZER_REPO},
+};
+
+/// Default host the server listens on.
+pub const DEFAULT_HOST: &str = "0.0.0.0";
+/// Default port the server listens on.
+pub const DEFAULT_PORT: u16 = 8081;
+/// Default host of the embedding service.
+pub const DEFAULT_EMBEDDING_HOST: &str = "127.0.0.1";
+/// Default port of the embedding service.
+pub const DEFAULT_EMBEDDING_PORT: u16 = 8080;
+
+/// Where the embeddings are stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VectorStore {
+    /// A Pinecone account, the default
+    Pinecone {
+        /// API key of the Pinecone account
+        api_key: String,
+        /// Host of the Pinecone index embeddings are stored in
+        host: String,
+    },
+    /// An in-memory store, which does not require a Pinecone account
+    Memory,
+}
+
+/// Transport the server is served over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// JSON over HTTP, the default
+    #[default]
+    Http,
+    /// gRPC
+    Grpc,
+}
+
+/// Settings of the server, read from environment variables by `Config::from_env`.
+///
+/// Each field is documented with the variable it is read from.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Host the server listens on, `HOST`
+    pub host: String,
+    /// Port the server listens on, `PORT`
+    pub port: u16,
+    /// Transport the server is served over, `TRANSPORT` (`http` or `grpc`)
+    pub transport: Transport,
+    /// Where the embeddings are stored, `VECTOR_STORE` (`pinecone` or `memory`), along
+    /// with `PINECONE_API_KEY` and `PINECONE_HOST` for Pinecone
+    pub vector_store: VectorStore,
+    /// Host of the embedding service, `EMBEDDING_HOST`
+    pub embedding_host: String,
+    /// Port of the embedding service, `EMBEDDING_PORT`
+    pub embedding_port: u16,
+    /// Optional base URL of the embedding service, taking precedence over its host and
+    /// port, `EMBEDDING_URL`
+    pub embedding_url: Option<String>,
+    /// Name of the JSON field holding the text in embedding requests, `EMBEDDING_INPUT_FIELD`
+    pub input_field: String,
+    /// Name of the JSON field holding the image URL in embedding requests, `EMBEDDING_IMAGE_FIELD`
+    pub image_field: String,
+    /// Connection handling of the requests to the embedding service,
+    /// `HTTP_POOL_MAX_IDLE_PER_HOST`, `HTTP_POOL_IDLE_TIMEOUT_SECS`, `HTTP_TCP_KEEPALIVE_SECS`
+    /// and `HTTP2_PRIOR_KNOWLEDGE`
+    pub http_options: HttpOptions,
+    /// Encoding of the embeddings requested, `EMBEDDING_ENCODING` (`float` or `base64`)
+    pub embedding_encoding: EmbeddingEncoding,
+    /// Optional id of the model of the embedding service, recorded in the indexes and checked
+    /// against them, `EMBEDDING_MODEL`
+    pub embedding_model: Option<String>,
+    /// How a mismatch with the recorded model of an index is handled, `MODEL_MISMATCH`
+    /// (`warn` or `error`)
+    pub model_mismatch: ModelMismatchMode,
+    /// Optional prefix prepended to document chunks before embedding them, `DOCUMENT_PREFIX`
+    pub document_prefix: Option<String>,
+    /// Optional prefix prepended to query texts before embedding them, `QUERY_PREFIX`
+    pub query_prefix: Option<String>,
+    /// Optional capacity of the embedding cache, `0` disabling it, `EMBEDDING_CACHE_CAPACITY`
+    pub embedding_cache_capacity: Option<NonZeroUsize>,
+    /// Maximum number of in-flight calls to the embedding service, `EMBEDDING_CONCURRENCY`
+    pub embedding_concurrency: usize,
+    /// Optional path of the file the id counter is persisted to, `COUNTER_FILE`
+    pub counter_file: Option<PathBuf>,
+    /// Optional prefix of the generated vector ids, `ID_PREFIX`
+    pub id_prefix: Option<String>,
+    /// Maximum number of vectors sent in a single upsert request, `UPSERT_BATCH_SIZE`
+    pub upsert_batch_size: usize,
+    /// Pinecone namespace used by requests not naming one, `NAMESPACE`
+    pub namespace: String,
+    /// Optional criteria documents are split with, `SPLIT_CRITERIA` (e.g. `token_count:512:1`),
+    /// see `AppState::new` for the default
+    pub split_criteria: Option<SplitCriteria>,
+    /// Optional path of the tokenizer file, required by the token based split criteria,
+    /// `TOKENIZER_PATH`
+    pub tokenizer_path: Option<PathBuf>,
+    /// Hugging Face repository the tokenizer is downloaded from when `tokenizer_path` is not
+    /// set, `TOKENIZER_REPO`
+    pub tokenizer_repo: String,
+    /// Kind of the tokenizer file, `TOKENIZER_KIND` (`huggingface` for a `tokenizer.json`, or
+    /// `tiktoken` for a `.tiktoken` encoding)
+    pub tokenizer_kind: TokenizerKind,
+    /// Limits on the size of requests, `MAX_BODY_BYTES`, `MAX_CHUNKS_PER_DOCUMENT`,
+    /// `MAX_TOTAL_TOKENS_PER_REQUEST` and `TOKEN_LIMIT_MODE` (`reject` or `truncate`)
+    pub limits: Limits,
+    /// Number of results returned by queries not setting `top_k`, `DEFAULT_TOP_K`
+    pub default_top_k: u32,
+    /// Maximum number of tokens of a query text, beyond which queries setting a
+    /// `long_query_strategy` are split, `MAX_QUERY_TOKENS`
+    pub max_query_tokens: usize,
+    /// Capacity of the idempotency cache, `0` disabling it, `IDEMPOTENCY_CACHE_CAPACITY`
+    pub idempotency_capacity: usize,
+    /// How long the idempotency cache remembers a document, `IDEMPOTENCY_TTL_SECS`
+    pub idempotency_ttl: Duration,
+    /// Number of consecutive failures of Pinecone opening the circuit breaker, 0 disabling it,
+    /// `PINECONE_BREAKER_THRESHOLD`
+    pub breaker_failure_threshold: usize,
+    /// How long the circuit breaker fails calls fast once open, `PINECONE_BREAKER_COOLDOWN_SECS`
+    pub breaker_cooldown: Duration,
+    /// Whether to detect the language of documents without one, `DETECT_LANGUAGE`
+    pub detect_language: bool,
+    /// Whether to prepend the topic and description of documents to the embedded text of their
+    /// chunks, `PREPEND_DOCUMENT_CONTEXT`
+    pub prepend_document_context: bool,
+    /// Whether to also store the raw content of each embedded document, `STORE_RAW_DOCUMENTS`
+    pub store_raw_documents: bool,
+    /// Whether to log samples of the texts embedded and stored, at `debug` level,
+    /// `DEBUG_SAMPLE_INPUTS`
+    pub debug_sample_inputs: bool,
+    /// Bounds of the pipeline of the bulk operations, `BULK_CONCURRENCY`, `BULK_BUFFER` and
+    /// `BULK_PROGRESS_INTERVAL`
+    pub bulk_pipeline: PipelineOptions,
+}
+
+impl Config {
+    /// Reads the configuration from the environment variables.
+    ///
+    /// # Errors
+    ///
+    /// See `Config::from_lookup`.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    /// Reads the configuration from the variables returned by `lookup`.
+    ///
+    /// Unset variables fall back to their defaults. Empty variables, as left by a copy of
+    /// `.env.example`, count as unset.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the variable if:
+    /// - A required variable is unset, e.g. `PINECONE_API_KEY` for the Pinecone store.
+    /// - A variable holds a value which cannot be parsed, e.g. a `PORT` which is not a number.
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let var = |key: &str| lookup(key).filter(|value| !value.is_empty());
+        let vector_store = match var("VECTOR_STORE").as_deref() {
+            None | Some("pinecone") => VectorStore::Pinecone {
+                api_key: required(var, "PINECONE_API_KEY")?,
+                host: required(var, "PINECONE_HOST")?,
+            },
+            Some("memory") => VectorStore::Memory,
+            Some(value) => {
+                return Err(invalid(
+                    "VECTOR_STORE",
+                    value,
+                    "expected `pinecone` or `memory`",
+                ))
+            }
+        };
+        let transport = match var("TRANSPORT").as_deref() {
+            None | Some("http") => Transport::Http,
+            Some("grpc") => Transport::Grpc,
+            Some(value) => return Err(invalid("TRANSPORT", value, "expected `http` or `grpc`")),
+        };
+        let embedding_encoding = match var("EMBEDDING_ENCODING").as_deref() {
+            None | Some("float") => EmbeddingEncoding::Float,
+            Some("base64") => EmbeddingEncoding::Base64,
+            Some(value) => {
+                return Err(invalid(
+                    "EMBEDDING_ENCODING",
+                    value,
+                    "expected `float` or `base64`",
+                ))
+            }
+        };
+        let default_limits = Limits::default();
+        let default_pipeline = PipelineOptions::default();
+        Ok(Config {
+            host: var("HOST").unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            port: parsed(var, "PORT")?.unwrap_or(DEFAULT_PORT),
+            transport,
+            vector_store,
+            embedding_host: var("EMBEDDING_HOST")
+                .unwrap_or_else(|| DEFAULT_EMBEDDING_HOST.to_string()),
+            embedding_port: parsed(var, "EMBEDDING_PORT")?.unwrap_or(DEFAULT_EMBEDDING_PORT),
+            embedding_url: var("EMBEDDING_URL"),
+            input_field: var("EMBEDDING_INPUT_FIELD")
+                .unwrap_or_else(|| DEFAULT_INPUT_FIELD.to_string()),
+            image_field: var("EMBEDDING_IMAGE_FIELD")
+                .unwrap_or_else(|| DEFAULT_IMAGE_FIELD.to_string()),
+            http_options: HttpOptions {
+                pool_max_idle_per_host: parsed(var, "HTTP_POOL_MAX_IDLE_PER_HOST")?,
+                pool_idle_timeout: parsed(var, "HTTP_POOL_IDLE_TIMEOUT_SECS")?
+                    .map(Duration::from_secs),
+                tcp_keepalive: parsed(var, "HTTP_TCP_KEEPALIVE_SECS")?.map(Duration::from_secs),
+                http2_prior_knowledge: parsed(var, "HTTP2_PRIOR_KNOWLEDGE")?.unwrap_or(false),
+            },
+            embedding_encoding,
+            embedding_model: var("EMBEDDING_MODEL"),
+            model_mismatch: parsed(var, "MODEL_MISMATCH")?.unwrap_or_default(),
+            document_prefix: var("DOCUMENT_PREFIX"),
+            query_prefix: var("QUERY_PREFIX"),
+            embedding_cache_capacity: parsed(var, "EMBEDDING_CACHE_CAPACITY")?
+                .and_then(NonZeroUsize::new),
+            embedding_concurrency: parsed(var, "EMBEDDING_CONCURRENCY")?
+                .unwrap_or(DEFAULT_EMBEDDING_CONCURRENCY),
+            counter_file: var("COUNTER_FILE").map(PathBuf::from),
+            id_prefix: var("ID_PREFIX"),
+            upsert_batch_size: parsed(var, "UPSERT_BATCH_SIZE")?
+                .unwrap_or(DEFAULT_UPSERT_BATCH_SIZE),
+            namespace: var("NAMESPACE").unwrap_or_else(|| CURRENT_NAME_SPACE.to_string()),
+            split_criteria: parsed(var, "SPLIT_CRITERIA")?,
+            tokenizer_path: var("TOKENIZER_PATH").map(PathBuf::from),
+            tokenizer_repo: var("TOKENIZER_REPO")
+                .unwrap_or_else(|| DEFAULT_TOKENIZER_REPO.to_string()),
+            tokenizer_kind: parsed(var, "TOKENIZER_KIND")?.unwrap_or_default(),
+            limits: Limits {
+                max_body_bytes: parsed(var, "MAX_BODY_BYTES")?
+                    .unwrap_or(default_limits.max_body_bytes),
+                max_chunks_per_document: parsed(var, "MAX_CHUNKS_PER_DOCUMENT")?
+                    .unwrap_or(default_limits.max_chunks_per_document),
+                max_total_tokens_per_request: parsed(var, "MAX_TOTAL_TOKENS_PER_REQUEST")?,
+                token_limit_mode: parsed(var, "TOKEN_LIMIT_MODE")?
+                    .unwrap_or(default_limits.token_limit_mode),
+            },
+            default_top_k: parsed(var, "DEFAULT_TOP_K")?.unwrap_or(DEFAULT_TOP_K),
+            max_query_tokens: parsed(var, "MAX_QUERY_TOKENS")?.unwrap_or(DEFAULT_MAX_QUERY_TOKENS),
+            idempotency_capacity: parsed(var, "IDEMPOTENCY_CACHE_CAPACITY")?
+                .unwrap_or(DEFAULT_IDEMPOTENCY_CAPACITY),
+            idempotency_ttl: Duration::from_secs(
+                parsed(var, "IDEMPOTENCY_TTL_SECS")?.unwrap_or(DEFAULT_IDEMPOTENCY_TTL_SECS),
+            ),
+            breaker_failure_threshold: parsed(var, "PINECONE_BREAKER_THRESHOLD")?
+                .unwrap_or(DEFAULT_BREAKER_FAILURE_THRESHOLD),
+            breaker_cooldown: Duration::from_secs(
+                parsed(var, "PINECONE_BREAKER_COOLDOWN_SECS")?
+                    .unwrap_or(DEFAULT_BREAKER_COOLDOWN_SECS),
+            ),
+            detect_language: parsed(var, "DETECT_LANGUAGE")?.unwrap_or(false),
+            prepend_document_context: parsed(var, "PREPEND_DOCUMENT_CONTEXT")?.unwrap_or(false),
+            store_raw_documents: parsed(var, "STORE_RAW_DOCUMENTS")?.unwrap_or(false),
+            debug_sample_inputs: parsed(var, "DEBUG_SAMPLE_INPUTS")?.unwrap_or(false),
+            bulk_pipeline: PipelineOptions {
+                concurrency: parsed::<NonZeroUsize>(var, "BULK_CONCURRENCY")?
+                    .map_or(default_pipeline.concurrency, NonZeroUsize::get),
+                buffer: parsed::<NonZeroUsize>(var, "BULK_BUFFER")?
+                    .map_or(default_pipeline.buffer, NonZeroUsize::get),
+                progress_interval: parsed(var, "BULK_PROGRESS_INTERVAL")?
+                    .unwrap_or(default_pipeline.progress_interval),
+            },
+        })
+    }
+}
+
+/// Returns the value of a required variable, or an error naming it.
+fn required(var: impl Fn(&str) -> Option<String>, key: &str) -> Result<String> {
+    var(key).ok_or_else(|| anyhow!("Missing required environment variable {}", key))
+}
+
+/// Parses the value of an optional variable, returning an error naming it if it is invalid.
+fn parsed<T>(var: impl Fn(&str) -> Option<String>, key: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    var(key)
+        .map(|value| value.parse().map_err(|e| invalid(key, &value, e)))
+        .transpose()
+}
+
+/// Builds the error of a variable holding an invalid value.
+fn invalid(key: &str, value: &str, reason: impl Display) -> anyhow::Error {
+    anyhow!(
+        "Invalid environment variable {} `{}`: {}",
+        key,
+        value,
+        reason
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::server::TokenLimitMode;
+
+    fn config(vars: &[(&str, &str)]) -> Result<Config> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Config::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_missing_required_var_is_named() {
+        let error = config(&[("PINECONE_HOST", "https://index.pinecone.io")]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Missing required environment variable PINECONE_API_KEY"
+        );
+        // Empty variables count as unset
+        let error = config(&[("PINECONE_API_KEY", "key"), ("PINECONE_HOST", "")]).unwrap_err();
+        assert!(error.to_string().contains("PINECONE_HOST"), "{}", error);
+
+        let error = config(&[("VECTOR_STORE", "memory"), ("PORT", "eighty")]).unwrap_err();
+        assert!(error.to_string().contains("PORT `eighty`"), "{}", error);
+    }
+
+    #[test]
+    fn test_defaults_and_overrides() {
+        let memory = config(&[("VECTOR_STORE", "memory")]).unwrap();
+        assert_eq!(memory.vector_store, VectorStore::Memory);
+        assert_eq!(memory.host, DEFAULT_HOST);
+        assert_eq!(memory.port, DEFAULT_PORT);
+        assert_eq!(memory.transport, Transport::Http);
+        assert_eq!(memory.namespace, CURRENT_NAME_SPACE);
+        assert!(memory.split_criteria.is_none());
+        assert_eq!(memory.http_options, HttpOptions::default());
+        assert_eq!(memory.tokenizer_kind, TokenizerKind::HuggingFace);
+        assert_eq!(memory.tokenizer_repo, DEFAULT_TOKENIZER_REPO);
+        assert_eq!(memory.bulk_pipeline, PipelineOptions::default());
+        assert_eq!(memory.limits.max_total_tokens_per_request, None);
+        assert_eq!(memory.limits.token_limit_mode, TokenLimitMode::Reject);
+        assert!(!memory.prepend_document_context);
+        assert!(!memory.store_raw_documents);
+        assert_eq!(memory.embedding_model, None);
+        assert_eq!(memory.model_mismatch, ModelMismatchMode::Warn);
+        assert_eq!(
+            memory.breaker_failure_threshold,
+            DEFAULT_BREAKER_FAILURE_THRESHOLD
+        );
+
+        let config = config(&[
+            ("PINECONE_API_KEY", "key"),
+            ("PINECONE_HOST", "https://index.pinecone.io"),
+            ("PORT", "9000"),
+            ("TRANSPORT", "grpc"),
+            ("NAMESPACE", "tweets"),
+            ("SPLIT_CRITERIA", "token_count:256:2"),
+            ("TOKENIZER_PATH", "cl100k_base.tiktoken"),
+            ("TOKENIZER_KIND", "tiktoken"),
+            ("DETECT_LANGUAGE", "true"),
+            ("PREPEND_DOCUMENT_CONTEXT", "true"),
+            ("STORE_RAW_DOCUMENTS", "true"),
+            ("EMBEDDING_MODEL", "BAAI/bge-small-en-v1.5"),
+            ("MODEL_MISMATCH", "error"),
+            ("DEBUG_SAMPLE_INPUTS", "true"),
+            ("HTTP_POOL_MAX_IDLE_PER_HOST", "16"),
+            ("HTTP2_PRIOR_KNOWLEDGE", "true"),
+            ("BULK_CONCURRENCY", "16"),
+            ("BULK_PROGRESS_INTERVAL", "0"),
+            ("MAX_TOTAL_TOKENS_PER_REQUEST", "100000"),
+            ("TOKEN_LIMIT_MODE", "truncate"),
+            ("MAX_QUERY_TOKENS", "256"),
+            ("PINECONE_BREAKER_THRESHOLD", "0"),
+            ("PINECONE_BREAKER_COOLDOWN_SECS", "5"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.vector_store,
+            VectorStore::Pinecone {
+                api_key: "key".to_string(),
+                host: "https://index.pinecone.io".to_string(),
+            }
+        );
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.transport, Transport::Grpc);
+        assert_eq!(config.namespace, "tweets");
+        assert!(matches!(
+            config.split_criteria,
+            Some(SplitCriteria::TokenCount {
+                max_tokens: 256,
+                context_sentences: 2,
+            })
+        ));
+        assert_eq!(
+            config.tokenizer_path,
+            Some(PathBuf::from("cl100k_base.tiktoken"))
+        );
+        assert_eq!(config.tokenizer_kind, TokenizerKind::Tiktoken);
+        assert!(config.detect_language);
+        assert!(config.prepend_document_context);
+        assert!(config.store_raw_documents);
+        assert_eq!(
+            config.embedding_model.as_deref(),
+            Some("BAAI/bge-small-en-v1.5")
+        );
+        assert_eq!(config.model_mismatch, ModelMismatchMode::Error);
+        assert!(config.debug_sample_inputs);
+        assert_eq!(config.http_options.pool_max_idle_per_host, Some(16));
+        assert!(config.http_options.http2_prior_knowledge);
+        assert_eq!(config.bulk_pipeline.concurrency, 16);
+        assert_eq!(config.bulk_pipeline.progress_interval, 0);
+        assert_eq!(config.limits.max_total_tokens_per_request, Some(100_000));
+        assert_eq!(config.limits.token_limit_mode, TokenLimitMode::Truncate);
+        assert_eq!(config.max_query_tokens, 256);
+        assert_eq!(config.breaker_failure_threshold, 0);
+        assert_eq!(config.breaker_cooldown, Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,55 @@
+use axum::http::StatusCode;
+use thiserror::Error;
+
+/// Typed errors of the RAG crate, for failures callers may want to handle specifically.
+///
+/// These are returned wrapped in an `anyhow::Error`, and can be recovered with
+/// `downcast_ref::<RagError>()`.
+#[derive(Debug, Error)]
+pub enum RagError {
+    /// The input provided by the caller is invalid
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    /// The resource the caller asked for does not exist
+    #[error("Not found: {0}")]
+    NotFound(String),
+    /// The resource the caller asked to create already exists
+    #[error("Already exists: {0}")]
+    AlreadyExists(String),
+    /// The embedding service failed, or responded with something else than an embedding
+    #[error("Embedding service error: {message}, with body: {body}")]
+    Embedding {
+        /// What went wrong
+        message: String,
+        /// A truncated snippet of the response body
+        body: String,
+    },
+    /// The index was built with another embedding model than the one of the server
+    #[error("Embedding model mismatch: {0}")]
+    ModelMismatch(String),
+    /// A dependency is unavailable, e.g. Pinecone while the circuit breaker is open
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl RagError {
+    /// Returns the HTTP status code matching the error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RagError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            RagError::NotFound(_) => StatusCode::NOT_FOUND,
+            RagError::AlreadyExists(_) => StatusCode::CONFLICT,
+            RagError::Embedding { .. } => StatusCode::BAD_GATEWAY,
+            RagError::ModelMismatch(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            RagError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// Returns the HTTP status code matching an error, `500` if it is not a `RagError`.
+pub fn status_code(error: &anyhow::Error) -> StatusCode {
+    match error.downcast_ref::<RagError>() {
+        Some(error) => error.status_code(),
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,394 @@
+//! gRPC transport of the server, mirroring the `/embed`, `/query` and `/create_index` routes.
+//!
+//! The gRPC methods convert their messages to the JSON input types and call the HTTP
+//! handlers, so both transports share the same `AppState` and behave the same.
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Error, Result};
+use axum::{extract::State, http::StatusCode, Json};
+use tonic::{transport::Server, Request, Response, Status};
+use tracing::{error, info, info_span, instrument};
+
+use crate::{
+    server::{self, AppState},
+    types::{self, LongQueryStrategy, MetricOptions, UpsertMode},
+    validation::{Validate, ValidatedJson},
+};
+
+/// Messages and services generated from `proto/rag.proto`.
+pub mod proto {
+    tonic::include_proto!("rag");
+}
+
+use proto::rag_server::{Rag, RagServer};
+
+/// The gRPC service, delegating to the HTTP handlers.
+pub struct RagService {
+    app_state: AppState,
+}
+
+impl RagService {
+    /// Constructor
+    pub fn new(app_state: AppState) -> Self {
+        Self { app_state }
+    }
+}
+
+#[tonic::async_trait]
+impl Rag for RagService {
+    async fn embed(
+        &self,
+        request: Request<proto::TextToEmbed>,
+    ) -> Result<Response<proto::EmbedResponse>, Status> {
+        let input = types::TextToEmbed::try_from(request.into_inner())?;
+        input
+            .validate()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let Json(response) = server::embed(State(self.app_state.clone()), ValidatedJson(input))
+            .await
+            .map_err(into_status)?;
+        Ok(Response::new(embed_response(&response)))
+    }
+
+    async fn query(
+        &self,
+        request: Request<proto::QueryInput>,
+    ) -> Result<Response<proto::QueryResults>, Status> {
+        let input = types::QueryInput::from(request.into_inner());
+        let Json(results) = server::query(State(self.app_state.clone()), Json(input))
+            .await
+            .map_err(into_status)?;
+        Ok(Response::new(results.into()))
+    }
+
+    async fn create_index(
+        &self,
+        request: Request<proto::CreateIndexInput>,
+    ) -> Result<Response<proto::CreateIndexResponse>, Status> {
+        let input = types::CreateIndexInput::from(request.into_inner());
+        server::create_index(State(self.app_state.clone()), Json(input))
+            .await
+            .map_err(into_status)?;
+        Ok(Response::new(proto::CreateIndexResponse {}))
+    }
+}
+
+/// Starts the gRPC server with the given application state.
+///
+/// # Arguments
+///
+/// * `host` - A string slice that holds the host address to bind the server to.
+/// * `port` - The port number to bind the server to.
+/// * `app_state` - The state shared by the handlers, as for `server::start`.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - The host address is invalid and cannot be parsed.
+/// - The server fails to bind to the specified address and port.
+/// - There's an error while serving the application.
+#[instrument(skip_all)]
+pub async fn start_grpc(host: &str, port: u16, app_state: AppState) -> Result<()> {
+    let span = info_span!("start-grpc-server");
+    let _enter = span.enter();
+    info!("Starting gRPC server on {}:{}", host, port);
+    let ip: IpAddr = match host.parse() {
+        Ok(ip) => ip,
+        Err(_) => {
+            error!("Invalid host address");
+            return Err(Error::msg("Invalid host address"));
+        }
+    };
+    let addr = SocketAddr::new(ip, port);
+    match Server::builder()
+        .add_service(RagServer::new(RagService::new(app_state)))
+        .serve(addr)
+        .await
+    {
+        Ok(()) => {
+            info!("gRPC server stopped");
+            Ok(())
+        }
+        Err(e) => {
+            error!("Error starting gRPC server: {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Maps the error of an HTTP handler to the matching gRPC status.
+fn into_status((status, message): (StatusCode, String)) -> Status {
+    match status {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::CONFLICT => Status::already_exists(message),
+        StatusCode::PAYLOAD_TOO_LARGE => Status::resource_exhausted(message),
+        StatusCode::UNPROCESSABLE_ENTITY => Status::failed_precondition(message),
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+/// Builds the gRPC response of `embed` from the JSON response of the HTTP handler.
+fn embed_response(response: &serde_json::Value) -> proto::EmbedResponse {
+    let chunks = response["chunks"]
+        .as_array()
+        .map(|chunks| {
+            chunks
+                .iter()
+                .map(|chunk| proto::ChunkPreview {
+                    text: chunk["text"].as_str().unwrap_or_default().to_string(),
+                    tokens: chunk["tokens"].as_u64(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let chunk_tokens = response["chunk_tokens"]
+        .as_array()
+        .map(|counts| counts.iter().filter_map(|count| count.as_u64()).collect())
+        .unwrap_or_default();
+    proto::EmbedResponse {
+        query_id: response["query_id"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        status: response["status"].as_str().unwrap_or_default().to_string(),
+        chunks,
+        chunk_tokens,
+    }
+}
+
+fn metric(metric: proto::Metric) -> Option<MetricOptions> {
+    match metric {
+        proto::Metric::Unspecified => None,
+        proto::Metric::Cosine => Some(MetricOptions::Cosine),
+        proto::Metric::Euclidean => Some(MetricOptions::Euclidean),
+        proto::Metric::Dotproduct => Some(MetricOptions::Dotproduct),
+    }
+}
+
+/// Converts a protobuf struct into a JSON object.
+fn json_object(value: prost_types::Struct) -> serde_json::Map<String, serde_json::Value> {
+    value
+        .fields
+        .into_iter()
+        .map(|(key, value)| (key, json_value(value)))
+        .collect()
+}
+
+/// Converts a protobuf value into a JSON value.
+fn json_value(value: prost_types::Value) -> serde_json::Value {
+    use prost_types::value::Kind;
+    match value.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        Some(Kind::BoolValue(b)) => b.into(),
+        Some(Kind::NumberValue(n)) => n.into(),
+        Some(Kind::StringValue(s)) => s.into(),
+        Some(Kind::ListValue(list)) => list.values.into_iter().map(json_value).collect(),
+        Some(Kind::StructValue(fields)) => json_object(fields).into(),
+    }
+}
+
+impl TryFrom<proto::TextToEmbed> for types::TextToEmbed {
+    type Error = Status;
+
+    fn try_from(input: proto::TextToEmbed) -> Result<Self, Status> {
+        let page = input
+            .page
+            .map(u16::try_from)
+            .transpose()
+            .map_err(|_| Status::invalid_argument("page must fit in 16 bits"))?;
+        let upsert_mode = match input.upsert_mode() {
+            proto::UpsertMode::Unspecified => None,
+            proto::UpsertMode::Append => Some(UpsertMode::Append),
+            proto::UpsertMode::Replace => Some(UpsertMode::Replace),
+        };
+        Ok(types::TextToEmbed {
+            metric: metric(input.metric()),
+            upsert_mode,
+            page,
+            query_id: input.query_id,
+            index_name: input.index_name,
+            content: input.content,
+            topic: input.topic,
+            description: input.description,
+            source: input.source,
+            author: input.author,
+            date: input.date,
+            create_if_missing: input.create_if_missing,
+            dimension: input.dimension,
+            dry_run: input.dry_run,
+            verbose: input.verbose,
+            extra: input.extra.map(json_object),
+            id_prefix: input.id_prefix,
+            namespace: input.namespace,
+            image_url: input.image_url,
+            detected_lang: input.detected_lang,
+        })
+    }
+}
+
+impl From<proto::QueryInput> for types::QueryInput {
+    fn from(input: proto::QueryInput) -> Self {
+        let long_query_strategy = match input.long_query_strategy() {
+            proto::LongQueryStrategy::Unspecified => None,
+            proto::LongQueryStrategy::Average => Some(LongQueryStrategy::Average),
+            proto::LongQueryStrategy::MultiVector => Some(LongQueryStrategy::MultiVector),
+        };
+        types::QueryInput {
+            long_query_strategy,
+            index_name: input.index_name,
+            query_text: input.query_text,
+            top_k: input.top_k,
+            score_threshold: input.score_threshold,
+            offset: input.offset,
+            filter: input.filter.map(json_object),
+            date_from: input.date_from,
+            date_to: input.date_to,
+            include_values: input.include_values,
+            normalize_scores: input.normalize_scores,
+            namespace: input.namespace,
+            context_window: input.context_window.map(|window| window as usize),
+            dedupe_by_document: input.dedupe_by_document,
+            recency_half_life_days: input.recency_half_life_days,
+        }
+    }
+}
+
+impl From<proto::CreateIndexInput> for types::CreateIndexInput {
+    fn from(input: proto::CreateIndexInput) -> Self {
+        types::CreateIndexInput {
+            metric: metric(input.metric()),
+            index_name: input.index_name,
+            dimension: input.dimension,
+            wait: input.wait,
+            wait_timeout_secs: input.wait_timeout_secs,
+        }
+    }
+}
+
+impl From<types::QueryResults> for proto::QueryResults {
+    fn from(results: types::QueryResults) -> Self {
+        proto::QueryResults {
+            results: results
+                .results
+                .into_iter()
+                .map(|result| proto::QueryResponse {
+                    id: result.id,
+                    score: result.score,
+                    raw_score: result.raw_score,
+                    embedding: result.embedding,
+                    text: result.text,
+                    index_name: result.index_name,
+                    query_id: result.query_id,
+                    chunk_index: result.chunk_index.map(|i| i as u64),
+                    context: result.context,
+                    timestamp: result.timestamp,
+                    norm: result.norm,
+                    author: result.author,
+                })
+                .collect(),
+            returned: results.returned as u64,
+            has_more: results.has_more,
+            top_k: results.top_k as u64,
+            warning: results.warning,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::MAX_TOP_K,
+        split_criteria::SplitCriteria,
+        test_utils::{spawn_server, test_client, MockEmbedder},
+    };
+    use proto::rag_client::RagClient;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    async fn spawn_grpc_server(app_state: AppState) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            Server::builder()
+                .add_service(RagServer::new(RagService::new(app_state)))
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .unwrap();
+        });
+        addr
+    }
+
+    fn query_input(top_k: Option<u32>) -> proto::QueryInput {
+        proto::QueryInput {
+            index_name: "test-index".to_string(),
+            query_text: "grpc query".to_string(),
+            top_k,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_through_grpc_client() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let app_state = AppState::new(test_client(addr), Some(SplitCriteria::EndOfSentence), None);
+        let grpc_addr = spawn_grpc_server(app_state).await;
+        let mut client = RagClient::connect(format!("http://{}", grpc_addr))
+            .await
+            .unwrap();
+
+        // Validation errors of the shared handler are mapped to gRPC statuses
+        let status = client
+            .query(query_input(Some(MAX_TOP_K + 1)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(embedder.inputs().is_empty());
+
+        // NOTE: There is no Pinecone index to query, so only the embedding step is checked here
+        let _ = client.query(query_input(Some(5))).await;
+        assert_eq!(embedder.inputs(), vec!["grpc query"]);
+    }
+
+    #[test]
+    fn test_proto_inputs_convert_to_json_types() {
+        let mut extra = prost_types::Struct::default();
+        extra.fields.insert(
+            "tags".to_string(),
+            prost_types::Value {
+                kind: Some(prost_types::value::Kind::ListValue(
+                    prost_types::ListValue {
+                        values: vec![prost_types::Value {
+                            kind: Some(prost_types::value::Kind::StringValue("rust".to_string())),
+                        }],
+                    },
+                )),
+            },
+        );
+        let input = proto::TextToEmbed {
+            query_id: "query-id".to_string(),
+            index_name: "index".to_string(),
+            content: "Some content.".to_string(),
+            page: Some(3),
+            metric: proto::Metric::Dotproduct.into(),
+            upsert_mode: proto::UpsertMode::Replace.into(),
+            extra: Some(extra),
+            ..Default::default()
+        };
+        let input = types::TextToEmbed::try_from(input).unwrap();
+        assert_eq!(input.page, Some(3));
+        assert!(matches!(input.metric, Some(MetricOptions::Dotproduct)));
+        assert_eq!(input.upsert_mode, Some(UpsertMode::Replace));
+        assert_eq!(input.extra.unwrap()["tags"], serde_json::json!(["rust"]));
+
+        let input = proto::TextToEmbed {
+            page: Some(u32::MAX),
+            ..Default::default()
+        };
+        let status = types::TextToEmbed::try_from(input).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
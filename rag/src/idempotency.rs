@@ -0,0 +1,117 @@
+//! In-memory cache of recent `/embed` responses, making retried requests idempotent.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use serde_json::Value;
+
+/// Default number of recently processed documents remembered by the idempotency cache.
+pub const DEFAULT_IDEMPOTENCY_CAPACITY: usize = 1024;
+/// Default number of seconds a processed document is remembered by the idempotency cache.
+pub const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 300;
+
+/// A cached response, along with the fingerprint of the request it answered.
+struct Entry {
+    inserted_at: Instant,
+    fingerprint: u64,
+    response: Value,
+}
+
+/// Remembers the responses of recently processed documents, keyed by `query_id`.
+///
+/// A client retrying `/embed` after a timeout would otherwise embed and store its
+/// document twice. Only a request identical to the cached one is short-circuited, so an
+/// edited document sent with the same `query_id` is processed again. Entries expire after
+/// the TTL, and are not persisted across restarts.
+pub struct IdempotencyCache {
+    entries: Mutex<LruCache<String, Entry>>,
+    ttl: Duration,
+}
+
+impl IdempotencyCache {
+    /// Creates a cache remembering up to `capacity` documents for `ttl`.
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+        }
+    }
+
+    /// Returns the cached response to the given request, unless it expired.
+    ///
+    /// # Arguments
+    ///
+    /// * `query_id` - The id of the document of the request.
+    /// * `request` - The serialized request, which must match the cached one.
+    pub fn get(&self, query_id: &str, request: &str) -> Option<Value> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(query_id)?;
+        if entry.inserted_at.elapsed() > self.ttl {
+            entries.pop(query_id);
+            return None;
+        }
+        (entry.fingerprint == fingerprint(request)).then(|| entry.response.clone())
+    }
+
+    /// Caches the response to the given request, replacing any previous one for the same `query_id`.
+    pub fn insert(&self, query_id: &str, request: &str, response: Value) {
+        self.entries.lock().unwrap().put(
+            query_id.to_string(),
+            Entry {
+                inserted_at: Instant::now(),
+                fingerprint: fingerprint(request),
+                response,
+            },
+        );
+    }
+}
+
+/// Hashes a serialized request. The hash only lives in memory, so it needs not be stable
+/// across builds.
+fn fingerprint(request: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn new_cache(capacity: usize, ttl: Duration) -> IdempotencyCache {
+        IdempotencyCache::new(NonZeroUsize::new(capacity).unwrap(), ttl)
+    }
+
+    #[test]
+    fn test_identical_request_hits() {
+        let cache = new_cache(2, Duration::from_secs(60));
+        assert!(cache.get("a", "request").is_none());
+        cache.insert("a", "request", json!({ "status": "success" }));
+        assert_eq!(
+            cache.get("a", "request"),
+            Some(json!({ "status": "success" }))
+        );
+        // An edited document with the same query id is processed again
+        assert!(cache.get("a", "edited request").is_none());
+    }
+
+    #[test]
+    fn test_entries_expire_and_are_evicted() {
+        let cache = new_cache(1, Duration::ZERO);
+        cache.insert("a", "request", json!({}));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache.get("a", "request").is_none());
+
+        let cache = new_cache(1, Duration::from_secs(60));
+        cache.insert("a", "request", json!({}));
+        cache.insert("b", "request", json!({}));
+        assert!(cache.get("a", "request").is_none());
+        assert!(cache.get("b", "request").is_some());
+    }
+}
@@ -0,0 +1,197 @@
+//! Lightweight language detection, filling the language of documents which lack one.
+//!
+//! The language is told from the script of the letters of the text, and for Latin script
+//! texts from the most frequent function words of a few common languages. Detection is
+//! fail-soft: texts too short or too ambiguous to be told apart are left undetected.
+
+/// Minimum number of letters of a text for its language to be detected.
+const MIN_LETTERS: usize = 3;
+
+/// Frequent function words of Latin script languages, keyed by ISO 639-1 code.
+const STOPWORDS: [(&str, &[&str]); 7] = [
+    (
+        "en",
+        &[
+            "the", "and", "is", "of", "to", "in", "that", "it", "with", "for", "this", "are",
+            "was", "on", "be", "not", "you", "have",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "et", "est", "des", "une", "un", "dans", "que", "pour", "pas",
+            "sur", "avec", "ce", "du", "qui", "je",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "das", "und", "ist", "nicht", "ein", "eine", "mit", "den", "zu", "von",
+            "auf", "ich", "sich", "auch",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "y", "es", "que", "del", "una", "por", "con", "para", "no", "se",
+            "como", "pero", "está",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "os", "as", "e", "é", "não", "uma", "do", "da", "dos", "em", "que", "com", "para",
+            "mais", "mas",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "gli", "e", "è", "di", "che", "non", "una", "per", "con", "del", "della", "sono",
+            "mi", "ma", "anche",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "de", "het", "een", "en", "is", "van", "niet", "dat", "op", "te", "zijn", "met",
+            "voor", "ook", "maar",
+        ],
+    ),
+];
+
+/// Scripts told apart by the detection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Greek,
+    Arabic,
+    Hebrew,
+    Devanagari,
+    Thai,
+    Hangul,
+    Kana,
+    Han,
+}
+
+impl Script {
+    /// Returns the script of a letter, if it is one of the detected scripts.
+    fn of(c: char) -> Option<Self> {
+        let script = match c {
+            'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => Self::Latin,
+            '\u{0370}'..='\u{03FF}' => Self::Greek,
+            '\u{0400}'..='\u{04FF}' => Self::Cyrillic,
+            '\u{0590}'..='\u{05FF}' => Self::Hebrew,
+            '\u{0600}'..='\u{06FF}' => Self::Arabic,
+            '\u{0900}'..='\u{097F}' => Self::Devanagari,
+            '\u{0E00}'..='\u{0E7F}' => Self::Thai,
+            '\u{1100}'..='\u{11FF}' | '\u{AC00}'..='\u{D7AF}' => Self::Hangul,
+            '\u{3040}'..='\u{30FF}' => Self::Kana,
+            '\u{4E00}'..='\u{9FFF}' => Self::Han,
+            _ => return None,
+        };
+        Some(script)
+    }
+}
+
+/// Detects the language of a text, as an ISO 639-1 code, e.g. `en` or `ja`.
+///
+/// Returns `None` if the text holds fewer than `MIN_LETTERS` letters, or if its Latin script
+/// words do not favor any of the known languages.
+pub fn detect_lang(text: &str) -> Option<String> {
+    let mut counts: Vec<(Script, usize)> = vec![];
+    for script in text.chars().filter_map(Script::of) {
+        match counts.iter_mut().find(|(s, _)| *s == script) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((script, 1)),
+        }
+    }
+    let count = |script| {
+        counts
+            .iter()
+            .find(|(s, _)| *s == script)
+            .map_or(0, |(_, count)| *count)
+    };
+    if counts.iter().map(|(_, count)| count).sum::<usize>() < MIN_LETTERS {
+        return None;
+    }
+    // NOTE: Japanese mixes kana with Han characters, which alone denote Chinese
+    let cjk = count(Script::Kana) + count(Script::Han);
+    let (script, _) = counts
+        .iter()
+        .copied()
+        .map(|(script, count)| match script {
+            Script::Kana | Script::Han => (script, cjk),
+            _ => (script, count),
+        })
+        .max_by_key(|(_, count)| *count)?;
+    let lang = match script {
+        Script::Latin => return detect_latin_lang(text),
+        Script::Kana | Script::Han if count(Script::Kana) > 0 => "ja",
+        Script::Kana | Script::Han => "zh",
+        Script::Cyrillic => "ru",
+        Script::Greek => "el",
+        Script::Arabic => "ar",
+        Script::Hebrew => "he",
+        Script::Devanagari => "hi",
+        Script::Thai => "th",
+        Script::Hangul => "ko",
+    };
+    Some(lang.to_string())
+}
+
+/// Detects the language of a Latin script text from its function words, if one language
+/// has strictly more of them than the others.
+fn detect_latin_lang(text: &str) -> Option<String> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let mut scores: Vec<(&str, usize)> = STOPWORDS
+        .iter()
+        .map(|(lang, stopwords)| {
+            let hits = words
+                .iter()
+                .filter(|word| stopwords.contains(&word.as_str()))
+                .count();
+            (*lang, hits)
+        })
+        .collect();
+    scores.sort_by_key(|(_, hits)| std::cmp::Reverse(*hits));
+    match scores.as_slice() {
+        [(lang, best), (_, second), ..] if *best > *second => Some(lang.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_english_and_japanese() {
+        assert_eq!(
+            detect_lang("The quick brown fox jumps over the lazy dog, and it is fast.").as_deref(),
+            Some("en")
+        );
+        assert_eq!(
+            detect_lang("今日はとても良い天気ですね。散歩に行きましょう。").as_deref(),
+            Some("ja")
+        );
+        assert_eq!(detect_lang("今天天气很好").as_deref(), Some("zh"));
+        assert_eq!(
+            detect_lang("Der Hund ist nicht auf dem Sofa, und die Katze auch nicht.").as_deref(),
+            Some("de")
+        );
+    }
+
+    #[test]
+    fn test_detect_lang_is_fail_soft() {
+        assert_eq!(detect_lang(""), None);
+        assert_eq!(detect_lang("42 + 1337 = ?"), None);
+        // No function word tells the language of a list of names apart
+        assert_eq!(detect_lang("Rust Pinecone Axum"), None);
+    }
+}
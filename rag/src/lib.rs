@@ -1,4 +1,23 @@
+pub mod circuit_breaker;
 pub mod client;
+pub mod compaction;
+pub mod config;
+pub mod error;
+pub mod grpc;
+pub mod idempotency;
+pub mod lang;
+pub mod math;
+pub mod metadata;
+pub mod model_guard;
+pub mod pipeline;
+pub mod rank;
+pub mod request_id;
 pub mod server;
 pub mod split_criteria;
+pub mod store;
+pub mod tokens;
 pub mod types;
+pub mod validation;
+
+#[cfg(test)]
+mod test_utils;
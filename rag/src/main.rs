@@ -1,45 +1,111 @@
 use anyhow::Result;
 use dotenv::dotenv;
-use rag::{client::EmbeddingClient, server::start};
-use std::env;
-use tracing::info;
+use rag::{
+    circuit_breaker::CircuitBreaker,
+    client::EmbeddingClient,
+    config::{Config, Transport, VectorStore},
+    grpc::start_grpc,
+    idempotency::IdempotencyCache,
+    model_guard::ModelGuard,
+    server::{start, AppState, DEBUG_SAMPLE_TARGET},
+    store::InMemoryStore,
+    tokens::resolve_token_counter,
+};
+use std::num::NonZeroUsize;
+use tracing::{info, warn, Level};
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
-    dotenv().expect("Failed to load .env file");
+    let loaded_env = dotenv();
+    // Every setting is read and validated up front, so a misconfigured server fails to start
+    let config = Config::from_env()?;
 
-    // Get host and port from environment variables or use defaults
-    let host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
-    let port = env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(8081);
-    let embedding_host = env::var("EMBEDDING_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let embedding_port = env::var("EMBEDDING_PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(8080);
+    // Initialize tracing, only the debug samples of the embedded texts being logged below `info`
+    let mut targets = Targets::new().with_default(Level::INFO);
+    if config.debug_sample_inputs {
+        targets = targets.with_target(DEBUG_SAMPLE_TARGET, Level::DEBUG);
+    }
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(targets))
+        .init();
+    if let Err(e) = loaded_env {
+        warn!("No .env file loaded: {}", e);
+    }
 
-    let pinecone_api_key = env::var("PINECONE_API_KEY").unwrap();
-    let pinecone_host = env::var("PINECONE_HOST").unwrap();
+    info!("Starting server on {}:{}", config.host, config.port);
 
-    // Initialize your EmbeddingClient here
-    // For example:
-    // let client = EmbeddingClient::new(/* parameters */);
-
-    info!("Starting server on {}:{}", host, port);
-
-    let client = EmbeddingClient::new(
-        embedding_host,
-        embedding_port,
-        pinecone_api_key,
-        pinecone_host,
-    )
-    .await?;
-    // Start the server
-    start(&host, port, client, None).await?;
+    // The in-memory vector store does not require a Pinecone account
+    let client = match &config.vector_store {
+        VectorStore::Pinecone { api_key, host } => {
+            EmbeddingClient::new(
+                config.embedding_host.clone(),
+                config.embedding_port,
+                api_key.clone(),
+                host.clone(),
+            )
+            .await?
+        }
+        VectorStore::Memory => {
+            EmbeddingClient::without_pinecone(config.embedding_host.clone(), config.embedding_port)?
+        }
+    }
+    .with_embedding_url(config.embedding_url.clone())
+    .with_prefixes(config.document_prefix.clone(), config.query_prefix.clone())
+    .with_input_field(config.input_field.clone())
+    .with_image_field(config.image_field.clone())
+    .with_embedding_encoding(config.embedding_encoding)
+    .with_http_options(&config.http_options)?
+    .with_counter_file(config.counter_file.clone())
+    .with_id_prefix(config.id_prefix.clone())
+    .with_embedding_cache(config.embedding_cache_capacity)
+    .with_upsert_batch_size(config.upsert_batch_size)
+    .with_namespace(config.namespace.clone())
+    // A threshold of 0 disables the circuit breaker
+    .with_circuit_breaker(
+        NonZeroUsize::new(config.breaker_failure_threshold)
+            .map(|threshold| CircuitBreaker::new(threshold, config.breaker_cooldown)),
+    );
+    // A capacity of 0 disables the idempotency cache
+    let idempotency_cache = NonZeroUsize::new(config.idempotency_capacity)
+        .map(|capacity| IdempotencyCache::new(capacity, config.idempotency_ttl));
+    let split_criteria = config.split_criteria.clone();
+    // The default split criteria counts tokens, so it requires a tokenizer, as does the token limit
+    let tokenizer_required = split_criteria
+        .as_ref()
+        .is_none_or(|criteria| criteria.needs_tokenizer())
+        || config.limits.max_total_tokens_per_request.is_some();
+    let tokenizer = resolve_token_counter(
+        config.tokenizer_kind,
+        config.tokenizer_path.as_deref(),
+        &config.tokenizer_repo,
+        tokenizer_required,
+    )?;
+    let app_state = match config.vector_store {
+        VectorStore::Memory => AppState::new(InMemoryStore::new(client), split_criteria, tokenizer),
+        VectorStore::Pinecone { .. } => AppState::new(client, split_criteria, tokenizer),
+    };
+    let app_state = app_state
+        .with_embedding_concurrency(config.embedding_concurrency)
+        .with_limits(config.limits)
+        .with_default_top_k(config.default_top_k)
+        .with_max_query_tokens(config.max_query_tokens)
+        .with_idempotency_cache(idempotency_cache)
+        .with_language_detection(config.detect_language)
+        .with_document_context(config.prepend_document_context)
+        .with_raw_documents(config.store_raw_documents)
+        .with_model_guard(
+            config
+                .embedding_model
+                .as_ref()
+                .map(|model| ModelGuard::new(model, config.model_mismatch)),
+        )
+        .with_bulk_pipeline(config.bulk_pipeline);
+    // Start the server, over HTTP unless the gRPC transport is selected
+    match config.transport {
+        Transport::Http => start(&config.host, config.port, app_state).await?,
+        Transport::Grpc => start_grpc(&config.host, config.port, app_state).await?,
+    }
 
     Ok(())
 }
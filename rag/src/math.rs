@@ -0,0 +1,145 @@
+use anyhow::{anyhow, Result};
+
+/// Computes the dot product of two vectors.
+///
+/// # Arguments
+///
+/// * `a` - The first vector.
+/// * `b` - The second vector.
+///
+/// # Errors
+///
+/// Returns an error if the vectors have different lengths.
+pub fn dot_product(a: &[f32], b: &[f32]) -> Result<f32> {
+    check_lengths(a, b)?;
+    Ok(a.iter().zip(b.iter()).map(|(x, y)| x * y).sum())
+}
+
+/// Computes the Euclidean (L2) distance between two vectors.
+///
+/// # Arguments
+///
+/// * `a` - The first vector.
+/// * `b` - The second vector.
+///
+/// # Errors
+///
+/// Returns an error if the vectors have different lengths.
+pub fn l2_distance(a: &[f32], b: &[f32]) -> Result<f32> {
+    check_lengths(a, b)?;
+    Ok(a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f32>()
+        .sqrt())
+}
+
+/// Computes the cosine similarity between two vectors.
+///
+/// # Arguments
+///
+/// * `a` - The first vector.
+/// * `b` - The second vector.
+///
+/// # Returns
+///
+/// Returns a value in `[-1, 1]`. If either vector has zero magnitude, the
+/// similarity is undefined and `0.0` is returned instead of `NaN`.
+///
+/// # Errors
+///
+/// Returns an error if the vectors have different lengths.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> Result<f32> {
+    let dot = dot_product(a, b)?;
+    let norm_a = l2_norm(a);
+    let norm_b = l2_norm(b);
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return Ok(0.0);
+    }
+    Ok(dot / (norm_a * norm_b))
+}
+
+/// Computes the L2 norm (magnitude) of a vector.
+pub fn l2_norm(a: &[f32]) -> f32 {
+    a.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+/// Computes the element-wise mean of vectors.
+///
+/// # Errors
+///
+/// Returns an error if there are no vectors, or if they have different lengths.
+pub fn mean_vector(vectors: &[Vec<f32>]) -> Result<Vec<f32>> {
+    let Some(first) = vectors.first() else {
+        return Err(anyhow!("Cannot average an empty set of vectors"));
+    };
+    let mut mean = vec![0.0; first.len()];
+    for vector in vectors {
+        check_lengths(&mean, vector)?;
+        for (sum, x) in mean.iter_mut().zip(vector) {
+            *sum += x;
+        }
+    }
+    let count = vectors.len() as f32;
+    mean.iter_mut().for_each(|sum| *sum /= count);
+    Ok(mean)
+}
+
+fn check_lengths(a: &[f32], b: &[f32]) -> Result<()> {
+    if a.len() != b.len() {
+        return Err(anyhow!(
+            "Vector length mismatch: {} vs {}",
+            a.len(),
+            b.len()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-6;
+
+    #[test]
+    fn test_orthogonal_vectors() {
+        let a = [1.0, 0.0, 0.0];
+        let b = [0.0, 1.0, 0.0];
+        assert!(cosine_similarity(&a, &b).unwrap().abs() < EPSILON);
+        assert!(dot_product(&a, &b).unwrap().abs() < EPSILON);
+        assert!((l2_distance(&a, &b).unwrap() - 2.0_f32.sqrt()).abs() < EPSILON);
+    }
+
+    #[test]
+    fn test_identical_vectors() {
+        let a = [0.5, -1.5, 2.0];
+        assert!((cosine_similarity(&a, &a).unwrap() - 1.0).abs() < EPSILON);
+        assert!((dot_product(&a, &a).unwrap() - 6.5).abs() < EPSILON);
+        assert!(l2_distance(&a, &a).unwrap().abs() < EPSILON);
+    }
+
+    #[test]
+    fn test_mismatched_lengths() {
+        let a = [1.0, 2.0];
+        let b = [1.0, 2.0, 3.0];
+        assert!(cosine_similarity(&a, &b).is_err());
+        assert!(dot_product(&a, &b).is_err());
+        assert!(l2_distance(&a, &b).is_err());
+        assert!(mean_vector(&[a.to_vec(), b.to_vec()]).is_err());
+        assert!(mean_vector(&[]).is_err());
+    }
+
+    #[test]
+    fn test_mean_vector() {
+        let mean = mean_vector(&[vec![1.0, -2.0], vec![3.0, 2.0]]).unwrap();
+        assert_eq!(mean, vec![2.0, 0.0]);
+    }
+
+    #[test]
+    fn test_zero_vector_cosine() {
+        let a = [0.0, 0.0];
+        let b = [1.0, 1.0];
+        assert_eq!(cosine_similarity(&a, &b).unwrap(), 0.0);
+    }
+}
@@ -0,0 +1,212 @@
+//! Metadata stored alongside the embeddings, independently of the Pinecone SDK types.
+
+use std::collections::BTreeMap;
+
+use pinecone_sdk::models::{Kind, Metadata, Value};
+use prost_types::ListValue;
+use serde::{Deserialize, Serialize};
+
+use crate::error::RagError;
+
+/// A value Pinecone can store as metadata: metadata is flat, so values are either scalars or
+/// lists of strings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MetadataValue {
+    /// A string, e.g. the text of a chunk
+    Str(String),
+    /// A number, stored as a 64-bit float, e.g. a timestamp
+    Num(f64),
+    /// A boolean
+    Bool(bool),
+    /// A list of strings, e.g. tags
+    List(Vec<String>),
+}
+
+/// The metadata fields of an embedding, keyed by name.
+pub type MetadataFields = BTreeMap<String, MetadataValue>;
+
+/// A metadata filter, as a JSON object in the Pinecone filter language, e.g.
+/// `{ "author": { "$eq": "atoma" } }`.
+pub type MetadataFilter = serde_json::Map<String, serde_json::Value>;
+
+impl MetadataValue {
+    /// Returns the string, if the value is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            MetadataValue::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the number, if the value is one.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            MetadataValue::Num(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for MetadataValue {
+    fn from(value: &str) -> Self {
+        MetadataValue::Str(value.to_string())
+    }
+}
+
+impl From<String> for MetadataValue {
+    fn from(value: String) -> Self {
+        MetadataValue::Str(value)
+    }
+}
+
+impl From<f64> for MetadataValue {
+    fn from(value: f64) -> Self {
+        MetadataValue::Num(value)
+    }
+}
+
+impl From<bool> for MetadataValue {
+    fn from(value: bool) -> Self {
+        MetadataValue::Bool(value)
+    }
+}
+
+impl From<Vec<String>> for MetadataValue {
+    fn from(values: Vec<String>) -> Self {
+        MetadataValue::List(values)
+    }
+}
+
+impl From<MetadataValue> for Value {
+    fn from(value: MetadataValue) -> Self {
+        let kind = match value {
+            MetadataValue::Str(value) => Kind::StringValue(value),
+            MetadataValue::Num(value) => Kind::NumberValue(value),
+            MetadataValue::Bool(value) => Kind::BoolValue(value),
+            MetadataValue::List(values) => Kind::ListValue(ListValue {
+                values: values
+                    .into_iter()
+                    .map(|value| Value {
+                        kind: Some(Kind::StringValue(value)),
+                    })
+                    .collect(),
+            }),
+        };
+        Value { kind: Some(kind) }
+    }
+}
+
+impl TryFrom<Value> for MetadataValue {
+    type Error = RagError;
+
+    /// Converts a Pinecone value, failing with a `RagError::InvalidInput` for nulls, nested
+    /// objects and lists of anything but strings, which Pinecone metadata cannot hold.
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value.kind {
+            Some(Kind::StringValue(value)) => Ok(MetadataValue::Str(value)),
+            Some(Kind::NumberValue(value)) => Ok(MetadataValue::Num(value)),
+            Some(Kind::BoolValue(value)) => Ok(MetadataValue::Bool(value)),
+            Some(Kind::ListValue(list)) => list
+                .values
+                .into_iter()
+                .map(|value| match value.kind {
+                    Some(Kind::StringValue(value)) => Ok(value),
+                    _ => Err(RagError::InvalidInput(
+                        "metadata lists may only contain strings".to_string(),
+                    )),
+                })
+                .collect::<Result<_, _>>()
+                .map(MetadataValue::List),
+            Some(Kind::StructValue(_)) => Err(RagError::InvalidInput(
+                "nested metadata objects are not supported".to_string(),
+            )),
+            Some(Kind::NullValue(_)) | None => Err(RagError::InvalidInput(
+                "null metadata values are not supported".to_string(),
+            )),
+        }
+    }
+}
+
+/// Converts metadata fields into the Pinecone metadata of a vector.
+pub fn to_sdk_metadata(fields: MetadataFields) -> Metadata {
+    Metadata {
+        fields: fields
+            .into_iter()
+            .map(|(key, value)| (key, value.into()))
+            .collect(),
+    }
+}
+
+/// Converts the Pinecone metadata of a vector into metadata fields, skipping the values that
+/// are not `MetadataValue`s, which the client never stores.
+pub fn from_sdk_metadata(metadata: Metadata) -> MetadataFields {
+    metadata
+        .fields
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, MetadataValue::try_from(value).ok()?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: MetadataValue) -> MetadataValue {
+        MetadataValue::try_from(Value::from(value)).unwrap()
+    }
+
+    #[test]
+    fn test_each_variant_round_trips_through_the_sdk() {
+        let values = vec![
+            MetadataValue::from("atoma"),
+            MetadataValue::from(1_730_462_400.0),
+            MetadataValue::from(true),
+            MetadataValue::from(vec!["rust".to_string(), "rag".to_string()]),
+            MetadataValue::List(Vec::new()),
+        ];
+        for value in values {
+            assert_eq!(round_trip(value.clone()), value);
+        }
+        assert_eq!(
+            Value::from(MetadataValue::from("atoma")).kind,
+            Some(Kind::StringValue("atoma".to_string()))
+        );
+
+        let fields = MetadataFields::from_iter(vec![
+            ("text".to_string(), MetadataValue::from("some text")),
+            ("likes".to_string(), MetadataValue::from(42.0)),
+        ]);
+        assert_eq!(from_sdk_metadata(to_sdk_metadata(fields.clone())), fields);
+    }
+
+    #[test]
+    fn test_unsupported_sdk_values_are_rejected() {
+        let null = Value {
+            kind: Some(Kind::NullValue(0)),
+        };
+        assert!(MetadataValue::try_from(null.clone()).is_err());
+        let numbers = Value {
+            kind: Some(Kind::ListValue(ListValue {
+                values: vec![MetadataValue::from(1.0).into()],
+            })),
+        };
+        assert!(MetadataValue::try_from(numbers).is_err());
+
+        let metadata = Metadata {
+            fields: BTreeMap::from_iter(vec![
+                ("text".to_string(), MetadataValue::from("kept").into()),
+                ("empty".to_string(), null),
+            ]),
+        };
+        assert_eq!(
+            from_sdk_metadata(metadata),
+            MetadataFields::from_iter(vec![("text".to_string(), MetadataValue::from("kept"))])
+        );
+        // Untagged, values serialize as their plain JSON counterparts
+        assert_eq!(
+            serde_json::to_value(MetadataValue::from(vec!["a".to_string()])).unwrap(),
+            serde_json::json!(["a"])
+        );
+    }
+}
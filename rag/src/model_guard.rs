@@ -0,0 +1,259 @@
+//! Detection of documents and queries embedded with another model than the one an index was
+//! built with, whose similarities are meaningless.
+
+use std::{collections::HashMap, str::FromStr, sync::Mutex};
+
+use anyhow::{Error, Result};
+use tracing::{info, warn};
+
+use crate::{
+    client::{string_field, StoredChunk},
+    error::RagError,
+    metadata::{MetadataFields, MetadataValue},
+    store::EmbeddingStore,
+};
+
+/// Namespace of the marker recording the embedding model of an index, out of the way of
+/// queries.
+pub const MODEL_MARKER_NAMESPACE: &str = "__markers__";
+/// Id of the marker recording the embedding model of an index.
+pub const MODEL_MARKER_ID: &str = "embedding-model";
+
+/// How a mismatch between the model of the server and the recorded model of an index is
+/// handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ModelMismatchMode {
+    /// Log a warning and report it in the response, the default
+    #[default]
+    Warn,
+    /// Reject the request with a `RagError::ModelMismatch`
+    Error,
+}
+
+impl FromStr for ModelMismatchMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(ModelMismatchMode::Warn),
+            "error" => Ok(ModelMismatchMode::Error),
+            _ => Err(anyhow::anyhow!("expected `warn` or `error`")),
+        }
+    }
+}
+
+/// The embedding model an index was built with, as recorded by its marker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexModel {
+    /// The id of the embedding model, e.g. `BAAI/bge-small-en-v1.5`
+    pub model: String,
+    /// The dimension of its embeddings
+    pub dimension: usize,
+}
+
+impl IndexModel {
+    fn from_metadata(metadata: &MetadataFields) -> Option<Self> {
+        Some(Self {
+            model: string_field(metadata, "embedding_model")?,
+            dimension: metadata.get("dimension")?.as_f64()? as usize,
+        })
+    }
+
+    fn to_metadata(&self) -> MetadataFields {
+        MetadataFields::from_iter(vec![
+            (
+                "embedding_model".to_string(),
+                MetadataValue::from(self.model.as_str()),
+            ),
+            (
+                "dimension".to_string(),
+                MetadataValue::from(self.dimension as f64),
+            ),
+        ])
+    }
+}
+
+/// Records the embedding model of the server in the indexes it embeds documents into, and
+/// checks it against the recorded model before embedding documents or queries.
+///
+/// The model is recorded by a marker vector, `MODEL_MARKER_ID` in the `MODEL_MARKER_NAMESPACE`
+/// of the index, written along with the first document embedded into an index without one.
+/// Indexes without a marker, e.g. built before models were recorded, are never reported.
+///
+/// # Notes
+///
+/// Markers are looked up once per index and process, so an index recreated with another
+/// model is only noticed after a restart.
+pub struct ModelGuard {
+    model: String,
+    mode: ModelMismatchMode,
+    /// Recorded models keyed by index name, `None` for indexes without a marker.
+    recorded: Mutex<HashMap<String, Option<IndexModel>>>,
+}
+
+impl ModelGuard {
+    /// Creates a guard for the given embedding model id.
+    pub fn new(model: impl Into<String>, mode: ModelMismatchMode) -> Self {
+        Self {
+            model: model.into(),
+            mode,
+            recorded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the model recorded by the marker of an index, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the marker cannot be fetched.
+    pub async fn recorded_model<S: EmbeddingStore + ?Sized>(
+        &self,
+        store: &S,
+        index_name: &str,
+    ) -> Result<Option<IndexModel>> {
+        if let Some(recorded) = self.recorded.lock().unwrap().get(index_name) {
+            return Ok(recorded.clone());
+        }
+        let markers = store
+            .fetch_chunks(
+                index_name,
+                &[MODEL_MARKER_ID.to_string()],
+                Some(MODEL_MARKER_NAMESPACE),
+            )
+            .await?;
+        let recorded = markers
+            .first()
+            .and_then(|marker| IndexModel::from_metadata(&marker.metadata));
+        self.recorded
+            .lock()
+            .unwrap()
+            .insert(index_name.to_string(), recorded.clone());
+        Ok(recorded)
+    }
+
+    /// Checks the model of the server against the recorded model of an index, returning the
+    /// warning to report if they differ and mismatches are only warned about.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RagError::ModelMismatch` if they differ and mismatches are errors, or an
+    /// error if the marker cannot be fetched.
+    pub async fn check<S: EmbeddingStore + ?Sized>(
+        &self,
+        store: &S,
+        index_name: &str,
+    ) -> Result<Option<String>> {
+        let Some(recorded) = self.recorded_model(store, index_name).await? else {
+            return Ok(None);
+        };
+        if recorded.model == self.model {
+            return Ok(None);
+        }
+        let message = format!(
+            "index {} was built with embedding model {} ({} dimensions), but the server embeds with {}",
+            index_name, recorded.model, recorded.dimension, self.model
+        );
+        match self.mode {
+            ModelMismatchMode::Warn => {
+                warn!("{}", message);
+                Ok(Some(message))
+            }
+            ModelMismatchMode::Error => Err(RagError::ModelMismatch(message).into()),
+        }
+    }
+
+    /// Checks the model of the server against the recorded model of an index, as `check`,
+    /// recording it if the index has no marker yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store holding the index.
+    /// * `index_name` - The name of the index, which must exist.
+    /// * `embedding` - An embedding of the server, stored as the vector of the marker, as
+    ///   Pinecone rejects vectors of zeros.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `check`, or an error if the marker cannot be stored.
+    pub async fn check_or_record<S: EmbeddingStore + ?Sized>(
+        &self,
+        store: &mut S,
+        index_name: &str,
+        embedding: Vec<f32>,
+    ) -> Result<Option<String>> {
+        if self.recorded_model(&*store, index_name).await?.is_some() {
+            return self.check(&*store, index_name).await;
+        }
+        let model = IndexModel {
+            model: self.model.clone(),
+            dimension: embedding.len(),
+        };
+        info!(
+            "Recording embedding model {} of index {}",
+            model.model, index_name
+        );
+        let marker = StoredChunk {
+            id: MODEL_MARKER_ID.to_string(),
+            text: String::new(),
+            query_id: None,
+            chunk_index: None,
+            embedding,
+            metadata: model.to_metadata(),
+        };
+        store
+            .replace_chunks(index_name, vec![marker], &[], Some(MODEL_MARKER_NAMESPACE))
+            .await?;
+        self.recorded
+            .lock()
+            .unwrap()
+            .insert(index_name.to_string(), Some(model));
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::FakeStore;
+
+    #[tokio::test]
+    async fn test_mismatched_model_is_warned_or_rejected() {
+        let mut store = FakeStore::new(4);
+        let recorder = ModelGuard::new("bge-small", ModelMismatchMode::Warn);
+        let recorded = recorder
+            .check_or_record(&mut store, "test-index", vec![1.0, 0.0, 0.0, 0.0])
+            .await
+            .unwrap();
+        assert_eq!(recorded, None);
+        assert_eq!(
+            recorder.recorded_model(&store, "test-index").await.unwrap(),
+            Some(IndexModel {
+                model: "bge-small".to_string(),
+                dimension: 4,
+            })
+        );
+        // The marker is kept out of the namespace of the documents
+        assert_eq!(
+            store.vectors("test-index")[0].namespace,
+            MODEL_MARKER_NAMESPACE
+        );
+        assert_eq!(recorder.check(&store, "test-index").await.unwrap(), None);
+
+        let warner = ModelGuard::new("e5-large", ModelMismatchMode::Warn);
+        let warning = warner.check(&store, "test-index").await.unwrap().unwrap();
+        assert!(warning.contains("bge-small"), "{}", warning);
+        assert!(warning.contains("e5-large"), "{}", warning);
+        // Indexes without a marker are never reported
+        assert_eq!(warner.check(&store, "other-index").await.unwrap(), None);
+
+        let rejecter = ModelGuard::new("e5-large", ModelMismatchMode::Error);
+        let error = rejecter
+            .check_or_record(&mut store, "test-index", vec![1.0, 0.0, 0.0, 0.0])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RagError>(),
+            Some(RagError::ModelMismatch(_))
+        ));
+    }
+}
@@ -0,0 +1,144 @@
+//! Bounded pipeline shared by the long-running bulk operations, e.g. `/embed_bulk`.
+//!
+//! Items are fed through a channel of limited capacity to a bounded number of concurrent
+//! workers, so producers wait when workers lag behind, and at most `buffer` parsed items wait
+//! for a worker, however many flow through. The producers' own input, e.g. the body of an
+//! `/embed_bulk` request, is not bounded by the pipeline.
+
+use futures::{Future, StreamExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::info;
+
+/// Default maximum number of items processed concurrently.
+pub const DEFAULT_PIPELINE_CONCURRENCY: usize = 4;
+/// Default number of items buffered ahead of the workers.
+pub const DEFAULT_PIPELINE_BUFFER: usize = 64;
+/// Default number of processed items between two progress logs.
+pub const DEFAULT_PROGRESS_INTERVAL: usize = 100;
+
+/// Bounds of a pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PipelineOptions {
+    /// Maximum number of items processed concurrently, at least 1
+    pub concurrency: usize,
+    /// Number of items buffered ahead of the workers, beyond which producers wait, at least 1
+    pub buffer: usize,
+    /// Number of processed items between two progress logs, `0` disabling them
+    pub progress_interval: usize,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_PIPELINE_CONCURRENCY,
+            buffer: DEFAULT_PIPELINE_BUFFER,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+        }
+    }
+}
+
+impl PipelineOptions {
+    /// Returns the channel to feed the items of a pipeline through, holding at most `buffer`
+    /// items.
+    pub fn channel<I>(&self) -> (mpsc::Sender<I>, mpsc::Receiver<I>) {
+        mpsc::channel(self.buffer.max(1))
+    }
+
+    /// Processes the items received until their channel is closed, with at most `concurrency`
+    /// of them in flight, and returns the outputs in the order their items were received.
+    ///
+    /// Items complete in any order, their outputs are sorted back once all of them completed.
+    ///
+    /// The progress of the `operation` is logged every `progress_interval` processed items.
+    pub async fn run<I, O, F, Fut>(
+        &self,
+        operation: &str,
+        items: mpsc::Receiver<I>,
+        mut process: F,
+    ) -> Vec<O>
+    where
+        F: FnMut(I) -> Fut,
+        Fut: Future<Output = O>,
+    {
+        let mut outputs = Vec::new();
+        let mut processed = ReceiverStream::new(items)
+            .enumerate()
+            .map(|(i, item)| {
+                let output = process(item);
+                async move { (i, output.await) }
+            })
+            .buffer_unordered(self.concurrency.max(1));
+        while let Some(output) = processed.next().await {
+            outputs.push(output);
+            if self.progress_interval > 0 && outputs.len() % self.progress_interval == 0 {
+                info!("{}: processed {} items", operation, outputs.len());
+            }
+        }
+        outputs.sort_by_key(|(i, _)| *i);
+        outputs.into_iter().map(|(_, output)| output).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_in_flight_items_never_exceed_the_bounds() {
+        let options = PipelineOptions {
+            concurrency: 3,
+            buffer: 5,
+            progress_interval: 50,
+        };
+        let sent = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let max_pending = Arc::new(AtomicUsize::new(0));
+
+        let (sender, receiver) = options.channel();
+        let producer = tokio::spawn({
+            let (sent, done, max_pending) = (sent.clone(), done.clone(), max_pending.clone());
+            async move {
+                for i in 0..200 {
+                    sender.send(i).await.unwrap();
+                    let pending =
+                        sent.fetch_add(1, Ordering::SeqCst) + 1 - done.load(Ordering::SeqCst);
+                    max_pending.fetch_max(pending, Ordering::SeqCst);
+                }
+            }
+        });
+        let outputs = options
+            .run("test", receiver, |i: usize| {
+                let (done, in_flight, max_in_flight) =
+                    (done.clone(), in_flight.clone(), max_in_flight.clone());
+                async move {
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(current, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    done.fetch_add(1, Ordering::SeqCst);
+                    i * 2
+                }
+            })
+            .await;
+        producer.await.unwrap();
+
+        assert_eq!(outputs.len(), 200);
+        assert_eq!(
+            outputs.iter().sum::<usize>(),
+            (0..200).map(|i| i * 2).sum::<usize>()
+        );
+        // Outputs are returned in the order of their items, whatever their completion order
+        assert_eq!(outputs, (0..200).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+        // The producer waits once the buffer and the workers are full
+        assert!(max_pending.load(Ordering::SeqCst) <= 5 + 3 + 1);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    hash::Hash,
+};
+
+/// BM25 term frequency saturation parameter.
+pub const BM25_K1: f32 = 1.2;
+/// BM25 document length normalization parameter.
+pub const BM25_B: f32 = 0.75;
+
+/// Splits a text into lowercase alphanumeric terms.
+fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Computes the BM25 score of each document for the given query.
+///
+/// Inverse document frequencies are computed over the given documents only, so the
+/// scores are meant to rank a small candidate set, not to be compared across queries.
+///
+/// # Arguments
+///
+/// * `query` - The query text.
+/// * `documents` - The texts of the candidate documents.
+///
+/// # Returns
+///
+/// Returns the score of each document, in the order of `documents`. Documents sharing no
+/// term with the query score `0.0`.
+pub fn bm25_scores(query: &str, documents: &[&str]) -> Vec<f32> {
+    let documents: Vec<Vec<String>> = documents.iter().map(|document| terms(document)).collect();
+    if documents.is_empty() {
+        return vec![];
+    }
+    let average_length =
+        documents.iter().map(Vec::len).sum::<usize>() as f32 / documents.len() as f32;
+    // NOTE: Query terms are ordered, so that their scores are always summed in the same order
+    let query_terms: BTreeSet<String> = terms(query).into_iter().collect();
+    let document_frequencies: BTreeMap<&String, usize> = query_terms
+        .iter()
+        .map(|term| {
+            let frequency = documents
+                .iter()
+                .filter(|document| document.contains(term))
+                .count();
+            (term, frequency)
+        })
+        .collect();
+    let count = documents.len() as f32;
+    documents
+        .iter()
+        .map(|document| {
+            let length_norm = if average_length > 0.0 {
+                document.len() as f32 / average_length
+            } else {
+                0.0
+            };
+            query_terms
+                .iter()
+                .map(|term| {
+                    let frequency = document.iter().filter(|t| *t == term).count() as f32;
+                    if frequency == 0.0 {
+                        return 0.0;
+                    }
+                    let document_frequency = document_frequencies[term] as f32;
+                    let idf =
+                        ((count - document_frequency + 0.5) / (document_frequency + 0.5)).ln_1p();
+                    idf * frequency * (BM25_K1 + 1.0)
+                        / (frequency + BM25_K1 * (1.0 - BM25_B + BM25_B * length_norm))
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// Fuses several rankings with reciprocal rank fusion (RRF).
+///
+/// Each item scores `1 / (k + rank)` in every ranking it appears in, with 1-based ranks,
+/// and the scores are summed across rankings. A larger `k` flattens the advantage of the
+/// top ranks.
+///
+/// # Returns
+///
+/// Returns the items with their fused scores, sorted by descending score. Ties keep the
+/// order in which the items first appear in the rankings.
+pub fn reciprocal_rank_fusion<T: Clone + Eq + Hash>(rankings: &[Vec<T>], k: f32) -> Vec<(T, f32)> {
+    let mut fused: Vec<(T, f32)> = Vec::new();
+    let mut positions: HashMap<T, usize> = HashMap::new();
+    for ranking in rankings {
+        for (rank, item) in ranking.iter().enumerate() {
+            let score = 1.0 / (k + rank as f32 + 1.0);
+            match positions.get(item) {
+                Some(&position) => fused[position].1 += score,
+                None => {
+                    positions.insert(item.clone(), fused.len());
+                    fused.push((item.clone(), score));
+                }
+            }
+        }
+    }
+    // NOTE: The sort is stable, so ties keep their order of first appearance
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bm25_ranks_matching_documents_first() {
+        let scores = bm25_scores(
+            "rust tokenizer",
+            &[
+                "A tokenizer written in Rust.",
+                "Python is a language.",
+                "Rust is fast.",
+            ],
+        );
+        assert!(scores[0] > scores[2]);
+        assert!(scores[2] > scores[1]);
+        assert_eq!(scores[1], 0.0);
+        assert!(bm25_scores("rust", &[]).is_empty());
+    }
+
+    #[test]
+    fn test_rrf_interleaves_rankings() {
+        let dense = vec!["a", "b", "c", "d"];
+        let sparse = vec!["c", "a", "e"];
+        let fused = reciprocal_rank_fusion(&[dense, sparse], 60.0);
+        let order: Vec<&str> = fused.iter().map(|(item, _)| *item).collect();
+        // a: 1/61 + 1/62, c: 1/63 + 1/61, b: 1/62, e: 1/63, d: 1/64
+        assert_eq!(order, vec!["a", "c", "b", "e", "d"]);
+        assert!((fused[0].1 - (1.0 / 61.0 + 1.0 / 62.0)).abs() < 1e-6);
+
+        // With a small k, the top ranks of each ranking dominate
+        let fused = reciprocal_rank_fusion(&[vec!["a", "b"], vec!["b", "c"]], 0.0);
+        let order: Vec<&str> = fused.iter().map(|(item, _)| *item).collect();
+        assert_eq!(order, vec!["b", "a", "c"]);
+    }
+}
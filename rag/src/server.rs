@@ -1,24 +1,85 @@
 use crate::{
-    client::EmbeddingClient,
-    split_criteria::SplitCriteria,
-    types::{CreateIndexInput, MetricOptions, QueryInput, QueryResponse, TextToEmbed},
+    client::{
+        apply_recency, assemble_context, build_metadata, context_window_ids, dedupe_by_document,
+        merge_multi_vector_results, normalize_scores, prepend_document_context, query_filter,
+        set_norms, string_field, strip_embeddings, validate_namespace, validate_top_k,
+        EmbeddingKind, QueryOptions, StoredDocument, DEFAULT_TOP_K, MAX_RAW_DOCUMENT_BYTES,
+    },
+    compaction::{compact_index as compact, CompactionSummary},
+    error::status_code,
+    error::RagError,
+    idempotency::IdempotencyCache,
+    lang,
+    math::{cosine_similarity, mean_vector},
+    model_guard::ModelGuard,
+    pipeline::PipelineOptions,
+    request_id::propagate_request_id,
+    split_criteria::{Chunk, SentenceSegmenter, SplitCriteria},
+    store::EmbeddingStore,
+    tokens::TokenCounter,
+    types::{
+        BatchQueryResult, CompactIndexInput, CountTokensInput, CreateIndexInput,
+        DeleteByFilterInput, DocumentInput, EmbedJsonInput, EstimateInput, LongQueryStrategy,
+        NamespacesInput, QueryInput, QueryResponse, QueryResults, RescoreInput, RescoreResponse,
+        TextToEmbed, UpsertMode,
+    },
+    validation::{Validate, ValidatedJson},
 };
 use anyhow::{Error, Result};
 use axum::{
-    extract::{Json, State},
+    extract::{DefaultBodyLimit, Json, Query, State},
     http::StatusCode,
+    middleware,
+    response::sse::{Event, Sse},
     routing::{get, post},
     Router,
 };
-use pinecone_sdk::models::Metric;
+use chrono::Utc;
+use futures::future::join_all;
+use pinecone_sdk::models::{Metric, WaitPolicy};
 use serde_json::json;
+use std::convert::Infallible;
 use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::{error, info, info_span, instrument};
+use std::time::Duration;
+use tokio::sync::{mpsc, RwLock, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, error, info, info_span, instrument, warn};
 
 const DEFAULT_MAX_TOKENS: usize = 512;
 const DEFAULT_CONTEXT_SENTENCES: usize = 1;
+/// Maximum number of results fetched from the index to serve a page of results.
+const MAX_QUERY_WINDOW: u32 = 1000;
+/// Factor by which the fetched window of results is widened when deduplicating by document.
+const DEDUPE_OVERFETCH: u32 = 4;
+/// Maximum number of neighboring chunks returned on each side of a query result.
+const MAX_CONTEXT_WINDOW: usize = 10;
+/// Default number of seconds `/create_index` waits for the index to be ready, if asked to.
+const DEFAULT_WAIT_TIMEOUT_SECS: u64 = 300;
+/// Maximum number of queries of a `/batch_query` request.
+const MAX_BATCH_QUERIES: usize = 32;
+/// Number of events buffered by `/query_stream` ahead of the client.
+const QUERY_STREAM_BUFFER: usize = 16;
+/// Default maximum number of in-flight calls to the embedding service.
+pub const DEFAULT_EMBEDDING_CONCURRENCY: usize = 8;
+
+/// Target of the debug samples of the texts `/embed` embeds and stores, logged at `debug`
+/// level, see `DEBUG_SAMPLE_INPUTS`.
+pub const DEBUG_SAMPLE_TARGET: &str = "rag::debug_sample";
+/// Maximum number of characters of a debug sample.
+const DEBUG_SAMPLE_CHARS: usize = 200;
+
+/// Default maximum number of tokens of a query text, see `AppState::with_max_query_tokens`.
+pub const DEFAULT_MAX_QUERY_TOKENS: usize = 512;
+
+/// Default maximum size of a request body, in bytes.
+pub const DEFAULT_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+/// Default maximum number of chunks a single document can be split into.
+pub const DEFAULT_MAX_CHUNKS_PER_DOCUMENT: usize = 1000;
+
+/// Stream of Server-Sent Events produced by the streaming handlers.
+type EventStream = ReceiverStream<Result<Event, Infallible>>;
 
 /// Represents the shared state of the application.
 ///
@@ -26,35 +87,240 @@ const DEFAULT_CONTEXT_SENTENCES: usize = 1;
 /// across different request handlers in the server.
 #[derive(Clone)]
 pub struct AppState {
-    /// The embedding client wrapped in an Arc<Mutex> for thread-safe access.
+    /// The embedding store wrapped in an Arc<RwLock> for thread-safe access.
     ///
-    /// This allows multiple handlers to access and modify the embedding client
-    /// concurrently without causing data races.
-    embedding_client: Arc<Mutex<EmbeddingClient>>,
+    /// Handlers embedding and querying share a read lock, so that their calls to the embedding
+    /// service run concurrently, while storing and deleting take the write lock.
+    embedding_client: Arc<RwLock<dyn EmbeddingStore>>,
     /// Split criteria for text splitting
     split_criteria: SplitCriteria,
+    /// Optional sentence segmenter used by the split criteria, defaults to Unicode segmentation
+    segmenter: Option<Arc<dyn SentenceSegmenter>>,
+    /// Optional tokenizer, required for token based splitting
+    tokenizer: Option<Arc<dyn TokenCounter>>,
+    /// Optional name of the tokenizer model, e.g. its Hugging Face repository, reported by `/info`
+    tokenizer_model: Option<String>,
+    /// Limits protecting the server from oversized requests
+    limits: Limits,
+    /// Number of results returned by queries not setting `top_k`
+    default_top_k: u32,
+    /// Maximum number of tokens of a query text, beyond which a query setting a
+    /// `long_query_strategy` is split into chunks
+    max_query_tokens: usize,
+    /// Optional cache of recent `/embed` responses, short-circuiting retried requests
+    idempotency_cache: Option<Arc<IdempotencyCache>>,
+    /// Optional guard recording the embedding model of the indexes, and checking it against
+    /// the model of the server
+    model_guard: Option<Arc<ModelGuard>>,
+    /// Permits bounding the number of in-flight calls to the embedding service, across all requests
+    embedding_permits: Arc<Semaphore>,
+    /// Whether to detect the language of documents not setting `detected_lang`
+    detect_language: bool,
+    /// Whether to prepend the `topic` and `description` of documents to the embedded text of
+    /// their chunks
+    prepend_document_context: bool,
+    /// Whether to also store the raw content of each embedded document, see `/document`
+    store_raw_documents: bool,
+    /// Bounds of the pipeline of the bulk operations, e.g. `/embed_bulk`
+    bulk_pipeline: PipelineOptions,
+}
+
+/// Limits on the size of the requests the server accepts.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum size of a request body, in bytes. Larger requests are rejected with a 413.
+    pub max_body_bytes: usize,
+    /// Maximum number of chunks a document can be split into. Larger documents are rejected with a 400.
+    pub max_chunks_per_document: usize,
+    /// Optional maximum number of tokens embedded for a document, summed over its chunks,
+    /// unlimited if `None`. Requires a tokenizer.
+    pub max_total_tokens_per_request: Option<usize>,
+    /// How documents over `max_total_tokens_per_request` are handled
+    pub token_limit_mode: TokenLimitMode,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_chunks_per_document: DEFAULT_MAX_CHUNKS_PER_DOCUMENT,
+            max_total_tokens_per_request: None,
+            token_limit_mode: TokenLimitMode::default(),
+        }
+    }
+}
+
+/// How documents over the `max_total_tokens_per_request` limit are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TokenLimitMode {
+    /// Reject the document with a 400, the default
+    #[default]
+    Reject,
+    /// Only embed the first chunks of the document within the limit
+    Truncate,
+}
+
+impl FromStr for TokenLimitMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(TokenLimitMode::Reject),
+            "truncate" => Ok(TokenLimitMode::Truncate),
+            _ => Err(anyhow::anyhow!("expected `reject` or `truncate`")),
+        }
+    }
 }
 
 impl AppState {
-    /// Constructor
-    pub fn new(client: EmbeddingClient, split_criteria: Option<SplitCriteria>) -> Self {
+    /// Constructor, taking the `EmbeddingClient` of the server or any other `EmbeddingStore`
+    pub fn new(
+        client: impl EmbeddingStore + 'static,
+        split_criteria: Option<SplitCriteria>,
+        tokenizer: Option<Arc<dyn TokenCounter>>,
+    ) -> Self {
         AppState {
-            embedding_client: Arc::new(Mutex::new(client)),
+            embedding_client: Arc::new(RwLock::new(client)),
             split_criteria: split_criteria.unwrap_or(SplitCriteria::TokenCount {
                 max_tokens: DEFAULT_MAX_TOKENS,
                 context_sentences: DEFAULT_CONTEXT_SENTENCES,
             }),
+            segmenter: None,
+            tokenizer,
+            tokenizer_model: None,
+            limits: Limits::default(),
+            default_top_k: DEFAULT_TOP_K,
+            max_query_tokens: DEFAULT_MAX_QUERY_TOKENS,
+            idempotency_cache: None,
+            model_guard: None,
+            embedding_permits: Arc::new(Semaphore::new(DEFAULT_EMBEDDING_CONCURRENCY)),
+            detect_language: false,
+            prepend_document_context: false,
+            store_raw_documents: false,
+            bulk_pipeline: PipelineOptions::default(),
         }
     }
+
+    /// Sets the maximum number of in-flight calls to the embedding service.
+    ///
+    /// Many concurrent requests would otherwise flood the embedding service, and cause
+    /// cascading timeouts.
+    pub fn with_embedding_concurrency(mut self, permits: usize) -> Self {
+        self.embedding_permits = Arc::new(Semaphore::new(permits));
+        self
+    }
+
+    /// Sets the cache of recent `/embed` responses, `None` disabling idempotency.
+    pub fn with_idempotency_cache(mut self, idempotency_cache: Option<IdempotencyCache>) -> Self {
+        self.idempotency_cache = idempotency_cache.map(Arc::new);
+        self
+    }
+
+    /// Sets the guard against embedding documents and queries with another model than the one
+    /// an index was built with, `None` disabling it.
+    pub fn with_model_guard(mut self, model_guard: Option<ModelGuard>) -> Self {
+        self.model_guard = model_guard.map(Arc::new);
+        self
+    }
+
+    /// Sets the number of results returned by queries not setting `top_k`.
+    pub fn with_default_top_k(mut self, default_top_k: u32) -> Self {
+        self.default_top_k = default_top_k;
+        self
+    }
+
+    /// Sets the maximum number of tokens of a query text, beyond which queries setting a
+    /// `long_query_strategy` are split into chunks, e.g. the input limit of the embedding model.
+    /// Defaults to `DEFAULT_MAX_QUERY_TOKENS`.
+    pub fn with_max_query_tokens(mut self, max_query_tokens: usize) -> Self {
+        self.max_query_tokens = max_query_tokens;
+        self
+    }
+
+    /// Sets the name of the tokenizer model reported by `/info`.
+    pub fn with_tokenizer_model(mut self, tokenizer_model: Option<String>) -> Self {
+        self.tokenizer_model = tokenizer_model;
+        self
+    }
+
+    /// Sets the sentence segmenter used to split documents, e.g. one specific to their language.
+    pub fn with_segmenter(mut self, segmenter: Arc<dyn SentenceSegmenter>) -> Self {
+        self.segmenter = Some(segmenter);
+        self
+    }
+
+    /// Sets the limits on the size of the requests the server accepts.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets whether to detect the language of documents not setting `detected_lang`, see
+    /// `lang::detect_lang`. Disabled by default.
+    pub fn with_language_detection(mut self, detect_language: bool) -> Self {
+        self.detect_language = detect_language;
+        self
+    }
+
+    /// Sets whether to prepend the `topic` and `description` of documents to the text embedded
+    /// for each of their chunks, see `prepend_document_context`. Disabled by default.
+    ///
+    /// The chunks are still stored with their own text, and the topic and description as
+    /// metadata either way.
+    pub fn with_document_context(mut self, prepend_document_context: bool) -> Self {
+        self.prepend_document_context = prepend_document_context;
+        self
+    }
+
+    /// Sets whether to also store the full content of each embedded document once, keyed by
+    /// its `query_id`, to be fetched back with `/document`. Disabled by default.
+    ///
+    /// Documents larger than `MAX_RAW_DOCUMENT_BYTES` are then rejected.
+    pub fn with_raw_documents(mut self, store_raw_documents: bool) -> Self {
+        self.store_raw_documents = store_raw_documents;
+        self
+    }
+
+    /// Sets the bounds of the pipeline of the bulk operations, so that they do not overwhelm
+    /// the embedding service nor Pinecone.
+    pub fn with_bulk_pipeline(mut self, bulk_pipeline: PipelineOptions) -> Self {
+        self.bulk_pipeline = bulk_pipeline;
+        self
+    }
+}
+
+/// Builds the router serving all the routes of the server.
+pub fn router(app_state: AppState) -> Router {
+    let max_body_bytes = app_state.limits.max_body_bytes;
+    Router::new()
+        .route("/batch_query", post(batch_query))
+        .route("/compact_index", post(compact_index))
+        .route("/count_tokens", get(count_tokens))
+        .route("/create_index", post(create_index))
+        .route("/debug/rescore", post(debug_rescore))
+        .route("/delete_by_filter", post(delete_by_filter))
+        .route("/document", get(fetch_document))
+        .route("/embed", post(embed))
+        .route("/embed_bulk", post(embed_bulk))
+        .route("/embed_json", post(embed_json))
+        .route("/estimate", post(estimate))
+        .route("/info", get(info))
+        .route("/namespaces", get(namespaces))
+        .route("/query", get(query))
+        .route("/query_stream", get(query_stream))
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .layer(middleware::from_fn(propagate_request_id))
+        .with_state(app_state)
 }
 
-/// Starts the server with the given configuration and embedding client.
+/// Starts the server with the given application state.
 ///
 /// # Arguments
 ///
 /// * `host` - A string slice that holds the host address to bind the server to.
 /// * `port` - The port number to bind the server to.
-/// * `client` - An instance of `EmbeddingClient` to be used for embedding operations.
+/// * `app_state` - The state shared by the handlers, built with `AppState::new` from the
+///   embedding client, split criteria and tokenizer, and configured with its `with_*` methods.
 ///
 /// # Returns
 ///
@@ -67,21 +333,11 @@ impl AppState {
 /// - The server fails to bind to the specified address and port.
 /// - There's an error while serving the application.
 #[instrument(skip_all)]
-pub async fn start(
-    host: &str,
-    port: u16,
-    client: EmbeddingClient,
-    split_criteria: Option<SplitCriteria>,
-) -> Result<()> {
+pub async fn start(host: &str, port: u16, app_state: AppState) -> Result<()> {
     let span = info_span!("start-server");
     let _enter = span.enter();
     info!("Starting server on {}:{}", host, port);
-    let app_state = AppState::new(client, split_criteria);
-    let router = Router::new()
-        .route("/create_index", post(create_index))
-        .route("/embed", post(embed))
-        .route("/query", get(query))
-        .with_state(app_state);
+    let router = router(app_state);
 
     let ip: IpAddr = match host.parse() {
         Ok(ip) => ip,
@@ -121,78 +377,597 @@ pub async fn start(
 /// Returns `Ok(Json(()))` if the embedding is successfully created and stored,
 /// or an error with an appropriate status code and message if any step fails.
 ///
+/// If `dry_run` is set on the input, the text is only split and the resulting chunks
+/// are returned along with their token counts (when a tokenizer is loaded), without
+/// calling the embedding server nor storing anything.
+///
+/// If `verbose` is set on the input, the response also holds the token count of each stored
+/// chunk as `chunk_tokens`, counted with the loaded tokenizer, or `null` if none is loaded.
+///
+/// If the idempotency cache is enabled, a request identical to one successfully processed
+/// within its TTL is answered with the cached response, without embedding anything again.
+///
+/// If language detection is enabled, the language of documents not setting `detected_lang`
+/// is detected from their content, and stored in the `lang` metadata of their chunks.
+///
+/// If raw documents are stored, the full content of the document is also stored once, after
+/// its chunks, along with the embedding of its first chunk (see `fetch_document`).
+///
 /// # Errors
 ///
+/// Payloads failing the `Validate` constraints of `TextToEmbed` are rejected before reaching
+/// the handler, with a `400` response whose `{ "error": ..., "field": ... }` body names the
+/// field at fault.
+///
 /// This function will return an error if:
+/// - The content is empty or only whitespace, or splits into empty chunks only (`400`).
+/// - Raw documents are stored and the content is larger than `MAX_RAW_DOCUMENT_BYTES` (`400`).
+/// - The extra metadata fields cannot be stored in Pinecone (`400`).
 /// - There's an issue creating the embedding.
 /// - There's a problem serializing the input data.
 /// - Storing the embedding in the index fails.
 #[instrument(skip_all)]
 pub async fn embed(
     State(app_state): State<AppState>,
-    Json(input): Json<TextToEmbed>,
+    ValidatedJson(mut input): ValidatedJson<TextToEmbed>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let span = info_span!("embed");
     let _enter = span.enter();
     info!("Embedding text, for query with id: {}", input.query_id);
-    let mut embedding_client = app_state.embedding_client.lock().await;
-    let pinecone_host = embedding_client.pinecone_host.clone();
-    let chunks = match app_state.split_criteria.split(&input.content, None) {
+    if input.content.trim().is_empty() {
+        error!("Empty content, for query with id: {}", input.query_id);
+        return Err((StatusCode::BAD_REQUEST, "content is empty".to_string()));
+    }
+    if app_state.store_raw_documents && input.content.len() > MAX_RAW_DOCUMENT_BYTES {
+        error!(
+            "Content too large to store raw, for query with id: {}",
+            input.query_id
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "content is {} bytes, more than the maximum of {} bytes of a raw document",
+                input.content.len(),
+                MAX_RAW_DOCUMENT_BYTES
+            ),
+        ));
+    }
+    let chunks = match app_state.split_criteria.split_chunks(
+        &input.content,
+        Some(&input.query_id),
+        app_state.tokenizer.as_deref(),
+        app_state.segmenter.as_deref(),
+    ) {
         Ok(chunks) => chunks,
         Err(e) => {
             error!("Error splitting text: {}", e);
             return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
         }
     };
-    let original_text = serde_json::to_string(&input)
+    if chunks.is_empty() {
+        error!("No non-empty chunks, for query with id: {}", input.query_id);
+        return Err((StatusCode::BAD_REQUEST, "content is empty".to_string()));
+    }
+    if chunks.len() > app_state.limits.max_chunks_per_document {
+        error!(
+            "Too many chunks ({}), for query with id: {}",
+            chunks.len(),
+            input.query_id
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "content is split into {} chunks, more than the maximum of {}",
+                chunks.len(),
+                app_state.limits.max_chunks_per_document
+            ),
+        ));
+    }
+    let (chunks, dropped_chunks) = enforce_token_limit(&app_state, chunks)?;
+    // NOTE: Detection is fail-soft, documents whose language cannot be told are stored without one
+    if app_state.detect_language && input.detected_lang.is_none() {
+        input.detected_lang = lang::detect_lang(&input.content);
+    }
+    if let Err(e) = build_metadata(String::new(), Some(&input), None) {
+        error!("Invalid extra metadata: {}", e);
+        return Err((StatusCode::BAD_REQUEST, e.to_string()));
+    }
+    if input.dry_run.unwrap_or(false) {
+        info!("Dry run, for query with id: {}", input.query_id);
+        let mut previews = Vec::with_capacity(chunks.len());
+        for chunk in chunks.iter() {
+            let tokens = match app_state.tokenizer.as_deref() {
+                Some(tokenizer) => match tokenizer.count(&chunk.text) {
+                    Ok(count) => Some(count),
+                    Err(e) => {
+                        error!("Error encoding chunk: {}", e);
+                        return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+                    }
+                },
+                None => None,
+            };
+            previews.push(json!({ "text": chunk.text, "tokens": tokens }));
+        }
+        let mut response = json!({
+            "query_id": input.query_id,
+            "status": "dry_run",
+            "chunks": previews,
+        });
+        if dropped_chunks > 0 {
+            response["dropped_chunks"] = dropped_chunks.into();
+        }
+        return Ok(Json(response));
+    }
+    // NOTE: Tokens are counted before embedding, so that no chunk is stored without its count
+    let chunk_tokens = match input.verbose.unwrap_or(false) {
+        true => Some(count_chunk_tokens(&app_state, &chunks)?),
+        false => None,
+    };
+    // The serialized input only identifies the request in the idempotency cache, each chunk
+    // being stored with its own text
+    let serialized_input = serde_json::to_string(&input)
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    for chunk in chunks.iter() {
-        let embedding = match embedding_client.create_embedding(chunk).await {
-            Ok(embedding) => embedding,
-            Err(e) => {
-                error!("Error creating embedding: {}", e);
-                return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
-            }
-        };
-        match embedding_client
-            .store_embedding(&pinecone_host, original_text.clone(), embedding)
+    if let Some(response) = cached_response(&app_state, &input.query_id, &serialized_input) {
+        return Ok(Json(response));
+    }
+    let mut embeddings = Vec::with_capacity(chunks.len());
+    {
+        // NOTE: Chunks are embedded under a read lock of the store, so that concurrent requests
+        // embed concurrently, only bounded by the embedding permits
+        let embedding_client = app_state.embedding_client.read().await;
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let embedded_text = match app_state.prepend_document_context {
+                true => prepend_document_context(
+                    &chunk.text,
+                    input.topic.as_deref(),
+                    input.description.as_deref(),
+                ),
+                false => chunk.text.clone(),
+            };
+            debug!(
+                target: DEBUG_SAMPLE_TARGET,
+                "Embedded text of chunk {}, for query with id {}: {:?}",
+                i,
+                input.query_id,
+                debug_sample(&embedded_text)
+            );
+            let embedding = match create_embedding_with_permit(
+                &app_state.embedding_permits,
+                &*embedding_client,
+                &embedded_text,
+                input.image_url.as_deref(),
+                EmbeddingKind::Document,
+            )
+            .await
+            {
+                Ok(embedding) => embedding,
+                Err(e) => {
+                    error!("Error creating embedding: {}", e);
+                    return Err((status_code(&e), e.to_string()));
+                }
+            };
+            debug!(
+                target: DEBUG_SAMPLE_TARGET,
+                "Stored text of chunk {}, for query with id {}: {:?}",
+                i,
+                input.query_id,
+                debug_sample(&chunk.text)
+            );
+            embeddings.push((chunk, embedding));
+        }
+    }
+    let mut embedding_client = app_state.embedding_client.write().await;
+    // NOTE: The cache is checked again while holding the write lock, so a retry sent while the
+    // original request is still being stored waits for it, and then hits the cache
+    if let Some(response) = cached_response(&app_state, &input.query_id, &serialized_input) {
+        return Ok(Json(response));
+    }
+    // NOTE: There is at least one chunk, as empty documents were rejected
+    let first_embedding = embeddings[0].1.concat();
+    if input.create_if_missing.unwrap_or(false) {
+        let dimension = input.dimension.unwrap_or(first_embedding.len() as i32);
+        if let Err(e) = embedding_client
+            .ensure_index(&input.index_name, dimension, input.metric.map(Metric::from))
             .await
         {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Error storing embedding: {}", e);
-                return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
-            }
+            error!("Error ensuring index exists: {}", e);
+            return Err((status_code(&e), e.to_string()));
+        }
+    }
+    // NOTE: The model is checked, or recorded, once the index exists
+    if let Some(model_guard) = app_state.model_guard.as_deref() {
+        if let Err(e) = model_guard
+            .check_or_record(
+                &mut *embedding_client,
+                &input.index_name,
+                first_embedding.clone(),
+            )
+            .await
+        {
+            error!("Error checking the embedding model of the index: {}", e);
+            return Err((status_code(&e), e.to_string()));
+        }
+    }
+    // NOTE: Old chunks are deleted after `ensure_index`, so that the index exists
+    if input.upsert_mode.unwrap_or_default() == UpsertMode::Replace {
+        if let Err(e) = embedding_client
+            .delete(
+                &input.index_name,
+                &input.query_id,
+                input.namespace.as_deref(),
+            )
+            .await
+        {
+            error!("Error deleting previous chunks: {}", e);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+        }
+    }
+    // NOTE: Pinecone rejects vectors of zeros, so the raw document is stored with the
+    // embedding of its first chunk, in a namespace of its own
+    let document_values = app_state.store_raw_documents.then_some(first_embedding);
+    // NOTE: The embeddings are stored once all the chunks are embedded, in batches of at most
+    // `upsert_batch_size` vectors, rather than with one upsert request per chunk
+    if let Err(e) = embedding_client
+        .store(
+            &input.index_name,
+            embeddings,
+            Some(&input),
+            Some(&app_state.split_criteria),
+        )
+        .await
+    {
+        error!("Error storing embeddings: {}", e);
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    }
+    if let Some(values) = document_values {
+        if let Err(e) = embedding_client
+            .store_document(&input.index_name, &input, values)
+            .await
+        {
+            error!("Error storing raw document: {}", e);
+            return Err((status_code(&e), e.to_string()));
         }
     }
 
-    Ok(Json(json!({
+    let mut response = json!({
         "query_id": input.query_id,
         "status": "success",
-    })))
+    });
+    if dropped_chunks > 0 {
+        response["dropped_chunks"] = dropped_chunks.into();
+    }
+    if let Some(chunk_tokens) = chunk_tokens {
+        response["chunk_tokens"] = chunk_tokens.into();
+    }
+    if let Some(cache) = &app_state.idempotency_cache {
+        cache.insert(&input.query_id, &serialized_input, response.clone());
+    }
+    Ok(Json(response))
+}
+
+/// Returns the cached response of an `embed` request identical to one already processed, if
+/// the idempotency cache is enabled.
+fn cached_response(
+    app_state: &AppState,
+    query_id: &str,
+    serialized_input: &str,
+) -> Option<serde_json::Value> {
+    let response = app_state
+        .idempotency_cache
+        .as_ref()?
+        .get(query_id, serialized_input)?;
+    info!("Duplicate request, for query with id: {}", query_id);
+    Some(response)
+}
+
+/// Truncates a text to its first `DEBUG_SAMPLE_CHARS` characters, for debug logs.
+fn debug_sample(text: &str) -> &str {
+    match text.char_indices().nth(DEBUG_SAMPLE_CHARS) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// Counts the tokens of each chunk with the loaded tokenizer, for verbose `embed` responses,
+/// returning `None` if no tokenizer is loaded, as dry runs do.
+///
+/// # Errors
+///
+/// Returns a `500` if counting the tokens of a chunk fails.
+fn count_chunk_tokens(
+    app_state: &AppState,
+    chunks: &[Chunk],
+) -> Result<Option<Vec<usize>>, (StatusCode, String)> {
+    let Some(tokenizer) = app_state.tokenizer.as_deref() else {
+        warn!("No tokenizer loaded to count the tokens of the chunks");
+        return Ok(None);
+    };
+    chunks
+        .iter()
+        .map(|chunk| {
+            tokenizer.count(&chunk.text).map_err(|e| {
+                error!("Error counting tokens: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Enforces the `max_total_tokens_per_request` limit on the chunks of a document, returning
+/// the chunks to embed along with the number of chunks dropped by truncation.
+///
+/// # Errors
+///
+/// Returns a `400` stating the limit and the total number of tokens of the document if it is
+/// over the limit and may not be truncated, or if even its first chunk is over the limit, and a
+/// `500` if no tokenizer is loaded to count the tokens.
+fn enforce_token_limit(
+    app_state: &AppState,
+    mut chunks: Vec<Chunk>,
+) -> Result<(Vec<Chunk>, usize), (StatusCode, String)> {
+    let Some(max_total_tokens) = app_state.limits.max_total_tokens_per_request else {
+        return Ok((chunks, 0));
+    };
+    let Some(tokenizer) = app_state.tokenizer.as_deref() else {
+        error!("No tokenizer loaded to enforce the token limit");
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "No tokenizer loaded".to_string(),
+        ));
+    };
+    let mut total_tokens = 0;
+    // Number of leading chunks within the limit
+    let mut within_limit = 0;
+    for chunk in chunks.iter() {
+        total_tokens += tokenizer.count(&chunk.text).map_err(|e| {
+            error!("Error counting tokens: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+        if total_tokens <= max_total_tokens {
+            within_limit += 1;
+        }
+    }
+    if total_tokens <= max_total_tokens {
+        return Ok((chunks, 0));
+    }
+    let rejection = format!(
+        "content has {} tokens, more than the maximum of {} per request",
+        total_tokens, max_total_tokens
+    );
+    if app_state.limits.token_limit_mode == TokenLimitMode::Reject || within_limit == 0 {
+        error!("{}", rejection);
+        return Err((StatusCode::BAD_REQUEST, rejection));
+    }
+    let dropped = chunks.len() - within_limit;
+    warn!("{}, dropping its last {} chunks", rejection, dropped);
+    chunks.truncate(within_limit);
+    for chunk in chunks.iter_mut() {
+        chunk.total = within_limit;
+    }
+    Ok((chunks, dropped))
+}
+
+/// Creates an embedding, along with the image if any, once a permit to call the embedding
+/// service is acquired.
+///
+/// # Errors
+///
+/// Returns an error if the semaphore is closed, or if creating the embedding fails.
+pub async fn create_embedding_with_permit(
+    permits: &Semaphore,
+    embedding_client: &dyn EmbeddingStore,
+    text: &str,
+    image_url: Option<&str>,
+    kind: EmbeddingKind,
+) -> Result<Vec<Vec<f32>>> {
+    let _permit = permits.acquire().await?;
+    match image_url {
+        Some(image_url) => {
+            embedding_client
+                .embed_with_image(text, image_url, kind)
+                .await
+        }
+        None => embedding_client.embed(text, kind).await,
+    }
+}
+
+/// Handles the bulk embedding of newline-delimited JSON (`application/x-ndjson`) documents.
+///
+/// Each non-empty line of the body is parsed as a `TextToEmbed`, and embedded as by `embed`,
+/// through the bulk pipeline of the state: lines are parsed as the documents before them are
+/// embedded, with at most `concurrency` documents in flight and `buffer` lines ahead of them.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the embedding client.
+/// * `body` - The request body, holding one JSON `TextToEmbed` per line.
+///
+/// # Returns
+///
+/// Returns a JSON summary `{ "processed": n, "failed": [...] }`, where each failure holds
+/// the 1-based `line` number, the `query_id` if the line could be parsed, and the `error`.
+///
+/// # Notes
+///
+/// Malformed lines and documents that fail to be embedded are reported in `failed`, and
+/// do not abort the rest of the body.
+///
+/// The body is read in full before its lines are fed to the pipeline, so only the parsed
+/// documents, not the body, are bounded by the pipeline.
+#[instrument(skip_all)]
+pub async fn embed_bulk(
+    State(app_state): State<AppState>,
+    body: String,
+) -> Json<serde_json::Value> {
+    let span = info_span!("embed_bulk");
+    let _enter = span.enter();
+    let pipeline = app_state.bulk_pipeline;
+    let (sender, receiver) = pipeline.channel();
+    tokio::spawn(async move {
+        for (i, line) in body.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            // The receiver is only dropped early if the request is cancelled
+            if sender.send((i + 1, line.to_string())).await.is_err() {
+                break;
+            }
+        }
+    });
+    let results = pipeline
+        .run("embed_bulk", receiver, |(line_number, line)| {
+            embed_line(app_state.clone(), line_number, line)
+        })
+        .await;
+    let processed = results.iter().filter(|result| result.is_ok()).count();
+    let mut failed: Vec<serde_json::Value> = results
+        .into_iter()
+        .filter_map(|result| result.err())
+        .collect();
+    failed.sort_by_key(|failure| failure["line"].as_u64());
+    info!(
+        "Bulk embedded {} documents, {} failed",
+        processed,
+        failed.len()
+    );
+    Json(json!({ "processed": processed, "failed": failed }))
+}
+
+/// Embeds a single line of an `/embed_bulk` request, returning the failure to report if any.
+async fn embed_line(
+    app_state: AppState,
+    line_number: usize,
+    line: String,
+) -> Result<(), serde_json::Value> {
+    let input = match serde_json::from_str::<TextToEmbed>(&line) {
+        Ok(input) => input,
+        Err(e) => {
+            error!("Malformed line {}: {}", line_number, e);
+            return Err(json!({ "line": line_number, "query_id": null, "error": e.to_string() }));
+        }
+    };
+    let query_id = input.query_id.clone();
+    embed_document(app_state, input).await.map_err(|e| {
+        error!("Error embedding line {}: {}", line_number, e);
+        json!({ "line": line_number, "query_id": query_id, "error": e })
+    })
+}
+
+/// Handles the embedding of the records of an arbitrary JSON array.
+///
+/// Each record is mapped into a `TextToEmbed`, its `content_field` being embedded and its
+/// `metadata_fields` stored alongside its chunks, and embedded as by `embed`, through the
+/// bulk pipeline of the state.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the embedding client.
+/// * `input` - The records, along with the selectors of their fields.
+///
+/// # Returns
+///
+/// Returns a JSON summary `{ "processed": n, "failed": [...] }`, where each failure holds
+/// the 0-based `index` of the record in `records`, its `query_id` if it could be mapped,
+/// and the `error`.
+///
+/// # Notes
+///
+/// Records missing their content field, and records that fail to be embedded, are
+/// reported in `failed`, and do not abort the rest of the records.
+#[instrument(skip_all)]
+pub async fn embed_json(
+    State(app_state): State<AppState>,
+    Json(input): Json<EmbedJsonInput>,
+) -> Json<serde_json::Value> {
+    let span = info_span!("embed_json");
+    let _enter = span.enter();
+    let pipeline = app_state.bulk_pipeline;
+    let (sender, receiver) = pipeline.channel();
+    tokio::spawn(async move {
+        for index in 0..input.records.len() {
+            // The receiver is only dropped early if the request is cancelled
+            if sender
+                .send((index, input.to_text_to_embed(index)))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    let results = pipeline
+        .run("embed_json", receiver, |(index, record)| {
+            let app_state = app_state.clone();
+            async move {
+                let input = record.map_err(|e| {
+                    error!("Invalid record {}: {}", index, e);
+                    json!({ "index": index, "query_id": null, "error": e.to_string() })
+                })?;
+                let query_id = input.query_id.clone();
+                embed_document(app_state, input).await.map_err(|e| {
+                    error!("Error embedding record {}: {}", index, e);
+                    json!({ "index": index, "query_id": query_id, "error": e })
+                })
+            }
+        })
+        .await;
+    let processed = results.iter().filter(|result| result.is_ok()).count();
+    let mut failed: Vec<serde_json::Value> = results
+        .into_iter()
+        .filter_map(|result| result.err())
+        .collect();
+    failed.sort_by_key(|failure| failure["index"].as_u64());
+    info!(
+        "Embedded {} JSON records, {} failed",
+        processed,
+        failed.len()
+    );
+    Json(json!({ "processed": processed, "failed": failed }))
+}
+
+/// Validates and embeds a single document of a bulk request, as by `embed`, returning the
+/// error to report if any.
+async fn embed_document(app_state: AppState, input: TextToEmbed) -> Result<(), String> {
+    input.validate().map_err(|e| e.to_string())?;
+    embed(State(app_state), ValidatedJson(input))
+        .await
+        .map(|_| ())
+        .map_err(|(_, e)| e)
 }
 
 /// Handles querying the vector database for similar embeddings.
 ///
 /// This function takes a query input, performs a similarity search in the specified index,
-/// and returns the top-k most similar results.
+/// and returns the requested page of the most similar results.
 ///
 /// # Arguments
 ///
 /// * `app_state` - The shared application state containing the embedding client.
-/// * `input` - The query input containing the index name, query text, and number of results to return.
+/// * `input` - The query input containing the index name, query text, number of results
+///   to return and the offset of the page.
 ///
 /// # Returns
 ///
-/// Returns `Ok(Json(Vec<QueryResponse>))` if the query is successful, where `QueryResponse`
-/// contains the matched documents and their similarity scores.
+/// Returns `Ok(Json(QueryResults))` if the query is successful, where `QueryResults`
+/// contains the matched documents and their similarity scores, along with whether more
+/// results are available.
 ///
 /// # Errors
 ///
 /// Returns a `(StatusCode, String)` error tuple if:
 /// - There's an issue accessing the embedding client.
+/// - The page does not fit in the fetched window, i.e. `offset + top_k + 1` is over
+///   `MAX_QUERY_WINDOW` (`400`).
 /// - The query operation fails in the vector database.
 ///
+/// # Notes
+///
+/// Pinecone does not support offset based paging, so `offset + top_k + 1` results are
+/// fetched and the page is sliced out of them. Paging is therefore best-effort, and the
+/// fetched window is capped at `MAX_QUERY_WINDOW` results. With `dedupe_by_document`, the
+/// window is widened by `DEDUPE_OVERFETCH` to make up for the chunks dropped, up to the cap.
+///
 /// # Example
 ///
 /// ```
@@ -207,89 +982,2347 @@ pub async fn embed(
 pub async fn query(
     State(app_state): State<AppState>,
     Json(input): Json<QueryInput>,
-) -> Result<Json<Vec<QueryResponse>>, (StatusCode, String)> {
+) -> Result<Json<QueryResults>, (StatusCode, String)> {
     let span = info_span!("query");
     let _enter = span.enter();
-    info!("Querying index: {}", input.index_name);
-    let QueryInput {
-        index_name,
-        query_text,
-        top_k,
-        score_threshold,
-    } = input;
-    let embedding_client = app_state.embedding_client.lock().await;
-    let mut query_response = match embedding_client
-        .query(&query_text, &index_name, top_k)
-        .await
-    {
-        Ok(query_response) => query_response,
-        Err(e) => {
-            error!("Error querying: {}", e);
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
-        }
-    };
-    if let Some(score_threshold) = score_threshold {
-        query_response.retain(|result| result.score >= score_threshold);
-    }
-    if let Some(top_k) = top_k {
-        query_response.truncate(top_k as usize);
-    }
-    Ok(Json(query_response))
+    run_query(&app_state, input).await.map(Json)
 }
 
-/// Handles the creation of a new index in the vector database.
+/// Handles querying the vector database, streaming the results as Server-Sent Events.
 ///
-/// This function takes the index creation input, processes it, and creates a new index
-/// in the underlying vector database using the embedding client.
+/// This function runs the same query as `query`, then emits each `QueryResponse` as a
+/// separate JSON `data:` event, in order, followed by a terminal `done` event.
 ///
 /// # Arguments
 ///
 /// * `app_state` - The shared application state containing the embedding client.
-/// * `input` - The input data for creating the index, including name, dimension, and metric.
+/// * `input` - The query input, as for `query`.
 ///
 /// # Returns
 ///
-/// Returns `Ok(())` if the index is successfully created, or an error with an appropriate
-/// status code and message if the creation fails.
+/// Returns an SSE stream of the query results.
 ///
 /// # Errors
 ///
-/// This function will return an error if:
-/// - There's an issue accessing the embedding client.
-/// - The index creation operation fails in the vector database.
+/// Returns a `(StatusCode, String)` error tuple, before any event is sent, if the query fails.
 ///
-/// # Example
+/// # Notes
 ///
-/// ```
-/// let create_index_input = CreateIndexInput {
-///     index_name: "my_new_index".to_string(),
-///     dimension: 768,
-///     metric: Some(MetricOptions::Cosine),
-/// };
-/// let result = create_index(State(app_state), Json(create_index_input)).await;
-/// ```
+/// Events are produced on a separate task, which stops as soon as the client disconnects.
 #[instrument(skip_all)]
-pub async fn create_index(
+pub async fn query_stream(
     State(app_state): State<AppState>,
-    Json(input): Json<CreateIndexInput>,
-) -> Result<(), (StatusCode, String)> {
-    let span = info_span!("create_index");
+    Json(input): Json<QueryInput>,
+) -> Result<Sse<EventStream>, (StatusCode, String)> {
+    let span = info_span!("query_stream");
     let _enter = span.enter();
-    info!("Creating index: {}", input.index_name);
-    let CreateIndexInput {
-        index_name,
-        dimension,
-        metric,
-    } = input;
-    let metric = metric.map(|m| match m {
-        MetricOptions::Cosine => Metric::Cosine,
-        MetricOptions::Euclidean => Metric::Euclidean,
-        MetricOptions::Dotproduct => Metric::Dotproduct,
+    let query_results = run_query(&app_state, input).await?;
+    Ok(Sse::new(stream_results(query_results.results)))
+}
+
+/// Streams query results as SSE events, followed by a terminal `done` event.
+fn stream_results(results: Vec<QueryResponse>) -> EventStream {
+    let (tx, rx) = mpsc::channel(QUERY_STREAM_BUFFER);
+    tokio::spawn(async move {
+        for result in results {
+            let event = match Event::default().json_data(&result) {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("Error serializing query result: {}", e);
+                    continue;
+                }
+            };
+            if tx.send(Ok(event)).await.is_err() {
+                info!("Client disconnected, stopping query stream");
+                return;
+            }
+        }
+        let done = Event::default().event("done").data("[DONE]");
+        if tx.send(Ok(done)).await.is_err() {
+            info!("Client disconnected, stopping query stream");
+        }
     });
-    let mut embedding_client = app_state.embedding_client.lock().await;
-    embedding_client
-        .create_index(&index_name, dimension, metric)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok(())
+    ReceiverStream::new(rx)
+}
+
+/// Handles running several independent queries at once.
+///
+/// The query texts are embedded in a single call to the embedding service, then the queries
+/// run concurrently, each as by `query`.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the embedding client.
+/// * `inputs` - The queries to run, at most `MAX_BATCH_QUERIES`.
+///
+/// # Returns
+///
+/// Returns `Ok(Json(Vec<BatchQueryResult>))`, holding for each query, in the order of the
+/// inputs, either the page of its results or its `error` and HTTP `status`.
+///
+/// # Errors
+///
+/// Returns a `(StatusCode, String)` error tuple if there are more than `MAX_BATCH_QUERIES`
+/// queries. Failed queries do not fail the batch.
+///
+/// # Notes
+///
+/// If the batch of query texts fails to embed, e.g. because of a single oversized text, each
+/// query embeds its own text instead, so that only the queries at fault fail.
+#[instrument(skip_all)]
+pub async fn batch_query(
+    State(app_state): State<AppState>,
+    Json(inputs): Json<Vec<QueryInput>>,
+) -> Result<Json<Vec<BatchQueryResult>>, (StatusCode, String)> {
+    let span = info_span!("batch_query");
+    let _enter = span.enter();
+    if inputs.len() > MAX_BATCH_QUERIES {
+        error!("Too many queries: {}", inputs.len());
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "a batch holds at most {} queries, got {}",
+                MAX_BATCH_QUERIES,
+                inputs.len()
+            ),
+        ));
+    }
+    let embedding_client = app_state.embedding_client.read().await;
+    let texts: Vec<String> = inputs
+        .iter()
+        .map(|input| input.query_text.clone())
+        .collect();
+    let query_vectors = if texts.is_empty() {
+        vec![]
+    } else {
+        let permit = app_state
+            .embedding_permits
+            .acquire()
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let embeddings = embedding_client
+            .embed_batch(&texts, EmbeddingKind::Query)
+            .await;
+        drop(permit);
+        match embeddings {
+            Ok(embeddings) => embeddings.into_iter().map(Some).collect(),
+            Err(e) => {
+                warn!(
+                    "Error embedding the batch of queries, embedding them one by one: {}",
+                    e
+                );
+                vec![None; texts.len()]
+            }
+        }
+    };
+    let results = join_all(
+        inputs
+            .into_iter()
+            .zip(query_vectors)
+            .map(|(input, query_vector)| {
+                query_store(&app_state, &*embedding_client, input, query_vector)
+            }),
+    )
+    .await;
+    let results = results
+        .into_iter()
+        .map(|result| match result {
+            Ok(query_results) => BatchQueryResult::Results(query_results.results),
+            Err((status, error)) => BatchQueryResult::Error {
+                error,
+                status: status.as_u16(),
+            },
+        })
+        .collect();
+    Ok(Json(results))
+}
+
+/// Runs a query, shared by the `query` and `query_stream` handlers.
+async fn run_query(
+    app_state: &AppState,
+    input: QueryInput,
+) -> Result<QueryResults, (StatusCode, String)> {
+    let embedding_client = app_state.embedding_client.read().await;
+    query_store(app_state, &*embedding_client, input, None).await
+}
+
+/// Splits a query text over `max_query_tokens` into chunks of at most `max_query_tokens`
+/// tokens, and embeds them in a single call, returning `None` for shorter texts.
+///
+/// # Errors
+///
+/// Returns a `500` if no tokenizer is loaded to count the tokens, or if splitting or embedding
+/// the text fails.
+async fn embed_long_query(
+    app_state: &AppState,
+    embedding_client: &dyn EmbeddingStore,
+    query_text: &str,
+) -> Result<Option<Vec<Vec<f32>>>, (StatusCode, String)> {
+    let internal_error = |e: Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let Some(tokenizer) = app_state.tokenizer.as_deref() else {
+        error!("No tokenizer loaded to tell long queries");
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "No tokenizer loaded".to_string(),
+        ));
+    };
+    let tokens = tokenizer.count(query_text).map_err(internal_error)?;
+    if tokens <= app_state.max_query_tokens {
+        return Ok(None);
+    }
+    let criteria = SplitCriteria::TokenCount {
+        max_tokens: app_state.max_query_tokens,
+        context_sentences: 0,
+    };
+    let chunks: Vec<String> = criteria
+        .split_iter_with_segmenter(query_text, Some(tokenizer), app_state.segmenter.as_deref())
+        .filter(|chunk| {
+            chunk
+                .as_ref()
+                .map_or(true, |chunk| !chunk.trim().is_empty())
+        })
+        .collect::<Result<_>>()
+        .map_err(internal_error)?;
+    info!(
+        "Query of {} tokens split into {} chunks",
+        tokens,
+        chunks.len()
+    );
+    let _permit = app_state
+        .embedding_permits
+        .acquire()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match embedding_client
+        .embed_batch(&chunks, EmbeddingKind::Query)
+        .await
+    {
+        Ok(vectors) => Ok(Some(vectors)),
+        Err(e) => {
+            error!("Error embedding the chunks of the query: {}", e);
+            Err((status_code(&e), e.to_string()))
+        }
+    }
+}
+
+/// Queries the store with each of the vectors, merging their results with
+/// `merge_multi_vector_results`.
+async fn query_multi_vector(
+    embedding_client: &dyn EmbeddingStore,
+    query_vectors: Vec<Vec<f32>>,
+    index_name: &str,
+    options: QueryOptions,
+) -> Result<Vec<QueryResponse>> {
+    let top_k = options.top_k.unwrap_or(DEFAULT_TOP_K) as usize;
+    let results = join_all(query_vectors.into_iter().map(|query_vector| {
+        embedding_client.query_vector(query_vector, index_name, options.clone())
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>>>()?;
+    let metric = embedding_client.index_metric(index_name).await?;
+    Ok(merge_multi_vector_results(results, &metric, top_k))
+}
+
+/// Runs a query against the store, embedding its text unless its `query_vector` is given.
+async fn query_store(
+    app_state: &AppState,
+    embedding_client: &dyn EmbeddingStore,
+    input: QueryInput,
+    query_vector: Option<Vec<f32>>,
+) -> Result<QueryResults, (StatusCode, String)> {
+    info!("Querying index: {}", input.index_name);
+    let QueryInput {
+        index_name,
+        query_text,
+        top_k,
+        score_threshold,
+        offset,
+        filter,
+        date_from,
+        date_to,
+        include_values,
+        normalize_scores: normalize,
+        namespace,
+        context_window,
+        dedupe_by_document: dedupe,
+        recency_half_life_days,
+        long_query_strategy,
+    } = input;
+    let include_values = include_values.unwrap_or(false);
+    let filter = match query_filter(filter.as_ref(), date_from.as_deref(), date_to.as_deref()) {
+        Ok(filter) => filter,
+        Err(e) => {
+            error!("Invalid query filter: {}", e);
+            return Err((status_code(&e), e.to_string()));
+        }
+    };
+    if let Err(e) = validate_namespace(namespace.as_deref()) {
+        error!("Invalid namespace: {}", e);
+        return Err((status_code(&e), e.to_string()));
+    }
+    let top_k = top_k.unwrap_or(app_state.default_top_k);
+    if let Err(e) = validate_top_k(top_k) {
+        error!("Invalid top_k: {}", e);
+        return Err((status_code(&e), e.to_string()));
+    }
+    let offset = offset.unwrap_or(0);
+    // NOTE: One more result than the page is fetched to tell whether there are more
+    let page_end = offset.saturating_add(top_k).saturating_add(1);
+    if page_end > MAX_QUERY_WINDOW {
+        error!(
+            "Page over the query window: offset {}, top_k {}",
+            offset, top_k
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "offset + top_k must be less than {}, got {}",
+                MAX_QUERY_WINDOW,
+                page_end - 1
+            ),
+        ));
+    }
+    let context_window = context_window.unwrap_or(0);
+    if context_window > MAX_CONTEXT_WINDOW {
+        error!("Invalid context_window: {}", context_window);
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "context_window must be at most {}, got {}",
+                MAX_CONTEXT_WINDOW, context_window
+            ),
+        ));
+    }
+    if let Some(half_life_days) = recency_half_life_days {
+        if !(half_life_days.is_finite() && half_life_days > 0.0) {
+            error!("Invalid recency_half_life_days: {}", half_life_days);
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "recency_half_life_days must be positive, got {}",
+                    half_life_days
+                ),
+            ));
+        }
+    }
+    // NOTE: Checked before embedding the query, so that a mismatch set to fail fails early
+    let warning = match &app_state.model_guard {
+        Some(model_guard) => match model_guard.check(embedding_client, &index_name).await {
+            Ok(warning) => warning,
+            Err(e) => {
+                error!("Error checking the embedding model of the index: {}", e);
+                return Err((status_code(&e), e.to_string()));
+            }
+        },
+        None => None,
+    };
+    let dedupe = dedupe.unwrap_or(false);
+    // NOTE: Deduplicated results are sparser, so a wider window is fetched to fill the page
+    let overfetch = if dedupe { DEDUPE_OVERFETCH } else { 1 };
+    let window = page_end.saturating_mul(overfetch).min(MAX_QUERY_WINDOW);
+    let options = QueryOptions {
+        top_k: Some(window),
+        filter,
+        include_values,
+        score_threshold,
+        namespace: namespace.clone(),
+    };
+    // NOTE: Over-long queries are embedded by chunks, even when their query vector is given
+    let query_vectors = match long_query_strategy {
+        Some(strategy) => embed_long_query(app_state, embedding_client, &query_text)
+            .await?
+            .map(|vectors| match strategy {
+                LongQueryStrategy::Average => mean_vector(&vectors).map(|mean| vec![mean]),
+                LongQueryStrategy::MultiVector => Ok(vectors),
+            })
+            .transpose()
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+        None => None,
+    };
+    let query_response = match query_vectors.or(query_vector.map(|vector| vec![vector])) {
+        Some(mut query_vectors) if query_vectors.len() == 1 => {
+            embedding_client
+                .query_vector(query_vectors.remove(0), &index_name, options)
+                .await
+        }
+        Some(query_vectors) => {
+            query_multi_vector(embedding_client, query_vectors, &index_name, options).await
+        }
+        None => {
+            // NOTE: The query text is embedded by `query`, so the permit is held for the whole query
+            let _permit = app_state
+                .embedding_permits
+                .acquire()
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            embedding_client
+                .query(&query_text, &index_name, options)
+                .await
+        }
+    };
+    let mut query_response = match query_response {
+        Ok(query_response) => query_response,
+        Err(e) => {
+            error!("Error querying: {}", e);
+            return Err((status_code(&e), e.to_string()));
+        }
+    };
+    let normalize = normalize.unwrap_or(false);
+    // NOTE: The threshold applied by `query` compares the raw scores of the index, before any rescaling
+    let metric = if normalize || recency_half_life_days.is_some() {
+        match embedding_client.index_metric(&index_name).await {
+            Ok(metric) => Some(metric),
+            Err(e) => {
+                error!("Error retrieving index metric: {}", e);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+            }
+        }
+    } else {
+        None
+    };
+    if include_values {
+        set_norms(&mut query_response);
+    } else {
+        strip_embeddings(&mut query_response);
+    }
+    // NOTE: The whole window is weighted by recency, so that recent results can rise into the page
+    if let (Some(half_life_days), Some(metric)) = (recency_half_life_days, &metric) {
+        apply_recency(
+            &mut query_response,
+            half_life_days,
+            Utc::now().timestamp(),
+            metric,
+        );
+    }
+    if dedupe {
+        query_response = dedupe_by_document(query_response);
+    }
+    let mut query_results =
+        QueryResults::from_window(query_response, offset as usize, top_k as usize);
+    query_results.warning = warning;
+    if let Some(metric) = metric.as_ref().filter(|_| normalize) {
+        normalize_scores(&mut query_results.results, metric);
+    }
+    // NOTE: Neighbors are only fetched for the results of the page
+    if context_window > 0 {
+        for result in query_results.results.iter_mut() {
+            let ids = context_window_ids(result, context_window);
+            let neighbors = match embedding_client
+                .fetch_chunks(&index_name, &ids, namespace.as_deref())
+                .await
+            {
+                Ok(neighbors) => neighbors,
+                Err(e) => {
+                    error!("Error fetching neighboring chunks: {}", e);
+                    return Err((status_code(&e), e.to_string()));
+                }
+            };
+            result.context = Some(assemble_context(result, neighbors, context_window));
+        }
+    }
+    Ok(query_results)
+}
+
+/// Handles counting the tokens of a text with the server's tokenizer.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the tokenizer.
+/// * `input` - The input containing the text to count the tokens of.
+///
+/// # Returns
+///
+/// Returns `Ok(Json(serde_json::Value))` holding the number of `tokens` of the text.
+///
+/// # Errors
+///
+/// Returns a `(StatusCode, String)` error tuple if:
+/// - No tokenizer is loaded by the server.
+/// - The tokenizer fails to encode the text.
+#[instrument(skip_all)]
+pub async fn count_tokens(
+    State(app_state): State<AppState>,
+    Json(input): Json<CountTokensInput>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let span = info_span!("count_tokens");
+    let _enter = span.enter();
+    info!("Counting tokens");
+    let tokenizer = match app_state.tokenizer.as_deref() {
+        Some(tokenizer) => tokenizer,
+        None => {
+            error!("No tokenizer loaded");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "No tokenizer loaded".to_string(),
+            ));
+        }
+    };
+    let count = tokenizer.count(&input.text).map_err(|e| {
+        error!("Error counting tokens: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+    Ok(Json(json!({ "tokens": count })))
+}
+
+/// Handles estimating the number of chunks and tokens of a document, without embedding it.
+///
+/// The content is split with the server's split criteria, as `/embed` would split it, and
+/// each non-empty chunk is counted as one embedding call.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the split criteria and tokenizer.
+/// * `input` - The input containing the content of the document.
+///
+/// # Returns
+///
+/// Returns `Ok(Json(serde_json::Value))` holding the number of `chunks`, their `total_tokens`
+/// and the `max_chunk_tokens` of the largest one.
+///
+/// # Errors
+///
+/// Returns a `(StatusCode, String)` error tuple if:
+/// - No tokenizer is loaded by the server.
+/// - Splitting the content or encoding a chunk fails.
+#[instrument(skip_all)]
+pub async fn estimate(
+    State(app_state): State<AppState>,
+    Json(input): Json<EstimateInput>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let span = info_span!("estimate");
+    let _enter = span.enter();
+    info!("Estimating embedding cost");
+    let tokenizer = match app_state.tokenizer.as_deref() {
+        Some(tokenizer) => tokenizer,
+        None => {
+            error!("No tokenizer loaded");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "No tokenizer loaded".to_string(),
+            ));
+        }
+    };
+    let (mut chunks, mut total_tokens, mut max_chunk_tokens) = (0, 0, 0);
+    for chunk in app_state.split_criteria.split_iter_with_segmenter(
+        &input.content,
+        Some(tokenizer),
+        app_state.segmenter.as_deref(),
+    ) {
+        let chunk = chunk.map_err(|e| {
+            error!("Error splitting text: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+        // NOTE: Empty chunks are skipped by `/embed`, so they incur no embedding call
+        if chunk.trim().is_empty() {
+            continue;
+        }
+        let count = tokenizer.count(&chunk).map_err(|e| {
+            error!("Error counting tokens: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+        chunks += 1;
+        total_tokens += count;
+        max_chunk_tokens = max_chunk_tokens.max(count);
+    }
+    Ok(Json(json!({
+        "chunks": chunks,
+        "total_tokens": total_tokens,
+        "max_chunk_tokens": max_chunk_tokens,
+    })))
+}
+
+/// Handles deleting all the embeddings of an index matching a metadata filter.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the embedding client.
+/// * `input` - The input containing the index name, the filter and the optional confirmation.
+///
+/// # Errors
+///
+/// Returns a `(StatusCode, String)` error tuple if:
+/// - The filter is not a JSON object, or is empty without `confirm` (`400`).
+/// - The namespace is an internal one, e.g. the one of the model marker (`400`).
+/// - The delete operation in the vector database fails.
+#[instrument(skip_all)]
+pub async fn delete_by_filter(
+    State(app_state): State<AppState>,
+    Json(input): Json<DeleteByFilterInput>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let span = info_span!("delete_by_filter");
+    let _enter = span.enter();
+    info!("Deleting embeddings from index: {}", input.index_name);
+    if let Err(e) = validate_namespace(input.namespace.as_deref()) {
+        error!("Invalid namespace: {}", e);
+        return Err((status_code(&e), e.to_string()));
+    }
+    let mut embedding_client = app_state.embedding_client.write().await;
+    match embedding_client
+        .delete_by_filter(
+            &input.index_name,
+            input.filter,
+            input.confirm.unwrap_or(false),
+            input.namespace.as_deref(),
+        )
+        .await
+    {
+        Ok(()) => Ok(Json(json!({
+            "index_name": input.index_name,
+            "status": "success",
+        }))),
+        Err(e) => {
+            error!("Error deleting embeddings: {}", e);
+            Err((status_code(&e), e.to_string()))
+        }
+    }
+}
+
+/// Handles compacting an index, merging the adjacent small chunks of each of its documents
+/// up to `target_tokens` tokens, see `compaction::compact_index`.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the embedding client and tokenizer.
+/// * `input` - The input containing the index name, the target token count and the optional
+///   namespace.
+///
+/// # Returns
+///
+/// Returns the `CompactionSummary` of the compaction, e.g. the number of chunks before and
+/// after it.
+///
+/// # Errors
+///
+/// Returns a `(StatusCode, String)` error tuple if:
+/// - No tokenizer is loaded by the server.
+/// - `target_tokens` is `0` (`400`).
+/// - The namespace is an internal one, e.g. the one of the model marker (`400`).
+/// - Listing, re-embedding or replacing the chunks fails.
+///
+/// # Notes
+///
+/// The embedding client is locked for the whole compaction, so other requests wait for it to
+/// end. It is meant to be run offline, e.g. after a bulk ingestion.
+#[instrument(skip_all)]
+pub async fn compact_index(
+    State(app_state): State<AppState>,
+    Json(input): Json<CompactIndexInput>,
+) -> Result<Json<CompactionSummary>, (StatusCode, String)> {
+    let span = info_span!("compact_index");
+    let _enter = span.enter();
+    info!("Compacting index: {}", input.index_name);
+    if let Err(e) = validate_namespace(input.namespace.as_deref()) {
+        error!("Invalid namespace: {}", e);
+        return Err((status_code(&e), e.to_string()));
+    }
+    let tokenizer = match app_state.tokenizer.as_deref() {
+        Some(tokenizer) => tokenizer,
+        None => {
+            error!("No tokenizer loaded");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "No tokenizer loaded".to_string(),
+            ));
+        }
+    };
+    let mut embedding_client = app_state.embedding_client.write().await;
+    match compact(
+        &mut *embedding_client,
+        tokenizer,
+        &input.index_name,
+        input.target_tokens,
+        input.namespace.as_deref(),
+        app_state.prepend_document_context,
+    )
+    .await
+    {
+        Ok(summary) => Ok(Json(summary)),
+        Err(e) => {
+            error!("Error compacting index: {}", e);
+            Err((status_code(&e), e.to_string()))
+        }
+    }
+}
+
+/// Handles listing the namespaces of an index holding embeddings.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the embedding client.
+/// * `input` - The query string naming the index, e.g. `?index=my-index`.
+///
+/// # Returns
+///
+/// Returns a JSON object holding the `index` name and its sorted `namespaces`.
+///
+/// # Errors
+///
+/// Returns a `(StatusCode, String)` error tuple if the statistics of the index cannot be retrieved.
+#[instrument(skip_all)]
+pub async fn namespaces(
+    State(app_state): State<AppState>,
+    Query(input): Query<NamespacesInput>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let span = info_span!("namespaces");
+    let _enter = span.enter();
+    info!("Listing namespaces of index: {}", input.index);
+    let embedding_client = app_state.embedding_client.read().await;
+    match embedding_client.list_namespaces(&input.index).await {
+        Ok(namespaces) => Ok(Json(json!({
+            "index": input.index,
+            "namespaces": namespaces,
+        }))),
+        Err(e) => {
+            error!("Error listing namespaces: {}", e);
+            Err((status_code(&e), e.to_string()))
+        }
+    }
+}
+
+/// Handles fetching the raw document stored for a `query_id`, when raw documents are stored
+/// (see `AppState::with_raw_documents`), e.g. to show the full document of a query result.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the embedding client.
+/// * `input` - The query string naming the index, the `query_id` and optionally the namespace,
+///   e.g. `?index=my-index&query_id=tweet-1`.
+///
+/// # Returns
+///
+/// Returns the `query_id`, full `content` and `metadata` of the document.
+///
+/// # Errors
+///
+/// Returns a `404` if no raw document is stored for the `query_id`, e.g. if it was embedded
+/// while raw documents were not stored, and a `500` if the document cannot be fetched.
+#[instrument(skip_all)]
+pub async fn fetch_document(
+    State(app_state): State<AppState>,
+    Query(input): Query<DocumentInput>,
+) -> Result<Json<StoredDocument>, (StatusCode, String)> {
+    let span = info_span!("fetch_document");
+    let _enter = span.enter();
+    info!(
+        "Fetching raw document, for query with id: {}",
+        input.query_id
+    );
+    let embedding_client = app_state.embedding_client.read().await;
+    match embedding_client
+        .fetch_document(&input.index, &input.query_id, input.namespace.as_deref())
+        .await
+    {
+        Ok(document) => Ok(Json(document)),
+        Err(e) => {
+            error!("Error fetching raw document: {}", e);
+            Err((status_code(&e), e.to_string()))
+        }
+    }
+}
+
+/// Handles re-embedding a stored chunk, to compare its fresh embedding with the stored one.
+///
+/// A similarity well below `1` flags that the embedding model, or its version, changed
+/// since the chunk was stored, so that queries are embedded differently than the index.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the embedding client.
+/// * `input` - The input naming the index and the full id of the chunk.
+///
+/// # Returns
+///
+/// Returns a `RescoreResponse`, holding the stored and fresh embeddings and their cosine similarity.
+///
+/// # Errors
+///
+/// Returns a `(StatusCode, String)` error tuple if:
+/// - No chunk is stored with the id (`404`).
+/// - The chunk cannot be fetched, or its text cannot be embedded.
+/// - The stored and fresh embeddings have different dimensions.
+#[instrument(skip_all)]
+pub async fn debug_rescore(
+    State(app_state): State<AppState>,
+    Json(input): Json<RescoreInput>,
+) -> Result<Json<RescoreResponse>, (StatusCode, String)> {
+    let span = info_span!("debug_rescore");
+    let _enter = span.enter();
+    info!("Rescoring chunk {} of index: {}", input.id, input.index);
+    let embedding_client = app_state.embedding_client.read().await;
+    let chunk = match embedding_client
+        .fetch_chunks(
+            &input.index,
+            std::slice::from_ref(&input.id),
+            input.namespace.as_deref(),
+        )
+        .await
+    {
+        Ok(chunks) => chunks.into_iter().next(),
+        Err(e) => {
+            error!("Error fetching chunk: {}", e);
+            return Err((status_code(&e), e.to_string()));
+        }
+    };
+    let Some(chunk) = chunk else {
+        let e = Error::new(RagError::NotFound(format!(
+            "No chunk with id {} in index {}",
+            input.id, input.index
+        )));
+        error!("{}", e);
+        return Err((status_code(&e), e.to_string()));
+    };
+    // NOTE: The chunk is re-embedded as it was embedded when stored
+    let embedded_text = match app_state.prepend_document_context {
+        true => prepend_document_context(
+            &chunk.text,
+            string_field(&chunk.metadata, "topic").as_deref(),
+            string_field(&chunk.metadata, "description").as_deref(),
+        ),
+        false => chunk.text.clone(),
+    };
+    let fresh_embedding = match create_embedding_with_permit(
+        &app_state.embedding_permits,
+        &*embedding_client,
+        &embedded_text,
+        None,
+        EmbeddingKind::Document,
+    )
+    .await
+    {
+        Ok(embedding) => embedding.concat(),
+        Err(e) => {
+            error!("Error creating embedding: {}", e);
+            return Err((status_code(&e), e.to_string()));
+        }
+    };
+    let similarity = cosine_similarity(&chunk.embedding, &fresh_embedding).map_err(|e| {
+        error!("Error comparing embeddings: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+    Ok(Json(RescoreResponse {
+        id: chunk.id,
+        text: chunk.text,
+        stored_embedding: chunk.embedding,
+        fresh_embedding,
+        similarity,
+    }))
+}
+
+/// Handles reporting the version and configuration of the running server.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the embedding client and split criteria.
+///
+/// # Returns
+///
+/// Returns a JSON object holding the crate `version`, the `tokenizer_model` (its configured
+/// name, else its model type, or `null` if no tokenizer is loaded), the `embedding_backend`
+/// URL, the `default_split` criteria and the Pinecone `namespace` used by requests not naming one.
+#[instrument(skip_all)]
+pub async fn info(State(app_state): State<AppState>) -> Json<serde_json::Value> {
+    let span = info_span!("info");
+    let _enter = span.enter();
+    let tokenizer_model = app_state.tokenizer_model.clone().or_else(|| {
+        app_state
+            .tokenizer
+            .as_deref()
+            .and_then(TokenCounter::model_name)
+    });
+    let embedding_client = app_state.embedding_client.read().await;
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "tokenizer_model": tokenizer_model,
+        "embedding_backend": embedding_client.embedding_backend(),
+        "default_split": app_state.split_criteria.to_string(),
+        "namespace": embedding_client.default_namespace(),
+    }))
+}
+
+/// Handles the creation of a new index in the vector database.
+///
+/// This function takes the index creation input, processes it, and creates a new index
+/// in the underlying vector database using the embedding client.
+///
+/// # Arguments
+///
+/// * `app_state` - The shared application state containing the embedding client.
+/// * `input` - The input data for creating the index, including name, dimension, and metric.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the index is successfully created, or an error with an appropriate
+/// status code and message if the creation fails.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - The index name does not follow the Pinecone naming rules (`400`).
+/// - The dimension is not in `1..=MAX_INDEX_DIMENSION` (`400`).
+/// - An index with the same name already exists (`409`).
+/// - There's an issue accessing the embedding client.
+/// - The index creation operation fails in the vector database.
+/// - `wait` is set, and the index is not ready within `wait_timeout_secs`.
+///
+/// Unknown metrics are rejected when deserializing the input (`422`), with the list of
+/// supported `MetricOptions`.
+///
+/// # Notes
+///
+/// With `wait` set, the embedding client stays locked until the index is ready, so other
+/// requests are blocked in the meantime.
+///
+/// # Example
+///
+/// ```
+/// let create_index_input = CreateIndexInput {
+///     index_name: "my-new-index".to_string(),
+///     dimension: 768,
+///     metric: Some(MetricOptions::Cosine),
+///     wait: Some(true),
+///     wait_timeout_secs: None,
+/// };
+/// let result = create_index(State(app_state), Json(create_index_input)).await;
+/// ```
+#[instrument(skip_all)]
+pub async fn create_index(
+    State(app_state): State<AppState>,
+    Json(input): Json<CreateIndexInput>,
+) -> Result<(), (StatusCode, String)> {
+    let span = info_span!("create_index");
+    let _enter = span.enter();
+    info!("Creating index: {}", input.index_name);
+    let CreateIndexInput {
+        index_name,
+        dimension,
+        metric,
+        wait,
+        wait_timeout_secs,
+    } = input;
+    let metric = metric.map(Metric::from);
+    let wait_policy = if wait.unwrap_or(false) {
+        WaitPolicy::WaitFor(Duration::from_secs(
+            wait_timeout_secs.unwrap_or(DEFAULT_WAIT_TIMEOUT_SECS),
+        ))
+    } else {
+        WaitPolicy::NoWait
+    };
+    let mut embedding_client = app_state.embedding_client.write().await;
+    embedding_client
+        .create_index(&index_name, dimension, metric, wait_policy)
+        .await
+        .map_err(|e| {
+            error!("Error creating index: {}", e);
+            (status_code(&e), e.to_string())
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MAX_TOP_K;
+    use crate::model_guard::ModelMismatchMode;
+    use crate::request_id::REQUEST_ID_HEADER;
+    use crate::store::InMemoryStore;
+    use crate::test_utils::{
+        embedded_chunks, mock_embedding, spawn_server, test_client, text_to_embed,
+        word_level_tokenizer, FakeStore, MockControlPlane, MockEmbedder,
+    };
+    use std::num::NonZeroUsize;
+
+    async fn test_state(embedder: &MockEmbedder) -> AppState {
+        let addr = spawn_server(embedder.router()).await;
+        AppState::new(test_client(addr), Some(SplitCriteria::EndOfSentence), None)
+    }
+
+    /// Builds a state embedding with the mocked service, and storing into an `InMemoryStore`.
+    async fn in_memory_state(embedder: &MockEmbedder) -> AppState {
+        let addr = spawn_server(embedder.router()).await;
+        AppState::new(
+            InMemoryStore::new(test_client(addr)),
+            Some(SplitCriteria::EndOfSentence),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_embed_rejects_empty_content() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await;
+        let result = embed(State(app_state), ValidatedJson(text_to_embed(""))).await;
+        let (status, message) = result.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(message, "content is empty");
+        assert!(embedder.inputs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_embed_rejects_whitespace_only_content() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await;
+        let result = embed(State(app_state), ValidatedJson(text_to_embed(" \n\t  \n"))).await;
+        let (status, message) = result.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(message, "content is empty");
+        assert!(embedder.inputs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_embed_valid_content_reaches_embedding_server() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await;
+        let result = embed(
+            State(app_state),
+            ValidatedJson(text_to_embed("First sentence. Second sentence.")),
+        )
+        .await;
+        // NOTE: There is no Pinecone index to store into, so only the validation and
+        // embedding steps are checked here. All the chunks are embedded before storing them
+        if let Err((status, _)) = result {
+            assert_ne!(status, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(
+            embedder.inputs(),
+            vec!["First sentence.", "Second sentence."]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embed_route_returns_structured_validation_errors() {
+        let store = FakeStore::new(16);
+        let app_state = AppState::new(store.clone(), Some(SplitCriteria::EndOfSentence), None);
+        let addr = spawn_server(router(app_state)).await;
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/embed", addr))
+            .json(&json!({ "query_id": "1", "index_name": "", "content": "Hello." }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["field"], "index_name");
+        assert_eq!(body["error"], "index_name must not be empty");
+        assert!(store.vectors("").is_empty());
+    }
+
+    /// Writer appending the logs to a shared buffer.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_debug_samples_log_the_embedded_chunks() {
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        // NOTE: The test runtime is single threaded, so the handler logs to this subscriber
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let store = FakeStore::new(16);
+        let app_state = AppState::new(store, Some(SplitCriteria::EndOfSentence), None);
+        let Json(response) = embed(
+            State(app_state),
+            ValidatedJson(text_to_embed("Rust is fast. Pinecone stores vectors.")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["status"], "success");
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let embedded: Vec<&str> = logs
+            .lines()
+            .filter(|line| line.contains("Embedded text of chunk"))
+            .collect();
+        assert_eq!(embedded.len(), 2, "{}", logs);
+        assert!(
+            embedded[0].ends_with(r#": "Rust is fast.""#),
+            "{}",
+            embedded[0]
+        );
+        assert!(
+            embedded[1].ends_with(r#": "Pinecone stores vectors.""#),
+            "{}",
+            embedded[1]
+        );
+        assert!(embedded
+            .iter()
+            .all(|line| line.contains(DEBUG_SAMPLE_TARGET)));
+        assert_eq!(logs.matches("Stored text of chunk").count(), 2);
+        assert_eq!(debug_sample(&"a".repeat(500)).len(), DEBUG_SAMPLE_CHARS);
+    }
+
+    #[tokio::test]
+    async fn test_embed_then_query_with_fake_store() {
+        let store = FakeStore::new(16);
+        let app_state = AppState::new(store.clone(), Some(SplitCriteria::EndOfSentence), None);
+        let addr = spawn_server(router(app_state.clone())).await;
+        let client = reqwest::Client::new();
+        let mut document = text_to_embed("Rust is fast. Pinecone stores vectors.");
+        document.upsert_mode = Some(UpsertMode::Replace);
+        document.author = Some("atoma".to_string());
+        for _ in 0..2 {
+            let response = client
+                .post(format!("http://{}/embed", addr))
+                .json(&document)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // Replacing the document deletes the chunks stored by the first request
+        assert_eq!(store.vectors("test-index").len(), 2);
+        // Each chunk is stored with its own text, and the fields of its document apart
+        let metadata = &store.vectors("test-index")[1].metadata;
+        assert_eq!(metadata["text"].as_str(), Some("Pinecone stores vectors."));
+        assert_eq!(metadata["author"].as_str(), Some("atoma"));
+
+        let mut input = query_input(Some(1));
+        input.query_text = "Rust is fast.".to_string();
+        let results: QueryResults = client
+            .get(format!("http://{}/query", addr))
+            .json(&input)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(results.returned, 1);
+        assert!(results.has_more);
+        assert!((results.results[0].score - 1.0).abs() < 1e-6);
+        assert!(results.results[0].embedding.is_empty());
+        // The matched chunk is returned, rather than the serialized input
+        assert_eq!(results.results[0].text, "Rust is fast.");
+        assert!(serde_json::from_str::<TextToEmbed>(&results.results[0].text).is_err());
+        assert_eq!(
+            results.results[0].query_id.as_deref(),
+            Some("test-query-id")
+        );
+        assert_eq!(results.results[0].norm, None);
+
+        input.include_values = Some(true);
+        let Json(results) = query(State(app_state.clone()), Json(input)).await.unwrap();
+        let embedding = &results.results[0].embedding;
+        let manual = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!(manual > 0.0);
+        assert_eq!(results.results[0].norm, Some(manual));
+    }
+
+    #[tokio::test]
+    async fn test_embed_detects_missing_language() {
+        let store = FakeStore::new(16);
+        let app_state = AppState::new(store.clone(), Some(SplitCriteria::EndOfSentence), None)
+            .with_language_detection(true);
+        for (query_id, content) in [
+            ("english", "This is the text of a document."),
+            ("japanese", "これは日本語の文書です。"),
+        ] {
+            let mut document = text_to_embed(content);
+            document.query_id = query_id.to_string();
+            let Json(response) = embed(State(app_state.clone()), ValidatedJson(document))
+                .await
+                .unwrap();
+            assert_eq!(response["query_id"], query_id);
+        }
+        // The detected language is stored as the `lang` metadata of the chunks
+        let vectors = store.vectors("test-index");
+        let langs: Vec<Option<&str>> = vectors
+            .iter()
+            .map(|vector| vector.metadata.get("lang").and_then(|lang| lang.as_str()))
+            .collect();
+        assert_eq!(langs, vec![Some("en"), Some("ja")]);
+    }
+
+    #[tokio::test]
+    async fn test_embed_prepends_topic_and_description_when_enabled() {
+        for enabled in [false, true] {
+            let store = FakeStore::new(16);
+            let app_state = AppState::new(store.clone(), Some(SplitCriteria::EndOfSentence), None)
+                .with_document_context(enabled);
+            let mut document = text_to_embed("Rust is fast.");
+            document.topic = Some("rust".to_string());
+            document.description = Some("Notes on Rust".to_string());
+            let Json(response) = embed(State(app_state), ValidatedJson(document))
+                .await
+                .unwrap();
+            assert_eq!(response["query_id"], "test-query-id");
+
+            let vectors = store.vectors("test-index");
+            assert_eq!(vectors.len(), 1);
+            // The topic and description are stored as metadata, and the chunk with its own text
+            let metadata = &vectors[0].metadata;
+            assert_eq!(metadata.get("topic").and_then(|v| v.as_str()), Some("rust"));
+            assert_eq!(
+                metadata.get("description").and_then(|v| v.as_str()),
+                Some("Notes on Rust")
+            );
+            assert_eq!(vectors[0].text, "Rust is fast.");
+            let embedded = match enabled {
+                true => "Topic: rust\nDescription: Notes on Rust\n\nRust is fast.",
+                false => "Rust is fast.",
+            };
+            assert_eq!(vectors[0].values, mock_embedding(embedded, 16));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefixes_reach_embedding_server_only() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let client = test_client(addr).with_prefixes(
+            Some("search_document: ".to_string()),
+            Some("search_query: ".to_string()),
+        );
+        let app_state = AppState::new(
+            InMemoryStore::new(client),
+            Some(SplitCriteria::EndOfSentence),
+            None,
+        );
+        let mut document = text_to_embed("The quick brown fox.");
+        document.create_if_missing = Some(true);
+        let Json(response) = embed(State(app_state.clone()), ValidatedJson(document))
+            .await
+            .unwrap();
+        assert_eq!(response["status"], "success");
+        let mut input = query_input(Some(1));
+        input.query_text = "fox".to_string();
+        let Json(results) = query(State(app_state.clone()), Json(input)).await.unwrap();
+        assert_eq!(
+            embedder.inputs(),
+            vec!["search_document: The quick brown fox.", "search_query: fox"]
+        );
+
+        // The stored chunk and the query results hold the text without its prefix
+        let chunks = app_state
+            .embedding_client
+            .read()
+            .await
+            .list_chunks("test-index", None)
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].text, "The quick brown fox.");
+        assert_eq!(
+            string_field(&chunks[0].metadata, "text").as_deref(),
+            Some("The quick brown fox.")
+        );
+        assert_eq!(results.results[0].text, "The quick brown fox.");
+    }
+
+    #[tokio::test]
+    async fn test_identical_embeds_store_identical_vectors() {
+        // Eleven identical chunks, tied on every query, with ids `9` and `10` ordered the other
+        // way around lexicographically
+        let content = "Rust is fast. ".repeat(11);
+        let mut runs = vec![];
+        for _ in 0..2 {
+            let embedder = MockEmbedder::new(16);
+            let app_state = in_memory_state(&embedder).await;
+            let mut document = text_to_embed(&content);
+            document.create_if_missing = Some(true);
+            document.extra =
+                serde_json::from_value(json!({ "tags": ["rust"], "stars": 5 })).unwrap();
+            let Json(response) = embed(State(app_state.clone()), ValidatedJson(document))
+                .await
+                .unwrap();
+            assert_eq!(response["status"], "success");
+            let chunks = app_state
+                .embedding_client
+                .read()
+                .await
+                .list_chunks("test-index", None)
+                .await
+                .unwrap();
+            let indexes: Vec<_> = chunks.iter().map(|chunk| chunk.chunk_index).collect();
+            assert_eq!(indexes, (0..11).map(Some).collect::<Vec<_>>());
+
+            let Json(results) = query(State(app_state), Json(query_input(Some(11))))
+                .await
+                .unwrap();
+            let ranked: Vec<_> = results
+                .results
+                .iter()
+                .map(|result| result.chunk_index)
+                .collect();
+            assert_eq!(ranked, indexes);
+            runs.push(format!("{:?}", chunks));
+        }
+        assert_eq!(runs[0], runs[1]);
+    }
+
+    #[tokio::test]
+    async fn test_query_reports_embedding_model_mismatch() {
+        let store = FakeStore::new(16);
+        let app_state = |model: &str, mode: ModelMismatchMode| {
+            AppState::new(store.clone(), Some(SplitCriteria::EndOfSentence), None)
+                .with_model_guard(Some(ModelGuard::new(model, mode)))
+        };
+        let Json(response) = embed(
+            State(app_state("bge-small", ModelMismatchMode::Error)),
+            ValidatedJson(text_to_embed("Rust is fast.")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["status"], "success");
+
+        let Json(results) = query(
+            State(app_state("bge-small", ModelMismatchMode::Error)),
+            Json(query_input(Some(1))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(results.warning, None);
+        let Json(results) = query(
+            State(app_state("e5-large", ModelMismatchMode::Warn)),
+            Json(query_input(Some(1))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(results.returned, 1);
+        let warning = results.warning.unwrap();
+        assert!(warning.contains("bge-small"), "{}", warning);
+        let (status, message) = query(
+            State(app_state("e5-large", ModelMismatchMode::Error)),
+            Json(query_input(Some(1))),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(message.contains("e5-large"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_raw_document_is_fetched_by_query_id() {
+        let store = FakeStore::new(16);
+        let app_state = AppState::new(store.clone(), Some(SplitCriteria::EndOfSentence), None)
+            .with_raw_documents(true);
+        let content = "First sentence. Second sentence. Third sentence.";
+        let mut document = text_to_embed(content);
+        document.namespace = Some("tweets".to_string());
+        let Json(response) = embed(State(app_state.clone()), ValidatedJson(document))
+            .await
+            .unwrap();
+        assert_eq!(response["status"], "success");
+        assert_eq!(store.vectors("test-index").len(), 3);
+
+        let addr = spawn_server(router(app_state.clone())).await;
+        let client = reqwest::Client::new();
+        let url = format!(
+            "http://{}/document?index=test-index&query_id=test-query-id&namespace=tweets",
+            addr
+        );
+        let response: serde_json::Value =
+            client.get(url).send().await.unwrap().json().await.unwrap();
+        assert_eq!(response["query_id"], "test-query-id");
+        assert_eq!(response["content"], content);
+        assert_eq!(response["metadata"]["text"], content);
+        // Documents are fetched from the namespace they were embedded in
+        let response = client
+            .get(format!(
+                "http://{}/document?index=test-index&query_id=test-query-id",
+                addr
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let too_large = text_to_embed(&"word ".repeat(MAX_RAW_DOCUMENT_BYTES));
+        let (status, _) = embed(State(app_state), ValidatedJson(too_large))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_namespaces_route_lists_stored_namespaces() {
+        let store = FakeStore::new(16);
+        let app_state = AppState::new(store.clone(), Some(SplitCriteria::EndOfSentence), None);
+        let addr = spawn_server(router(app_state)).await;
+        let client = reqwest::Client::new();
+        for namespace in [None, Some("tweets")] {
+            let mut document = text_to_embed("Rust is fast.");
+            document.namespace = namespace.map(str::to_string);
+            let response = client
+                .post(format!("http://{}/embed", addr))
+                .json(&document)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response: serde_json::Value = client
+            .get(format!("http://{}/namespaces?index=test-index", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(
+            response["namespaces"],
+            json!([crate::client::CURRENT_NAME_SPACE, "tweets"])
+        );
+
+        // Queries only see the chunks of their namespace
+        let mut input = query_input(None);
+        input.namespace = Some("tweets".to_string());
+        let results: QueryResults = client
+            .get(format!("http://{}/query", addr))
+            .json(&input)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(results.returned, 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_returns_context_window() {
+        let mut store = FakeStore::new(16);
+        for (query_id, chunks) in [
+            (
+                "first",
+                vec!["Intro.", "Setup.", "Details.", "Results.", "Outro."],
+            ),
+            ("second", vec!["Another document."]),
+        ] {
+            let embeddings = embedded_chunks(&chunks, 16);
+            let mut document = text_to_embed("");
+            document.query_id = query_id.to_string();
+            store
+                .store("test-index", embeddings, Some(&document), None)
+                .await
+                .unwrap();
+        }
+        let app_state = AppState::new(store, Some(SplitCriteria::EndOfSentence), None);
+
+        let query = |query_text: &str, context_window: Option<usize>| QueryInput {
+            query_text: query_text.to_string(),
+            context_window,
+            ..query_input(Some(1))
+        };
+        let results = run_query(&app_state, query("Details.", None))
+            .await
+            .unwrap();
+        assert_eq!(results.results[0].text, "Details.");
+        assert_eq!(results.results[0].context, None);
+
+        let results = run_query(&app_state, query("Details.", Some(1)))
+            .await
+            .unwrap();
+        assert_eq!(
+            results.results[0].context.as_deref(),
+            Some("Setup.\nDetails.\nResults.")
+        );
+
+        // The context stops at the end of the document, other documents are never mixed in
+        let results = run_query(&app_state, query("Outro.", Some(2)))
+            .await
+            .unwrap();
+        assert_eq!(
+            results.results[0].context.as_deref(),
+            Some("Details.\nResults.\nOutro.")
+        );
+
+        let (status, _) = run_query(&app_state, query("Outro.", Some(MAX_CONTEXT_WINDOW + 1)))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_query_dedupes_by_document() {
+        let mut store = FakeStore::new(16);
+        for (query_id, chunks) in [
+            ("first", vec!["Rust is fast.", "Rust is fast and safe."]),
+            ("second", vec!["Python is slow."]),
+        ] {
+            let embeddings = embedded_chunks(&chunks, 16);
+            let mut document = text_to_embed("");
+            document.query_id = query_id.to_string();
+            store
+                .store("test-index", embeddings, Some(&document), None)
+                .await
+                .unwrap();
+        }
+        let app_state = AppState::new(store, Some(SplitCriteria::EndOfSentence), None);
+
+        let query = |dedupe_by_document: Option<bool>| QueryInput {
+            query_text: "Rust is fast.".to_string(),
+            dedupe_by_document,
+            ..query_input(Some(3))
+        };
+        let results = run_query(&app_state, query(None)).await.unwrap();
+        let first_chunks = |results: &QueryResults| {
+            results
+                .results
+                .iter()
+                .filter(|result| result.query_id.as_deref() == Some("first"))
+                .count()
+        };
+        assert_eq!(first_chunks(&results), 2);
+
+        // Only the best chunk of each document is kept
+        let results = run_query(&app_state, query(Some(true))).await.unwrap();
+        assert_eq!(first_chunks(&results), 1);
+        assert_eq!(results.returned, 2);
+        assert_eq!(results.results[0].text, "Rust is fast.");
+        assert_eq!(results.results[1].query_id.as_deref(), Some("second"));
+    }
+
+    #[tokio::test]
+    async fn test_long_queries_are_split_by_their_strategy() {
+        let mut store = FakeStore::new(16);
+        let texts = [
+            "Rust is fast.",
+            "Pinecone stores vectors.",
+            "Python is slow.",
+        ];
+        store
+            .store(
+                "test-index",
+                embedded_chunks(&texts, 16),
+                Some(&text_to_embed("")),
+                None,
+            )
+            .await
+            .unwrap();
+        let tokenizer: Arc<dyn TokenCounter> = Arc::new(word_level_tokenizer());
+        let app_state = AppState::new(
+            store.clone(),
+            Some(SplitCriteria::EndOfSentence),
+            Some(tokenizer),
+        )
+        .with_max_query_tokens(4);
+        let query = |query_text: &str, strategy: LongQueryStrategy| QueryInput {
+            query_text: query_text.to_string(),
+            long_query_strategy: Some(strategy),
+            ..query_input(Some(2))
+        };
+        // 8 tokens, as the periods are tokens of their own
+        let long_query = "Rust is fast. Pinecone stores vectors.";
+
+        let results = run_query(
+            &app_state,
+            query(long_query, LongQueryStrategy::MultiVector),
+        )
+        .await
+        .unwrap();
+        let chunks = vec![
+            "Rust is fast.".to_string(),
+            "Pinecone stores vectors.".to_string(),
+        ];
+        assert_eq!(store.batches.lock().unwrap().last(), Some(&chunks));
+        // Each chunk matches its own document best
+        let matched: Vec<&str> = results
+            .results
+            .iter()
+            .map(|result| result.text.as_str())
+            .collect();
+        assert_eq!(matched.len(), 2);
+        assert!(
+            matched.contains(&"Rust is fast.") && matched.contains(&"Pinecone stores vectors.")
+        );
+        assert!(results
+            .results
+            .iter()
+            .all(|result| (result.score - 1.0).abs() < 1e-6));
+
+        let results = run_query(&app_state, query(long_query, LongQueryStrategy::Average))
+            .await
+            .unwrap();
+        assert_eq!(store.batches.lock().unwrap().len(), 2);
+        let mean = mean_vector(&[
+            mock_embedding(&chunks[0], 16),
+            mock_embedding(&chunks[1], 16),
+        ])
+        .unwrap();
+        let expected = store
+            .query_vector(mean, "test-index", QueryOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(results.results[0].id, expected[0].id);
+        assert!((results.results[0].score - expected[0].score).abs() < 1e-6);
+
+        // Short queries are embedded as is
+        let results = run_query(
+            &app_state,
+            query("Python is slow.", LongQueryStrategy::Average),
+        )
+        .await
+        .unwrap();
+        assert_eq!(store.batches.lock().unwrap().len(), 2);
+        assert_eq!(results.results[0].text, "Python is slow.");
+    }
+
+    #[tokio::test]
+    async fn test_batch_query_reports_failures_in_place() {
+        let mut store = FakeStore::new(16);
+        let embeddings = embedded_chunks(&["Rust is fast.", "Python is slow."], 16);
+        store
+            .store("test-index", embeddings, Some(&text_to_embed("")), None)
+            .await
+            .unwrap();
+        let app_state = AppState::new(store.clone(), Some(SplitCriteria::EndOfSentence), None);
+
+        let query = |query_text: &str, top_k: u32| QueryInput {
+            query_text: query_text.to_string(),
+            ..query_input(Some(top_k))
+        };
+        let inputs = vec![
+            query("Rust is fast.", 1),
+            query("Rust is fast.", 0),
+            query("Python is slow.", 1),
+        ];
+        let Json(results) = batch_query(State(app_state), Json(inputs)).await.unwrap();
+        assert_eq!(results.len(), 3);
+        let BatchQueryResult::Results(first) = &results[0] else {
+            panic!("expected results, got {:?}", results[0]);
+        };
+        assert_eq!(first[0].text, "Rust is fast.");
+        // The invalid `top_k` only fails its own query
+        let BatchQueryResult::Error { status, .. } = &results[1] else {
+            panic!("expected an error, got {:?}", results[1]);
+        };
+        assert_eq!(*status, StatusCode::BAD_REQUEST.as_u16());
+        let BatchQueryResult::Results(third) = &results[2] else {
+            panic!("expected results, got {:?}", results[2]);
+        };
+        assert_eq!(third[0].text, "Python is slow.");
+        // The three query texts are embedded in a single batch
+        assert_eq!(store.batches.lock().unwrap().len(), 1);
+        assert_eq!(store.batches.lock().unwrap()[0].len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_debug_rescore_compares_stored_and_fresh_embeddings() {
+        let mut store = FakeStore::new(16);
+        // The second chunk was embedded by another model than the one now serving embeddings
+        let mut embeddings = embedded_chunks(&["Fresh text.", "Drifted text."], 16);
+        embeddings[1].1 = vec![mock_embedding("Other model", 16)];
+        store
+            .store("test-index", embeddings, Some(&text_to_embed("")), None)
+            .await
+            .unwrap();
+        let app_state = AppState::new(store, Some(SplitCriteria::EndOfSentence), None);
+        let input = |id: &str| RescoreInput {
+            index: "test-index".to_string(),
+            id: id.to_string(),
+            namespace: None,
+        };
+
+        let Json(response) = debug_rescore(State(app_state.clone()), Json(input("0")))
+            .await
+            .unwrap();
+        assert_eq!(response.text, "Fresh text.");
+        assert_eq!(response.fresh_embedding, response.stored_embedding);
+        assert!((response.similarity - 1.0).abs() < 1e-6);
+
+        let Json(response) = debug_rescore(State(app_state.clone()), Json(input("1")))
+            .await
+            .unwrap();
+        let expected = cosine_similarity(
+            &mock_embedding("Other model", 16),
+            &mock_embedding("Drifted text.", 16),
+        )
+        .unwrap();
+        assert_eq!(
+            response.fresh_embedding,
+            mock_embedding("Drifted text.", 16)
+        );
+        assert!((response.similarity - expected).abs() < 1e-6);
+        assert!(response.similarity < 0.99);
+
+        let (status, _) = debug_rescore(State(app_state), Json(input("2")))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_embed_duplicate_query_id_is_short_circuited() {
+        let embedder = MockEmbedder::new(4);
+        let cache = IdempotencyCache::new(NonZeroUsize::new(8).unwrap(), Duration::from_secs(60));
+        let app_state = test_state(&embedder)
+            .await
+            .with_idempotency_cache(Some(cache));
+        let input = text_to_embed("First sentence. Second sentence.");
+        // NOTE: There is no Pinecone index to store into, so the first request is recorded
+        // as if it had been successfully processed
+        let response = json!({ "query_id": input.query_id, "status": "success" });
+        app_state.idempotency_cache.as_ref().unwrap().insert(
+            &input.query_id,
+            &serde_json::to_string(&input).unwrap(),
+            response.clone(),
+        );
+
+        let Json(retried) = embed(State(app_state.clone()), ValidatedJson(input))
+            .await
+            .unwrap();
+        assert_eq!(retried, response);
+        assert!(embedder.inputs().is_empty());
+
+        // An edited document with the same query id is embedded again
+        let _ = embed(
+            State(app_state),
+            ValidatedJson(text_to_embed("First sentence, edited.")),
+        )
+        .await;
+        assert_eq!(embedder.inputs(), vec!["First sentence, edited."]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_embeds_are_bounded_by_the_embedding_concurrency() {
+        let embedder = MockEmbedder::new(4).with_delay(Duration::from_millis(50));
+        let app_state = in_memory_state(&embedder)
+            .await
+            .with_embedding_concurrency(3);
+        let responses = futures::future::join_all((0..8).map(|i| {
+            let mut input = text_to_embed(&format!("Document number {}.", i));
+            input.query_id = format!("query-{}", i);
+            input.create_if_missing = Some(true);
+            embed(State(app_state.clone()), ValidatedJson(input))
+        }))
+        .await;
+        assert!(responses.iter().all(|response| response.is_ok()));
+        assert_eq!(embedder.inputs().len(), 8);
+        // Concurrent requests embed concurrently, up to the bound and never beyond it
+        assert_eq!(embedder.max_in_flight(), 3);
+        let store = app_state.embedding_client.read().await;
+        assert_eq!(
+            store.list_chunks("test-index", None).await.unwrap().len(),
+            8
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embed_dry_run_returns_chunks_without_embedding() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await;
+        let mut input = text_to_embed("First sentence. Second sentence.");
+        input.dry_run = Some(true);
+        let Json(response) = embed(State(app_state), ValidatedJson(input)).await.unwrap();
+        assert_eq!(response["status"], "dry_run");
+        assert_eq!(
+            response["chunks"],
+            json!([
+                { "text": "First sentence.", "tokens": null },
+                { "text": "Second sentence.", "tokens": null },
+            ])
+        );
+        assert!(embedder.inputs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_embed_rejects_too_many_chunks() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await.with_limits(Limits {
+            max_chunks_per_document: 2,
+            ..Default::default()
+        });
+        let result = embed(
+            State(app_state),
+            ValidatedJson(text_to_embed(
+                "First sentence. Second sentence. Third sentence.",
+            )),
+        )
+        .await;
+        let (status, message) = result.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message.contains("3 chunks"), "{}", message);
+        assert!(embedder.inputs().is_empty());
+    }
+
+    fn token_limited_state(app_state: AppState, mode: TokenLimitMode) -> AppState {
+        AppState {
+            tokenizer: Some(Arc::new(word_level_tokenizer())),
+            ..app_state
+        }
+        .with_limits(Limits {
+            max_total_tokens_per_request: Some(7),
+            token_limit_mode: mode,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_embed_within_the_token_limit_is_unchanged() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = token_limited_state(test_state(&embedder).await, TokenLimitMode::Reject);
+        let mut input = text_to_embed("First sentence. Second sentence.");
+        input.dry_run = Some(true);
+        let Json(response) = embed(State(app_state), ValidatedJson(input)).await.unwrap();
+        // Each sentence is 3 tokens, as the period is a token of its own
+        assert_eq!(response["chunks"].as_array().unwrap().len(), 2);
+        assert!(response.get("dropped_chunks").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_embed_over_the_token_limit_is_rejected() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = token_limited_state(test_state(&embedder).await, TokenLimitMode::Reject);
+        let mut input = text_to_embed("First sentence. Second sentence. Third sentence.");
+        input.dry_run = Some(true);
+        let (status, message) = embed(State(app_state), ValidatedJson(input))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            message,
+            "content has 9 tokens, more than the maximum of 7 per request"
+        );
+        assert!(embedder.inputs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_embed_over_the_token_limit_is_truncated() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = token_limited_state(test_state(&embedder).await, TokenLimitMode::Truncate);
+        let mut input = text_to_embed("First sentence. Second sentence. Third sentence.");
+        input.dry_run = Some(true);
+        let Json(response) = embed(State(app_state.clone()), ValidatedJson(input))
+            .await
+            .unwrap();
+        assert_eq!(
+            response["chunks"],
+            json!([
+                { "text": "First sentence.", "tokens": 3 },
+                { "text": "Second sentence.", "tokens": 3 },
+            ])
+        );
+        assert_eq!(response["dropped_chunks"], 1);
+
+        // A first chunk over the limit leaves nothing to embed
+        let input = text_to_embed("A first sentence of far too many tokens.");
+        let (status, _) = embed(State(app_state), ValidatedJson(input))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_verbose_embed_reports_chunk_tokens() {
+        let store = FakeStore::new(16);
+        let app_state = AppState {
+            tokenizer: Some(Arc::new(word_level_tokenizer())),
+            ..AppState::new(store.clone(), Some(SplitCriteria::EndOfSentence), None)
+        };
+        let content = "First sentence. A much longer second sentence.";
+        let Json(response) = embed(
+            State(app_state.clone()),
+            ValidatedJson(text_to_embed(content)),
+        )
+        .await
+        .unwrap();
+        assert!(response.get("chunk_tokens").is_none());
+
+        let mut input = text_to_embed(content);
+        input.verbose = Some(true);
+        let Json(response) = embed(State(app_state), ValidatedJson(input)).await.unwrap();
+        assert_eq!(response["chunk_tokens"], json!([3, 6]));
+        let tokenizer = word_level_tokenizer();
+        let stored_tokens: Vec<usize> = store.vectors("test-index")[2..]
+            .iter()
+            .map(|vector| tokenizer.count(&vector.text).unwrap())
+            .collect();
+        assert_eq!(response["chunk_tokens"], json!(stored_tokens));
+
+        // Without a tokenizer, the chunks are still embedded and their counts are `null`
+        let app_state = AppState::new(store.clone(), Some(SplitCriteria::EndOfSentence), None);
+        let mut input = text_to_embed(content);
+        input.verbose = Some(true);
+        let Json(response) = embed(State(app_state), ValidatedJson(input)).await.unwrap();
+        assert_eq!(response["status"], "success");
+        assert!(response["chunk_tokens"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_is_rejected() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await.with_limits(Limits {
+            max_body_bytes: 1024,
+            ..Default::default()
+        });
+        let addr = spawn_server(router(app_state)).await;
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/embed", addr))
+            .json(&text_to_embed(&"Too long. ".repeat(200)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(embedder.inputs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_embed_bulk_stays_within_the_pipeline_bounds() {
+        let embedder = MockEmbedder::new(4).with_delay(Duration::from_millis(5));
+        let app_state = in_memory_state(&embedder)
+            .await
+            .with_embedding_concurrency(16)
+            .with_bulk_pipeline(PipelineOptions {
+                concurrency: 2,
+                buffer: 4,
+                progress_interval: 10,
+            });
+        let lines: Vec<String> = (0..40)
+            .map(|i| {
+                let mut input = text_to_embed(&format!("Document number {}.", i));
+                input.query_id = format!("doc-{}", i);
+                input.create_if_missing = Some(true);
+                serde_json::to_string(&input).unwrap()
+            })
+            .collect();
+
+        let Json(summary) = embed_bulk(State(app_state.clone()), lines.join("\n")).await;
+        assert_eq!(summary["processed"], 40);
+        assert_eq!(summary["failed"], json!([]));
+        assert_eq!(embedder.inputs().len(), 40);
+        // The workers embed concurrently, up to the pipeline concurrency and never beyond it
+        assert_eq!(embedder.max_in_flight(), 2);
+        let store = app_state.embedding_client.read().await;
+        assert_eq!(
+            store.list_chunks("test-index", None).await.unwrap().len(),
+            40
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embed_bulk_reports_malformed_lines() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await;
+        let mut lines = Vec::new();
+        for i in 0..3 {
+            let mut input = text_to_embed("First sentence. Second sentence.");
+            input.query_id = format!("doc-{}", i);
+            input.dry_run = Some(true);
+            lines.push(serde_json::to_string(&input).unwrap());
+        }
+        lines.insert(2, r#"{"query_id": "broken""#.to_string());
+
+        let Json(summary) = embed_bulk(State(app_state), lines.join("\n")).await;
+        assert_eq!(summary["processed"], 3);
+        let failed = summary["failed"].as_array().unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0]["line"], 3);
+        assert!(failed[0]["query_id"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_embed_json_maps_records_and_reports_missing_content() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await;
+        let input: EmbedJsonInput = serde_json::from_value(json!({
+            "index_name": "test-index",
+            "records": [
+                { "id": "a", "body": "First post.", "title": "First", "url": "https://a.b/1" },
+                { "id": "b", "title": "Missing its body" },
+                { "id": "c", "body": "Third post.", "title": "Third", "url": "https://a.b/3" },
+            ],
+            "content_field": "body",
+            "metadata_fields": ["title", "url"],
+            "id_field": "id",
+            "dry_run": true,
+        }))
+        .unwrap();
+
+        let Json(summary) = embed_json(State(app_state), Json(input)).await;
+        assert_eq!(summary["processed"], 2);
+        let failed = summary["failed"].as_array().unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0]["index"], 1);
+        assert!(failed[0]["error"]
+            .as_str()
+            .unwrap()
+            .contains("missing content field `body`"));
+    }
+
+    fn query_input(top_k: Option<u32>) -> QueryInput {
+        QueryInput {
+            index_name: "test-index".to_string(),
+            query_text: "query".to_string(),
+            top_k,
+            score_threshold: None,
+            offset: None,
+            filter: None,
+            date_from: None,
+            date_to: None,
+            include_values: None,
+            normalize_scores: None,
+            namespace: None,
+            context_window: None,
+            dedupe_by_document: None,
+            recency_half_life_days: None,
+            long_query_strategy: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_rejects_top_k_over_max() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await;
+        let result = query(State(app_state), Json(query_input(Some(MAX_TOP_K + 1)))).await;
+        let (status, message) = result.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message.contains("top_k"), "{}", message);
+        assert!(embedder.inputs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_rejects_pages_over_the_query_window() {
+        let store = FakeStore::new(4);
+        let app_state = AppState::new(store, Some(SplitCriteria::EndOfSentence), None);
+        for (offset, top_k) in [
+            (0, MAX_QUERY_WINDOW),
+            (MAX_QUERY_WINDOW - 10, 10),
+            (0, 5000),
+        ] {
+            let mut input = query_input(Some(top_k));
+            input.offset = Some(offset);
+            let (status, message) = query(State(app_state.clone()), Json(input))
+                .await
+                .unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(message.contains("offset + top_k"), "{}", message);
+        }
+
+        // The largest page fitting in the window is served
+        let mut input = query_input(Some(9));
+        input.offset = Some(MAX_QUERY_WINDOW - 10);
+        let Json(results) = query(State(app_state), Json(input)).await.unwrap();
+        assert_eq!(results.returned, 0);
+        assert!(!results.has_more);
+    }
+
+    #[tokio::test]
+    async fn test_query_applies_default_top_k() {
+        let embedder = MockEmbedder::new(4);
+        // A default over the maximum is only rejected when applied to a query without `top_k`
+        let app_state = test_state(&embedder)
+            .await
+            .with_default_top_k(MAX_TOP_K + 1);
+        let (status, message) = query(State(app_state.clone()), Json(query_input(None)))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            message.contains(&(MAX_TOP_K + 1).to_string()),
+            "{}",
+            message
+        );
+
+        let app_state = app_state.with_default_top_k(3);
+        assert_eq!(app_state.default_top_k, 3);
+        let page = QueryResults::from_window(vec![], 0, app_state.default_top_k as usize);
+        assert_eq!(page.top_k, 3);
+    }
+
+    #[tokio::test]
+    async fn test_default_split_criteria_label() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let app_state = AppState::new(test_client(addr), None, None);
+        let metadata =
+            build_metadata(String::new(), None, Some(&app_state.split_criteria)).unwrap();
+        assert_eq!(
+            metadata["split"],
+            crate::metadata::MetadataValue::from("token_count:512:1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_count_tokens_route() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let app_state = AppState::new(
+            test_client(addr),
+            None,
+            Some(Arc::new(word_level_tokenizer())),
+        );
+        let input = CountTokensInput {
+            text: "Count these four".to_string(),
+        };
+        let Json(response) = count_tokens(State(app_state), Json(input)).await.unwrap();
+        assert_eq!(response["tokens"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_compact_index_merges_the_chunks_of_a_document() {
+        let store = FakeStore::new(16);
+        let app_state = AppState::new(
+            store.clone(),
+            Some(SplitCriteria::EndOfSentence),
+            Some(Arc::new(word_level_tokenizer())),
+        );
+        let Json(response) = embed(
+            State(app_state.clone()),
+            ValidatedJson(text_to_embed(
+                "Rust is fast. Pinecone stores vectors. Chunks are merged.",
+            )),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["status"], "success");
+        assert_eq!(store.vectors("test-index").len(), 3);
+
+        let addr = spawn_server(router(app_state)).await;
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/compact_index", addr))
+            .json(&json!({ "index_name": "test-index", "target_tokens": 100 }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let summary: CompactionSummary = response.json().await.unwrap();
+        assert_eq!((summary.chunks_before, summary.chunks_after), (3, 1));
+        let vectors = store.vectors("test-index");
+        assert_eq!(vectors.len(), 1);
+        // The text between the sentences is not stored, they are joined by newlines
+        assert_eq!(
+            vectors[0].text,
+            "Rust is fast.\nPinecone stores vectors.\nChunks are merged."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_by_filter_rejects_unconfirmed_empty_filter() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await;
+        let input = DeleteByFilterInput {
+            index_name: "test-index".to_string(),
+            filter: json!({}),
+            confirm: None,
+            namespace: None,
+        };
+        let (status, message) = delete_by_filter(State(app_state), Json(input))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message.contains("confirm"));
+    }
+
+    #[tokio::test]
+    async fn test_internal_namespaces_are_rejected() {
+        let embedder = MockEmbedder::new(4);
+        let app_state = test_state(&embedder).await;
+        for namespace in [
+            crate::model_guard::MODEL_MARKER_NAMESPACE.to_string(),
+            crate::client::documents_namespace("tweets"),
+        ] {
+            let mut input = query_input(None);
+            input.namespace = Some(namespace.clone());
+            let (status, message) = query(State(app_state.clone()), Json(input))
+                .await
+                .unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(message.contains(&namespace), "{}", message);
+
+            let input = DeleteByFilterInput {
+                index_name: "test-index".to_string(),
+                filter: json!({"author": "atoma"}),
+                confirm: None,
+                namespace: Some(namespace),
+            };
+            let (status, _) = delete_by_filter(State(app_state.clone()), Json(input))
+                .await
+                .unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        assert!(embedder.inputs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_estimate_counts_chunks_and_tokens() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let tokenizer = word_level_tokenizer();
+        let app_state = AppState::new(
+            test_client(addr),
+            Some(SplitCriteria::EndOfSentence),
+            Some(Arc::new(tokenizer.clone())),
+        );
+        let content = "A first, short sentence. The second sentence is a little longer!  ";
+        let input = EstimateInput {
+            content: content.to_string(),
+        };
+        let Json(response) = estimate(State(app_state), Json(input)).await.unwrap();
+
+        let counts: Vec<usize> = [
+            "A first, short sentence.",
+            "The second sentence is a little longer!",
+        ]
+        .iter()
+        .map(|sentence| tokenizer.count(sentence).unwrap())
+        .collect();
+        assert_eq!(counts, vec![6, 8]);
+        assert_eq!(response["chunks"], 2);
+        assert_eq!(response["total_tokens"], 14);
+        assert_eq!(response["max_chunk_tokens"], 8);
+        assert!(embedder.inputs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_info_reports_version_and_split() {
+        let embedder = MockEmbedder::new(4);
+        let addr = spawn_server(embedder.router()).await;
+        let app_state = AppState::new(
+            test_client(addr),
+            Some(SplitCriteria::EndOfSentence),
+            Some(Arc::new(word_level_tokenizer())),
+        );
+        let Json(response) = info(State(app_state.clone())).await;
+        assert_eq!(response["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(response["default_split"], "end_of_sentence");
+        assert_eq!(response["tokenizer_model"], "WordLevel");
+        assert_eq!(
+            response["embedding_backend"],
+            format!("http://{}:{}/embed", addr.ip(), addr.port())
+        );
+        assert_eq!(response["namespace"], crate::client::CURRENT_NAME_SPACE);
+
+        let app_state = app_state.with_tokenizer_model(Some("BAAI/bge-small-en".to_string()));
+        let Json(response) = info(State(app_state)).await;
+        assert_eq!(response["tokenizer_model"], "BAAI/bge-small-en");
+    }
+
+    fn create_index_input(index_name: &str) -> CreateIndexInput {
+        CreateIndexInput {
+            index_name: index_name.to_string(),
+            dimension: 4,
+            metric: Some(crate::types::MetricOptions::Dotproduct),
+            wait: None,
+            wait_timeout_secs: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_index_rejects_duplicate_name() {
+        let control_plane = MockControlPlane::default().with_index("existing", 4, "cosine");
+        let addr = spawn_server(control_plane.router()).await;
+        let app_state = AppState::new(test_client(addr), None, None);
+
+        let (status, message) = create_index(
+            State(app_state.clone()),
+            Json(create_index_input("existing")),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(message.contains("index existing already exists"));
+        assert_eq!(*control_plane.create_calls.lock().unwrap(), 0);
+
+        create_index(State(app_state), Json(create_index_input("created")))
+            .await
+            .unwrap();
+        assert_eq!(*control_plane.create_calls.lock().unwrap(), 1);
+        let indexes = control_plane.indexes.lock().unwrap();
+        assert_eq!(indexes["created"]["metric"], "dotproduct");
+    }
+
+    #[tokio::test]
+    async fn test_create_index_rejects_unknown_metric() {
+        let control_plane = MockControlPlane::default();
+        let addr = spawn_server(control_plane.router()).await;
+        let app_addr = spawn_server(router(AppState::new(test_client(addr), None, None))).await;
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/create_index", app_addr))
+            .json(&json!({ "index_name": "index", "dimension": 4, "metric": "Manhattan" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(response.text().await.unwrap().contains("Dotproduct"));
+        assert_eq!(*control_plane.create_calls.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_request_id_is_echoed_and_propagated() {
+        let embedder = MockEmbedder::new(4);
+        let app_addr = spawn_server(router(test_state(&embedder).await)).await;
+        let http = reqwest::Client::new();
+
+        let response = http
+            .get(format!("http://{}/query", app_addr))
+            .header(REQUEST_ID_HEADER, "test-request-id")
+            .json(&json!({ "index_name": "test-index", "query_text": "hello" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "test-request-id");
+        // The query itself fails against the mocked control plane, after the embedding call
+        assert_eq!(
+            *embedder.request_ids.lock().unwrap(),
+            vec![Some("test-request-id".to_string())]
+        );
+
+        // Without the header, an id is generated for the request
+        let response = http
+            .get(format!("http://{}/info", app_addr))
+            .send()
+            .await
+            .unwrap();
+        let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(!request_id.is_empty());
+        assert_ne!(request_id, "test-request-id");
+    }
+
+    #[tokio::test]
+    async fn test_query_stream_events() {
+        use axum::response::IntoResponse;
+
+        let results: Vec<QueryResponse> = (0..3)
+            .map(|i| QueryResponse {
+                id: i.to_string(),
+                score: 1.0 - i as f32 / 10.0,
+                raw_score: None,
+                embedding: vec![],
+                text: format!("result {}", i),
+                index_name: None,
+                query_id: None,
+                chunk_index: None,
+                context: None,
+                timestamp: None,
+                author: None,
+                norm: None,
+            })
+            .collect();
+        let response = Sse::new(stream_results(results)).into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        let data: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect();
+        assert_eq!(data.len(), 4);
+        for (i, event) in data[..3].iter().enumerate() {
+            let result: QueryResponse = serde_json::from_str(event).unwrap();
+            assert_eq!(result.text, format!("result {}", i));
+        }
+        assert_eq!(data[3], "[DONE]");
+        assert!(body.contains("event: done"));
+    }
 }
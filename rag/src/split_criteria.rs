@@ -1,17 +1,103 @@
+use std::{fmt, str::FromStr};
+
 use anyhow::{anyhow, Result};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use tokenizers::Tokenizer;
 use unicode_segmentation::UnicodeSegmentation;
 
+use crate::tokens::TokenCounter;
+
+/// A chunk of a document, as produced by `SplitCriteria::split_chunks` and stored along with
+/// its embedding.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    /// The text of the chunk
+    pub text: String,
+    /// The `query_id` of the document the chunk is part of, if any
+    pub query_id: Option<String>,
+    /// The position of the chunk among the chunks of its document, from `0`
+    pub index: usize,
+    /// The number of chunks of its document
+    pub total: usize,
+    /// The byte range of the chunk in the content of its document, unless its text is not
+    /// found as is in the content, e.g. for words packed across line breaks
+    pub source_offsets: Option<(usize, usize)>,
+}
+
+impl Chunk {
+    /// Builds the chunks of a document from the texts it is split into, in order.
+    ///
+    /// Each text is looked for in `content` from the start of the previous chunk on, as chunks
+    /// holding context sentences overlap the previous ones.
+    pub fn from_texts(texts: Vec<String>, content: &str, query_id: Option<&str>) -> Vec<Chunk> {
+        let total = texts.len();
+        let mut cursor = 0;
+        texts
+            .into_iter()
+            .enumerate()
+            .map(|(index, text)| {
+                let source_offsets = content[cursor..].find(text.as_str()).map(|start| {
+                    let start = cursor + start;
+                    // NOTE: The next chunk starts after this one, even when their texts are equal
+                    cursor = start + text.chars().next().map_or(0, char::len_utf8);
+                    (start, start + text.len())
+                });
+                Chunk {
+                    text,
+                    query_id: query_id.map(str::to_string),
+                    index,
+                    total,
+                    source_offsets,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Splits a text into sentences, used by the sentence based split criteria.
+///
+/// The default `UnicodeSentenceSegmenter` follows the Unicode sentence boundary rules,
+/// which struggle with languages using other terminators, or no spaces between sentences.
+/// A language specific segmenter can be injected with `SplitCriteria::split_with_segmenter`.
+pub trait SentenceSegmenter: Send + Sync {
+    /// Returns the sentences of the text, in order and trimmed.
+    fn sentences(&self, text: &str) -> Vec<String>;
+}
+
+/// Segments sentences following the Unicode sentence boundary rules (UAX #29).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnicodeSentenceSegmenter;
+
+impl SentenceSegmenter for UnicodeSentenceSegmenter {
+    fn sentences(&self, text: &str) -> Vec<String> {
+        text.unicode_sentences()
+            .map(|s| s.trim().to_string())
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 /// Defines the criteria for splitting text into chunks.
+///
+/// The criteria are displayed as their variant name and parameters, e.g. `token_count:512:1`.
 pub enum SplitCriteria {
     /// Splits the text at the end of each sentence.
     EndOfSentence,
     /// Splits the text at paragraph breaks.
+    ///
+    /// Headings are kept with the paragraph following them, rather than standing alone as
+    /// meaningless chunks. A paragraph is taken for a heading only if it is a single line of
+    /// at most `MAX_HEADING_CHARS` characters, which is either a Markdown heading (e.g.
+    /// `# Title`) or does not end like a sentence or a clause (with `.`, `!`, `?`, `,` or `;`).
+    /// Consecutive headings are all kept with the next paragraph, and headings ending the
+    /// text are kept as a chunk of their own.
     Paragraph,
     /// Splits the text based on a maximum token count and includes context sentences.
     ///
+    /// Each chunk holds whole sentences: a sentence and its context sentences. Words are
+    /// only packed when a single sentence exceeds `max_tokens`, and then never across a
+    /// sentence boundary, so chunks always end where a sentence ends whenever it fits.
+    ///
     /// # Arguments
     ///
     /// * `max_tokens` - The maximum number of tokens allowed per chunk.
@@ -20,15 +106,271 @@ pub enum SplitCriteria {
         max_tokens: usize,
         context_sentences: usize,
     },
+    /// Splits the text at paragraph breaks, splitting oversized paragraphs by token count.
+    ///
+    /// Paragraphs fitting in `max_tokens` are kept whole as a single chunk. Only the
+    /// paragraphs exceeding it are split as with `TokenCount`, without context sentences,
+    /// so chunks never span two paragraphs. Empty paragraphs are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_tokens` - The maximum number of tokens allowed per chunk.
+    ParagraphBounded { max_tokens: usize },
+}
+
+impl fmt::Display for SplitCriteria {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitCriteria::EndOfSentence => write!(f, "end_of_sentence"),
+            SplitCriteria::Paragraph => write!(f, "paragraph"),
+            SplitCriteria::TokenCount {
+                max_tokens,
+                context_sentences,
+            } => write!(f, "token_count:{}:{}", max_tokens, context_sentences),
+            SplitCriteria::ParagraphBounded { max_tokens } => {
+                write!(f, "paragraph_bounded:{}", max_tokens)
+            }
+        }
+    }
+}
+
+/// Parses split criteria from their `Display` form, e.g. `token_count:512:1`.
+impl FromStr for SplitCriteria {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = |value: &str| {
+            value
+                .parse::<usize>()
+                .map_err(|e| anyhow!("Invalid split criteria `{}`: {}", s, e))
+        };
+        match s.split(':').collect::<Vec<_>>().as_slice() {
+            ["end_of_sentence"] => Ok(SplitCriteria::EndOfSentence),
+            ["paragraph"] => Ok(SplitCriteria::Paragraph),
+            ["token_count", max_tokens, context_sentences] => Ok(SplitCriteria::TokenCount {
+                max_tokens: parse(max_tokens)?,
+                context_sentences: parse(context_sentences)?,
+            }),
+            ["paragraph_bounded", max_tokens] => Ok(SplitCriteria::ParagraphBounded {
+                max_tokens: parse(max_tokens)?,
+            }),
+            _ => Err(anyhow!(
+                "Invalid split criteria `{}`, expected `end_of_sentence`, `paragraph`, \
+                 `token_count:<max_tokens>:<context_sentences>` or `paragraph_bounded:<max_tokens>`",
+                s
+            )),
+        }
+    }
+}
+
+/// A chunk of a text, as previewed by `SplitCriteria::preview`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkPreview {
+    /// The text of the chunk
+    pub text: String,
+    /// The number of tokens of the chunk, if a tokenizer is given
+    pub tokens: Option<usize>,
+}
+
+/// Lazily produced chunks of a text, see `SplitCriteria::split_iter`.
+pub type Chunks<'a> = Box<dyn Iterator<Item = Result<String>> + Send + 'a>;
+
+/// Packs the sentences of a text into chunks of at most `max_tokens` tokens, with
+/// `context_sentences` previous sentences each, one chunk at a time.
+struct TokenCountChunks<'a> {
+    sentences: Vec<String>,
+    index: usize,
+    max_tokens: usize,
+    context_sentences: usize,
+    tokenizer: &'a dyn TokenCounter,
+}
+
+impl TokenCountChunks<'_> {
+    /// Packs the chunk of the current sentence, if any, and moves to the next sentence.
+    fn next_chunk(&mut self) -> Result<Option<String>> {
+        let index = self.index;
+        self.index += 1;
+        let max_tokens = self.max_tokens;
+        let tokenizer = self.tokenizer;
+        let sentences = &mut self.sentences;
+
+        // Determine the start index for context
+        let context_start = index.saturating_sub(self.context_sentences);
+
+        // Collect context sentences and the current sentence
+        let current_sentences: Vec<&str> = sentences[context_start..=index]
+            .iter()
+            .map(|s| s.as_str())
+            .collect();
+        let mut current_chunk_text = current_sentences.join(" ");
+
+        // Tokenize the current chunk
+        let token_count = tokenizer.count(&current_chunk_text)?;
+
+        // If token count exceeds max_tokens, adjust current_sentences
+        if token_count <= max_tokens {
+            return Ok(Some(current_chunk_text.trim().to_string()));
+        }
+        // Remove the earliest context sentences
+        let mut adjusted_current_sentences = current_sentences.clone();
+        while adjusted_current_sentences.len() > 1 {
+            adjusted_current_sentences.remove(0); // Remove first sentence
+            current_chunk_text = adjusted_current_sentences.join(" ");
+            let token_count = tokenizer.count(&current_chunk_text)?;
+            if token_count <= max_tokens {
+                break;
+            }
+        }
+
+        // If token count still exceeds max_tokens, split the sentence
+        if token_count <= max_tokens {
+            return Ok(Some(current_chunk_text.trim().to_string()));
+        }
+        // Split the sentence into words and fit as many as possible
+        let sentence = &sentences[index];
+        let words: Vec<&str> = sentence.unicode_words().collect();
+        let mut word_index = 0;
+        let mut word_chunk = Vec::new();
+        let mut word_chunk_text = String::new();
+        let mut word_token_count = 0;
+
+        while word_index < words.len() {
+            let word = words[word_index];
+            let word_to_encode = if word_index == 0 {
+                word
+            } else {
+                // Include a leading space
+                &format!(" {}", word)
+            };
+
+            // Tokenize the word
+            let word_token_len = tokenizer.encode(word_to_encode)?.len();
+
+            if word_token_len > max_tokens {
+                // NOTE: If a single word exceeds max_tokens, place it in a chunk by itself
+                if word_chunk.is_empty() {
+                    word_chunk.push(word_to_encode.to_string());
+                    word_chunk_text = word_chunk.join("");
+                    word_index += 1;
+                }
+                break;
+            }
+
+            if word_token_count + word_token_len > max_tokens {
+                break;
+            }
+
+            word_chunk.push(word_to_encode.to_string());
+            word_chunk_text = word_chunk.join("");
+            word_token_count += word_token_len;
+            word_index += 1;
+        }
+
+        // Move to the next set of words
+        if word_index < words.len() {
+            // There are remaining words in the sentence
+            let remaining_sentence = words[word_index..].join(" ");
+            sentences.insert(index + 1, remaining_sentence);
+        }
+
+        if word_chunk.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(word_chunk_text.trim().to_string()))
+        }
+    }
+}
+
+impl Iterator for TokenCountChunks<'_> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.sentences.len() {
+            match self.next_chunk() {
+                Ok(Some(chunk)) => return Some(Ok(chunk)),
+                Ok(None) => continue,
+                Err(e) => {
+                    // NOTE: The iterator ends after an error, as `split` stops at the first one
+                    self.index = self.sentences.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Maximum number of characters of a paragraph taken for a heading by `Paragraph` splitting.
+pub const MAX_HEADING_CHARS: usize = 80;
+
+/// Whether a paragraph looks like a heading, see `SplitCriteria::Paragraph`.
+fn is_heading(paragraph: &str) -> bool {
+    !paragraph.is_empty()
+        && !paragraph.contains('\n')
+        && paragraph.chars().count() <= MAX_HEADING_CHARS
+        && (paragraph.starts_with('#') || !paragraph.ends_with(['.', '!', '?', ',', ';']))
+}
+
+/// Paragraphs of a text, each joined with the headings right before it.
+struct Paragraphs<'a> {
+    paragraphs: std::str::Split<'a, &'static str>,
+}
+
+impl Iterator for Paragraphs<'_> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut headings = Vec::new();
+        for paragraph in self.paragraphs.by_ref() {
+            let paragraph = paragraph.trim();
+            // NOTE: Empty paragraphs between a heading and its body are dropped
+            if paragraph.is_empty() && !headings.is_empty() {
+                continue;
+            }
+            let heading = is_heading(paragraph);
+            headings.push(paragraph);
+            if !heading {
+                return Some(Ok(headings.join("\n\n")));
+            }
+        }
+        (!headings.is_empty()).then(|| Ok(headings.join("\n\n")))
+    }
+}
+
+/// Ends a chunk iterator after its first error.
+struct StopAtError<'a> {
+    chunks: Chunks<'a>,
+    failed: bool,
+}
+
+impl Iterator for StopAtError<'_> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let chunk = self.chunks.next()?;
+        self.failed = chunk.is_err();
+        Some(chunk)
+    }
 }
 
 impl SplitCriteria {
+    /// Whether the criteria counts tokens, and so requires a tokenizer to split texts.
+    pub fn needs_tokenizer(&self) -> bool {
+        matches!(
+            self,
+            SplitCriteria::TokenCount { .. } | SplitCriteria::ParagraphBounded { .. }
+        )
+    }
+
     /// Splits the given text into chunks based on the specified criteria.
     ///
     /// # Arguments
     ///
     /// * `text` - The input text to be split into chunks.
-    /// * `tokenizer` - An optional reference to a `Tokenizer` used for token-based splitting.
+    /// * `tokenizer` - An optional `TokenCounter` used for token-based splitting.
     ///
     /// # Returns
     ///
@@ -42,179 +384,236 @@ impl SplitCriteria {
     /// - `EndOfSentence`: Splits at the end of each sentence.
     /// - `Paragraph`: Splits at paragraph breaks (empty lines).
     /// - `TokenCount`: Splits based on a maximum token count per chunk and includes context sentences.
+    /// - `ParagraphBounded`: Splits at paragraph breaks, and by token count within oversized paragraphs.
     ///
-    /// For `TokenCount`, a tokenizer must be provided. Each chunk will include
+    /// For `TokenCount` and `ParagraphBounded`, a tokenizer must be provided. Each chunk will include
     /// the specified number of previous sentences as context, without exceeding the maximum token count.
     ///
     /// # Errors
     ///
     /// Returns an error if:
-    /// - Tokenization fails when using `TokenCount` criteria.
-    /// - No tokenizer is provided for `TokenCount` criteria.
-    pub fn split(&self, text: &str, tokenizer: Option<&Tokenizer>) -> Result<Vec<String>> {
+    /// - Tokenization fails when using `TokenCount` or `ParagraphBounded` criteria.
+    /// - No tokenizer is provided for `TokenCount` or `ParagraphBounded` criteria.
+    pub fn split(&self, text: &str, tokenizer: Option<&dyn TokenCounter>) -> Result<Vec<String>> {
+        self.split_with_segmenter(text, tokenizer, None)
+    }
+
+    /// Splits the given text into chunks, as `split` does, with the given sentence segmenter.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The input text to be split into chunks.
+    /// * `tokenizer` - An optional `TokenCounter` used for token-based splitting.
+    /// * `segmenter` - An optional segmenter splitting the text into sentences for the
+    ///   `EndOfSentence`, `TokenCount` and `ParagraphBounded` criteria, defaults to
+    ///   `UnicodeSentenceSegmenter`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error in the same cases as `split`.
+    pub fn split_with_segmenter(
+        &self,
+        text: &str,
+        tokenizer: Option<&dyn TokenCounter>,
+        segmenter: Option<&dyn SentenceSegmenter>,
+    ) -> Result<Vec<String>> {
+        self.split_iter_with_segmenter(text, tokenizer, segmenter)
+            .collect()
+    }
+
+    /// Splits the content of a document into its `Chunk`s, as `split_with_segmenter` does.
+    ///
+    /// Chunks that are empty once trimmed are not worth embedding, so they are dropped before
+    /// the chunks are numbered.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error in the same cases as `split`.
+    pub fn split_chunks(
+        &self,
+        content: &str,
+        query_id: Option<&str>,
+        tokenizer: Option<&dyn TokenCounter>,
+        segmenter: Option<&dyn SentenceSegmenter>,
+    ) -> Result<Vec<Chunk>> {
+        let texts = self
+            .split_with_segmenter(content, tokenizer, segmenter)?
+            .into_iter()
+            .filter(|text| !text.trim().is_empty())
+            .collect();
+        Ok(Chunk::from_texts(texts, content, query_id))
+    }
+
+    /// Lazily splits the given text into chunks, yielding the same chunks as `split`.
+    ///
+    /// Chunks are produced one at a time, so a caller can start embedding the first chunks
+    /// of a large document before the rest of it is split, without holding all of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The input text to be split into chunks.
+    /// * `tokenizer` - An optional `TokenCounter` used for token-based splitting.
+    ///
+    /// # Errors
+    ///
+    /// The iterator yields an error in the same cases as `split`, and then ends.
+    pub fn split_iter<'a>(
+        &'a self,
+        text: &'a str,
+        tokenizer: Option<&'a dyn TokenCounter>,
+    ) -> Chunks<'a> {
+        self.split_iter_with_segmenter(text, tokenizer, None)
+    }
+
+    /// Lazily splits the given text into chunks, as `split_iter` does, with the given
+    /// sentence segmenter.
+    ///
+    /// The default `UnicodeSentenceSegmenter` segments `EndOfSentence` chunks lazily, while
+    /// custom segmenters return all the sentences at once. `TokenCount` always segments the
+    /// whole text (or paragraph, for `ParagraphBounded`) upfront to look back at context
+    /// sentences, but still packs chunks one at a time.
+    pub fn split_iter_with_segmenter<'a>(
+        &'a self,
+        text: &'a str,
+        tokenizer: Option<&'a dyn TokenCounter>,
+        segmenter: Option<&'a dyn SentenceSegmenter>,
+    ) -> Chunks<'a> {
         match self {
-            SplitCriteria::EndOfSentence => {
-                let sentences = text
-                    .unicode_sentences()
-                    .map(|s| s.trim().to_string())
-                    .collect();
-                Ok(sentences)
-            }
-            SplitCriteria::Paragraph => {
-                let paragraphs = text.split("\n\n").map(|p| p.trim().to_string()).collect();
-                Ok(paragraphs)
-            }
+            SplitCriteria::EndOfSentence => match segmenter {
+                Some(segmenter) => Box::new(segmenter.sentences(text).into_iter().map(Ok)),
+                None => Box::new(text.unicode_sentences().map(|s| Ok(s.trim().to_string()))),
+            },
+            SplitCriteria::Paragraph => Box::new(Paragraphs {
+                paragraphs: text.split("\n\n"),
+            }),
             SplitCriteria::TokenCount {
                 max_tokens,
                 context_sentences,
-            } => {
-                if let Some(tokenizer) = tokenizer {
-                    let mut chunks = Vec::new();
-                    // Change sentences to own its data
-                    let mut sentences: Vec<String> = text
-                        .unicode_sentences()
-                        .map(|s| s.trim().to_string())
-                        .collect();
-                    let mut index = 0;
-
-                    while index < sentences.len() {
-                        // Determine the start index for context
-                        let context_start = if index >= *context_sentences {
-                            index - *context_sentences
-                        } else {
-                            0
-                        };
-
-                        // Collect context sentences and the current sentence
-                        let current_sentences: Vec<&str> = sentences[context_start..=index]
-                            .iter()
-                            .map(|s| s.as_str())
-                            .collect();
-                        let mut current_chunk_text = current_sentences.join(" ");
-
-                        // Tokenize the current chunk
-                        let encoding =
-                            tokenizer
-                                .encode(current_chunk_text.clone(), true)
-                                .map_err(|e| {
-                                    anyhow!(
-                                        "Failed to encode text: '{}', with error: {}",
-                                        current_chunk_text,
-                                        e
-                                    )
-                                })?;
-                        let token_count = encoding.get_ids().len();
-
-                        // If token count exceeds max_tokens, adjust current_sentences
-                        if token_count > *max_tokens {
-                            // Remove the earliest context sentences
-                            let mut adjusted_current_sentences = current_sentences.clone();
-                            while adjusted_current_sentences.len() > 1 {
-                                adjusted_current_sentences.remove(0); // Remove first sentence
-                                current_chunk_text = adjusted_current_sentences.join(" ");
-                                let encoding = tokenizer
-                                    .encode(current_chunk_text.clone(), true)
-                                    .map_err(|e| {
-                                    anyhow!(
-                                        "Failed to encode text: '{}', with error: {}",
-                                        current_chunk_text,
-                                        e
-                                    )
-                                })?;
-                                let token_count = encoding.get_ids().len();
-                                if token_count <= *max_tokens {
-                                    break;
-                                }
-                            }
-
-                            // If token count still exceeds max_tokens, split the sentence
-                            if token_count > *max_tokens {
-                                // Split the sentence into words and fit as many as possible
-                                let sentence = &sentences[index];
-                                let words: Vec<&str> = sentence.unicode_words().collect();
-                                let mut word_index = 0;
-                                let mut word_chunk = Vec::new();
-                                let mut word_chunk_text = String::new();
-                                let mut word_token_count = 0;
-
-                                while word_index < words.len() {
-                                    let word = words[word_index];
-                                    let word_to_encode = if word_index == 0 {
-                                        word
-                                    } else {
-                                        // Include a leading space
-                                        &format!(" {}", word)
-                                    };
-
-                                    // Tokenize the word
-                                    let encoding =
-                                        tokenizer.encode(word_to_encode, false).map_err(|e| {
-                                            anyhow!(
-                                                "Failed to encode word: '{}', with error: {}",
-                                                word_to_encode,
-                                                e
-                                            )
-                                        })?;
-                                    let word_tokens = encoding.get_ids();
-                                    let word_token_len = word_tokens.len();
-
-                                    if word_token_len > *max_tokens {
-                                        // NOTE: If a single word exceeds max_tokens, place it in a chunk by itself
-                                        if word_chunk.is_empty() {
-                                            word_chunk.push(word_to_encode.to_string());
-                                            word_chunk_text = word_chunk.join("");
-                                            word_index += 1;
-                                        }
-                                        break;
-                                    }
-
-                                    if word_token_count + word_token_len > *max_tokens {
-                                        break;
-                                    }
-
-                                    word_chunk.push(word_to_encode.to_string());
-                                    word_chunk_text = word_chunk.join("");
-                                    word_token_count += word_token_len;
-                                    word_index += 1;
-                                }
-
-                                if !word_chunk.is_empty() {
-                                    chunks.push(word_chunk_text.trim().to_string());
-                                }
-
-                                // Move to the next set of words
-                                if word_index < words.len() {
-                                    // There are remaining words in the sentence
-                                    let remaining_sentence = words[word_index..].join(" ");
-                                    sentences.insert(index + 1, remaining_sentence);
-                                }
-                            } else {
-                                chunks.push(current_chunk_text.trim().to_string());
+            } => match tokenizer {
+                Some(tokenizer) => {
+                    let segmenter = segmenter.unwrap_or(&UnicodeSentenceSegmenter);
+                    Box::new(TokenCountChunks {
+                        sentences: segmenter.sentences(text),
+                        index: 0,
+                        max_tokens: *max_tokens,
+                        context_sentences: *context_sentences,
+                        tokenizer,
+                    })
+                }
+                None => Box::new(std::iter::once(Err(anyhow!(
+                    "No tokenizer provided for TokenCount splitting"
+                )))),
+            },
+            SplitCriteria::ParagraphBounded { max_tokens } => {
+                let Some(tokenizer) = tokenizer else {
+                    return Box::new(std::iter::once(Err(anyhow!(
+                        "No tokenizer provided for ParagraphBounded splitting"
+                    ))));
+                };
+                let max_tokens = *max_tokens;
+                let segmenter = segmenter.unwrap_or(&UnicodeSentenceSegmenter);
+                let chunks = text
+                    .split("\n\n")
+                    .map(str::trim)
+                    .filter(|paragraph| !paragraph.is_empty())
+                    .flat_map(move |paragraph| -> Chunks<'a> {
+                        match tokenizer.count(paragraph) {
+                            Ok(count) if count <= max_tokens => {
+                                Box::new(std::iter::once(Ok(paragraph.to_string())))
                             }
-                        } else {
-                            chunks.push(current_chunk_text.trim().to_string());
+                            Ok(_) => Box::new(TokenCountChunks {
+                                sentences: segmenter.sentences(paragraph),
+                                index: 0,
+                                max_tokens,
+                                context_sentences: 0,
+                                tokenizer,
+                            }),
+                            Err(e) => Box::new(std::iter::once(Err(e))),
                         }
-
-                        index += 1;
-                    }
-
-                    Ok(chunks)
-                } else {
-                    Err(anyhow!("No tokenizer provided for TokenCount splitting"))
-                }
+                    });
+                Box::new(StopAtError {
+                    chunks: Box::new(chunks),
+                    failed: false,
+                })
             }
         }
     }
+
+    /// Splits the given text into chunks, processing its paragraphs in parallel.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The input text to be split into chunks.
+    /// * `tokenizer` - An optional `TokenCounter` used for token-based splitting.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing a `Vec<String>` of text chunks if successful,
+    /// or an `Error` if splitting any of the paragraphs fails.
+    ///
+    /// # Behavior
+    ///
+    /// The text is first split at paragraph breaks (empty lines), then each paragraph is
+    /// split with `split` on the `rayon` thread pool, and the chunks are concatenated in
+    /// paragraph order. Since paragraphs are split independently, `TokenCount` context
+    /// sentences never cross a paragraph boundary, unlike with `split`. Sentences are
+    /// segmented with the default `UnicodeSentenceSegmenter`.
+    pub fn split_parallel(
+        &self,
+        text: &str,
+        tokenizer: Option<&dyn TokenCounter>,
+    ) -> Result<Vec<String>> {
+        // Paragraph chunks are whole paragraphs already, possibly joined with their headings
+        if let SplitCriteria::Paragraph = self {
+            return self.split(text, tokenizer);
+        }
+        let paragraphs: Vec<&str> = text.split("\n\n").collect();
+        let chunks = paragraphs
+            .par_iter()
+            .map(|paragraph| self.split(paragraph, tokenizer))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(chunks.into_iter().flatten().collect())
+    }
+
+    /// Splits the given text with `split`, and counts the tokens of each chunk.
+    ///
+    /// Chunks that are empty once trimmed are left out, as they are never embedded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error in the same cases as `split`, or if counting the tokens of a chunk fails.
+    pub fn preview(
+        &self,
+        text: &str,
+        tokenizer: Option<&dyn TokenCounter>,
+    ) -> Result<Vec<ChunkPreview>> {
+        self.split(text, tokenizer)?
+            .into_iter()
+            .filter(|chunk| !chunk.trim().is_empty())
+            .map(|text| {
+                let tokens = tokenizer
+                    .map(|tokenizer| tokenizer.count(&text))
+                    .transpose()?;
+                Ok(ChunkPreview { text, tokens })
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::word_level_tokenizer;
     use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
     use serial_test::serial;
+    use tokenizers::Tokenizer;
 
-    // Helper function to create a simple tokenizer for testing
+    // Helper function to create a simple tokenizer for testing, loaded from `TOKENIZER_PATH`
+    // if set, e.g. in air-gapped environments
     fn create_test_tokenizer() -> Tokenizer {
+        if let Ok(path) = std::env::var("TOKENIZER_PATH") {
+            return Tokenizer::from_file(path).expect("Failed to load the tokenizer");
+        }
         let model_id = "TinyLlama/TinyLlama-1.1B-Chat-v1.0".to_string();
         let revision = "main".to_string();
         let api = ApiBuilder::new()
@@ -231,6 +630,155 @@ mod tests {
         Tokenizer::from_file(tokenizer_filename).expect("Failed to load the tokenizer")
     }
 
+    /// Counts one token per whitespace separated word, with no special tokens.
+    struct WhitespaceCounter;
+
+    impl TokenCounter for WhitespaceCounter {
+        fn count(&self, text: &str) -> Result<usize> {
+            Ok(text.split_whitespace().count())
+        }
+
+        fn encode(&self, text: &str) -> Result<Vec<u32>> {
+            Ok((0..text.split_whitespace().count() as u32).collect())
+        }
+
+        fn decode(&self, _ids: &[u32]) -> Result<String> {
+            Err(anyhow!("Word counts cannot be decoded"))
+        }
+    }
+
+    struct PipeSegmenter;
+
+    impl SentenceSegmenter for PipeSegmenter {
+        fn sentences(&self, text: &str) -> Vec<String> {
+            text.split('|').map(|s| s.trim().to_string()).collect()
+        }
+    }
+
+    #[test]
+    fn test_split_iter_matches_split() {
+        let tokenizer = word_level_tokenizer();
+        let text = "First sentence here. Second one!\n\nA new paragraph, with a rather long sentence that needs splitting. Short.\n\nEnd.";
+        let criteria = [
+            SplitCriteria::EndOfSentence,
+            SplitCriteria::Paragraph,
+            SplitCriteria::TokenCount {
+                max_tokens: 6,
+                context_sentences: 1,
+            },
+            SplitCriteria::ParagraphBounded { max_tokens: 6 },
+        ];
+        for criteria in criteria {
+            let chunks: Vec<String> = criteria
+                .split_iter(text, Some(&tokenizer))
+                .collect::<Result<_>>()
+                .unwrap();
+            assert_eq!(chunks, criteria.split(text, Some(&tokenizer)).unwrap());
+            assert!(!chunks.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_split_iter_takes_first_chunks_and_stops_at_error() {
+        let tokenizer = word_level_tokenizer();
+        let text = "Some sentence. ".repeat(10_000);
+        let criteria = SplitCriteria::TokenCount {
+            max_tokens: 8,
+            context_sentences: 1,
+        };
+        let first: Vec<String> = criteria
+            .split_iter(&text, Some(&tokenizer))
+            .take(2)
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            first,
+            vec!["Some sentence.", "Some sentence. Some sentence."]
+        );
+
+        let mut chunks = SplitCriteria::ParagraphBounded { max_tokens: 8 }.split_iter(&text, None);
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn test_paragraph_bounded_keeps_small_paragraph_whole() {
+        let tokenizer = word_level_tokenizer();
+        let criteria = SplitCriteria::ParagraphBounded { max_tokens: 10 };
+        let text = "A short paragraph. With two sentences.";
+        let chunks = criteria.split(text, Some(&tokenizer)).unwrap();
+        assert_eq!(chunks, vec![text]);
+    }
+
+    #[test]
+    fn test_paragraph_bounded_splits_giant_paragraph() {
+        let tokenizer = word_level_tokenizer();
+        let criteria = SplitCriteria::ParagraphBounded { max_tokens: 4 };
+        let text = "One two three. Four five six. Seven eight nine ten eleven.";
+        let chunks = criteria.split(text, Some(&tokenizer)).unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                "One two three.",
+                "Four five six.",
+                "Seven eight nine ten",
+                "eleven"
+            ]
+        );
+        for chunk in &chunks {
+            assert!(tokenizer.count(chunk).unwrap() <= 4);
+        }
+    }
+
+    #[test]
+    fn test_paragraph_bounded_mixed_input() {
+        let tokenizer = word_level_tokenizer();
+        let criteria = SplitCriteria::ParagraphBounded { max_tokens: 6 };
+        let text =
+            "Small one. Fits.\n\nThis paragraph is too long. So it gets split.\n\n\n\nLast one.";
+        let chunks = criteria.split(text, Some(&tokenizer)).unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                "Small one. Fits.",
+                "This paragraph is too long.",
+                "So it gets split.",
+                "Last one."
+            ]
+        );
+        assert_eq!(criteria.to_string(), "paragraph_bounded:6");
+        assert!(criteria.split(text, None).is_err());
+    }
+
+    #[test]
+    fn test_split_with_custom_segmenter() {
+        let text = "first part | second part. still second | third";
+        let chunks = SplitCriteria::EndOfSentence
+            .split_with_segmenter(text, None, Some(&PipeSegmenter))
+            .unwrap();
+        assert_eq!(
+            chunks,
+            vec!["first part", "second part. still second", "third"]
+        );
+
+        let tokenizer = word_level_tokenizer();
+        let criteria = SplitCriteria::TokenCount {
+            max_tokens: 10,
+            context_sentences: 1,
+        };
+        let chunks = criteria
+            .split_with_segmenter(text, Some(&tokenizer), Some(&PipeSegmenter))
+            .unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                "first part",
+                "first part second part. still second",
+                "second part. still second third"
+            ]
+        );
+    }
+
     #[test]
     fn test_split_end_of_sentence() {
         let text = "This is a test. It has three sentences. Last one here.";
@@ -274,8 +822,8 @@ mod tests {
         let chunks = criteria.split(text, Some(&tokenizer)).unwrap();
         assert!(chunks.len() > 1);
         for chunk in chunks.iter() {
-            let tokens = tokenizer.encode(chunk.clone(), false).unwrap();
-            assert!(tokens.get_ids().len() <= 5);
+            let tokens = TokenCounter::encode(&tokenizer, chunk).unwrap();
+            assert!(tokens.len() <= 5);
         }
         println!("chunks: {:?}", chunks);
         std::fs::remove_dir_all("./cache/").expect("Failed to remove cache directory");
@@ -297,6 +845,23 @@ mod tests {
         std::fs::remove_dir_all("./cache/").expect("Failed to remove cache directory");
     }
 
+    #[test]
+    fn test_split_token_count_with_custom_counter() {
+        let text = "One two three. Four five six seven eight nine.";
+        let criteria = SplitCriteria::TokenCount {
+            max_tokens: 4,
+            context_sentences: 1,
+        };
+        let chunks = criteria.split(text, Some(&WhitespaceCounter)).unwrap();
+        assert_eq!(
+            chunks,
+            vec!["One two three.", "Four five six seven", "eight nine"]
+        );
+        for chunk in chunks.iter() {
+            assert!(WhitespaceCounter.count(chunk).unwrap() <= 4);
+        }
+    }
+
     #[test]
     fn test_split_token_count_no_tokenizer() {
         let text = "This should fail.";
@@ -337,6 +902,70 @@ mod tests {
         assert_eq!(chunks[2], "And a question?");
     }
 
+    #[test]
+    fn test_split_chunks_carry_their_position() {
+        let text = "First one. Second one. First one.\n\n   \n\nLast paragraph.";
+        let chunks = SplitCriteria::EndOfSentence
+            .split_chunks(text, Some("doc"), None, None)
+            .unwrap();
+        let positions: Vec<(&str, usize, usize)> = chunks
+            .iter()
+            .map(|chunk| (chunk.text.as_str(), chunk.index, chunk.total))
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                ("First one.", 0, 4),
+                ("Second one.", 1, 4),
+                ("First one.", 2, 4),
+                ("Last paragraph.", 3, 4),
+            ]
+        );
+        // Repeated texts are located after the previous chunk
+        assert_eq!(chunks[0].source_offsets, Some((0, 10)));
+        assert_eq!(chunks[2].source_offsets, Some((23, 33)));
+        let (start, end) = chunks[3].source_offsets.unwrap();
+        assert_eq!(&text[start..end], "Last paragraph.");
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.query_id.as_deref() == Some("doc")));
+
+        // Chunks not found as is in the content have no offsets
+        let chunks = Chunk::from_texts(vec!["packed words".to_string()], "packed\nwords", None);
+        assert_eq!(chunks[0].source_offsets, None);
+    }
+
+    #[test]
+    fn test_paragraph_split_keeps_headings_with_their_body() {
+        let text = "# Title\n\nThe body of the document.\n\n## Section\n\nOverview\n\nThe body of the section.\n\nNot a heading.\n\nLast paragraph.\n\n# Trailing";
+        let chunks = SplitCriteria::Paragraph.split(text, None).unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                "# Title\n\nThe body of the document.",
+                "## Section\n\nOverview\n\nThe body of the section.",
+                "Not a heading.",
+                "Last paragraph.",
+                "# Trailing",
+            ]
+        );
+        // Joined with their headings, chunks are still found in the content
+        let chunks = SplitCriteria::Paragraph
+            .split_chunks(text, None, None, None)
+            .unwrap();
+        assert_eq!(chunks[0].source_offsets, Some((0, 34)));
+
+        // Multi-line and long paragraphs are never headings
+        let long = "A line without punctuation ".repeat(4);
+        for heading in ["Two lines\nwithout punctuation", long.trim()] {
+            let text = format!("{}\n\nBody.", heading);
+            assert_eq!(
+                SplitCriteria::Paragraph.split(&text, None).unwrap().len(),
+                2
+            );
+        }
+    }
+
     #[test]
     fn test_paragraph_split() {
         let text = "Paragraph one.\n\nParagraph two.\n\nParagraph three.";
@@ -400,8 +1029,7 @@ mod tests {
         // Test that the long sentence is split into smaller chunks
         assert!(!chunks.is_empty());
         for chunk in chunks {
-            let encoding = tokenizer.encode(chunk.clone(), true).unwrap();
-            assert!(encoding.get_ids().len() <= 5);
+            assert!(tokenizer.count(&chunk).unwrap() <= 5);
         }
     }
 
@@ -497,8 +1125,7 @@ mod tests {
 
         // Ensure that context is included correctly and chunks respect the max token limit
         for chunk in chunks {
-            let encoding = tokenizer.encode(chunk.clone(), true).unwrap();
-            assert!(encoding.get_ids().len() <= 15);
+            assert!(tokenizer.count(&chunk).unwrap() <= 15);
         }
     }
 
@@ -507,8 +1134,7 @@ mod tests {
     fn test_max_tokens_exact_match() {
         let text = "This is a test.";
         let tokenizer = create_test_tokenizer();
-        let encoding = tokenizer.encode(text, true).unwrap();
-        let token_count = encoding.get_ids().len();
+        let token_count = tokenizer.count(text).unwrap();
 
         let criteria = SplitCriteria::TokenCount {
             max_tokens: token_count,
@@ -606,4 +1232,94 @@ mod tests {
         assert_eq!(chunks.len(), 1);
         assert_eq!(chunks[0], "No sentences here but some words");
     }
+
+    #[test]
+    fn test_split_parallel_matches_sequential() {
+        let text = "First paragraph. It has two sentences.\n\nSecond paragraph is a single, rather long sentence with many words in it.\n\nThird one. Short. Done.";
+        let tokenizer = word_level_tokenizer();
+        let criteria = SplitCriteria::TokenCount {
+            max_tokens: 6,
+            context_sentences: 1,
+        };
+        let sequential: Vec<String> = text
+            .split("\n\n")
+            .flat_map(|paragraph| criteria.split(paragraph, Some(&tokenizer)).unwrap())
+            .collect();
+        let parallel = criteria.split_parallel(text, Some(&tokenizer)).unwrap();
+        assert!(sequential.len() > 3);
+        assert_eq!(parallel, sequential);
+
+        for criteria in [SplitCriteria::EndOfSentence, SplitCriteria::Paragraph] {
+            assert_eq!(
+                criteria.split_parallel(text, None).unwrap(),
+                criteria.split(text, None).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_split_criteria_from_str_round_trips() {
+        for criteria in [
+            SplitCriteria::EndOfSentence,
+            SplitCriteria::Paragraph,
+            SplitCriteria::TokenCount {
+                max_tokens: 512,
+                context_sentences: 1,
+            },
+            SplitCriteria::ParagraphBounded { max_tokens: 64 },
+        ] {
+            let parsed: SplitCriteria = criteria.to_string().parse().unwrap();
+            assert_eq!(parsed.to_string(), criteria.to_string());
+        }
+        for invalid in ["", "sentence", "token_count:512", "paragraph_bounded:many"] {
+            assert!(invalid.parse::<SplitCriteria>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_preview_fixture_chunks() {
+        let text = include_str!("../fixtures/chunk_preview.txt");
+        let tokenizer = word_level_tokenizer();
+        let criteria: SplitCriteria = "paragraph_bounded:12".parse().unwrap();
+        let previews = criteria.preview(text, Some(&tokenizer)).unwrap();
+        assert_eq!(previews.len(), 4);
+        assert!(previews
+            .iter()
+            .all(|preview| preview.tokens.is_some_and(|tokens| tokens <= 12)));
+
+        let previews = SplitCriteria::Paragraph.preview(text, None).unwrap();
+        assert_eq!(previews.len(), 3);
+        assert!(previews.iter().all(|preview| preview.tokens.is_none()));
+    }
+
+    #[test]
+    fn test_token_count_chunks_end_at_sentence_boundaries() {
+        let text = "One two three. Four five six seven. Eight nine.";
+        let tokenizer = word_level_tokenizer();
+
+        let criteria = SplitCriteria::TokenCount {
+            max_tokens: 5,
+            context_sentences: 0,
+        };
+        let chunks = criteria.split(text, Some(&tokenizer)).unwrap();
+        assert_eq!(
+            chunks,
+            vec!["One two three.", "Four five six seven.", "Eight nine."]
+        );
+
+        // With headroom for context, chunks still hold whole sentences only
+        let criteria = SplitCriteria::TokenCount {
+            max_tokens: 12,
+            context_sentences: 1,
+        };
+        let chunks = criteria.split(text, Some(&tokenizer)).unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                "One two three.",
+                "One two three. Four five six seven.",
+                "Four five six seven. Eight nine."
+            ]
+        );
+    }
 }
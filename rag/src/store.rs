@@ -0,0 +1,1086 @@
+//! Operations of the vector store the handlers depend on, and their implementations.
+
+use std::{cmp::Ordering, collections::BTreeMap, future::Future, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use pinecone_sdk::models::{Metric, WaitPolicy};
+
+use crate::{
+    circuit_breaker::CircuitBreaker,
+    client::{
+        apply_score_threshold, check_existing_dimension, check_raw_document_size,
+        chunk_index_field, chunk_metadata, delete_filter, is_internal_namespace, string_field,
+        timestamp_field, validate_dimension, validate_index_name, validate_top_k, vector_id,
+        EmbeddingClient, EmbeddingKind, QueryOptions, StoredChunk, StoredDocument, DEFAULT_TOP_K,
+    },
+    error::RagError,
+    math::{cosine_similarity, dot_product, l2_distance},
+    metadata::MetadataFields,
+    split_criteria::{Chunk, SplitCriteria},
+    types::{QueryResponse, TextToEmbed},
+};
+
+/// Embeds texts, and stores and queries their embeddings.
+///
+/// The handlers only go through this trait, so that tests can serve them from an in-memory
+/// fake rather than a live embedding server and Pinecone index. `EmbeddingClient` is the
+/// implementation used by the server.
+#[async_trait]
+pub trait EmbeddingStore: Send + Sync {
+    /// Creates the embedding of a text, see `EmbeddingClient::create_embedding`.
+    async fn embed(&self, text: &str, kind: EmbeddingKind) -> Result<Vec<Vec<f32>>>;
+
+    /// Creates the embedding of a text along with an image, see
+    /// `EmbeddingClient::create_embedding_with_image`.
+    async fn embed_with_image(
+        &self,
+        text: &str,
+        image_url: &str,
+        kind: EmbeddingKind,
+    ) -> Result<Vec<Vec<f32>>>;
+
+    /// Creates the embeddings of several texts, one flattened embedding per text, in order.
+    ///
+    /// Texts are embedded one by one by default, stores whose embedding service takes batches
+    /// embed them in a single request.
+    async fn embed_batch(&self, texts: &[String], kind: EmbeddingKind) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for text in texts {
+            embeddings.push(self.embed(text, kind).await?.concat());
+        }
+        Ok(embeddings)
+    }
+
+    /// Stores the embeddings of the chunks of a document, returning how many were stored.
+    ///
+    /// Each embedding is stored along with its chunk (see `chunk_metadata`), in the namespace of the document if it
+    /// names one, see `EmbeddingClient::store_embeddings`.
+    async fn store(
+        &mut self,
+        index_name: &str,
+        embeddings: Vec<(Chunk, Vec<Vec<f32>>)>,
+        document: Option<&TextToEmbed>,
+        split: Option<&SplitCriteria>,
+    ) -> Result<usize>;
+
+    /// Returns the stored embeddings most similar to a text, dropping those not passing the
+    /// optional score threshold, see `EmbeddingClient::query`.
+    async fn query(
+        &self,
+        query: &str,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>>;
+
+    /// Returns the stored embeddings most similar to an already embedded query, as `query`,
+    /// see `EmbeddingClient::query_with_vector`.
+    async fn query_vector(
+        &self,
+        query_vector: Vec<f32>,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>>;
+
+    /// Creates a new index, failing if it already exists, see `EmbeddingClient::create_index`.
+    async fn create_index(
+        &mut self,
+        index_name: &str,
+        dimension: i32,
+        metric: Option<Metric>,
+        wait_policy: WaitPolicy,
+    ) -> Result<()>;
+
+    /// Creates an index unless it already exists, returning whether it was created.
+    ///
+    /// Ensuring an existing index with another dimension fails with a `RagError::AlreadyExists`.
+    async fn ensure_index(
+        &mut self,
+        index_name: &str,
+        dimension: i32,
+        metric: Option<Metric>,
+    ) -> Result<bool>;
+
+    /// Deletes the embeddings stored for the document with the given `query_id`, in the given
+    /// namespace or else the default one.
+    async fn delete(
+        &mut self,
+        index_name: &str,
+        query_id: &str,
+        namespace: Option<&str>,
+    ) -> Result<()>;
+
+    /// Deletes the embeddings matching a metadata filter, see `EmbeddingClient::delete_by_filter`.
+    async fn delete_by_filter(
+        &mut self,
+        index_name: &str,
+        filter: serde_json::Value,
+        confirm: bool,
+        namespace: Option<&str>,
+    ) -> Result<()>;
+
+    /// Fetches stored chunks by their full ids, in the given namespace or else the default
+    /// one, see `EmbeddingClient::fetch_chunks`.
+    async fn fetch_chunks(
+        &self,
+        index_name: &str,
+        ids: &[String],
+        namespace: Option<&str>,
+    ) -> Result<Vec<StoredChunk>>;
+
+    /// Lists every stored chunk of the given namespace, or else the default one, see
+    /// `EmbeddingClient::list_chunks`.
+    async fn list_chunks(
+        &self,
+        index_name: &str,
+        namespace: Option<&str>,
+    ) -> Result<Vec<StoredChunk>>;
+
+    /// Upserts chunks with their embedding and metadata, then deletes the vectors with the
+    /// `stale_ids`, see `EmbeddingClient::replace_chunks`.
+    async fn replace_chunks(
+        &mut self,
+        index_name: &str,
+        chunks: Vec<StoredChunk>,
+        stale_ids: &[String],
+        namespace: Option<&str>,
+    ) -> Result<()>;
+
+    /// Stores the raw content of a document once, keyed by its `query_id`, along with a
+    /// vector of the dimension of the index, see `EmbeddingClient::store_document`.
+    async fn store_document(
+        &mut self,
+        index_name: &str,
+        document: &TextToEmbed,
+        values: Vec<f32>,
+    ) -> Result<()>;
+
+    /// Fetches the raw document stored for a `query_id`, in the given namespace or else the
+    /// default one, failing with a `RagError::NotFound` if there is none, see
+    /// `EmbeddingClient::fetch_document`.
+    async fn fetch_document(
+        &self,
+        index_name: &str,
+        query_id: &str,
+        namespace: Option<&str>,
+    ) -> Result<StoredDocument>;
+
+    /// Lists the namespaces of an index holding embeddings, sorted.
+    async fn list_namespaces(&self, index_name: &str) -> Result<Vec<String>>;
+
+    /// Returns the similarity metric of an index.
+    async fn index_metric(&self, index_name: &str) -> Result<Metric>;
+
+    /// Returns the URL of the embedding service, reported by `/info`.
+    fn embedding_backend(&self) -> String;
+
+    /// Returns the namespace used by requests not naming one, reported by `/info`.
+    fn default_namespace(&self) -> String;
+}
+
+/// Runs a call to Pinecone through the circuit breaker of the client, if it has one.
+async fn guarded<T>(
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    call: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match circuit_breaker {
+        Some(circuit_breaker) => circuit_breaker.call(call).await,
+        None => call.await,
+    }
+}
+
+/// Stores the embeddings in the Pinecone index of the client.
+///
+/// # Notes
+///
+/// - Storing and deleting embeddings go through the `pinecone_host` of the client, whichever
+///   index is named. The named index is only described to refresh its cached dimension when
+///   an upsert fails as if it was recreated.
+/// - Every operation but embedding goes through the circuit breaker of the client, if any,
+///   so that queries fail fast without being embedded while Pinecone is down.
+#[async_trait]
+impl EmbeddingStore for EmbeddingClient {
+    async fn embed(&self, text: &str, kind: EmbeddingKind) -> Result<Vec<Vec<f32>>> {
+        self.create_embedding(text, kind).await
+    }
+
+    async fn embed_with_image(
+        &self,
+        text: &str,
+        image_url: &str,
+        kind: EmbeddingKind,
+    ) -> Result<Vec<Vec<f32>>> {
+        self.create_embedding_with_image(text, Some(image_url), kind)
+            .await
+    }
+
+    async fn embed_batch(&self, texts: &[String], kind: EmbeddingKind) -> Result<Vec<Vec<f32>>> {
+        self.create_embeddings(texts, kind).await
+    }
+
+    async fn store(
+        &mut self,
+        index_name: &str,
+        embeddings: Vec<(Chunk, Vec<Vec<f32>>)>,
+        document: Option<&TextToEmbed>,
+        split: Option<&SplitCriteria>,
+    ) -> Result<usize> {
+        let host = self.pinecone_host.clone();
+        guarded(
+            self.circuit_breaker.clone(),
+            self.store_embeddings(&host, index_name, embeddings, document, split),
+        )
+        .await
+    }
+
+    async fn query(
+        &self,
+        query: &str,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::query(self, query, index_name, options),
+        )
+        .await
+    }
+
+    async fn query_vector(
+        &self,
+        query_vector: Vec<f32>,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>> {
+        guarded(
+            self.circuit_breaker.clone(),
+            self.query_with_vector(query_vector, index_name, options),
+        )
+        .await
+    }
+
+    async fn create_index(
+        &mut self,
+        index_name: &str,
+        dimension: i32,
+        metric: Option<Metric>,
+        wait_policy: WaitPolicy,
+    ) -> Result<()> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::create_index(self, index_name, dimension, metric, wait_policy),
+        )
+        .await
+    }
+
+    async fn ensure_index(
+        &mut self,
+        index_name: &str,
+        dimension: i32,
+        metric: Option<Metric>,
+    ) -> Result<bool> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::ensure_index(self, index_name, dimension, metric),
+        )
+        .await
+    }
+
+    async fn delete(
+        &mut self,
+        _index_name: &str,
+        query_id: &str,
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        guarded(
+            self.circuit_breaker.clone(),
+            self.delete_by_query_id(&self.pinecone_host, query_id, namespace),
+        )
+        .await
+    }
+
+    async fn delete_by_filter(
+        &mut self,
+        index_name: &str,
+        filter: serde_json::Value,
+        confirm: bool,
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::delete_by_filter(self, index_name, filter, confirm, namespace),
+        )
+        .await
+    }
+
+    async fn fetch_chunks(
+        &self,
+        index_name: &str,
+        ids: &[String],
+        namespace: Option<&str>,
+    ) -> Result<Vec<StoredChunk>> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::fetch_chunks(self, index_name, ids, namespace),
+        )
+        .await
+    }
+
+    async fn list_chunks(
+        &self,
+        index_name: &str,
+        namespace: Option<&str>,
+    ) -> Result<Vec<StoredChunk>> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::list_chunks(self, index_name, namespace),
+        )
+        .await
+    }
+
+    async fn replace_chunks(
+        &mut self,
+        _index_name: &str,
+        chunks: Vec<StoredChunk>,
+        stale_ids: &[String],
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::replace_chunks(
+                self,
+                &self.pinecone_host,
+                chunks,
+                stale_ids,
+                namespace,
+            ),
+        )
+        .await
+    }
+
+    async fn store_document(
+        &mut self,
+        _index_name: &str,
+        document: &TextToEmbed,
+        values: Vec<f32>,
+    ) -> Result<()> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::store_document(self, &self.pinecone_host, document, values),
+        )
+        .await
+    }
+
+    async fn fetch_document(
+        &self,
+        index_name: &str,
+        query_id: &str,
+        namespace: Option<&str>,
+    ) -> Result<StoredDocument> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::fetch_document(self, index_name, query_id, namespace),
+        )
+        .await
+    }
+
+    async fn list_namespaces(&self, index_name: &str) -> Result<Vec<String>> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::list_namespaces(self, index_name),
+        )
+        .await
+    }
+
+    async fn index_metric(&self, index_name: &str) -> Result<Metric> {
+        guarded(
+            self.circuit_breaker.clone(),
+            EmbeddingClient::index_metric(self, index_name),
+        )
+        .await
+    }
+
+    fn embedding_backend(&self) -> String {
+        self.embed_url()
+    }
+
+    fn default_namespace(&self) -> String {
+        self.namespace.clone()
+    }
+}
+
+/// Keeps the embeddings in memory, to run the server without a Pinecone account.
+///
+/// Texts are still embedded by the embedding service of the `EmbeddingClient`, which can be
+/// built with `EmbeddingClient::without_pinecone`. Queries compare the query vector to every
+/// vector of the index, with the metric of the index.
+///
+/// # Notes
+///
+/// - Nothing survives a restart.
+/// - Namespaces default to the one of the `EmbeddingClient`.
+/// - Metadata filters are not supported: queries with a filter, including a date range, and
+///   deletes with a non-empty filter are rejected (`RagError::InvalidInput`).
+pub struct InMemoryStore {
+    /// Client of the embedding service.
+    embedder: EmbeddingClient,
+    /// Indexes keyed by name.
+    indexes: BTreeMap<String, InMemoryIndex>,
+    /// Counter for generating unique ids for stored embeddings.
+    counter: usize,
+}
+
+/// An index of an `InMemoryStore`.
+struct InMemoryIndex {
+    /// Dimension of the vectors of the index.
+    dimension: usize,
+    /// Similarity metric of the index.
+    metric: Metric,
+    /// Stored vectors keyed by id, storing a vector with an existing id overwrites it. Ids are
+    /// ordered lexicographically, so vectors are listed and ranked in `chunk_order` instead.
+    vectors: BTreeMap<String, StoredVector>,
+    /// Raw documents keyed by namespace and `query_id`.
+    documents: BTreeMap<(String, String), StoredDocument>,
+}
+
+/// A vector stored in an `InMemoryIndex`, with the fields of its metadata used by the store.
+struct StoredVector {
+    values: Vec<f32>,
+    text: String,
+    query_id: Option<String>,
+    chunk_index: Option<usize>,
+    timestamp: Option<i64>,
+    author: Option<String>,
+    namespace: String,
+    metadata: MetadataFields,
+}
+
+impl InMemoryStore {
+    /// Constructor
+    pub fn new(embedder: EmbeddingClient) -> Self {
+        Self {
+            embedder,
+            indexes: BTreeMap::new(),
+            counter: 0,
+        }
+    }
+
+    fn index(&self, index_name: &str) -> Result<&InMemoryIndex> {
+        self.indexes
+            .get(index_name)
+            .ok_or_else(|| anyhow!("Index {} does not exist", index_name))
+    }
+
+    /// Checks that a query can be served: its `top_k` is valid, it sets no metadata filter and
+    /// its index exists.
+    fn check_query(&self, index_name: &str, options: &QueryOptions) -> Result<()> {
+        validate_top_k(options.top_k.unwrap_or(DEFAULT_TOP_K))?;
+        if options.filter.is_some() {
+            return Err(RagError::InvalidInput(
+                "metadata filters are not supported by the in-memory store".to_string(),
+            )
+            .into());
+        }
+        self.index(index_name).map(|_| ())
+    }
+
+    /// Scores the vectors of the namespace of a checked query against its embedding, returning
+    /// the `top_k` best passing its score threshold.
+    fn search(
+        &self,
+        query_vector: Vec<f32>,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>> {
+        let QueryOptions {
+            top_k,
+            include_values,
+            score_threshold,
+            namespace,
+            ..
+        } = options;
+        let top_k = top_k.unwrap_or(DEFAULT_TOP_K);
+        let index = self.index(index_name)?;
+        let namespace = self.embedder.namespace_or_default(namespace.as_deref());
+        let mut results = Vec::with_capacity(index.vectors.len());
+        for (id, vector) in index.vectors.iter() {
+            if vector.namespace != namespace {
+                continue;
+            }
+            results.push(QueryResponse {
+                id: id.clone(),
+                score: score(&index.metric, &query_vector, &vector.values)?,
+                raw_score: None,
+                embedding: if include_values {
+                    vector.values.clone()
+                } else {
+                    vec![]
+                },
+                text: vector.text.clone(),
+                index_name: Some(index_name.to_string()),
+                query_id: vector.query_id.clone(),
+                chunk_index: vector.chunk_index,
+                context: None,
+                timestamp: vector.timestamp,
+                author: vector.author.clone(),
+                norm: None,
+            });
+        }
+        // NOTE: Euclidean distances are better when lower, ties are broken by chunk order for
+        // stable results
+        results.sort_by(|a, b| {
+            match index.metric {
+                Metric::Euclidean => a.score.total_cmp(&b.score),
+                _ => b.score.total_cmp(&a.score),
+            }
+            .then_with(|| {
+                chunk_order(
+                    (a.query_id.as_ref(), a.chunk_index, &a.id),
+                    (b.query_id.as_ref(), b.chunk_index, &b.id),
+                )
+            })
+        });
+        if let Some(score_threshold) = score_threshold {
+            apply_score_threshold(&mut results, score_threshold, &index.metric);
+        }
+        results.truncate(top_k as usize);
+        Ok(results)
+    }
+
+    fn index_mut(&mut self, index_name: &str) -> Result<&mut InMemoryIndex> {
+        self.indexes
+            .get_mut(index_name)
+            .ok_or_else(|| anyhow!("Index {} does not exist", index_name))
+    }
+}
+
+/// Orders the chunks of an `InMemoryStore` by document, then by position within their document
+/// and by id, so that e.g. `doc-10` comes after `doc-9`, as their chunks do.
+fn chunk_order(
+    a: (Option<&String>, Option<usize>, &str),
+    b: (Option<&String>, Option<usize>, &str),
+) -> Ordering {
+    a.cmp(&b)
+}
+
+/// Scores a stored vector against a query vector, as Pinecone does for the metric.
+fn score(metric: &Metric, query: &[f32], values: &[f32]) -> Result<f32> {
+    match metric {
+        Metric::Cosine => cosine_similarity(query, values),
+        Metric::Euclidean => l2_distance(query, values),
+        Metric::Dotproduct => dot_product(query, values),
+    }
+}
+
+#[async_trait]
+impl EmbeddingStore for InMemoryStore {
+    async fn embed(&self, text: &str, kind: EmbeddingKind) -> Result<Vec<Vec<f32>>> {
+        self.embedder.create_embedding(text, kind).await
+    }
+
+    async fn embed_with_image(
+        &self,
+        text: &str,
+        image_url: &str,
+        kind: EmbeddingKind,
+    ) -> Result<Vec<Vec<f32>>> {
+        self.embedder
+            .create_embedding_with_image(text, Some(image_url), kind)
+            .await
+    }
+
+    async fn embed_batch(&self, texts: &[String], kind: EmbeddingKind) -> Result<Vec<Vec<f32>>> {
+        self.embedder.create_embeddings(texts, kind).await
+    }
+
+    async fn store(
+        &mut self,
+        index_name: &str,
+        embeddings: Vec<(Chunk, Vec<Vec<f32>>)>,
+        document: Option<&TextToEmbed>,
+        split: Option<&SplitCriteria>,
+    ) -> Result<usize> {
+        let id_prefix = document
+            .and_then(|document| document.id_prefix.as_deref())
+            .or(self.embedder.id_prefix.as_deref());
+        let namespace = self
+            .embedder
+            .namespace_or_default(document.and_then(|document| document.namespace.as_deref()));
+        let index = self
+            .indexes
+            .get_mut(index_name)
+            .ok_or_else(|| anyhow!("Index {} does not exist", index_name))?;
+        let mut vectors = Vec::with_capacity(embeddings.len());
+        for (i, (chunk, embedding)) in embeddings.into_iter().enumerate() {
+            let values: Vec<f32> = embedding.into_iter().flatten().collect();
+            if values.len() != index.dimension {
+                return Err(RagError::InvalidInput(format!(
+                    "vector dimension {} does not match the dimension {} of index {}",
+                    values.len(),
+                    index.dimension,
+                    index_name
+                ))
+                .into());
+            }
+            let metadata = chunk_metadata(&chunk, document, split)?;
+            vectors.push((
+                vector_id(id_prefix, self.counter + i),
+                StoredVector {
+                    values,
+                    text: string_field(&metadata, "text").unwrap_or_default(),
+                    query_id: string_field(&metadata, "query_id"),
+                    chunk_index: chunk_index_field(&metadata),
+                    timestamp: timestamp_field(&metadata),
+                    author: string_field(&metadata, "author"),
+                    namespace: namespace.to_string(),
+                    metadata,
+                },
+            ));
+        }
+        let stored = vectors.len();
+        index.vectors.extend(vectors);
+        self.counter += stored;
+        Ok(stored)
+    }
+
+    async fn query(
+        &self,
+        query: &str,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>> {
+        // NOTE: The query is checked first, so that an invalid query is not embedded
+        self.check_query(index_name, &options)?;
+        let query_vector: Vec<f32> = self
+            .embedder
+            .create_embedding(query, EmbeddingKind::Query)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        self.search(query_vector, index_name, options)
+    }
+
+    async fn query_vector(
+        &self,
+        query_vector: Vec<f32>,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>> {
+        self.check_query(index_name, &options)?;
+        self.search(query_vector, index_name, options)
+    }
+
+    async fn create_index(
+        &mut self,
+        index_name: &str,
+        dimension: i32,
+        metric: Option<Metric>,
+        _wait_policy: WaitPolicy,
+    ) -> Result<()> {
+        validate_index_name(index_name)?;
+        validate_dimension(dimension)?;
+        if self.indexes.contains_key(index_name) {
+            return Err(
+                RagError::AlreadyExists(format!("index {} already exists", index_name)).into(),
+            );
+        }
+        self.indexes.insert(
+            index_name.to_string(),
+            InMemoryIndex {
+                dimension: dimension as usize,
+                metric: metric.unwrap_or(Metric::Cosine),
+                vectors: BTreeMap::new(),
+                documents: BTreeMap::new(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn ensure_index(
+        &mut self,
+        index_name: &str,
+        dimension: i32,
+        metric: Option<Metric>,
+    ) -> Result<bool> {
+        if let Some(index) = self.indexes.get(index_name) {
+            check_existing_dimension(index_name, index.dimension as i32, dimension)?;
+            return Ok(false);
+        }
+        self.create_index(index_name, dimension, metric, WaitPolicy::NoWait)
+            .await?;
+        Ok(true)
+    }
+
+    async fn delete(
+        &mut self,
+        index_name: &str,
+        query_id: &str,
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        let namespace = self.embedder.namespace_or_default(namespace).to_string();
+        let index = self.index_mut(index_name)?;
+        index.vectors.retain(|_, vector| {
+            vector.namespace != namespace || vector.query_id.as_deref() != Some(query_id)
+        });
+        Ok(())
+    }
+
+    async fn delete_by_filter(
+        &mut self,
+        index_name: &str,
+        filter: serde_json::Value,
+        confirm: bool,
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        if delete_filter(&filter, confirm)?.is_some() {
+            return Err(RagError::InvalidInput(
+                "metadata filters are not supported by the in-memory store".to_string(),
+            )
+            .into());
+        }
+        let namespace = self.embedder.namespace_or_default(namespace).to_string();
+        let index = self.index_mut(index_name)?;
+        index
+            .vectors
+            .retain(|_, vector| vector.namespace != namespace);
+        Ok(())
+    }
+
+    async fn fetch_chunks(
+        &self,
+        index_name: &str,
+        ids: &[String],
+        namespace: Option<&str>,
+    ) -> Result<Vec<StoredChunk>> {
+        let index = self.index(index_name)?;
+        let namespace = self.embedder.namespace_or_default(namespace);
+        Ok(ids
+            .iter()
+            .filter_map(|id| {
+                let vector = index.vectors.get(id)?;
+                (vector.namespace == namespace).then(|| StoredChunk {
+                    id: id.clone(),
+                    text: vector.text.clone(),
+                    query_id: vector.query_id.clone(),
+                    chunk_index: vector.chunk_index,
+                    embedding: vector.values.clone(),
+                    metadata: vector.metadata.clone(),
+                })
+            })
+            .collect())
+    }
+
+    async fn list_chunks(
+        &self,
+        index_name: &str,
+        namespace: Option<&str>,
+    ) -> Result<Vec<StoredChunk>> {
+        let mut vectors: Vec<_> = self.index(index_name)?.vectors.iter().collect();
+        vectors.sort_by(|(a_id, a), (b_id, b)| {
+            chunk_order(
+                (a.query_id.as_ref(), a.chunk_index, a_id),
+                (b.query_id.as_ref(), b.chunk_index, b_id),
+            )
+        });
+        let ids: Vec<String> = vectors.into_iter().map(|(id, _)| id.clone()).collect();
+        self.fetch_chunks(index_name, &ids, namespace).await
+    }
+
+    async fn replace_chunks(
+        &mut self,
+        index_name: &str,
+        chunks: Vec<StoredChunk>,
+        stale_ids: &[String],
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        let namespace = self.embedder.namespace_or_default(namespace).to_string();
+        let index = self.index_mut(index_name)?;
+        for chunk in chunks {
+            index.vectors.insert(
+                chunk.id,
+                StoredVector {
+                    values: chunk.embedding,
+                    text: string_field(&chunk.metadata, "text").unwrap_or_default(),
+                    query_id: string_field(&chunk.metadata, "query_id"),
+                    chunk_index: chunk_index_field(&chunk.metadata),
+                    timestamp: timestamp_field(&chunk.metadata),
+                    author: string_field(&chunk.metadata, "author"),
+                    namespace: namespace.clone(),
+                    metadata: chunk.metadata,
+                },
+            );
+        }
+        index
+            .vectors
+            .retain(|id, vector| vector.namespace != namespace || !stale_ids.contains(id));
+        Ok(())
+    }
+
+    async fn store_document(
+        &mut self,
+        index_name: &str,
+        document: &TextToEmbed,
+        _values: Vec<f32>,
+    ) -> Result<()> {
+        check_raw_document_size(document)?;
+        let stored = StoredDocument::new(document)?;
+        let namespace = self
+            .embedder
+            .namespace_or_default(document.namespace.as_deref())
+            .to_string();
+        let index = self.index_mut(index_name)?;
+        index
+            .documents
+            .insert((namespace, stored.query_id.clone()), stored);
+        Ok(())
+    }
+
+    async fn fetch_document(
+        &self,
+        index_name: &str,
+        query_id: &str,
+        namespace: Option<&str>,
+    ) -> Result<StoredDocument> {
+        let namespace = self.embedder.namespace_or_default(namespace).to_string();
+        let key = (namespace, query_id.to_string());
+        match self.index(index_name)?.documents.get(&key) {
+            Some(document) => Ok(document.clone()),
+            None => Err(RagError::NotFound(format!(
+                "no raw document stored for query with id {}",
+                query_id
+            ))
+            .into()),
+        }
+    }
+
+    async fn list_namespaces(&self, index_name: &str) -> Result<Vec<String>> {
+        let mut namespaces: Vec<String> = self
+            .index(index_name)?
+            .vectors
+            .values()
+            .map(|vector| vector.namespace.clone())
+            .filter(|namespace| !is_internal_namespace(namespace))
+            .collect();
+        namespaces.sort();
+        namespaces.dedup();
+        Ok(namespaces)
+    }
+
+    async fn index_metric(&self, index_name: &str) -> Result<Metric> {
+        Ok(self.index(index_name)?.metric.clone())
+    }
+
+    fn embedding_backend(&self) -> String {
+        self.embedder.embed_url()
+    }
+
+    fn default_namespace(&self) -> String {
+        self.embedder.namespace.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::CURRENT_NAME_SPACE;
+    use crate::test_utils::{chunk, mock_embedding, spawn_server, text_to_embed, MockEmbedder};
+
+    const DIMENSION: usize = 8;
+
+    async fn in_memory_store() -> InMemoryStore {
+        let embedder = MockEmbedder::new(DIMENSION);
+        let addr = spawn_server(embedder.router()).await;
+        let embedder =
+            EmbeddingClient::without_pinecone(addr.ip().to_string(), addr.port()).unwrap();
+        let mut store = InMemoryStore::new(embedder);
+        store
+            .create_index("test-index", DIMENSION as i32, None, WaitPolicy::NoWait)
+            .await
+            .unwrap();
+        store
+    }
+
+    async fn store_texts(store: &mut InMemoryStore, texts: &[&str]) {
+        let chunks = Chunk::from_texts(
+            texts.iter().map(|text| text.to_string()).collect(),
+            "",
+            None,
+        );
+        let mut embeddings = vec![];
+        for chunk in chunks {
+            let embedding = store
+                .embed(&chunk.text, EmbeddingKind::Document)
+                .await
+                .unwrap();
+            embeddings.push((chunk, embedding));
+        }
+        let document = text_to_embed("unused");
+        let stored = store
+            .store("test-index", embeddings, Some(&document), None)
+            .await
+            .unwrap();
+        assert_eq!(stored, texts.len());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_and_query_round_trip() {
+        let mut store = in_memory_store().await;
+        store_texts(&mut store, &["hello world", "goodbye"]).await;
+
+        let results = store
+            .query(
+                "hello world",
+                "test-index",
+                QueryOptions {
+                    top_k: Some(1),
+                    include_values: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, "0");
+        assert_eq!(results[0].text, "hello world");
+        assert!((results[0].score - 1.0).abs() < 1e-6);
+        assert_eq!(
+            results[0].embedding,
+            mock_embedding("hello world", DIMENSION)
+        );
+
+        // Deleting the document removes all its chunks
+        store
+            .delete("test-index", "test-query-id", None)
+            .await
+            .unwrap();
+        let results = store
+            .query("hello world", "test-index", QueryOptions::default())
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+
+        let error = store
+            .create_index("test-index", DIMENSION as i32, None, WaitPolicy::NoWait)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RagError>(),
+            Some(RagError::AlreadyExists(_))
+        ));
+        // Ensuring it is idempotent, unless the dimension differs
+        assert!(!store
+            .ensure_index("test-index", DIMENSION as i32, None)
+            .await
+            .unwrap());
+        assert!(store
+            .ensure_index("test-index", DIMENSION as i32 + 1, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_query_drops_matches_below_threshold() {
+        let mut store = in_memory_store().await;
+        store_texts(&mut store, &["hello world", "goodbye"]).await;
+        let results = store
+            .query(
+                "hello world",
+                "test-index",
+                QueryOptions {
+                    score_threshold: Some(0.99),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let texts: Vec<&str> = results.iter().map(|result| result.text.as_str()).collect();
+        assert_eq!(texts, vec!["hello world"]);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_query_orders_top_k_by_score() {
+        let mut store = in_memory_store().await;
+        let texts = ["aaaa", "aabb", "abcd", "efgh", "aaab"];
+        store_texts(&mut store, &texts).await;
+
+        let query = mock_embedding("aaaa", DIMENSION);
+        let mut expected: Vec<(f32, &str)> = texts
+            .iter()
+            .map(|text| {
+                let score = cosine_similarity(&query, &mock_embedding(text, DIMENSION)).unwrap();
+                (score, *text)
+            })
+            .collect();
+        expected.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let results = store
+            .query(
+                "aaaa",
+                "test-index",
+                QueryOptions {
+                    top_k: Some(3),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let texts: Vec<&str> = results.iter().map(|result| result.text.as_str()).collect();
+        let expected: Vec<&str> = expected.iter().take(3).map(|(_, text)| *text).collect();
+        assert_eq!(texts, expected);
+        assert!(results.windows(2).all(|w| w[0].score >= w[1].score));
+        assert!(results.iter().all(|result| result.embedding.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_namespaces_are_isolated() {
+        let mut store = in_memory_store().await;
+        store_texts(&mut store, &["hello world"]).await;
+        let embedding = store
+            .embed("hello world", EmbeddingKind::Document)
+            .await
+            .unwrap();
+        let document = TextToEmbed::builder("tweet", "test-index", "hello world")
+            .with_namespace("tweets")
+            .build();
+        store
+            .store(
+                "test-index",
+                vec![(chunk("hello world"), embedding)],
+                Some(&document),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            store.list_namespaces("test-index").await.unwrap(),
+            vec![CURRENT_NAME_SPACE, "tweets"]
+        );
+
+        let options = QueryOptions {
+            namespace: Some("tweets".to_string()),
+            ..Default::default()
+        };
+        let results = store
+            .query("hello world", "test-index", options.clone())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, "1");
+
+        // Deleting from the default namespace leaves the other one untouched
+        store
+            .delete_by_filter("test-index", serde_json::json!({}), true, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.list_namespaces("test-index").await.unwrap(),
+            vec!["tweets"]
+        );
+        let results = store
+            .query("hello world", "test-index", options)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
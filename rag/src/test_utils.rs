@@ -0,0 +1,978 @@
+//! Shared helpers for unit tests that need a running embedding server or
+//! Pinecone control and data planes, or an in-memory embedding store.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use pinecone_sdk::{
+    models::{Metric, WaitPolicy},
+    pinecone::PineconeClientConfig,
+};
+use serde_json::{json, Value};
+use tokenizers::Tokenizer;
+use tracing::info_span;
+
+use crate::{
+    client::{
+        apply_score_threshold, chunk_metadata, delete_filter, is_internal_namespace, parse_date,
+        string_field, timestamp_field, EmbeddingClient, EmbeddingEncoding, EmbeddingKind,
+        QueryOptions, StoredChunk, StoredDocument, CURRENT_NAME_SPACE, DEFAULT_INPUT_FIELD,
+    },
+    error::RagError,
+    math::cosine_similarity,
+    metadata::MetadataFields,
+    request_id::REQUEST_ID_HEADER,
+    split_criteria::{Chunk, SplitCriteria},
+    store::EmbeddingStore,
+    types::{QueryResponse, TextToEmbed},
+};
+
+/// Serves the given router on an ephemeral local port and returns its address.
+pub async fn spawn_server(router: Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, router).await.unwrap();
+    });
+    addr
+}
+
+/// Builds an `EmbeddingClient` whose embedding service and Pinecone control plane
+/// both point to the given local address.
+pub fn test_client(addr: SocketAddr) -> EmbeddingClient {
+    let pinecone_client = PineconeClientConfig {
+        api_key: Some("test-api-key".to_string()),
+        control_plane_host: Some(format!("http://{}", addr)),
+        ..Default::default()
+    }
+    .client()
+    .unwrap();
+    EmbeddingClient {
+        counter: 0,
+        counter_file: None,
+        id_prefix: None,
+        embedding_client: reqwest::Client::new(),
+        pinecone_client,
+        pinecone_host: addr.to_string(),
+        embedding_host: addr.ip().to_string(),
+        embedding_port: addr.port(),
+        embedding_url: None,
+        input_field: DEFAULT_INPUT_FIELD.to_string(),
+        image_field: crate::client::DEFAULT_IMAGE_FIELD.to_string(),
+        embedding_encoding: EmbeddingEncoding::default(),
+        document_prefix: None,
+        query_prefix: None,
+        index_metrics: Default::default(),
+        index_dimensions: Default::default(),
+        known_indexes: Default::default(),
+        sequence_guard: None,
+        embedding_cache: None,
+        rrf_k: crate::client::DEFAULT_RRF_K,
+        upsert_batch_size: crate::client::DEFAULT_UPSERT_BATCH_SIZE,
+        namespace: CURRENT_NAME_SPACE.to_string(),
+        result_post_processor: None,
+        circuit_breaker: None,
+        span: info_span!("test_embedding_client"),
+    }
+}
+
+/// Builds an offline tokenizer producing one token per word or punctuation group.
+pub fn word_level_tokenizer() -> Tokenizer {
+    let config = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": { "type": "Whitespace" },
+        "post_processor": null,
+        "decoder": null,
+        "model": { "type": "WordLevel", "vocab": { "[UNK]": 0 }, "unk_token": "[UNK]" },
+    });
+    config.to_string().parse().unwrap()
+}
+
+/// Builds the single chunk of a document from its text.
+pub fn chunk(text: &str) -> Chunk {
+    Chunk {
+        text: text.to_string(),
+        query_id: None,
+        index: 0,
+        total: 1,
+        source_offsets: None,
+    }
+}
+
+/// Builds the chunks of a document from their texts, along with their `mock_embedding`s.
+pub fn embedded_chunks(texts: &[&str], dimension: usize) -> Vec<(Chunk, Vec<Vec<f32>>)> {
+    let texts = texts.iter().map(|text| text.to_string()).collect();
+    Chunk::from_texts(texts, "", None)
+        .into_iter()
+        .map(|chunk| {
+            let embedding = vec![mock_embedding(&chunk.text, dimension)];
+            (chunk, embedding)
+        })
+        .collect()
+}
+
+/// Builds a `TextToEmbed` with the given content and no optional fields set.
+pub fn text_to_embed(content: &str) -> TextToEmbed {
+    TextToEmbed::builder("test-query-id", "test-index", content).build()
+}
+
+/// A mocked embedding service, recording the request bodies it receives.
+#[derive(Clone)]
+pub struct MockEmbedder {
+    /// Dimension of the returned embeddings.
+    pub dimension: usize,
+    /// Request bodies received, in order.
+    pub requests: Arc<Mutex<Vec<Value>>>,
+    /// `X-Request-Id` headers of the requests received, in order.
+    pub request_ids: Arc<Mutex<Vec<Option<String>>>>,
+    /// Name of the JSON field holding the text in requests.
+    pub input_field: String,
+    /// Whether to return a single bare embedding rather than a batch.
+    pub single_response: bool,
+    /// Optional delay before responding, to simulate a slow embedding service.
+    pub delay: Option<Duration>,
+    /// Number of requests currently being served, and the maximum reached.
+    pub in_flight: Arc<Mutex<(usize, usize)>>,
+}
+
+impl MockEmbedder {
+    /// Creates a mocked embedding service returning embeddings of the given dimension.
+    pub fn new(dimension: usize) -> Self {
+        Self {
+            dimension,
+            requests: Default::default(),
+            request_ids: Default::default(),
+            input_field: DEFAULT_INPUT_FIELD.to_string(),
+            single_response: false,
+            delay: None,
+            in_flight: Default::default(),
+        }
+    }
+
+    /// Waits for the given delay before responding to each request.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Returns the maximum number of requests served concurrently.
+    pub fn max_in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().1
+    }
+
+    /// Reads the text of requests from the given field instead of `DEFAULT_INPUT_FIELD`.
+    pub fn with_input_field(mut self, input_field: &str) -> Self {
+        self.input_field = input_field.to_string();
+        self
+    }
+
+    /// Returns a single bare embedding, e.g. `[0.1, 0.2]`, rather than a batch.
+    pub fn with_single_response(mut self) -> Self {
+        self.single_response = true;
+        self
+    }
+
+    /// Returns the text of every request received, in order.
+    pub fn inputs(&self) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| {
+                request[self.input_field.as_str()]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    /// Returns a router serving the `/embed` route of the embedding service.
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/embed", post(embed))
+            .with_state(self.clone())
+    }
+}
+
+/// Deterministic embedding of a text, counting its bytes modulo the dimension.
+pub fn mock_embedding(text: &str, dimension: usize) -> Vec<f32> {
+    let mut embedding = vec![0.0; dimension];
+    for byte in text.bytes() {
+        embedding[byte as usize % dimension] += 1.0;
+    }
+    embedding
+}
+
+async fn embed(
+    State(state): State<MockEmbedder>,
+    headers: HeaderMap,
+    Json(request): Json<Value>,
+) -> Json<Value> {
+    // NOTE: Batched requests hold an array of texts, embedded one by one
+    let texts: Vec<String> = match &request[state.input_field.as_str()] {
+        Value::Array(texts) => texts
+            .iter()
+            .map(|text| text.as_str().unwrap_or_default().to_string())
+            .collect(),
+        text => vec![text.as_str().unwrap_or_default().to_string()],
+    };
+    state.requests.lock().unwrap().push(request);
+    let request_id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    state.request_ids.lock().unwrap().push(request_id);
+    if let Some(delay) = state.delay {
+        {
+            let mut in_flight = state.in_flight.lock().unwrap();
+            in_flight.0 += 1;
+            in_flight.1 = in_flight.1.max(in_flight.0);
+        }
+        tokio::time::sleep(delay).await;
+        state.in_flight.lock().unwrap().0 -= 1;
+    }
+    let embeddings: Vec<Vec<f32>> = texts
+        .iter()
+        .map(|text| mock_embedding(text, state.dimension))
+        .collect();
+    if state.single_response {
+        Json(json!(embeddings[0]))
+    } else {
+        Json(json!(embeddings))
+    }
+}
+
+/// In-memory state of a mocked Pinecone control plane.
+#[derive(Clone, Default)]
+pub struct MockControlPlane {
+    /// Index models keyed by index name.
+    pub indexes: Arc<Mutex<BTreeMap<String, Value>>>,
+    /// Number of index creation requests received.
+    pub create_calls: Arc<Mutex<usize>>,
+    /// Number of index description requests received.
+    pub describe_calls: Arc<Mutex<usize>>,
+    /// Number of descriptions for which created indexes still report not being ready.
+    pub pending_describes: Arc<Mutex<usize>>,
+}
+
+impl MockControlPlane {
+    /// Registers an existing index with the given dimension and metric.
+    pub fn with_index(self, name: &str, dimension: i32, metric: &str) -> Self {
+        self.indexes
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), index_model(name, dimension, metric));
+        self
+    }
+
+    /// Makes created indexes report not being ready for the given number of descriptions.
+    pub fn with_pending_describes(self, pending_describes: usize) -> Self {
+        *self.pending_describes.lock().unwrap() = pending_describes;
+        self
+    }
+
+    /// Returns a router serving the Pinecone control plane index routes.
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/indexes", get(list_indexes).post(create_index))
+            .route("/indexes/:name", get(describe_index))
+            .with_state(self.clone())
+    }
+}
+
+fn index_model(name: &str, dimension: i32, metric: &str) -> Value {
+    json!({
+        "name": name,
+        "dimension": dimension,
+        "metric": metric,
+        "host": format!("{}.svc.pinecone.io", name),
+        "spec": { "serverless": { "cloud": "aws", "region": "us-east-1" } },
+        "status": { "ready": true, "state": "Ready" },
+    })
+}
+
+async fn list_indexes(State(state): State<MockControlPlane>) -> Json<Value> {
+    let indexes = state.indexes.lock().unwrap();
+    Json(json!({ "indexes": indexes.values().cloned().collect::<Vec<_>>() }))
+}
+
+async fn create_index(
+    State(state): State<MockControlPlane>,
+    Json(request): Json<Value>,
+) -> (StatusCode, Json<Value>) {
+    *state.create_calls.lock().unwrap() += 1;
+    let name = request["name"].as_str().unwrap();
+    let dimension = request["dimension"].as_i64().unwrap() as i32;
+    let metric = request["metric"].as_str().unwrap_or("cosine");
+    let mut model = index_model(name, dimension, metric);
+    model["status"]["ready"] = json!(*state.pending_describes.lock().unwrap() == 0);
+    state
+        .indexes
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), model.clone());
+    (StatusCode::CREATED, Json(model))
+}
+
+async fn describe_index(
+    State(state): State<MockControlPlane>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    *state.describe_calls.lock().unwrap() += 1;
+    let mut pending_describes = state.pending_describes.lock().unwrap();
+    let mut indexes = state.indexes.lock().unwrap();
+    let model = indexes.get_mut(&name).ok_or(StatusCode::NOT_FOUND)?;
+    if *pending_describes > 0 {
+        *pending_describes -= 1;
+    } else {
+        model["status"]["ready"] = json!(true);
+    }
+    Ok(Json(model.clone()))
+}
+
+/// Upsert request of the Pinecone data plane, decoding only the ids of the vectors.
+#[derive(Clone, PartialEq, prost::Message)]
+struct MockUpsertRequest {
+    #[prost(message, repeated, tag = "1")]
+    vectors: Vec<MockVector>,
+}
+
+/// Vector of an upsert request of the Pinecone data plane.
+#[derive(Clone, PartialEq, prost::Message)]
+struct MockVector {
+    #[prost(string, tag = "1")]
+    id: String,
+}
+
+/// Upsert response of the Pinecone data plane.
+#[derive(Clone, PartialEq, prost::Message)]
+struct MockUpsertResponse {
+    #[prost(uint32, tag = "1")]
+    upserted_count: u32,
+}
+
+/// Query request of the Pinecone data plane, decoding only its namespace and `top_k`.
+#[derive(Clone, PartialEq, prost::Message)]
+struct MockQueryRequest {
+    #[prost(string, tag = "1")]
+    namespace: String,
+    #[prost(uint32, tag = "2")]
+    top_k: u32,
+}
+
+/// Scored match of a query response of the Pinecone data plane.
+#[derive(Clone, PartialEq, prost::Message)]
+struct MockScoredVector {
+    #[prost(string, tag = "1")]
+    id: String,
+    #[prost(float, tag = "2")]
+    score: f32,
+    #[prost(message, optional, tag = "4")]
+    metadata: Option<prost_types::Struct>,
+}
+
+/// Query response of the Pinecone data plane.
+#[derive(Clone, PartialEq, prost::Message)]
+struct MockQueryResponse {
+    #[prost(message, repeated, tag = "2")]
+    matches: Vec<MockScoredVector>,
+    #[prost(string, tag = "3")]
+    namespace: String,
+}
+
+/// Fetch request of the Pinecone data plane.
+#[derive(Clone, PartialEq, prost::Message)]
+struct MockFetchRequest {
+    #[prost(string, repeated, tag = "1")]
+    ids: Vec<String>,
+    #[prost(string, tag = "2")]
+    namespace: String,
+}
+
+/// Vector of a fetch response of the Pinecone data plane.
+#[derive(Clone, PartialEq, prost::Message)]
+struct MockFetchedVector {
+    #[prost(string, tag = "1")]
+    id: String,
+    #[prost(float, repeated, tag = "2")]
+    values: Vec<f32>,
+}
+
+/// Fetch response of the Pinecone data plane.
+#[derive(Clone, PartialEq, prost::Message)]
+struct MockFetchResponse {
+    #[prost(map = "string, message", tag = "1")]
+    vectors: HashMap<String, MockFetchedVector>,
+    #[prost(string, tag = "2")]
+    namespace: String,
+}
+
+/// Namespace and id of a vector served by a `MockDataPlane`.
+type VectorKey = (String, String);
+
+/// In-memory state of a mocked Pinecone data plane, serving upserts, queries and fetches over
+/// gRPC.
+#[derive(Clone, Default)]
+pub struct MockDataPlane {
+    /// Ids of the vectors of each upsert request received, in order.
+    pub upserts: Arc<Mutex<Vec<Vec<String>>>>,
+    /// Errors the next upserts fail with, in order.
+    pub upsert_errors: Arc<Mutex<Vec<(tonic::Code, String)>>>,
+    /// Matches returned by every query as id, score and text, in the order Pinecone would
+    /// rank them, at most `top_k` of them.
+    pub matches: Arc<Mutex<Vec<(String, f32, String)>>>,
+    /// Namespace and `top_k` of each query request received, in order.
+    pub queries: Arc<Mutex<Vec<(String, u32)>>>,
+    /// Values of the vectors served by fetches, keyed by namespace and id.
+    pub vectors: Arc<Mutex<BTreeMap<VectorKey, Vec<f32>>>>,
+}
+
+impl MockDataPlane {
+    /// Adds a match returned by queries, after the ones already added.
+    pub fn with_match(self, id: &str, score: f32, text: &str) -> Self {
+        self.matches
+            .lock()
+            .unwrap()
+            .push((id.to_string(), score, text.to_string()));
+        self
+    }
+
+    /// Adds a vector served by fetches in the given namespace.
+    pub fn with_vector(self, namespace: &str, id: &str, values: Vec<f32>) -> Self {
+        self.vectors
+            .lock()
+            .unwrap()
+            .insert((namespace.to_string(), id.to_string()), values);
+        self
+    }
+
+    /// Makes the next upsert without a pending error fail with the given error.
+    pub fn fail_next_upsert(&self, code: tonic::Code, message: &str) {
+        self.upsert_errors
+            .lock()
+            .unwrap()
+            .push((code, message.to_string()));
+    }
+}
+
+impl tonic::server::NamedService for MockDataPlane {
+    const NAME: &'static str = "VectorService";
+}
+
+impl<B> tonic::codegen::Service<tonic::codegen::http::Request<B>> for MockDataPlane
+where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<tonic::codegen::StdError> + Send + 'static,
+{
+    type Response = tonic::codegen::http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = tonic::codegen::BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: tonic::codegen::http::Request<B>) -> Self::Future {
+        let data_plane = self.clone();
+        Box::pin(async move {
+            match request.uri().path() {
+                "/VectorService/Upsert" => Ok(tonic::server::Grpc::new(
+                    tonic::codec::ProstCodec::default(),
+                )
+                .unary(MockUpsert(data_plane), request)
+                .await),
+                "/VectorService/Query" => Ok(tonic::server::Grpc::new(
+                    tonic::codec::ProstCodec::default(),
+                )
+                .unary(MockQuery(data_plane), request)
+                .await),
+                "/VectorService/Fetch" => Ok(tonic::server::Grpc::new(
+                    tonic::codec::ProstCodec::default(),
+                )
+                .unary(MockFetch(data_plane), request)
+                .await),
+                _ => Ok(tonic::Status::unimplemented("not mocked").to_http()),
+            }
+        })
+    }
+}
+
+/// The upsert method of a `MockDataPlane`.
+struct MockUpsert(MockDataPlane);
+
+impl tonic::server::UnaryService<MockUpsertRequest> for MockUpsert {
+    type Response = MockUpsertResponse;
+    type Future = tonic::codegen::BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+
+    fn call(&mut self, request: tonic::Request<MockUpsertRequest>) -> Self::Future {
+        let data_plane = self.0.clone();
+        Box::pin(async move {
+            let ids: Vec<String> = request
+                .into_inner()
+                .vectors
+                .into_iter()
+                .map(|vector| vector.id)
+                .collect();
+            let upserted_count = ids.len() as u32;
+            data_plane.upserts.lock().unwrap().push(ids);
+            let mut errors = data_plane.upsert_errors.lock().unwrap();
+            if !errors.is_empty() {
+                let (code, message) = errors.remove(0);
+                return Err(tonic::Status::new(code, message));
+            }
+            Ok(tonic::Response::new(MockUpsertResponse { upserted_count }))
+        })
+    }
+}
+
+/// The query method of a `MockDataPlane`.
+struct MockQuery(MockDataPlane);
+
+impl tonic::server::UnaryService<MockQueryRequest> for MockQuery {
+    type Response = MockQueryResponse;
+    type Future = tonic::codegen::BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+
+    fn call(&mut self, request: tonic::Request<MockQueryRequest>) -> Self::Future {
+        let data_plane = self.0.clone();
+        Box::pin(async move {
+            let MockQueryRequest { namespace, top_k } = request.into_inner();
+            data_plane
+                .queries
+                .lock()
+                .unwrap()
+                .push((namespace.clone(), top_k));
+            let matches = data_plane
+                .matches
+                .lock()
+                .unwrap()
+                .iter()
+                .take(top_k as usize)
+                .map(|(id, score, text)| MockScoredVector {
+                    id: id.clone(),
+                    score: *score,
+                    metadata: Some(prost_types::Struct {
+                        fields: [(
+                            "text".to_string(),
+                            prost_types::Value {
+                                kind: Some(prost_types::value::Kind::StringValue(text.clone())),
+                            },
+                        )]
+                        .into(),
+                    }),
+                })
+                .collect();
+            Ok(tonic::Response::new(MockQueryResponse {
+                matches,
+                namespace,
+            }))
+        })
+    }
+}
+
+/// The fetch method of a `MockDataPlane`.
+struct MockFetch(MockDataPlane);
+
+impl tonic::server::UnaryService<MockFetchRequest> for MockFetch {
+    type Response = MockFetchResponse;
+    type Future = tonic::codegen::BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+
+    fn call(&mut self, request: tonic::Request<MockFetchRequest>) -> Self::Future {
+        let data_plane = self.0.clone();
+        Box::pin(async move {
+            let MockFetchRequest { ids, namespace } = request.into_inner();
+            let stored = data_plane.vectors.lock().unwrap();
+            let vectors = ids
+                .into_iter()
+                .filter_map(|id| {
+                    let values = stored.get(&(namespace.clone(), id.clone()))?.clone();
+                    Some((id.clone(), MockFetchedVector { id, values }))
+                })
+                .collect();
+            Ok(tonic::Response::new(MockFetchResponse {
+                vectors,
+                namespace,
+            }))
+        })
+    }
+}
+
+/// Serves a mocked Pinecone data plane on an ephemeral local port and returns its address.
+pub async fn spawn_data_plane(data_plane: MockDataPlane) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        tonic::transport::Server::builder()
+            .add_service(data_plane)
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener))
+            .await
+            .unwrap();
+    });
+    addr
+}
+
+/// A vector stored by `FakeStore`.
+#[derive(Clone, Debug)]
+pub struct StoredVector {
+    pub id: String,
+    pub query_id: Option<String>,
+    pub chunk_index: usize,
+    pub namespace: String,
+    pub values: Vec<f32>,
+    pub text: String,
+    pub timestamp: Option<i64>,
+    /// The metadata the chunk would be stored with, see `chunk_metadata`.
+    pub metadata: MetadataFields,
+}
+
+/// Index name, namespace and `query_id` of a raw document stored in a `FakeStore`.
+type DocumentKey = (String, String, String);
+
+/// In-memory `EmbeddingStore`, embedding texts with `mock_embedding` and ranking the
+/// stored vectors by cosine similarity.
+///
+/// Metadata filters are not supported, queries ignore them. Namespaces default to
+/// `CURRENT_NAME_SPACE`.
+#[derive(Clone)]
+pub struct FakeStore {
+    /// Dimension of the embeddings.
+    pub dimension: usize,
+    /// Stored vectors keyed by index name, indexes being created on first use.
+    pub indexes: Arc<Mutex<BTreeMap<String, Vec<StoredVector>>>>,
+    /// Texts of the batches embedded by `embed_batch`, in order.
+    pub batches: Arc<Mutex<Vec<Vec<String>>>>,
+    /// Raw documents keyed by index name, namespace and `query_id`.
+    pub documents: Arc<Mutex<BTreeMap<DocumentKey, StoredDocument>>>,
+}
+
+impl FakeStore {
+    /// Creates an empty store, embedding texts with the given dimension.
+    pub fn new(dimension: usize) -> Self {
+        Self {
+            dimension,
+            indexes: Default::default(),
+            batches: Default::default(),
+            documents: Default::default(),
+        }
+    }
+
+    /// Returns the vectors stored in an index.
+    pub fn vectors(&self, index_name: &str) -> Vec<StoredVector> {
+        self.indexes
+            .lock()
+            .unwrap()
+            .get(index_name)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl EmbeddingStore for FakeStore {
+    async fn embed(&self, text: &str, _kind: EmbeddingKind) -> Result<Vec<Vec<f32>>> {
+        Ok(vec![mock_embedding(text, self.dimension)])
+    }
+
+    async fn embed_with_image(
+        &self,
+        text: &str,
+        image_url: &str,
+        _kind: EmbeddingKind,
+    ) -> Result<Vec<Vec<f32>>> {
+        let text = format!("{}{}", text, image_url);
+        Ok(vec![mock_embedding(&text, self.dimension)])
+    }
+
+    async fn embed_batch(&self, texts: &[String], _kind: EmbeddingKind) -> Result<Vec<Vec<f32>>> {
+        self.batches.lock().unwrap().push(texts.to_vec());
+        Ok(texts
+            .iter()
+            .map(|text| mock_embedding(text, self.dimension))
+            .collect())
+    }
+
+    async fn store(
+        &mut self,
+        index_name: &str,
+        embeddings: Vec<(Chunk, Vec<Vec<f32>>)>,
+        document: Option<&TextToEmbed>,
+        split: Option<&SplitCriteria>,
+    ) -> Result<usize> {
+        let mut indexes = self.indexes.lock().unwrap();
+        let vectors = indexes.entry(index_name.to_string()).or_default();
+        let stored = embeddings.len();
+        let namespace = document
+            .and_then(|document| document.namespace.as_deref())
+            .unwrap_or(CURRENT_NAME_SPACE);
+        for (chunk, embedding) in embeddings {
+            let metadata = chunk_metadata(&chunk, document, split)?;
+            vectors.push(StoredVector {
+                id: vectors.len().to_string(),
+                query_id: chunk
+                    .query_id
+                    .or_else(|| document.map(|document| document.query_id.clone())),
+                chunk_index: chunk.index,
+                namespace: namespace.to_string(),
+                values: embedding.concat(),
+                text: chunk.text,
+                timestamp: document
+                    .and_then(|document| document.date.as_deref())
+                    .and_then(|date| parse_date(date).ok()),
+                metadata,
+            });
+        }
+        Ok(stored)
+    }
+
+    async fn query(
+        &self,
+        query: &str,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>> {
+        let query_vector = mock_embedding(query, self.dimension);
+        self.query_vector(query_vector, index_name, options).await
+    }
+
+    async fn query_vector(
+        &self,
+        query_vector: Vec<f32>,
+        index_name: &str,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResponse>> {
+        let namespace = options.namespace.as_deref().unwrap_or(CURRENT_NAME_SPACE);
+        let mut results = Vec::new();
+        for vector in self.vectors(index_name) {
+            if vector.namespace != namespace {
+                continue;
+            }
+            results.push(QueryResponse {
+                id: vector.id,
+                score: cosine_similarity(&query_vector, &vector.values)?,
+                raw_score: None,
+                embedding: vector.values,
+                text: vector.text,
+                index_name: Some(index_name.to_string()),
+                query_id: vector.query_id,
+                chunk_index: Some(vector.chunk_index),
+                context: None,
+                timestamp: vector.timestamp,
+                author: string_field(&vector.metadata, "author"),
+                norm: None,
+            });
+        }
+        results.sort_by(|a, b| b.score.total_cmp(&a.score));
+        if let Some(score_threshold) = options.score_threshold {
+            apply_score_threshold(&mut results, score_threshold, &Metric::Cosine);
+        }
+        results.truncate(options.top_k.unwrap_or(u32::MAX) as usize);
+        Ok(results)
+    }
+
+    async fn create_index(
+        &mut self,
+        index_name: &str,
+        _dimension: i32,
+        _metric: Option<Metric>,
+        _wait_policy: WaitPolicy,
+    ) -> Result<()> {
+        if !self.ensure_index(index_name, 0, None).await? {
+            return Err(
+                RagError::AlreadyExists(format!("index {} already exists", index_name)).into(),
+            );
+        }
+        Ok(())
+    }
+
+    async fn ensure_index(
+        &mut self,
+        index_name: &str,
+        _dimension: i32,
+        _metric: Option<Metric>,
+    ) -> Result<bool> {
+        let mut indexes = self.indexes.lock().unwrap();
+        let created = !indexes.contains_key(index_name);
+        indexes.entry(index_name.to_string()).or_default();
+        Ok(created)
+    }
+
+    async fn delete(
+        &mut self,
+        index_name: &str,
+        query_id: &str,
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        let namespace = namespace.unwrap_or(CURRENT_NAME_SPACE);
+        if let Some(vectors) = self.indexes.lock().unwrap().get_mut(index_name) {
+            vectors.retain(|vector| {
+                vector.namespace != namespace || vector.query_id.as_deref() != Some(query_id)
+            });
+        }
+        Ok(())
+    }
+
+    async fn delete_by_filter(
+        &mut self,
+        index_name: &str,
+        filter: Value,
+        confirm: bool,
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        if delete_filter(&filter, confirm)?.is_some() {
+            anyhow::bail!("metadata filters are not supported by the fake store");
+        }
+        let namespace = namespace.unwrap_or(CURRENT_NAME_SPACE);
+        if let Some(vectors) = self.indexes.lock().unwrap().get_mut(index_name) {
+            vectors.retain(|vector| vector.namespace != namespace);
+        }
+        Ok(())
+    }
+
+    async fn fetch_chunks(
+        &self,
+        index_name: &str,
+        ids: &[String],
+        namespace: Option<&str>,
+    ) -> Result<Vec<StoredChunk>> {
+        let namespace = namespace.unwrap_or(CURRENT_NAME_SPACE);
+        Ok(self
+            .vectors(index_name)
+            .into_iter()
+            .filter(|vector| vector.namespace == namespace && ids.contains(&vector.id))
+            .map(|vector| StoredChunk {
+                id: vector.id,
+                text: vector.text,
+                query_id: vector.query_id,
+                chunk_index: Some(vector.chunk_index),
+                embedding: vector.values,
+                metadata: vector.metadata,
+            })
+            .collect())
+    }
+
+    async fn list_chunks(
+        &self,
+        index_name: &str,
+        namespace: Option<&str>,
+    ) -> Result<Vec<StoredChunk>> {
+        let ids: Vec<String> = self
+            .vectors(index_name)
+            .into_iter()
+            .map(|vector| vector.id)
+            .collect();
+        self.fetch_chunks(index_name, &ids, namespace).await
+    }
+
+    async fn replace_chunks(
+        &mut self,
+        index_name: &str,
+        chunks: Vec<StoredChunk>,
+        stale_ids: &[String],
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        let namespace = namespace.unwrap_or(CURRENT_NAME_SPACE);
+        let mut indexes = self.indexes.lock().unwrap();
+        let vectors = indexes.entry(index_name.to_string()).or_default();
+        for chunk in chunks {
+            let vector = StoredVector {
+                id: chunk.id,
+                query_id: chunk.query_id,
+                chunk_index: chunk.chunk_index.unwrap_or_default(),
+                namespace: namespace.to_string(),
+                values: chunk.embedding,
+                text: chunk.text,
+                timestamp: timestamp_field(&chunk.metadata),
+                metadata: chunk.metadata,
+            };
+            match vectors
+                .iter_mut()
+                .find(|stored| stored.namespace == namespace && stored.id == vector.id)
+            {
+                Some(stored) => *stored = vector,
+                None => vectors.push(vector),
+            }
+        }
+        vectors.retain(|vector| vector.namespace != namespace || !stale_ids.contains(&vector.id));
+        Ok(())
+    }
+
+    async fn store_document(
+        &mut self,
+        index_name: &str,
+        document: &TextToEmbed,
+        _values: Vec<f32>,
+    ) -> Result<()> {
+        let namespace = document.namespace.as_deref().unwrap_or(CURRENT_NAME_SPACE);
+        self.documents.lock().unwrap().insert(
+            (
+                index_name.to_string(),
+                namespace.to_string(),
+                document.query_id.clone(),
+            ),
+            StoredDocument::new(document)?,
+        );
+        Ok(())
+    }
+
+    async fn fetch_document(
+        &self,
+        index_name: &str,
+        query_id: &str,
+        namespace: Option<&str>,
+    ) -> Result<StoredDocument> {
+        let key = (
+            index_name.to_string(),
+            namespace.unwrap_or(CURRENT_NAME_SPACE).to_string(),
+            query_id.to_string(),
+        );
+        self.documents
+            .lock()
+            .unwrap()
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| RagError::NotFound(format!("no raw document for {}", query_id)).into())
+    }
+
+    async fn list_namespaces(&self, index_name: &str) -> Result<Vec<String>> {
+        let mut namespaces: Vec<String> = self
+            .vectors(index_name)
+            .into_iter()
+            .map(|vector| vector.namespace)
+            .filter(|namespace| !is_internal_namespace(namespace))
+            .collect();
+        namespaces.sort();
+        namespaces.dedup();
+        Ok(namespaces)
+    }
+
+    async fn index_metric(&self, _index_name: &str) -> Result<Metric> {
+        Ok(Metric::Cosine)
+    }
+
+    fn embedding_backend(&self) -> String {
+        "fake".to_string()
+    }
+
+    fn default_namespace(&self) -> String {
+        CURRENT_NAME_SPACE.to_string()
+    }
+}
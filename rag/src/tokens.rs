@@ -0,0 +1,441 @@
+use std::{collections::HashMap, fs, ops::Deref, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hf_hub::api::sync::Api;
+use regex::Regex;
+use tokenizers::{Encoding, Tokenizer};
+use tracing::{info, warn};
+
+/// Hugging Face repository the tokenizer is downloaded from when no local file is set.
+pub const DEFAULT_TOKENIZER_REPO: &str = "TinyLlama/TinyLlama-1.1B-Chat-v1.0";
+
+/// Counts, encodes and decodes the tokens of texts, as the tokenizer of an embedding model.
+///
+/// Implemented by Hugging Face `Tokenizer`s and by `TiktokenCounter`, for the byte pair
+/// encodings of OpenAI models, whose token counts differ significantly.
+pub trait TokenCounter: Send + Sync {
+    /// Counts the number of tokens of the text, including the special tokens the tokenizer
+    /// adds, i.e. as the text is embedded.
+    fn count(&self, text: &str) -> Result<usize>;
+
+    /// Encodes the text into the ids of its tokens, without special tokens.
+    fn encode(&self, text: &str) -> Result<Vec<u32>>;
+
+    /// Decodes token ids back into text, skipping special tokens.
+    fn decode(&self, ids: &[u32]) -> Result<String>;
+
+    /// Truncates the text to its longest prefix holding at most `max_tokens` tokens, as
+    /// counted by `count`. The text is returned unchanged if it already fits.
+    ///
+    /// The default implementation searches the longest fitting prefix ending at a character
+    /// boundary, which may cut a token in two.
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> Result<&'a str> {
+        if self.count(text)? <= max_tokens {
+            return Ok(text);
+        }
+        let boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+        // `boundaries[0]`, the empty prefix, is assumed to fit
+        let (mut fits, mut exceeds) = (0, boundaries.len());
+        while exceeds - fits > 1 {
+            let middle = (fits + exceeds) / 2;
+            if self.count(&text[..boundaries[middle]])? <= max_tokens {
+                fits = middle;
+            } else {
+                exceeds = middle;
+            }
+        }
+        Ok(&text[..boundaries[fits]])
+    }
+
+    /// Returns the name of the tokenizer model, if known, reported by `/info`.
+    fn model_name(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Encodes a text with a Hugging Face tokenizer, with or without its special tokens.
+fn hf_encoding(tokenizer: &Tokenizer, text: &str, add_special_tokens: bool) -> Result<Encoding> {
+    // NOTE: The inherent methods are those of the dereferenced `TokenizerImpl`
+    tokenizer
+        .deref()
+        .encode(text, add_special_tokens)
+        .map_err(|e| anyhow!("Failed to encode text: '{}', with error: {}", text, e))
+}
+
+impl TokenCounter for Tokenizer {
+    fn count(&self, text: &str) -> Result<usize> {
+        let encoding = hf_encoding(self, text, true)?;
+        Ok(encoding.get_ids().len())
+    }
+
+    fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        let encoding = hf_encoding(self, text, false)?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    fn decode(&self, ids: &[u32]) -> Result<String> {
+        self.deref()
+            .decode(ids, true)
+            .map_err(|e| anyhow!("Failed to decode tokens: {}", e))
+    }
+
+    /// Cuts the text at a token boundary, from the offsets of its encoding.
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> Result<&'a str> {
+        let count = self.count(text)?;
+        if count <= max_tokens {
+            return Ok(text);
+        }
+        let encoding = hf_encoding(self, text, false)?;
+        // Special tokens added by the tokenizer also count towards the limit
+        let special_tokens = count - encoding.get_ids().len();
+        let kept = max_tokens.saturating_sub(special_tokens);
+        if kept == 0 {
+            return Ok("");
+        }
+        let end = encoding.get_offsets()[kept - 1].1;
+        Ok(&text[..end])
+    }
+
+    fn model_name(&self) -> Option<String> {
+        let model = serde_json::to_value(self.get_model()).ok()?;
+        model["type"].as_str().map(str::to_string)
+    }
+}
+
+/// Pre-tokenizer pattern of the `cl100k_base` encoding, less its `\s+(?!\S)` alternative,
+/// which the `regex` crate cannot express and `TiktokenCounter::pieces` emulates.
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+";
+
+/// Counts tokens with a tiktoken byte pair encoding, e.g. `cl100k_base` of OpenAI models.
+///
+/// The encoding is loaded from a `.tiktoken` file, holding a base64 encoded token and its
+/// rank per line, and texts are pre-tokenized as by `cl100k_base`. Tiktoken encodings add
+/// no special tokens.
+pub struct TiktokenCounter {
+    /// Name of the encoding, e.g. `cl100k_base`
+    name: Option<String>,
+    /// Rank of each token, by its bytes
+    ranks: HashMap<Vec<u8>, u32>,
+    /// Bytes of each token, by its rank
+    tokens: HashMap<u32, Vec<u8>>,
+    /// Pre-tokenizer splitting texts into the pieces encoded separately
+    pattern: Regex,
+}
+
+impl TiktokenCounter {
+    /// Builds a counter from the rank of each token, by its bytes.
+    pub fn new(ranks: HashMap<Vec<u8>, u32>) -> Self {
+        let tokens = ranks
+            .iter()
+            .map(|(token, rank)| (*rank, token.clone()))
+            .collect();
+        TiktokenCounter {
+            name: None,
+            ranks,
+            tokens,
+            pattern: Regex::new(CL100K_PATTERN).expect("Invalid pre-tokenizer pattern"),
+        }
+    }
+
+    /// Loads the encoding of a `.tiktoken` file, named after the file, e.g. `cl100k_base`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read, or a line is not a base64 encoded token
+    /// followed by its rank.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read encoding {}: {}", path.display(), e))?;
+        let mut ranks = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = || anyhow!("Invalid line {} of encoding {}", number + 1, path.display());
+            let (token, rank) = line.split_once(' ').ok_or_else(invalid)?;
+            let token = STANDARD.decode(token).map_err(|_| invalid())?;
+            let rank = rank.trim().parse().map_err(|_| invalid())?;
+            ranks.insert(token, rank);
+        }
+        let mut counter = Self::new(ranks);
+        counter.name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned());
+        Ok(counter)
+    }
+
+    /// Splits a text into the pieces encoded separately.
+    ///
+    /// A run of whitespace followed by a word leaves its last character to the word, as the
+    /// `\s+(?!\S)` alternative of the `cl100k_base` pattern does.
+    fn pieces<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        let mut pieces = vec![];
+        let mut start = 0;
+        while let Some(found) = self.pattern.find_at(text, start) {
+            let mut end = found.end();
+            let piece = found.as_str();
+            let before_word = text[end..].starts_with(|c: char| !c.is_whitespace());
+            if before_word && piece.chars().all(char::is_whitespace) {
+                if let Some((last, _)) = piece.char_indices().last().filter(|(i, _)| *i > 0) {
+                    end = found.start() + last;
+                }
+            }
+            pieces.push(&text[found.start()..end]);
+            start = end;
+        }
+        pieces
+    }
+
+    /// Encodes a piece by repeatedly merging its adjacent parts of lowest rank.
+    fn encode_piece(&self, piece: &[u8]) -> Result<Vec<u32>> {
+        if let Some(rank) = self.ranks.get(piece) {
+            return Ok(vec![*rank]);
+        }
+        // Start offsets of the parts, followed by the end of the piece
+        let mut bounds: Vec<usize> = (0..=piece.len()).collect();
+        while bounds.len() > 2 {
+            let merge = (0..bounds.len() - 2)
+                .filter_map(|i| {
+                    let rank = self.ranks.get(&piece[bounds[i]..bounds[i + 2]])?;
+                    Some((*rank, i))
+                })
+                .min();
+            match merge {
+                Some((_, i)) => {
+                    bounds.remove(i + 1);
+                }
+                None => break,
+            }
+        }
+        bounds
+            .windows(2)
+            .map(|part| {
+                let bytes = &piece[part[0]..part[1]];
+                self.ranks
+                    .get(bytes)
+                    .copied()
+                    .ok_or_else(|| anyhow!("No token of the encoding for bytes {:?}", bytes))
+            })
+            .collect()
+    }
+}
+
+impl TokenCounter for TiktokenCounter {
+    fn count(&self, text: &str) -> Result<usize> {
+        Ok(self.encode(text)?.len())
+    }
+
+    fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        let mut ids = vec![];
+        for piece in self.pieces(text) {
+            ids.extend(self.encode_piece(piece.as_bytes())?);
+        }
+        Ok(ids)
+    }
+
+    fn decode(&self, ids: &[u32]) -> Result<String> {
+        let mut bytes = vec![];
+        for id in ids {
+            let token = self
+                .tokens
+                .get(id)
+                .ok_or_else(|| anyhow!("Unknown token {}", id))?;
+            bytes.extend_from_slice(token);
+        }
+        String::from_utf8(bytes).map_err(|e| anyhow!("Tokens do not decode to UTF-8: {}", e))
+    }
+
+    /// Cuts the text after its first `max_tokens` tokens, or before, at a character boundary,
+    /// as tokens may hold part of a character.
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> Result<&'a str> {
+        let ids = self.encode(text)?;
+        if ids.len() <= max_tokens {
+            return Ok(text);
+        }
+        let mut end: usize = ids[..max_tokens]
+            .iter()
+            .map(|id| self.tokens.get(id).map_or(0, Vec::len))
+            .sum();
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        Ok(&text[..end])
+    }
+
+    fn model_name(&self) -> Option<String> {
+        self.name.clone()
+    }
+}
+
+/// Kind of the tokenizer file loaded by `load_token_counter`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TokenizerKind {
+    /// A Hugging Face `tokenizer.json`, the default
+    #[default]
+    HuggingFace,
+    /// A tiktoken `.tiktoken` encoding
+    Tiktoken,
+}
+
+impl FromStr for TokenizerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "huggingface" => Ok(TokenizerKind::HuggingFace),
+            "tiktoken" => Ok(TokenizerKind::Tiktoken),
+            _ => Err(anyhow!("expected `huggingface` or `tiktoken`")),
+        }
+    }
+}
+
+/// Loads a tokenizer from a local `tokenizer.json` file.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, or is not a valid tokenizer.
+pub fn load_tokenizer(path: &Path) -> Result<Tokenizer> {
+    Tokenizer::from_file(path)
+        .map_err(|e| anyhow!("Failed to load tokenizer from {}: {}", path.display(), e))
+}
+
+/// Loads the token counter of a local tokenizer file of the given kind.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, or is not a valid tokenizer of its kind.
+pub fn load_token_counter(kind: TokenizerKind, path: &Path) -> Result<Arc<dyn TokenCounter>> {
+    Ok(match kind {
+        TokenizerKind::HuggingFace => Arc::new(load_tokenizer(path)?),
+        TokenizerKind::Tiktoken => Arc::new(TiktokenCounter::from_file(path)?),
+    })
+}
+
+/// Downloads the `tokenizer.json` of a Hugging Face repository, or reuses the cached one.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be downloaded, e.g. without network access, or is not a
+/// valid tokenizer.
+pub fn download_tokenizer(repo: &str) -> Result<Tokenizer> {
+    let path = Api::new()
+        .and_then(|api| api.model(repo.to_string()).get("tokenizer.json"))
+        .map_err(|e| anyhow!("Failed to download tokenizer from {}: {}", repo, e))?;
+    load_tokenizer(&path)
+}
+
+/// Resolves the token counter of the server.
+///
+/// The tokenizer is loaded from the local `path` if set, so that air-gapped deployments need no
+/// network access. Otherwise the `tokenizer.json` of the Hugging Face `repo` is downloaded,
+/// which is only possible for the `HuggingFace` kind. Without either, no token counter is
+/// loaded, unless it is `required`, e.g. by a token based split criteria.
+///
+/// # Errors
+///
+/// Returns an error if the local file cannot be loaded, or if a required tokenizer is neither
+/// set locally nor downloaded.
+pub fn resolve_token_counter(
+    kind: TokenizerKind,
+    path: Option<&Path>,
+    repo: &str,
+    required: bool,
+) -> Result<Option<Arc<dyn TokenCounter>>> {
+    if let Some(path) = path {
+        return load_token_counter(kind, path).map(Some);
+    }
+    let downloaded = match kind {
+        TokenizerKind::HuggingFace => download_tokenizer(repo),
+        TokenizerKind::Tiktoken => Err(anyhow!("tiktoken encodings cannot be downloaded")),
+    };
+    match downloaded {
+        Ok(tokenizer) => {
+            info!("Loaded tokenizer from {}", repo);
+            Ok(Some(Arc::new(tokenizer)))
+        }
+        Err(e) if required => Err(anyhow!(
+            "A tokenizer is required but none is available, set TOKENIZER_PATH to a local \
+             tokenizer file: {}",
+            e
+        )),
+        Err(e) => {
+            warn!("No tokenizer loaded: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::word_level_tokenizer;
+
+    #[test]
+    fn test_load_tokenizer_from_local_fixture() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/tokenizer.json");
+        let counter = resolve_token_counter(TokenizerKind::HuggingFace, Some(&path), "", true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            counter.encode("The quick brown fox.").unwrap(),
+            [1, 2, 3, 4, 5]
+        );
+        assert_eq!(counter.model_name().as_deref(), Some("WordLevel"));
+
+        let missing = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/missing.json");
+        assert!(
+            resolve_token_counter(TokenizerKind::HuggingFace, Some(&missing), "", false).is_err()
+        );
+        // tiktoken encodings are only loaded from local files
+        let error = resolve_token_counter(TokenizerKind::Tiktoken, None, "", true)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("TOKENIZER_PATH"));
+        assert!(
+            resolve_token_counter(TokenizerKind::Tiktoken, None, "", false)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_count_tokens_matches_encoding() {
+        let tokenizer = word_level_tokenizer();
+        let text = "This is a test, with punctuation.";
+        let expected = hf_encoding(&tokenizer, text, true).unwrap().get_ids().len();
+        assert_eq!(tokenizer.count(text).unwrap(), expected);
+        assert_eq!(tokenizer.count(text).unwrap(), 8);
+        assert_eq!(tokenizer.count("").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_truncate_to_tokens() {
+        let tokenizer = word_level_tokenizer();
+        let text = "one two three, four";
+        assert_eq!(tokenizer.truncate(text, 10).unwrap(), text);
+        assert_eq!(tokenizer.truncate(text, 5).unwrap(), text);
+        assert_eq!(tokenizer.truncate(text, 3).unwrap(), "one two three");
+        assert_eq!(tokenizer.truncate(text, 0).unwrap(), "");
+    }
+
+    #[test]
+    fn test_tiktoken_merges_pairs_by_rank() {
+        let mut ranks: HashMap<Vec<u8>, u32> =
+            (0..=255u8).map(|byte| (vec![byte], byte as u32)).collect();
+        for (rank, token) in ["ab", "abc", " ab"].iter().enumerate() {
+            ranks.insert(token.as_bytes().to_vec(), 256 + rank as u32);
+        }
+        let counter = TiktokenCounter::new(ranks);
+        // "abc" merges "ab" first, then "abc"; the space before a word is merged with it
+        assert_eq!(counter.encode("abc").unwrap(), vec![257]);
+        assert_eq!(
+            counter.encode("abc  abd").unwrap(),
+            vec![257, b' ' as u32, 258, b'd' as u32]
+        );
+        let ids = counter.encode("abc é").unwrap();
+        assert_eq!(counter.decode(&ids).unwrap(), "abc é");
+        // The two byte `é` is not cut in half
+        assert_eq!(counter.truncate("abc é", 3).unwrap(), "abc ");
+    }
+}
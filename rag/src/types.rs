@@ -1,3 +1,5 @@
+use crate::error::RagError;
+use pinecone_sdk::models::Metric;
 use serde::{Deserialize, Serialize};
 
 /// Represents a text document to be embedded
@@ -21,6 +23,200 @@ pub struct TextToEmbed {
     pub page: Option<u16>,
     /// Optional publication date of the document
     pub date: Option<String>,
+    /// Whether to create the index if it does not exist yet
+    pub create_if_missing: Option<bool>,
+    /// Optional dimension of the index to create, inferred from the embedding if not set
+    pub dimension: Option<i32>,
+    /// Optional similarity metric of the index to create
+    pub metric: Option<MetricOptions>,
+    /// Whether to only split the content and return the chunks, without storing them
+    pub dry_run: Option<bool>,
+    /// Whether to report the token count of each chunk in the response, as `chunk_tokens`
+    pub verbose: Option<bool>,
+    /// Optional additional metadata fields, stored alongside each chunk.
+    ///
+    /// Values must be strings, numbers, booleans or lists of strings.
+    pub extra: Option<serde_json::Map<String, serde_json::Value>>,
+    /// How to handle chunks previously stored for the same `query_id`, defaults to `Append`
+    pub upsert_mode: Option<UpsertMode>,
+    /// Optional prefix of the ids of the stored chunks, e.g. `"tweet-"`, overriding the server's one
+    pub id_prefix: Option<String>,
+    /// Optional Pinecone namespace the chunks are stored in, overriding the server's one
+    pub namespace: Option<String>,
+    /// Optional URL of an image embedded along with each chunk, by a multimodal embedding model
+    pub image_url: Option<String>,
+    /// Optional ISO 639-1 language of the content, e.g. `en`, detected by the server if unset
+    /// and language detection is enabled
+    pub detected_lang: Option<String>,
+}
+
+/// Available modes for storing the chunks of a document
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UpsertMode {
+    /// Add the new chunks, keeping the ones previously stored for the same `query_id`
+    #[default]
+    Append,
+    /// Delete all the chunks previously stored for the same `query_id` before adding the new ones
+    Replace,
+}
+
+/// Builder of a `TextToEmbed`, requiring its `query_id`, `index_name` and `content`, and
+/// defaulting the optional fields to `None`
+#[derive(Debug)]
+pub struct TextToEmbedBuilder {
+    text_to_embed: TextToEmbed,
+}
+
+impl TextToEmbed {
+    /// Starts building a `TextToEmbed` from its required fields
+    pub fn builder(
+        query_id: impl Into<String>,
+        index_name: impl Into<String>,
+        content: impl Into<String>,
+    ) -> TextToEmbedBuilder {
+        TextToEmbedBuilder::new(query_id, index_name, content)
+    }
+}
+
+impl TextToEmbedBuilder {
+    /// Constructor, from the required fields
+    pub fn new(
+        query_id: impl Into<String>,
+        index_name: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
+        Self {
+            text_to_embed: TextToEmbed {
+                query_id: query_id.into(),
+                index_name: index_name.into(),
+                content: content.into(),
+                topic: None,
+                description: None,
+                source: None,
+                author: None,
+                page: None,
+                date: None,
+                create_if_missing: None,
+                dimension: None,
+                metric: None,
+                dry_run: None,
+                verbose: None,
+                extra: None,
+                upsert_mode: None,
+                id_prefix: None,
+                namespace: None,
+                image_url: None,
+                detected_lang: None,
+            },
+        }
+    }
+
+    /// Sets the topic of the document
+    pub fn with_topic(mut self, topic: impl Into<String>) -> Self {
+        self.text_to_embed.topic = Some(topic.into());
+        self
+    }
+
+    /// Sets the description of the document
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.text_to_embed.description = Some(description.into());
+        self
+    }
+
+    /// Sets the source of the document
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.text_to_embed.source = Some(source.into());
+        self
+    }
+
+    /// Sets the author of the document
+    pub fn with_author(mut self, author: impl Into<String>) -> Self {
+        self.text_to_embed.author = Some(author.into());
+        self
+    }
+
+    /// Sets the page number of the document
+    pub fn with_page(mut self, page: u16) -> Self {
+        self.text_to_embed.page = Some(page);
+        self
+    }
+
+    /// Sets the publication date of the document
+    pub fn with_date(mut self, date: impl Into<String>) -> Self {
+        self.text_to_embed.date = Some(date.into());
+        self
+    }
+
+    /// Sets whether to create the index if it does not exist yet
+    pub fn with_create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.text_to_embed.create_if_missing = Some(create_if_missing);
+        self
+    }
+
+    /// Sets the dimension of the index to create
+    pub fn with_dimension(mut self, dimension: i32) -> Self {
+        self.text_to_embed.dimension = Some(dimension);
+        self
+    }
+
+    /// Sets the similarity metric of the index to create
+    pub fn with_metric(mut self, metric: MetricOptions) -> Self {
+        self.text_to_embed.metric = Some(metric);
+        self
+    }
+
+    /// Sets whether to only split the content and return the chunks
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.text_to_embed.dry_run = Some(dry_run);
+        self
+    }
+
+    /// Sets whether to report the token count of each chunk in the response
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.text_to_embed.verbose = Some(verbose);
+        self
+    }
+
+    /// Sets the additional metadata fields, leaving them unset if empty
+    pub fn with_extra(mut self, extra: serde_json::Map<String, serde_json::Value>) -> Self {
+        self.text_to_embed.extra = (!extra.is_empty()).then_some(extra);
+        self
+    }
+
+    /// Sets how to handle chunks previously stored for the same `query_id`
+    pub fn with_upsert_mode(mut self, upsert_mode: UpsertMode) -> Self {
+        self.text_to_embed.upsert_mode = Some(upsert_mode);
+        self
+    }
+
+    /// Sets the prefix of the ids of the stored chunks
+    pub fn with_id_prefix(mut self, id_prefix: impl Into<String>) -> Self {
+        self.text_to_embed.id_prefix = Some(id_prefix.into());
+        self
+    }
+
+    /// Sets the Pinecone namespace the chunks are stored in
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.text_to_embed.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Sets the URL of the image embedded along with each chunk
+    pub fn with_image_url(mut self, image_url: impl Into<String>) -> Self {
+        self.text_to_embed.image_url = Some(image_url.into());
+        self
+    }
+
+    /// Sets the ISO 639-1 language of the content
+    pub fn with_detected_lang(mut self, detected_lang: impl Into<String>) -> Self {
+        self.text_to_embed.detected_lang = Some(detected_lang.into());
+        self
+    }
+
+    /// Builds the `TextToEmbed`
+    pub fn build(self) -> TextToEmbed {
+        self.text_to_embed
+    }
 }
 
 /// Input parameters for querying the index
@@ -30,21 +226,309 @@ pub struct QueryInput {
     pub index_name: String,
     /// The text to search for in the index
     pub query_text: String,
-    /// Optional number of top results to return
+    /// Optional number of top results to return, defaults to the server's default `top_k`
     pub top_k: Option<u32>,
     /// Optional score threshold for filtering results
     pub score_threshold: Option<f32>,
+    /// Optional number of results to skip, for paging through results
+    pub offset: Option<u32>,
+    /// Optional Pinecone metadata filter the results must match
+    pub filter: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Optional earliest date of the results, inclusive
+    pub date_from: Option<String>,
+    /// Optional latest date of the results, inclusive
+    pub date_to: Option<String>,
+    /// Whether to return the embedding of each result, defaults to `false` as embeddings make
+    /// up most of the response payload and are rarely used
+    pub include_values: Option<bool>,
+    /// Whether to rescale the scores of the results to [0, 1], keeping the original score
+    /// in `raw_score`. Defaults to `false`
+    pub normalize_scores: Option<bool>,
+    /// Optional Pinecone namespace to query, defaults to the server's one
+    pub namespace: Option<String>,
+    /// Optional number of chunks of the same document to return before and after each
+    /// result, concatenated with it in `context`. Defaults to `0`, returning no context
+    pub context_window: Option<usize>,
+    /// Whether to keep only the best ranked chunk of each document, telling documents apart
+    /// by their stored `query_id`. Defaults to `false`
+    pub dedupe_by_document: Option<bool>,
+    /// Optional number of days after which the score of a result is halved, favoring recent
+    /// results by their stored `timestamp`. Results without one are not weighted
+    pub recency_half_life_days: Option<f32>,
+    /// How to handle a query text over the server's `max_query_tokens`, which the embedding
+    /// model would otherwise truncate. Unset by default, embedding the text as is
+    pub long_query_strategy: Option<LongQueryStrategy>,
+}
+
+/// Available strategies for queries over the server's `max_query_tokens`, which are split
+/// into chunks embedded separately
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LongQueryStrategy {
+    /// Query with the mean of the embeddings of the chunks
+    Average,
+    /// Query with each embedding, keeping the best score of each result
+    MultiVector,
 }
 
 /// Represents a single query response item
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct QueryResponse {
-    /// Similarity score of the result
+    /// Id of the stored vector
+    #[serde(default)]
+    pub id: String,
+    /// Similarity score of the result, rescaled to [0, 1] if `normalize_scores` is set
     pub score: f32,
+    /// Score of the result as returned by the index, set when `score` is rescaled or weighted
+    /// by recency
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_score: Option<f32>,
     /// Vector representation of the text
     pub embedding: Vec<f32>,
     /// The actual text content of the result
     pub text: String,
+    /// The name of the index the result comes from
+    pub index_name: Option<String>,
+    /// The `query_id` of the document the result is a chunk of, if stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_id: Option<String>,
+    /// The position of the result among the chunks of its document, if stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_index: Option<usize>,
+    /// The text of the result along with its neighboring chunks, set when a `context_window`
+    /// is requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    /// The date of the document the result is a chunk of, as the number of seconds since the
+    /// epoch stored as its `timestamp`, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    /// The author of the document the result is a chunk of, if stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// The L2 norm of `embedding`, set when `include_values` is, to tell unnormalized or
+    /// degenerate vectors apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub norm: Option<f32>,
+}
+
+/// A page of query results
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QueryResults {
+    /// The results in the requested page
+    pub results: Vec<QueryResponse>,
+    /// The number of results in the page
+    pub returned: usize,
+    /// Whether more results are available after this page
+    pub has_more: bool,
+    /// The effective number of results requested, after applying the default
+    pub top_k: usize,
+    /// A warning about the results, e.g. that the index was built with another embedding
+    /// model than the one the query was embedded with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+impl QueryResults {
+    /// Builds a page of results from a window of results fetched from the index.
+    ///
+    /// The first `offset` results are skipped and at most `limit` results are kept.
+    /// `has_more` is set if the window holds results past the end of the page.
+    pub fn from_window(window: Vec<QueryResponse>, offset: usize, limit: usize) -> Self {
+        let has_more = window.len() > offset.saturating_add(limit);
+        let results: Vec<QueryResponse> = window.into_iter().skip(offset).take(limit).collect();
+        QueryResults {
+            returned: results.len(),
+            results,
+            has_more,
+            top_k: limit,
+            warning: None,
+        }
+    }
+}
+
+/// The outcome of a single query of a `/batch_query` request
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum BatchQueryResult {
+    /// The page of results of a successful query
+    Results(Vec<QueryResponse>),
+    /// The error of a failed query, with the HTTP status it would have been answered with
+    Error { error: String, status: u16 },
+}
+
+/// Input parameters for embedding the records of an arbitrary JSON array, see `/embed_json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbedJsonInput {
+    /// The name of the index in Pinecone storage
+    pub index_name: String,
+    /// The records, each embedded as a document
+    pub records: Vec<serde_json::Value>,
+    /// Selector of the string field of each record that is embedded, e.g. `body` or `post.text`
+    pub content_field: String,
+    /// Optional selectors of the fields stored as metadata, under their selector, skipped
+    /// for the records that do not hold them
+    pub metadata_fields: Option<Vec<String>>,
+    /// Optional selector of the field used as the `query_id` of each record, defaults to the
+    /// position of the record in `records`
+    pub id_field: Option<String>,
+    /// Whether to create the index if it does not exist yet
+    pub create_if_missing: Option<bool>,
+    /// Whether to only split the contents, without storing them
+    pub dry_run: Option<bool>,
+}
+
+impl EmbedJsonInput {
+    /// Maps the record at the given position into the document it is embedded as.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RagError::InvalidInput` if the record has no string content field, or if
+    /// its id field is neither a string nor a number.
+    pub fn to_text_to_embed(&self, index: usize) -> Result<TextToEmbed, RagError> {
+        let record = &self.records[index];
+        let content = match select(record, &self.content_field) {
+            Some(serde_json::Value::String(content)) => content.clone(),
+            Some(_) => {
+                return Err(RagError::InvalidInput(format!(
+                    "content field `{}` is not a string",
+                    self.content_field
+                )))
+            }
+            None => {
+                return Err(RagError::InvalidInput(format!(
+                    "missing content field `{}`",
+                    self.content_field
+                )))
+            }
+        };
+        let query_id = match &self.id_field {
+            Some(id_field) => match select(record, id_field) {
+                Some(serde_json::Value::String(id)) => id.clone(),
+                Some(serde_json::Value::Number(id)) => id.to_string(),
+                _ => {
+                    return Err(RagError::InvalidInput(format!(
+                        "missing id field `{}`",
+                        id_field
+                    )))
+                }
+            },
+            None => index.to_string(),
+        };
+        let extra: serde_json::Map<String, serde_json::Value> = self
+            .metadata_fields
+            .iter()
+            .flatten()
+            .filter_map(|field| match select(record, field) {
+                Some(serde_json::Value::Null) | None => None,
+                Some(value) => Some((field.clone(), value.clone())),
+            })
+            .collect();
+        let mut builder = TextToEmbed::builder(query_id, &self.index_name, content);
+        if !extra.is_empty() {
+            builder = builder.with_extra(extra);
+        }
+        if let Some(create_if_missing) = self.create_if_missing {
+            builder = builder.with_create_if_missing(create_if_missing);
+        }
+        if let Some(dry_run) = self.dry_run {
+            builder = builder.with_dry_run(dry_run);
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Selects a field of a JSON value with a dotted path, e.g. `author.name` or `tags.0`,
+/// numeric segments indexing into arrays.
+pub fn select<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.')
+        .try_fold(value, |value, segment| match value {
+            serde_json::Value::Object(fields) => fields.get(segment),
+            serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+/// Input parameters for deleting the embeddings matching a metadata filter
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteByFilterInput {
+    /// The name of the index to delete from
+    pub index_name: String,
+    /// The Pinecone metadata filter the deleted embeddings match
+    pub filter: serde_json::Value,
+    /// Whether deleting everything is intended, required for an empty filter
+    pub confirm: Option<bool>,
+    /// Optional Pinecone namespace to delete from, defaults to the server's one
+    pub namespace: Option<String>,
+}
+
+/// Input parameters for compacting the chunks of an index
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompactIndexInput {
+    /// The name of the index to compact
+    pub index_name: String,
+    /// The maximum number of tokens of a merged chunk
+    pub target_tokens: usize,
+    /// Optional Pinecone namespace to compact, defaults to the server's one
+    pub namespace: Option<String>,
+}
+
+/// Query string parameters for listing the namespaces of an index
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NamespacesInput {
+    /// The name of the index to list the namespaces of
+    pub index: String,
+}
+
+/// Query string parameters for fetching the raw document of a `query_id`, see `/document`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentInput {
+    /// The name of the index the document is stored in
+    pub index: String,
+    /// The `query_id` of the document
+    pub query_id: String,
+    /// Optional namespace the document was embedded in, the default one if unset
+    pub namespace: Option<String>,
+}
+
+/// Input parameters for re-embedding a stored chunk, see `/debug/rescore`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RescoreInput {
+    /// The name of the index the chunk is stored in
+    pub index: String,
+    /// The full id of the stored chunk
+    pub id: String,
+    /// Optional Pinecone namespace the chunk is stored in, defaults to the server's one
+    pub namespace: Option<String>,
+}
+
+/// A stored chunk re-embedded by `/debug/rescore`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RescoreResponse {
+    /// The full id of the stored chunk
+    pub id: String,
+    /// The text stored alongside the embedding, which is re-embedded
+    pub text: String,
+    /// The embedding stored in the index
+    pub stored_embedding: Vec<f32>,
+    /// The embedding the embedding service now returns for the text
+    pub fresh_embedding: Vec<f32>,
+    /// Cosine similarity of the stored and fresh embeddings, close to `1` unless the
+    /// embedding model changed since the chunk was stored
+    pub similarity: f32,
+}
+
+/// Input parameters for counting the tokens of a text
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CountTokensInput {
+    /// The text to count the tokens of
+    pub text: String,
+}
+
+/// Input parameters for estimating the cost of embedding a document
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EstimateInput {
+    /// The content of the document, split as `/embed` would split it
+    pub content: String,
 }
 
 /// Input parameters for creating a new index
@@ -56,6 +540,10 @@ pub struct CreateIndexInput {
     pub dimension: i32,
     /// Optional similarity metric to use for the index
     pub metric: Option<MetricOptions>,
+    /// Whether to wait for the index to be ready before responding, defaults to `false`
+    pub wait: Option<bool>,
+    /// Maximum number of seconds to wait for the index to be ready, defaults to 300
+    pub wait_timeout_secs: Option<u64>,
 }
 
 /// Available similarity metrics for index creation
@@ -68,3 +556,139 @@ pub enum MetricOptions {
     /// Dot product
     Dotproduct,
 }
+
+impl From<MetricOptions> for Metric {
+    fn from(metric: MetricOptions) -> Self {
+        match metric {
+            MetricOptions::Cosine => Metric::Cosine,
+            MetricOptions::Euclidean => Metric::Euclidean,
+            MetricOptions::Dotproduct => Metric::Dotproduct,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_to_embed_builder() {
+        let minimal = TextToEmbed::builder("query-id", "index", "Some content.").build();
+        assert_eq!(minimal.query_id, "query-id");
+        assert_eq!(minimal.index_name, "index");
+        assert_eq!(minimal.content, "Some content.");
+        assert!(minimal.topic.is_none() && minimal.extra.is_none() && minimal.id_prefix.is_none());
+
+        let mut extra = serde_json::Map::new();
+        extra.insert("likes".to_string(), 42.into());
+        let full = TextToEmbed::builder("query-id", "index", "Some content.")
+            .with_topic("topic")
+            .with_description("description")
+            .with_source("x")
+            .with_author("atoma")
+            .with_page(3)
+            .with_date("2024-11-01T12:00:00.000Z")
+            .with_create_if_missing(true)
+            .with_dimension(768)
+            .with_metric(MetricOptions::Dotproduct)
+            .with_dry_run(false)
+            .with_extra(extra)
+            .with_upsert_mode(UpsertMode::Replace)
+            .with_id_prefix("doc-")
+            .build();
+        assert_eq!(full.topic.as_deref(), Some("topic"));
+        assert_eq!(full.description.as_deref(), Some("description"));
+        assert_eq!(full.source.as_deref(), Some("x"));
+        assert_eq!(full.author.as_deref(), Some("atoma"));
+        assert_eq!(full.page, Some(3));
+        assert_eq!(full.date.as_deref(), Some("2024-11-01T12:00:00.000Z"));
+        assert_eq!(full.create_if_missing, Some(true));
+        assert_eq!(full.dimension, Some(768));
+        assert!(matches!(full.metric, Some(MetricOptions::Dotproduct)));
+        assert_eq!(full.dry_run, Some(false));
+        assert_eq!(full.extra.unwrap()["likes"], 42);
+        assert_eq!(full.upsert_mode, Some(UpsertMode::Replace));
+        assert_eq!(full.id_prefix.as_deref(), Some("doc-"));
+    }
+
+    #[test]
+    fn test_embed_json_records_are_mapped_with_their_selected_fields() {
+        let input: EmbedJsonInput = serde_json::from_value(serde_json::json!({
+            "index_name": "index",
+            "records": [
+                { "body": "First post.", "title": "First", "meta": { "url": "https://a.b/1" } },
+                { "body": "Second post.", "title": "Second" },
+                { "title": "No body" },
+            ],
+            "content_field": "body",
+            "metadata_fields": ["title", "meta.url"],
+        }))
+        .unwrap();
+
+        let first = input.to_text_to_embed(0).unwrap();
+        assert_eq!(first.query_id, "0");
+        assert_eq!(first.index_name, "index");
+        assert_eq!(first.content, "First post.");
+        let extra = first.extra.unwrap();
+        assert_eq!(extra["title"], "First");
+        assert_eq!(extra["meta.url"], "https://a.b/1");
+
+        let second = input.to_text_to_embed(1).unwrap();
+        assert_eq!(second.query_id, "1");
+        assert_eq!(second.content, "Second post.");
+        assert_eq!(second.extra.unwrap().len(), 1);
+
+        let error = input.to_text_to_embed(2).unwrap_err();
+        assert!(error.to_string().contains("missing content field `body`"));
+        assert_eq!(
+            select(&serde_json::json!({ "tags": ["a", "b"] }), "tags.1"),
+            Some(&serde_json::json!("b"))
+        );
+    }
+
+    #[test]
+    fn test_query_results_paging() {
+        let window: Vec<QueryResponse> = (0..15)
+            .map(|i| QueryResponse {
+                id: i.to_string(),
+                score: 1.0 - i as f32 / 100.0,
+                raw_score: None,
+                embedding: vec![],
+                text: format!("result {}", i),
+                index_name: None,
+                query_id: None,
+                chunk_index: None,
+                context: None,
+                timestamp: None,
+                author: None,
+                norm: None,
+            })
+            .collect();
+
+        let mut pages = vec![];
+        let mut offset = 0;
+        loop {
+            let page = QueryResults::from_window(window.clone(), offset, 5);
+            offset += page.returned;
+            let has_more = page.has_more;
+            pages.push(page);
+            if !has_more {
+                break;
+            }
+        }
+
+        assert_eq!(pages.len(), 3);
+        for (i, page) in pages.iter().enumerate() {
+            assert_eq!(page.returned, 5);
+            assert_eq!(page.top_k, 5);
+            assert_eq!(page.results[0].text, format!("result {}", i * 5));
+        }
+        assert!(pages[0].has_more);
+        assert!(pages[1].has_more);
+        assert!(!pages[2].has_more);
+
+        let past_end = QueryResults::from_window(window, 15, 5);
+        assert_eq!(past_end.returned, 0);
+        assert!(!past_end.has_more);
+    }
+}
@@ -0,0 +1,227 @@
+//! Validation of request payloads, rejecting invalid ones with a structured `400` body.
+
+use crate::{
+    client::{validate_dimension, MAX_INDEX_NAME_LENGTH},
+    types::TextToEmbed,
+};
+use axum::{
+    async_trait,
+    extract::{FromRequest, Json, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+
+/// Why a payload was rejected, returned as the `{ "error": ..., "field": ... }` body of the
+/// response.
+///
+/// `field` is the path of the offending field, e.g. `content` or `metric`, and is `None` if
+/// the payload as a whole is invalid, e.g. when it is not JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ValidationError {
+    /// What is wrong with the payload
+    pub error: String,
+    /// The path of the field at fault, if any
+    pub field: Option<String>,
+    /// The status of the response, `400` unless the body could not be read, e.g. `413` for
+    /// bodies over the size limit
+    #[serde(skip)]
+    pub status: StatusCode,
+}
+
+impl ValidationError {
+    /// Constructor, for an error on the given field
+    pub fn field(field: &str, error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            field: Some(field.to_string()),
+            status: StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "{}: {}", field, self.error),
+            None => f.write_str(&self.error),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl IntoResponse for ValidationError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+/// Payloads checking their own constraints once deserialized.
+pub trait Validate {
+    /// Checks the constraints of the payload.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `ValidationError` naming the first field violating a constraint.
+    fn validate(&self) -> Result<(), ValidationError>;
+}
+
+impl Validate for TextToEmbed {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.index_name.trim().is_empty() {
+            return Err(ValidationError::field(
+                "index_name",
+                "index_name must not be empty",
+            ));
+        }
+        if self.index_name.len() > MAX_INDEX_NAME_LENGTH {
+            return Err(ValidationError::field(
+                "index_name",
+                format!(
+                    "index_name must be at most {} characters long, got {}",
+                    MAX_INDEX_NAME_LENGTH,
+                    self.index_name.len()
+                ),
+            ));
+        }
+        if self.content.trim().is_empty() {
+            return Err(ValidationError::field("content", "content is empty"));
+        }
+        if self.page == Some(0) {
+            return Err(ValidationError::field(
+                "page",
+                "page numbers start at 1, got 0",
+            ));
+        }
+        if let Some(dimension) = self.dimension {
+            validate_dimension(dimension)
+                .map_err(|e| ValidationError::field("dimension", e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// JSON extractor rejecting payloads that cannot be deserialized, or that fail their
+/// `Validate` constraints, with a `ValidationError`.
+///
+/// Unlike `Json`, whose rejections are plain text, the rejection names the offending field,
+/// including for missing fields and fields of the wrong type.
+#[derive(Debug, Clone)]
+pub struct ValidatedJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ValidationError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        // NOTE: Going through a `Value` keeps the content type and syntax checks of `Json`,
+        // while the typed deserialization below can track the path of the field at fault
+        let Json(value) = Json::<serde_json::Value>::from_request(request, state)
+            .await
+            .map_err(|rejection| ValidationError {
+                error: rejection.body_text(),
+                field: None,
+                status: rejection.status(),
+            })?;
+        let input: T = serde_path_to_error::deserialize(value).map_err(deserialize_error)?;
+        input.validate()?;
+        Ok(Self(input))
+    }
+}
+
+/// Converts a deserialization error into a `ValidationError` naming the field at fault.
+fn deserialize_error(error: serde_path_to_error::Error<serde_json::Error>) -> ValidationError {
+    let path = error.path().to_string();
+    let message = error.into_inner().to_string();
+    // Missing fields are reported on their parent, with their name in the message
+    let missing = message
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.split_once('`'))
+        .map(|(name, _)| name);
+    let field = match (missing, path.as_str()) {
+        (Some(name), ".") => Some(name.to_string()),
+        (Some(name), parent) => Some(format!("{}.{}", parent, name)),
+        (None, ".") => None,
+        (None, path) => Some(path.to_string()),
+    };
+    ValidationError {
+        error: message,
+        field,
+        status: StatusCode::BAD_REQUEST,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::header::CONTENT_TYPE};
+    use serde_json::json;
+
+    async fn extract(body: serde_json::Value) -> Result<TextToEmbed, ValidationError> {
+        let request = Request::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let ValidatedJson(input) = ValidatedJson::from_request(request, &()).await?;
+        Ok(input)
+    }
+
+    #[tokio::test]
+    async fn test_rejects_missing_content() {
+        let error = extract(json!({ "query_id": "1", "index_name": "test-index" }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.field.as_deref(), Some("content"));
+        assert!(error.error.contains("missing field `content`"));
+
+        let error = extract(json!({ "query_id": "1", "index_name": "test-index", "content": 1 }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.field.as_deref(), Some("content"));
+
+        let response = ValidationError::field("content", "content is empty").into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_empty_index_name() {
+        let error = extract(json!({ "query_id": "1", "index_name": " ", "content": "Hello." }))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error,
+            ValidationError::field("index_name", "index_name must not be empty")
+        );
+
+        let error = extract(json!({
+            "query_id": "1", "index_name": "test-index", "content": "Hello.", "page": 0
+        }))
+        .await
+        .unwrap_err();
+        assert_eq!(error.field.as_deref(), Some("page"));
+    }
+
+    #[tokio::test]
+    async fn test_accepts_valid_payload() {
+        let input = extract(json!({
+            "query_id": "1", "index_name": "test-index", "content": "Hello.", "page": 3
+        }))
+        .await
+        .unwrap();
+        assert_eq!(input.content, "Hello.");
+        assert_eq!(input.page, Some(3));
+
+        let request = Request::builder().body(Body::from("not json")).unwrap();
+        let error = ValidatedJson::<TextToEmbed>::from_request(request, &())
+            .await
+            .unwrap_err();
+        assert_eq!(error.field, None);
+        assert_eq!(error.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
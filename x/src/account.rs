@@ -0,0 +1,182 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use rag::types::{TextToEmbed, TextToEmbedBuilder};
+use serde::Deserialize;
+
+/// Default `source` of the embedded tweets.
+pub const DEFAULT_SOURCE: &str = "x";
+
+/// An X account, and where its tweets are stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    /// The author of the tweets
+    pub author: String,
+    /// The `source` the tweets are tagged with, e.g. `x`
+    pub source: String,
+    /// The name of the index to store the embeddings in
+    pub index: String,
+    /// Optional Pinecone namespace to store the embeddings in, the server's default if `None`
+    pub namespace: Option<String>,
+}
+
+impl Account {
+    /// Starts building the `TextToEmbed` of a tweet of the account, tagged with its source and
+    /// author, and stored in its index and namespace.
+    pub fn builder(
+        &self,
+        query_id: impl Into<String>,
+        content: impl Into<String>,
+    ) -> TextToEmbedBuilder {
+        let builder = TextToEmbed::builder(query_id, &self.index, content)
+            .with_source(&self.source)
+            .with_author(&self.author);
+        match &self.namespace {
+            Some(namespace) => builder.with_namespace(namespace),
+            None => builder,
+        }
+    }
+}
+
+/// The archive files of an account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountArchive {
+    /// The account the archive belongs to
+    pub account: Account,
+    /// Path to the note tweets archive file
+    pub note_tweets: String,
+    /// Optional path to the tweets archive file, used to match note tweets to their tweets
+    pub tweets: Option<String>,
+    /// Optional path to the state file of the account (see `Watermark`)
+    pub state_file: Option<PathBuf>,
+}
+
+/// An account as listed in an accounts file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccountEntry {
+    author: String,
+    source: Option<String>,
+    index: Option<String>,
+    namespace: Option<String>,
+    note_tweets: String,
+    tweets: Option<String>,
+    state_file: Option<PathBuf>,
+}
+
+/// Parses the JSON array of the accounts to index, e.g.
+/// `[{"author": "atoma", "note_tweets": "atoma/note-tweet.js", "index": "atoma"}]`.
+///
+/// Each account may set its `source`, `index`, `namespace`, `tweets` and `state_file`, the
+/// missing `source`, `index` and `namespace` defaulting to the given ones.
+///
+/// Fails if no account is listed, or if two accounts share a state file, as each run would
+/// overwrite the watermark of the other.
+pub fn parse_accounts(
+    json: &str,
+    source: &str,
+    index: &str,
+    namespace: Option<&str>,
+) -> Result<Vec<AccountArchive>> {
+    let entries: Vec<AccountEntry> = serde_json::from_str(json)?;
+    if entries.is_empty() {
+        bail!("No account listed");
+    }
+    let archives: Vec<AccountArchive> = entries
+        .into_iter()
+        .map(|entry| AccountArchive {
+            account: Account {
+                author: entry.author,
+                source: entry.source.unwrap_or_else(|| source.to_string()),
+                index: entry.index.unwrap_or_else(|| index.to_string()),
+                namespace: entry.namespace.or_else(|| namespace.map(str::to_string)),
+            },
+            note_tweets: entry.note_tweets,
+            tweets: entry.tweets,
+            state_file: entry.state_file,
+        })
+        .collect();
+    for (i, archive) in archives.iter().enumerate() {
+        let Some(state_file) = &archive.state_file else {
+            continue;
+        };
+        if let Some(other) = archives[..i]
+            .iter()
+            .find(|other| other.state_file.as_ref() == Some(state_file))
+        {
+            bail!(
+                "Accounts {} and {} share the state file {}",
+                other.account.author,
+                archive.account.author,
+                state_file.display()
+            );
+        }
+    }
+    Ok(archives)
+}
+
+/// Reads the accounts file at `path`, see `parse_accounts`.
+pub fn load_accounts(
+    path: &str,
+    source: &str,
+    index: &str,
+    namespace: Option<&str>,
+) -> Result<Vec<AccountArchive>> {
+    let json = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read accounts file {}", path))?;
+    parse_accounts(&json, source, index, namespace)
+        .with_context(|| format!("Invalid accounts file {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accounts_default_to_the_given_target() {
+        let archives = parse_accounts(
+            r#"[
+                {"author": "atoma", "note_tweets": "atoma/note-tweet.js", "state_file": "atoma.json"},
+                {"author": "rustacean", "note_tweets": "rust/note-tweet.js",
+                 "tweets": "rust/tweets.js", "source": "x-rust", "index": "rust", "namespace": "rust"}
+            ]"#,
+            DEFAULT_SOURCE,
+            "tweets",
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            archives[0],
+            AccountArchive {
+                account: Account {
+                    author: "atoma".to_string(),
+                    source: DEFAULT_SOURCE.to_string(),
+                    index: "tweets".to_string(),
+                    namespace: None,
+                },
+                note_tweets: "atoma/note-tweet.js".to_string(),
+                tweets: None,
+                state_file: Some(PathBuf::from("atoma.json")),
+            }
+        );
+        assert_eq!(
+            archives[1].account,
+            Account {
+                author: "rustacean".to_string(),
+                source: "x-rust".to_string(),
+                index: "rust".to_string(),
+                namespace: Some("rust".to_string()),
+            }
+        );
+        assert_eq!(archives[1].tweets.as_deref(), Some("rust/tweets.js"));
+
+        assert!(parse_accounts("[]", DEFAULT_SOURCE, "tweets", None).is_err());
+        let shared_state_file = r#"[
+            {"author": "a", "note_tweets": "a.js", "state_file": "state.json"},
+            {"author": "b", "note_tweets": "b.js", "state_file": "state.json"}
+        ]"#;
+        assert!(parse_accounts(shared_state_file, DEFAULT_SOURCE, "tweets", None).is_err());
+        let unknown_field = r#"[{"author": "a", "note_tweets": "a.js", "indx": "typo"}]"#;
+        assert!(parse_accounts(unknown_field, DEFAULT_SOURCE, "tweets", None).is_err());
+    }
+}
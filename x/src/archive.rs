@@ -0,0 +1,98 @@
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use regex::Regex;
+use std::{fs, io::Read, sync::LazyLock};
+
+/// Magic bytes starting every gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Matches the JavaScript assignment prefixing X archive files, e.g. `window.YTD.tweets.part0 = `.
+static ASSIGNMENT_PREFIX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*window\.YTD\.[A-Za-z0-9_-]+\.part\d+\s*=\s*").unwrap());
+
+/// Reads an X archive file and returns its JSON content.
+///
+/// Gzip compressed archives (e.g. `tweets.js.gz`), detected by their `.gz` extension
+/// or their magic bytes, are transparently decompressed. The content is decoded as UTF-8,
+/// which X archives are encoded in, so that e.g. the `…` truncation marker of tweets reads
+/// as such rather than as `â€¦`.
+///
+/// # Arguments
+///
+/// * `file_path` - A string slice that holds the path to the archive file.
+///
+/// # Errors
+///
+/// This function will return an error if the file cannot be opened, read or decompressed, or
+/// if its content is not valid UTF-8.
+pub fn read_archive(file_path: &str) -> Result<String> {
+    let bytes = fs::read(file_path)?;
+
+    let content = if file_path.ends_with(".gz") || bytes.starts_with(&GZIP_MAGIC) {
+        let mut content = String::new();
+        GzDecoder::new(bytes.as_slice()).read_to_string(&mut content)?;
+        content
+    } else {
+        String::from_utf8(bytes)?
+    };
+
+    Ok(strip_assignment_prefix(&content).to_string())
+}
+
+/// Strips the `window.YTD.<name>.partN = ` assignment from an archive file content.
+///
+/// Content that is already a bare JSON array is returned unchanged.
+pub fn strip_assignment_prefix(content: &str) -> &str {
+    match ASSIGNMENT_PREFIX.find(content) {
+        Some(prefix) => &content[prefix.end()..],
+        None => content,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_tweets_prefix() {
+        let content = r#"window.YTD.tweets.part0 = [{"tweet": {}}]"#;
+        assert_eq!(strip_assignment_prefix(content), r#"[{"tweet": {}}]"#);
+    }
+
+    #[test]
+    fn test_strip_other_prefix() {
+        let content = "window.YTD.deleted_tweets.part12 = [\n]";
+        assert_eq!(strip_assignment_prefix(content), "[\n]");
+        let content = "window.YTD.note_tweet.part0 = []";
+        assert_eq!(strip_assignment_prefix(content), "[]");
+    }
+
+    #[test]
+    fn test_bare_array_unchanged() {
+        let content = r#"[{"noteTweet": {}}]"#;
+        assert_eq!(strip_assignment_prefix(content), content);
+    }
+
+    #[test]
+    fn test_read_gzipped_archive() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let content = r#"window.YTD.tweets.part0 = [{"tweet": {"id": "1"}}]"#;
+        let dir = std::env::temp_dir();
+        let plain_path = dir.join(format!("x-archive-{}.js", std::process::id()));
+        let gz_path = dir.join(format!("x-archive-{}.js.gz", std::process::id()));
+        fs::write(&plain_path, content).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        fs::write(&gz_path, encoder.finish().unwrap()).unwrap();
+
+        let plain = read_archive(plain_path.to_str().unwrap()).unwrap();
+        let gzipped = read_archive(gz_path.to_str().unwrap()).unwrap();
+        assert_eq!(plain, r#"[{"tweet": {"id": "1"}}]"#);
+        assert_eq!(gzipped, plain);
+
+        fs::remove_file(plain_path).unwrap();
+        fs::remove_file(gz_path).unwrap();
+    }
+}
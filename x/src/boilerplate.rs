@@ -0,0 +1,118 @@
+use anyhow::Result;
+use rag::types::TextToEmbed;
+use regex::Regex;
+
+/// Patterns of the tweet boilerplate stripped by default: retweet prefixes, mention-only
+/// prefixes, `via @app` suffixes and trailing blocks of consecutive hashtags.
+pub const DEFAULT_PATTERNS: &[&str] = &[
+    r"^RT @\w+:\s*",
+    r"^(?:@\w+\s+)+",
+    r"\s+via @\w+\s*$",
+    r"(?:\s+#\w+){2,}\s*$",
+];
+
+/// Metadata key under which the text of a tweet is kept before its boilerplate is stripped.
+pub const ORIGINAL_TEXT_KEY: &str = "original_text";
+
+/// Strips boilerplate from the text of tweets before it is embedded, as it dilutes the
+/// embeddings without telling tweets apart.
+#[derive(Debug, Clone)]
+pub struct BoilerplateStripper {
+    patterns: Vec<Regex>,
+}
+
+impl Default for BoilerplateStripper {
+    fn default() -> Self {
+        Self::new(DEFAULT_PATTERNS).expect("Default boilerplate patterns are valid")
+    }
+}
+
+impl BoilerplateStripper {
+    /// Constructor, from the regexes of the boilerplate to strip
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a pattern is not a valid regex.
+    pub fn new(patterns: &[impl AsRef<str>]) -> Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns })
+    }
+
+    /// Removes the matches of every pattern from the text, in order, and trims the result.
+    pub fn strip(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for pattern in &self.patterns {
+            text = pattern.replace_all(&text, "").into_owned();
+        }
+        text.trim().to_string()
+    }
+
+    /// Strips the boilerplate of the content to embed, keeping the original content as the
+    /// `ORIGINAL_TEXT_KEY` metadata if it changed. Content made only of boilerplate is left as is,
+    /// as there would be nothing left to embed.
+    pub fn apply(&self, text_to_embed: &mut TextToEmbed) {
+        let stripped = self.strip(&text_to_embed.content);
+        if stripped.is_empty() || stripped == text_to_embed.content {
+            return;
+        }
+        let original = std::mem::replace(&mut text_to_embed.content, stripped);
+        text_to_embed
+            .extra
+            .get_or_insert_with(serde_json::Map::new)
+            .insert(ORIGINAL_TEXT_KEY.to_string(), original.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strips_retweet_prefix() {
+        let stripper = BoilerplateStripper::default();
+        assert_eq!(
+            stripper.strip("RT @x: Decentralized inference is here"),
+            "Decentralized inference is here"
+        );
+        assert_eq!(
+            stripper.strip("@atoma @x thanks for shipping this via @buffer"),
+            "thanks for shipping this"
+        );
+
+        let mut text_to_embed =
+            TextToEmbed::builder("1", "test", "RT @x: Decentralized inference is here").build();
+        stripper.apply(&mut text_to_embed);
+        assert_eq!(text_to_embed.content, "Decentralized inference is here");
+        assert_eq!(
+            text_to_embed.extra.unwrap()[ORIGINAL_TEXT_KEY],
+            "RT @x: Decentralized inference is here"
+        );
+    }
+
+    #[test]
+    fn test_strips_trailing_hashtag_block() {
+        let stripper = BoilerplateStripper::default();
+        assert_eq!(
+            stripper.strip("New release is out #AI #crypto #web3"),
+            "New release is out"
+        );
+        // A single hashtag, or hashtags within the text, are part of the tweet
+        assert_eq!(
+            stripper.strip("Shipping #rust code today #AI"),
+            "Shipping #rust code today #AI"
+        );
+
+        // Tweets made only of boilerplate, or without any, are left unchanged
+        let mut text_to_embed = TextToEmbed::builder("1", "test", "#AI #crypto").build();
+        stripper.apply(&mut text_to_embed);
+        assert_eq!(text_to_embed.content, "#AI #crypto");
+        assert!(text_to_embed.extra.is_none());
+
+        let stripper = BoilerplateStripper::new(&[r"\s+#\w+\s*$"]).unwrap();
+        assert_eq!(stripper.strip("Shipping today #AI"), "Shipping today");
+        assert!(BoilerplateStripper::new(&["("]).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use rag::config::DEFAULT_PORT;
+
+use crate::{
+    account::{load_accounts, Account, AccountArchive, DEFAULT_SOURCE},
+    embed::OnError,
+};
+
+/// Default index name used when none is provided.
+pub const DEFAULT_INDEX_NAME: &str = "atoma-alpha-mistral";
+
+/// Command line interface for indexing X (Twitter) archive data into the RAG server.
+///
+/// Every flag falls back to its corresponding environment variable (which may be
+/// set through a `.env` file), so existing env-based setups keep working.
+#[derive(Debug, Parser)]
+#[command(name = "x", version, about)]
+pub struct Cli {
+    /// Host of the RAG server
+    #[arg(long, env = "HOST", default_value = "127.0.0.1", global = true)]
+    pub host: String,
+    /// Port of the RAG server
+    #[arg(long, env = "PORT", default_value_t = DEFAULT_PORT, global = true)]
+    pub port: u16,
+    /// The command to run
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// Available subcommands
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Embeds the note tweets of an archive into the given index
+    Index(IndexArgs),
+}
+
+/// Arguments for the `index` subcommand
+#[derive(Debug, Args)]
+pub struct IndexArgs {
+    /// Path to the note tweets archive file
+    #[arg(long, env = "NOTE_TWEET_FILE", required_unless_present = "accounts")]
+    pub note_tweets: Option<String>,
+    /// Optional path to the tweets archive file, used to match note tweets to their tweets
+    #[arg(long, env = "TWEETS_FILE")]
+    pub tweets: Option<String>,
+    /// The name of the index to store the embeddings in
+    #[arg(long, default_value = DEFAULT_INDEX_NAME)]
+    pub index: String,
+    /// The author of the tweets
+    #[arg(long, env = "USERNAME", required_unless_present = "accounts")]
+    pub author: Option<String>,
+    /// The `source` the tweets are tagged with
+    #[arg(long, env = "SOURCE", default_value = DEFAULT_SOURCE)]
+    pub source: String,
+    /// Optional Pinecone namespace to store the embeddings in
+    #[arg(long, env = "NAMESPACE")]
+    pub namespace: Option<String>,
+    /// Optional path to a JSON file listing several accounts to index in one run, each with
+    /// its own archive files, author, and optionally source, index, namespace and state file
+    /// (see `parse_accounts`); the single account flags are then ignored, and `--source`,
+    /// `--index` and `--namespace` are the defaults of the listed accounts
+    #[arg(long, env = "ACCOUNTS_FILE")]
+    pub accounts: Option<String>,
+    /// Optional path to a state file holding the latest embedded note tweet, so that
+    /// subsequent runs only embed newer note tweets
+    #[arg(long, env = "STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+    /// Whether to index note tweets whose tweet is a retweet, requires `--tweets`
+    #[arg(long)]
+    pub include_retweets: bool,
+    /// Whether to index note tweets whose tweet is a reply, requires `--tweets`
+    #[arg(long)]
+    pub include_replies: bool,
+    /// What to do when a note tweet fails to embed
+    #[arg(long, value_enum, default_value_t = OnError::Skip)]
+    pub on_error: OnError,
+    /// Whether to strip boilerplate, such as `RT @user:` prefixes and trailing hashtags, from
+    /// the embedded text, keeping the original text as metadata
+    #[arg(long)]
+    pub strip_boilerplate: bool,
+    /// Regex of the boilerplate to strip, may be repeated, replaces the default patterns
+    #[arg(long = "boilerplate-pattern", requires = "strip_boilerplate")]
+    pub boilerplate_patterns: Vec<String>,
+}
+
+impl IndexArgs {
+    /// Returns the archives to index, those of the accounts file if any, or else the one
+    /// given by the single account flags.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the accounts file cannot be read or is invalid.
+    pub fn archives(&self) -> anyhow::Result<Vec<AccountArchive>> {
+        if let Some(accounts) = &self.accounts {
+            return load_accounts(
+                accounts,
+                &self.source,
+                &self.index,
+                self.namespace.as_deref(),
+            );
+        }
+        // Both are required by clap without an accounts file
+        let (Some(author), Some(note_tweets)) = (&self.author, &self.note_tweets) else {
+            anyhow::bail!("--author and --note-tweets are required without --accounts");
+        };
+        Ok(vec![AccountArchive {
+            account: Account {
+                author: author.clone(),
+                source: self.source.clone(),
+                index: self.index.clone(),
+                namespace: self.namespace.clone(),
+            },
+            note_tweets: note_tweets.clone(),
+            tweets: self.tweets.clone(),
+            state_file: self.state_file.clone(),
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{error::ErrorKind, CommandFactory};
+
+    #[test]
+    fn test_index_subcommand_flags() {
+        let cli = Cli::try_parse_from([
+            "x",
+            "--host",
+            "10.0.0.1",
+            "index",
+            "--note-tweets",
+            "note-tweet.js",
+            "--author",
+            "atoma",
+            "--port",
+            "9000",
+        ])
+        .unwrap();
+        assert_eq!(cli.host, "10.0.0.1");
+        assert_eq!(cli.port, 9000);
+        let Command::Index(args) = cli.command;
+        assert_eq!(args.note_tweets.as_deref(), Some("note-tweet.js"));
+        assert_eq!(args.author.as_deref(), Some("atoma"));
+        assert_eq!(args.index, DEFAULT_INDEX_NAME);
+        assert_eq!(args.on_error, OnError::Skip);
+        assert!(!args.strip_boilerplate);
+        assert!(args.boilerplate_patterns.is_empty());
+
+        let cli = Cli::try_parse_from([
+            "x",
+            "index",
+            "--note-tweets",
+            "note-tweet.js",
+            "--tweets",
+            "tweets.js",
+            "--index",
+            "my-index",
+            "--author",
+            "atoma",
+            "--on-error",
+            "retry",
+            "--strip-boilerplate",
+            "--boilerplate-pattern",
+            r"^RT @\w+:\s*",
+            "--boilerplate-pattern",
+            r"\s+via @\w+$",
+        ])
+        .unwrap();
+        let Command::Index(args) = cli.command;
+        assert_eq!(args.tweets.as_deref(), Some("tweets.js"));
+        assert_eq!(args.on_error, OnError::Retry);
+        assert_eq!(args.index, "my-index");
+        assert!(args.strip_boilerplate);
+        assert_eq!(
+            args.boilerplate_patterns,
+            [r"^RT @\w+:\s*", r"\s+via @\w+$"]
+        );
+    }
+
+    #[test]
+    fn test_index_subcommand_requires_note_tweets() {
+        // NOTE: The environment fallback is turned off rather than the variable unset, as the
+        // environment is shared with the tests running in parallel
+        let command = Cli::command().mut_subcommand("index", |index| {
+            index.mut_arg("note_tweets", |note_tweets| note_tweets.env(None))
+        });
+        let result = command.try_get_matches_from(["x", "index", "--author", "atoma"]);
+        let error = result.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::MissingRequiredArgument);
+        assert!(error.to_string().contains("--note-tweets"), "{}", error);
+    }
+
+    #[test]
+    fn test_index_subcommand_single_account() {
+        let cli = Cli::try_parse_from([
+            "x",
+            "index",
+            "--note-tweets",
+            "note-tweet.js",
+            "--author",
+            "atoma",
+            "--source",
+            "x-atoma",
+            "--namespace",
+            "atoma",
+        ])
+        .unwrap();
+        let Command::Index(args) = cli.command;
+        let archives = args.archives().unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(
+            archives[0].account,
+            Account {
+                author: "atoma".to_string(),
+                source: "x-atoma".to_string(),
+                index: DEFAULT_INDEX_NAME.to_string(),
+                namespace: Some("atoma".to_string()),
+            }
+        );
+        assert_eq!(archives[0].note_tweets, "note-tweet.js");
+    }
+}
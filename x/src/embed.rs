@@ -0,0 +1,221 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use rag::types::TextToEmbed;
+use tracing::{error, info, warn};
+
+/// Maximum number of attempts to embed a document with `OnError::Retry`.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+/// Delay before the first retry with `OnError::Retry`, doubled after each failed attempt.
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// What to do when a document fails to embed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnError {
+    /// Log the failure and move on to the next document
+    #[default]
+    Skip,
+    /// Stop indexing at the first failure
+    Abort,
+    /// Retry the document with exponential backoff, then skip it if it still fails
+    Retry,
+}
+
+/// How the indexing loop handles documents failing to embed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailurePolicy {
+    /// What to do when a document fails to embed
+    pub on_error: OnError,
+    /// Maximum number of attempts per document, only used by `OnError::Retry`
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after each failed attempt
+    pub initial_backoff: Duration,
+}
+
+impl FailurePolicy {
+    /// Builds the policy with the default number of attempts and backoff.
+    pub fn new(on_error: OnError) -> Self {
+        Self {
+            on_error,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+        }
+    }
+}
+
+/// The outcome of embedding a sequence of documents.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EmbedSummary {
+    /// Number of documents successfully embedded
+    pub embedded: usize,
+    /// Query ids of the documents that failed to embed, in order
+    pub failed: Vec<String>,
+}
+
+impl EmbedSummary {
+    /// Logs the number of embedded documents, and the query ids of the failed ones.
+    pub fn log(&self) {
+        info!(
+            "Embedded {} of {} documents",
+            self.embedded,
+            self.embedded + self.failed.len()
+        );
+        if !self.failed.is_empty() {
+            warn!(
+                "{} documents failed to embed: {}",
+                self.failed.len(),
+                self.failed.join(", ")
+            );
+        }
+    }
+}
+
+/// Embeds each document with `embed`, handling failures according to the policy.
+///
+/// # Errors
+///
+/// With `OnError::Abort`, returns the error of the first document failing to embed. Failures
+/// are otherwise collected in the returned `EmbedSummary`.
+pub async fn embed_all<F, Fut>(
+    texts_to_embed: Vec<TextToEmbed>,
+    policy: &FailurePolicy,
+    mut embed: F,
+) -> Result<EmbedSummary>
+where
+    F: FnMut(&TextToEmbed) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut summary = EmbedSummary::default();
+    for text_to_embed in texts_to_embed {
+        let max_attempts = match policy.on_error {
+            OnError::Retry => policy.max_attempts.max(1),
+            OnError::Skip | OnError::Abort => 1,
+        };
+        let mut backoff = policy.initial_backoff;
+        let mut attempt = 1;
+        let result = loop {
+            match embed(&text_to_embed).await {
+                Ok(()) => break Ok(()),
+                Err(e) if attempt < max_attempts => {
+                    warn!(
+                        "Failed to embed query_id {} (attempt {} of {}), retrying in {:?}: {}",
+                        text_to_embed.query_id, attempt, max_attempts, backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        match result {
+            Ok(()) => {
+                info!("Successfully embedded query_id {}", text_to_embed.query_id);
+                summary.embedded += 1;
+            }
+            Err(e) if policy.on_error == OnError::Abort => {
+                error!("Failed to embed query_id {}: {}", text_to_embed.query_id, e);
+                return Err(anyhow::anyhow!(
+                    "Failed to embed query_id {}: {}",
+                    text_to_embed.query_id,
+                    e
+                ));
+            }
+            Err(e) => {
+                error!("Failed to embed query_id {}: {}", text_to_embed.query_id, e);
+                summary.failed.push(text_to_embed.query_id);
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::*;
+
+    fn documents(query_ids: &[&str]) -> Vec<TextToEmbed> {
+        query_ids
+            .iter()
+            .map(|query_id| TextToEmbed::builder(*query_id, "index", "content").build())
+            .collect()
+    }
+
+    /// Number of failures left before each listed query id embeds successfully.
+    fn failures(counts: &[(&str, u32)]) -> RefCell<HashMap<String, u32>> {
+        RefCell::new(
+            counts
+                .iter()
+                .map(|(query_id, count)| (query_id.to_string(), *count))
+                .collect(),
+        )
+    }
+
+    /// Fails to embed a document while it has failures left.
+    fn flaky_embed(
+        failures: &RefCell<HashMap<String, u32>>,
+        text_to_embed: &TextToEmbed,
+    ) -> impl Future<Output = Result<()>> {
+        let result = match failures.borrow_mut().get_mut(&text_to_embed.query_id) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                Err(anyhow::anyhow!("embedding server unavailable"))
+            }
+            _ => Ok(()),
+        };
+        async move { result }
+    }
+
+    #[tokio::test]
+    async fn test_skip_completes_and_reports_failures() {
+        let failures = failures(&[("2", u32::MAX)]);
+        let policy = FailurePolicy::new(OnError::Skip);
+        let summary = embed_all(documents(&["1", "2", "3"]), &policy, |text_to_embed| {
+            flaky_embed(&failures, text_to_embed)
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            summary,
+            EmbedSummary {
+                embedded: 2,
+                failed: vec!["2".to_string()],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abort_stops_at_first_failure() {
+        let failures = failures(&[("2", u32::MAX)]);
+        let mut attempted = vec![];
+        let policy = FailurePolicy::new(OnError::Abort);
+        let result = embed_all(documents(&["1", "2", "3"]), &policy, |text_to_embed| {
+            attempted.push(text_to_embed.query_id.clone());
+            flaky_embed(&failures, text_to_embed)
+        })
+        .await;
+        assert!(result.unwrap_err().to_string().contains("query_id 2"));
+        assert_eq!(attempted, vec!["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn test_retry_recovers_from_transient_failures() {
+        let failures = failures(&[("1", 2), ("2", u32::MAX)]);
+        let policy = FailurePolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..FailurePolicy::new(OnError::Retry)
+        };
+        let summary = embed_all(documents(&["1", "2"]), &policy, |text_to_embed| {
+            flaky_embed(&failures, text_to_embed)
+        })
+        .await
+        .unwrap();
+        assert_eq!(summary.embedded, 1);
+        assert_eq!(summary.failed, vec!["2"]);
+        // The failing document used up all its attempts
+        assert_eq!(failures.borrow()["2"], u32::MAX - DEFAULT_MAX_ATTEMPTS);
+    }
+}
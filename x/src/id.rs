@@ -0,0 +1,41 @@
+use sha2::{Digest, Sha256};
+
+/// Generates an id from the fields identifying a value, stable across Rust versions and
+/// platforms.
+///
+/// The id is the hex encoded SHA-256 digest of an explicit encoding of the fields: each field
+/// is written as its length in bytes, as a little-endian `u64`, followed by its UTF-8 bytes,
+/// so that `["ab", "c"]` and `["a", "bc"]` get different ids. The `Hash` of a value is not
+/// used, as its output may change between compiler versions.
+pub fn stable_id(fields: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for field in fields {
+        hasher.update((field.len() as u64).to_le_bytes());
+        hasher.update(field.as_bytes());
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stable_id_is_a_fixed_digest() {
+        let id = stable_id(&["note_tweet", "42"]);
+        assert_eq!(id.len(), 64);
+        assert_eq!(id, stable_id(&["note_tweet", "42"]));
+        assert_ne!(id, stable_id(&["note_tweet", "43"]));
+        assert_ne!(stable_id(&["ab", "c"]), stable_id(&["a", "bc"]));
+        // SHA-256 of 10 as a little-endian `u64`, `b"note_tweet"`, 2 as a little-endian `u64`
+        // and `b"42"`
+        assert_eq!(
+            id,
+            "726193506c9b3acfbb61479c2518726aaea4304faba2b5992e0c406612efdb6e"
+        );
+    }
+}
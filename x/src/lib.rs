@@ -1,3 +1,10 @@
+pub mod account;
+pub mod archive;
+pub mod boilerplate;
+pub mod cli;
+pub mod embed;
+pub mod id;
 pub mod note_tweet;
 pub mod parser;
 pub mod tweets;
+pub mod watermark;
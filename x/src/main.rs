@@ -1,62 +1,122 @@
 use anyhow::Result;
+use clap::Parser;
 use dotenv::dotenv;
 use rag::types::TextToEmbed;
 use reqwest::Client;
-use std::{
-    env,
-    hash::{DefaultHasher, Hash, Hasher},
+use tracing::{info, warn};
+use x::{
+    account::AccountArchive,
+    boilerplate::BoilerplateStripper,
+    cli::{Cli, Command, IndexArgs},
+    embed::{embed_all, FailurePolicy},
+    note_tweet::parse_note_tweets,
+    parser::{note_tweet_to_embed, parse_tweet_data_to_embed},
+    tweets::{parse_tweets, TweetFilter},
+    watermark::{filter_new, Watermark},
 };
-use tracing::{error, info};
-use x::note_tweet::parse_note_tweets;
-
-const INDEX_NAME: &str = "atoma-alpha-mistral";
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    dotenv().expect("Failed to load .env file");
-    let username = env::var("USERNAME").expect("USERNAME not set");
-    let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port = env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(8081);
+    tracing_subscriber::fmt::init();
+    if let Err(e) = dotenv() {
+        warn!("No .env file loaded: {}", e);
+    }
+    let cli = Cli::parse();
 
-    let note_tweets =
-        parse_note_tweets(&env::var("NOTE_TWEET_FILE").expect("NOTE_TWEET_FILE not set"))
-            .expect("Failed to parse note tweets json file");
+    match cli.command {
+        Command::Index(args) => index(&cli.host, cli.port, args).await,
+    }
+}
+
+async fn index(host: &str, port: u16, args: IndexArgs) -> Result<()> {
+    let archives = args.archives()?;
+    let IndexArgs {
+        include_retweets,
+        include_replies,
+        on_error,
+        strip_boilerplate,
+        boilerplate_patterns,
+        ..
+    } = args;
+    let filter = TweetFilter {
+        include_retweets,
+        include_replies,
+    };
+    let stripper = match (strip_boilerplate, boilerplate_patterns.is_empty()) {
+        (false, _) => None,
+        (true, true) => Some(BoilerplateStripper::default()),
+        (true, false) => Some(BoilerplateStripper::new(&boilerplate_patterns)?),
+    };
 
     let client = Client::new();
-    for note_tweet in note_tweets {
-        let mut default_hasher = DefaultHasher::new();
-        note_tweet.hash(&mut default_hasher);
-        let query_id = default_hasher.finish().to_string();
-        let text_to_embed = TextToEmbed {
-            query_id: query_id.clone(),
-            index_name: INDEX_NAME.to_string(),
-            content: note_tweet.core.text,
-            topic: None,
-            description: None,
-            source: Some("x".to_string()),
-            author: Some(username.clone()),
-            page: None,
-            date: Some(note_tweet.created_at),
-        };
-
-        match client
-            .post(format!("http://{}:{}/embed", host, port))
-            .json(&text_to_embed)
-            .send()
-            .await
-        {
-            Ok(response) => {
-                info!("Successfully embedded result: {:?}", response);
+    let url = format!("http://{}:{}/embed", host, port);
+    let policy = FailurePolicy::new(on_error);
+    // Accounts are indexed one after the other, each with its own watermark
+    for archive in archives {
+        info!(
+            "Indexing account {} into index {}",
+            archive.account.author, archive.account.index
+        );
+        let (texts_to_embed, latest) = texts_to_embed(&archive, &filter, stripper.as_ref())?;
+        let summary = embed_all(texts_to_embed, &policy, |text_to_embed| {
+            let request = client.post(&url).json(text_to_embed);
+            async move {
+                let response = request.send().await?;
+                if !response.status().is_success() {
+                    anyhow::bail!("{:?}", response);
+                }
+                Ok(())
             }
-            Err(e) => {
-                error!("Error: {:?}", e);
-                panic!("Failed to successfully embed the tweet data for query_id: {}, with error: {:?}", query_id, e);
+        })
+        .await?;
+        summary.log();
+        let all_embedded = summary.failed.is_empty();
+
+        // The watermark only moves forward once the whole batch is embedded, so a failed run is
+        // retried in full on the next one
+        if let (Some(state_file), Some(latest)) = (&archive.state_file, latest) {
+            if all_embedded {
+                latest.save(state_file)?;
+            } else {
+                warn!("Some note tweets failed to embed, leaving the watermark unchanged");
             }
         }
     }
 
     Ok(())
 }
+
+/// Parses the archive of an account into the texts to embed, skipping the note tweets embedded
+/// by a previous run, and returns them along with the watermark of the latest of them.
+fn texts_to_embed(
+    archive: &AccountArchive,
+    filter: &TweetFilter,
+    stripper: Option<&BoilerplateStripper>,
+) -> Result<(Vec<TextToEmbed>, Option<Watermark>)> {
+    let note_tweets =
+        parse_note_tweets(&archive.note_tweets).expect("Failed to parse note tweets json file");
+    let watermark = match &archive.state_file {
+        Some(state_file) => Watermark::load(state_file)?,
+        None => None,
+    };
+    let note_tweets = filter_new(note_tweets, watermark.as_ref());
+    info!("Embedding {} new note tweets", note_tweets.len());
+    let latest = Watermark::latest(&note_tweets);
+
+    let mut texts_to_embed = match &archive.tweets {
+        Some(tweets) => {
+            let tweets = parse_tweets(tweets).expect("Failed to parse tweets json file");
+            parse_tweet_data_to_embed(&archive.account, note_tweets, tweets, filter)?
+        }
+        None => note_tweets
+            .into_iter()
+            .map(|note_tweet| note_tweet_to_embed(note_tweet, &archive.account, None))
+            .collect(),
+    };
+    if let Some(stripper) = stripper {
+        texts_to_embed
+            .iter_mut()
+            .for_each(|text_to_embed| stripper.apply(text_to_embed));
+    }
+    Ok((texts_to_embed, latest))
+}
@@ -1,12 +1,14 @@
-use std::io::Read;
 use types::{NoteTweet, NoteTweetContainer};
 
 use anyhow::Result;
 
+use crate::archive::read_archive;
+
 /// Parses note tweets from a given file.
 ///
 /// This function reads a file containing note tweet data in a specific JSON format,
-/// processes it, and returns a vector of `NoteTweet` objects.
+/// processes it, and returns a vector of `NoteTweet` objects. The file can either be
+/// a `window.YTD.<name>.partN = ` assignment, as found in X archives, or a bare JSON array.
 ///
 /// # Arguments
 ///
@@ -32,15 +34,9 @@ use anyhow::Result;
 /// println!("Parsed {} note tweets", note_tweets.len());
 /// ```
 pub fn parse_note_tweets(file_path: &str) -> Result<Vec<NoteTweet>> {
-    let file = std::fs::File::open(file_path)?;
-    let reader = std::io::BufReader::new(file);
-
-    let mut content = String::new();
-    std::io::Read::read_to_string(&mut reader.take(u64::MAX), &mut content)?;
-
-    // Remove the "window.YTD.note_tweet.part0 = " prefix
-    let json_content = content.trim_start_matches("window.YTD.note_tweet.part0 = ");
-    let containers: Vec<NoteTweetContainer> = serde_json::from_str(json_content)?;
+    // Removes the "window.YTD.note_tweet.part0 = " prefix, if any
+    let json_content = read_archive(file_path)?;
+    let containers: Vec<NoteTweetContainer> = serde_json::from_str(&json_content)?;
     let note_tweets: Vec<NoteTweet> = containers.into_iter().map(|c| c.note_tweet).collect();
 
     Ok(note_tweets)
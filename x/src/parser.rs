@@ -1,60 +1,532 @@
-use std::hash::{DefaultHasher, Hash, Hasher};
+use std::collections::HashMap;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use rag::types::TextToEmbed;
+use tracing::info;
 
-use crate::{note_tweet::types::NoteTweet, tweets::types::Tweet};
+use crate::{
+    account::Account,
+    id::stable_id,
+    note_tweet::types::NoteTweet,
+    tweets::{latest_versions, types::Tweet, TweetFilter},
+};
 
+/// Prefix of the ids of the stored note tweet chunks, telling them apart from other sources.
+pub const ID_PREFIX: &str = "tweet-";
+
+/// Builds the `TextToEmbed` of a note tweet of an account, tagged with its source and author,
+/// and stored in its index and namespace.
+///
+/// The cashtags and hashtags of the note tweet, and of its tweet if any, are stored as the
+/// `cashtags` and `hashtags` metadata lists, without their `$` and `#` signs, so that queries
+/// can filter on them, e.g. with `{"cashtags": {"$in": ["TSLA"]}}`. The language of the
+/// tweet, if any, is set as the `detected_lang` of the note tweet.
+pub fn note_tweet_to_embed(
+    note_tweet: NoteTweet,
+    account: &Account,
+    tweet: Option<&Tweet>,
+) -> TextToEmbed {
+    let query_id = stable_id(&["note_tweet", &note_tweet.note_tweet_id]);
+    let mut cashtags = note_tweet.core.cashtags.clone();
+    let mut hashtags = note_tweet.core.hashtags.clone();
+    if let Some(tweet) = tweet {
+        cashtags.extend(tweet.entities.symbols.iter().map(|s| s.text.clone()));
+        hashtags.extend(tweet.entities.hashtags.iter().map(|h| h.text.clone()));
+    }
+    let mut extra = serde_json::Map::new();
+    for (key, tags, sign) in [("cashtags", cashtags, '$'), ("hashtags", hashtags, '#')] {
+        let tags = normalize_tags(tags, sign);
+        if !tags.is_empty() {
+            extra.insert(key.to_string(), tags.into());
+        }
+    }
+
+    let mut text_to_embed = account
+        .builder(query_id, note_tweet.core.text)
+        .with_date(note_tweet.created_at)
+        .with_extra(extra)
+        .with_id_prefix(ID_PREFIX)
+        .build();
+    text_to_embed.detected_lang = tweet.and_then(tweet_lang);
+    text_to_embed
+}
+
+/// Builds the `TextToEmbed` of a tweet of an account.
+///
+/// Only the displayed part of the tweet is embedded (see `Tweet::display_text`), without the
+/// leading mentions of replies and the trailing media links. Its cashtags and hashtags are
+/// stored as metadata lists, and its language as `detected_lang`, as for note tweets.
+pub fn tweet_to_embed(tweet: &Tweet, account: &Account) -> TextToEmbed {
+    let query_id = stable_id(&["tweet", &tweet.id_str]);
+    let mut extra = serde_json::Map::new();
+    let cashtags = tweet
+        .entities
+        .symbols
+        .iter()
+        .map(|s| s.text.clone())
+        .collect();
+    let hashtags = tweet
+        .entities
+        .hashtags
+        .iter()
+        .map(|h| h.text.clone())
+        .collect();
+    for (key, tags, sign) in [("cashtags", cashtags, '$'), ("hashtags", hashtags, '#')] {
+        let tags = normalize_tags(tags, sign);
+        if !tags.is_empty() {
+            extra.insert(key.to_string(), tags.into());
+        }
+    }
+
+    let mut text_to_embed = account
+        .builder(query_id, tweet.display_text())
+        .with_date(tweet.created_at.clone())
+        .with_extra(extra)
+        .with_id_prefix(ID_PREFIX)
+        .build();
+    text_to_embed.detected_lang = tweet_lang(tweet);
+    text_to_embed
+}
+
+/// Returns the language X detected for a tweet, as an ISO 639-1 code.
+///
+/// X marks tweets without a language with codes such as `und` (undetermined) or `zxx` (no
+/// linguistic content), which are left out.
+fn tweet_lang(tweet: &Tweet) -> Option<String> {
+    (tweet.lang.len() == 2).then(|| tweet.lang.clone())
+}
+
+/// Strips the leading sign of tags and removes duplicates, keeping the first occurrence.
+fn normalize_tags(tags: Vec<String>, sign: char) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim_start_matches(sign).to_string();
+        if !tag.is_empty() && !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+/// Marker X appends to the truncated text of the tweet of a note tweet.
+pub const ELLIPSIS: char = '…';
+
+/// `ELLIPSIS` as it reads in legacy archives, once its UTF-8 bytes got decoded as Windows-1252.
+pub const MOJIBAKE_ELLIPSIS: &str = "â€¦";
+
+/// Number of leading characters of the truncated tweet text looked for in the note tweet text.
+const MATCH_PREFIX_CHARS: usize = 10;
+
+/// Returns the text of a tweet before its truncation marker, either `ELLIPSIS` or
+/// `MOJIBAKE_ELLIPSIS`, or the whole text if it holds neither.
+pub fn truncated_prefix(full_text: &str) -> &str {
+    let end = [full_text.find(ELLIPSIS), full_text.find(MOJIBAKE_ELLIPSIS)]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or(full_text.len());
+    full_text[..end].trim_end()
+}
+
+/// Tells whether a tweet is the truncated form of a note tweet, i.e. whether the note tweet
+/// text contains the first `MATCH_PREFIX_CHARS` characters of the tweet text before its
+/// truncation marker. Tweets with nothing before the marker match no note tweet.
+fn truncates_note_tweet(full_text: &str, note_text: &str) -> bool {
+    let prefix = truncated_prefix(full_text);
+    let end = prefix
+        .char_indices()
+        .nth(MATCH_PREFIX_CHARS)
+        .map_or(prefix.len(), |(i, _)| i);
+    !prefix.is_empty() && note_text.contains(&prefix[..end])
+}
+
+/// Builds the `TextToEmbed` of each note tweet, matched to its tweet.
+///
+/// A tweet matches a note tweet if it is its truncated form (see `truncated_prefix`). Fails if a
+/// note tweet matches no tweet.
+///
+/// Note tweets whose tweet is rejected by the filter, e.g. replies by default, are skipped.
+///
+/// Only the latest text of an edited tweet is embedded: the note tweets matched to any version
+/// of its edit chain are filtered against its latest version (see `latest_versions`). As the
+/// versions of a tweet usually share the prefix matched, a note tweet is only kept if it holds
+/// the whole truncated text of the latest version, the latest such note tweet if several do.
 pub fn parse_tweet_data_to_embed(
-    author: String,
-    index_name: String,
+    account: &Account,
     note_tweets: Vec<NoteTweet>,
     tweets: Vec<Tweet>,
+    filter: &TweetFilter,
 ) -> Result<Vec<TextToEmbed>> {
-    let mut text_to_embeds = vec![];
+    // The latest version of each edit chain, by the id of its initial version
+    let latest: HashMap<&str, &Tweet> = latest_versions(&tweets)
+        .into_iter()
+        .filter(|tweet| tweet.edit_tweet_ids().len() > 1)
+        .map(|tweet| (tweet.initial_id(), tweet))
+        .collect();
+    // The note tweets to embed, along with the latest version of their tweet
+    let mut matched: Vec<(NoteTweet, &Tweet)> = vec![];
     for note_tweet in note_tweets {
-        println!("\n\nNOTE_TWEET: {}", note_tweet.core.text);
-        let _tweet = tweets
+        let tweet = tweets
             .iter()
-            .find(|t| {
-                let text = t.full_text.split('…').next().unwrap();
-                println!("TWEET: {}\n\n", text.get(0..10).unwrap());
-                note_tweet.core.text.contains(&text.get(0..10).unwrap())
-            })
-            .expect("Failed ot extract tweet from node tweet");
-        let mut default_hasher = DefaultHasher::new();
-        note_tweet.hash(&mut default_hasher);
-        text_to_embeds.push(TextToEmbed {
-            query_id: default_hasher.finish().to_string(),
-            index_name: index_name.clone(),
-            content: note_tweet.core.text,
-            topic: None,
-            description: None,
-            source: Some("x".to_string()),
-            author: Some(author.clone()),
-            page: None,
-            date: Some(note_tweet.created_at),
-        });
+            .find(|t| truncates_note_tweet(&t.full_text, &note_tweet.core.text))
+            .ok_or_else(|| anyhow!("No tweet matches note tweet {}", note_tweet.note_tweet_id))?;
+        let Some(&tweet) = latest.get(tweet.initial_id()) else {
+            matched.push((note_tweet, tweet));
+            continue;
+        };
+        if !note_tweet
+            .core
+            .text
+            .contains(truncated_prefix(&tweet.full_text))
+        {
+            info!(
+                "Skipping note tweet {} of a superseded version of tweet {}",
+                note_tweet.note_tweet_id, tweet.id_str
+            );
+            continue;
+        }
+        match matched
+            .iter_mut()
+            .find(|(_, kept)| kept.id_str == tweet.id_str)
+        {
+            Some(kept) if kept.0.created_at < note_tweet.created_at => *kept = (note_tweet, tweet),
+            Some(_) => {}
+            None => matched.push((note_tweet, tweet)),
+        }
     }
-    Ok(text_to_embeds)
+    Ok(matched
+        .into_iter()
+        .filter(|(_, tweet)| filter.accepts(tweet))
+        .map(|(note_tweet, tweet)| note_tweet_to_embed(note_tweet, account, Some(tweet)))
+        .collect())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{note_tweet::parse_note_tweets, tweets::parse_tweets};
+    use crate::{account::DEFAULT_SOURCE, note_tweet::parse_note_tweets, tweets::parse_tweets};
 
     use super::*;
 
+    fn account(author: &str) -> Account {
+        Account {
+            author: author.to_string(),
+            source: DEFAULT_SOURCE.to_string(),
+            index: "test".to_string(),
+            namespace: None,
+        }
+    }
+
+    fn note_tweet(cashtags: &[&str], hashtags: &[&str]) -> NoteTweet {
+        note_tweet_with_text("Long note about $TSLA and $AAPL", cashtags, hashtags)
+    }
+
+    fn note_tweet_with_text(text: &str, cashtags: &[&str], hashtags: &[&str]) -> NoteTweet {
+        serde_json::from_value(serde_json::json!({
+            "noteTweetId": "1",
+            "updatedAt": "2024-11-01T12:00:00.000Z",
+            "lifecycle": {
+                "value": "0",
+                "name": "Initial",
+                "originalName": "Initial",
+                "annotations": {}
+            },
+            "createdAt": "2024-11-01T12:00:00.000Z",
+            "core": {
+                "styletags": null,
+                "urls": [],
+                "text": text,
+                "mentions": [],
+                "cashtags": cashtags,
+                "hashtags": hashtags
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_note_tweet_tags_metadata() {
+        let text_to_embed = note_tweet_to_embed(
+            note_tweet(&["TSLA", "$AAPL", "TSLA"], &["#stocks"]),
+            &account("atoma"),
+            None,
+        );
+        let extra = text_to_embed.extra.unwrap();
+        assert_eq!(extra["cashtags"], serde_json::json!(["TSLA", "AAPL"]));
+        assert_eq!(extra["hashtags"], serde_json::json!(["stocks"]));
+        assert_eq!(text_to_embed.id_prefix.as_deref(), Some(ID_PREFIX));
+
+        let text_to_embed = note_tweet_to_embed(note_tweet(&[], &[]), &account("atoma"), None);
+        assert!(text_to_embed.extra.is_none());
+    }
+
+    #[test]
+    fn test_accounts_tag_their_texts_to_embed() {
+        let accounts = [
+            account("atoma"),
+            Account {
+                author: "rustacean".to_string(),
+                source: "x-rust".to_string(),
+                index: "rust".to_string(),
+                namespace: Some("rustacean".to_string()),
+            },
+        ];
+        let texts_to_embed: Vec<_> = accounts
+            .iter()
+            .map(|account| {
+                parse_tweet_data_to_embed(
+                    account,
+                    vec![note_tweet(&[], &[])],
+                    vec![tweet("Long note about $TSLA… https://t.co/abc", None)],
+                    &TweetFilter::default(),
+                )
+                .unwrap()
+                .remove(0)
+            })
+            .collect();
+        let targets: Vec<_> = texts_to_embed
+            .iter()
+            .map(|text_to_embed| {
+                (
+                    text_to_embed.source.as_deref(),
+                    text_to_embed.author.as_deref(),
+                    text_to_embed.index_name.as_str(),
+                    text_to_embed.namespace.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                (Some("x"), Some("atoma"), "test", None),
+                (Some("x-rust"), Some("rustacean"), "rust", Some("rustacean")),
+            ]
+        );
+
+        let tweet = tweet("A tweet", None);
+        let text_to_embed = tweet_to_embed(&tweet, &accounts[1]);
+        assert_eq!(text_to_embed.author.as_deref(), Some("rustacean"));
+        assert_eq!(text_to_embed.index_name, "rust");
+    }
+
+    fn tweet(full_text: &str, in_reply_to_status_id: Option<&str>) -> Tweet {
+        serde_json::from_value(serde_json::json!({
+            "edit_info": { "edit": null, "initial": null },
+            "retweeted": false,
+            "source": "web",
+            "entities": { "hashtags": [], "symbols": [], "user_mentions": [], "urls": [] },
+            "display_text_range": ["0", "280"],
+            "favorite_count": "0",
+            "id_str": "1",
+            "truncated": false,
+            "retweet_count": "0",
+            "id": "1",
+            "created_at": "Fri Nov 01 12:00:00 +0000 2024",
+            "favorited": false,
+            "full_text": full_text,
+            "lang": "en",
+            "in_reply_to_status_id": in_reply_to_status_id,
+        }))
+        .unwrap()
+    }
+
+    /// A version of an edited tweet, with the given id, listing the ids of the chain.
+    fn edited_tweet(full_text: &str, id: &str, versions: &[&str]) -> Tweet {
+        let mut tweet = tweet(full_text, None);
+        tweet.id_str = id.to_string();
+        tweet.id = id.to_string();
+        let control = serde_json::json!({
+            "editTweetIds": versions,
+            "editableUntil": "2024-11-01T13:00:00.000Z",
+            "editsRemaining": "4",
+            "isEditEligible": true
+        });
+        tweet.edit_info = serde_json::from_value(if id == versions[0] {
+            serde_json::json!({ "edit": null, "initial": control })
+        } else {
+            serde_json::json!({
+                "edit": { "initialTweetId": versions[0], "editControlInitial": control },
+                "initial": null
+            })
+        })
+        .unwrap();
+        tweet
+    }
+
+    #[test]
+    fn test_edit_chain_embeds_latest_version() {
+        let versions = ["1", "2", "3"];
+        let tweets = || {
+            vec![
+                edited_tweet("First draft of a tweet", "1", &versions),
+                edited_tweet("Second draft of a tweet", "2", &versions),
+                edited_tweet("Final text of a tweet", "3", &versions),
+                tweet("A tweet never edited", None),
+            ]
+        };
+        let texts: Vec<_> = latest_versions(&tweets())
+            .iter()
+            .map(|tweet| tweet_to_embed(tweet, &account("atoma")).content)
+            .collect();
+        assert_eq!(texts, ["Final text of a tweet", "A tweet never edited"]);
+
+        // The note tweets of superseded versions are skipped
+        let note_tweets = ["First draft of a long note", "Final text of a long note"]
+            .iter()
+            .map(|text| note_tweet_with_text(text, &[], &[]))
+            .collect();
+        let tweets = vec![
+            edited_tweet("First draft of…", "1", &versions[..2]),
+            edited_tweet("Final text of…", "2", &versions[..2]),
+        ];
+        let texts_to_embed = parse_tweet_data_to_embed(
+            &account("atoma"),
+            note_tweets,
+            tweets,
+            &TweetFilter::default(),
+        )
+        .unwrap();
+        assert_eq!(texts_to_embed.len(), 1);
+        assert_eq!(texts_to_embed[0].content, "Final text of a long note");
+    }
+
+    #[test]
+    fn test_edit_chain_with_shared_prefix_embeds_latest_note_tweet() {
+        // Both note tweets match the first 10 chars of either version
+        let note_tweets = [
+            "Long note abot $TSLA and stuff",
+            "Long note about $TSLA and stuff",
+        ]
+        .iter()
+        .map(|text| note_tweet_with_text(text, &[], &[]))
+        .collect();
+        let tweets = vec![
+            edited_tweet("Long note abot $TSLA…", "1", &["1", "2"]),
+            edited_tweet("Long note about $TSLA…", "2", &["1", "2"]),
+        ];
+        let texts_to_embed = parse_tweet_data_to_embed(
+            &account("atoma"),
+            note_tweets,
+            tweets,
+            &TweetFilter::default(),
+        )
+        .unwrap();
+        assert_eq!(texts_to_embed.len(), 1);
+        assert_eq!(texts_to_embed[0].content, "Long note about $TSLA and stuff");
+    }
+
+    #[test]
+    fn test_tweet_to_embed_trims_leading_mention() {
+        let mut reply = tweet(
+            "@atoma Thanks for the details! https://t.co/abc",
+            Some("42"),
+        );
+        reply.display_text_range = vec!["7".to_string(), "30".to_string()];
+        let text_to_embed = tweet_to_embed(&reply, &account("atoma"));
+        assert_eq!(text_to_embed.content, "Thanks for the details!");
+        assert_eq!(text_to_embed.detected_lang.as_deref(), Some("en"));
+        reply.lang = "und".to_string();
+        assert_eq!(
+            tweet_to_embed(&reply, &account("atoma")).detected_lang,
+            None
+        );
+
+        // The indices count characters, not bytes
+        let mut accented = tweet("@zoé Très bien", None);
+        accented.display_text_range = vec!["5".to_string(), "14".to_string()];
+        assert_eq!(accented.display_text(), "Très bien");
+
+        for range in [
+            vec!["7"],
+            vec!["a", "b"],
+            vec!["10", "5"],
+            vec!["0", "1000"],
+        ] {
+            reply.display_text_range = range.into_iter().map(str::to_string).collect();
+            assert_eq!(reply.display_text(), reply.full_text);
+        }
+    }
+
+    #[test]
+    fn test_tweet_filter_counts() {
+        let texts = [
+            "An original long note",
+            "A reply to some tweet",
+            "RT @atoma: A retweeted note",
+        ];
+        let count = |filter: TweetFilter| {
+            let note_tweets = texts
+                .iter()
+                .map(|text| note_tweet_with_text(text, &[], &[]))
+                .collect();
+            let tweets = vec![
+                tweet("An original long…", None),
+                tweet("A reply to some…", Some("42")),
+                tweet("RT @atoma: A retweeted…", None),
+            ];
+            parse_tweet_data_to_embed(&account("atoma"), note_tweets, tweets, &filter)
+                .unwrap()
+                .len()
+        };
+        assert_eq!(count(TweetFilter::default()), 1);
+        let include_replies = TweetFilter {
+            include_replies: true,
+            ..Default::default()
+        };
+        assert_eq!(count(include_replies), 2);
+        let include_retweets = TweetFilter {
+            include_retweets: true,
+            ..Default::default()
+        };
+        assert_eq!(count(include_retweets), 2);
+        let include_all = TweetFilter {
+            include_retweets: true,
+            include_replies: true,
+        };
+        assert_eq!(count(include_all), 3);
+    }
+
+    #[test]
+    fn test_truncated_tweet_matches_note_tweet() {
+        let parse = |full_text: &str| {
+            parse_tweet_data_to_embed(
+                &account("atoma"),
+                vec![note_tweet(&[], &[])],
+                vec![tweet(full_text, None)],
+                &TweetFilter::default(),
+            )
+        };
+        assert_eq!(
+            truncated_prefix("Long note about $TSLA… https://t.co/abc"),
+            "Long note about $TSLA"
+        );
+        assert_eq!(
+            parse("Long note about $TSLA… https://t.co/abc")
+                .unwrap()
+                .len(),
+            1
+        );
+        // Legacy archives decoded with the wrong charset
+        assert_eq!(
+            parse("Long note about $TSLAâ€¦ https://t.co/abc")
+                .unwrap()
+                .len(),
+            1
+        );
+        // Short and multibyte prefixes do not panic, and unmatched note tweets are errors
+        assert!(parse("Très…").is_err());
+        assert!(parse("… https://t.co/abc").is_err());
+    }
+
     #[test]
     fn test_parse_tweet_data_to_embed() {
         dotenv::dotenv().unwrap();
         let note_tweets = parse_note_tweets(&std::env::var("NOTE_TWEET_FILE").unwrap()).unwrap();
         let tweets = parse_tweets(&std::env::var("TWEETS_FILE").unwrap()).unwrap();
         let text_to_embeds = parse_tweet_data_to_embed(
-            "Twen1Ack".to_string(),
-            "test".to_string(),
+            &account("Twen1Ack"),
             note_tweets,
             tweets,
+            &TweetFilter::default(),
         )
         .unwrap();
         println!("{:?}", text_to_embeds);
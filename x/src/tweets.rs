@@ -1,25 +1,57 @@
+use std::collections::HashSet;
+
 use anyhow::Result;
-use std::fs::File;
-use std::io::{BufReader, Read};
 use types::{Tweet, TweetContainer};
 
-pub fn parse_tweets(file_path: &str) -> Result<Vec<Tweet>> {
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
-
-    let mut content = String::new();
-    std::io::Read::read_to_string(&mut reader.take(u64::MAX), &mut content)?;
+use crate::archive::read_archive;
 
-    let json_content = content.trim_start_matches("window.YTD.tweets.part0 = ");
+pub fn parse_tweets(file_path: &str) -> Result<Vec<Tweet>> {
+    let json_content = read_archive(file_path)?;
 
-    let containers: Vec<TweetContainer> = serde_json::from_str(json_content)?;
+    let containers: Vec<TweetContainer> = serde_json::from_str(&json_content)?;
 
     let tweets: Vec<Tweet> = containers.into_iter().map(|c| c.tweet).collect();
 
     Ok(tweets)
 }
 
+/// Collapses the edit chains of tweets to their latest version.
+///
+/// Archives hold every version of an edited tweet as a tweet of its own, which would index
+/// superseded texts. A tweet is dropped if a later version of it, as listed by its
+/// `editTweetIds`, is in `tweets`, so the latest version in the archive is kept even if the
+/// chain itself goes further. Tweets never edited are kept as is.
+pub fn latest_versions(tweets: &[Tweet]) -> Vec<&Tweet> {
+    let ids: HashSet<String> = tweets.iter().map(|tweet| tweet.id_str.clone()).collect();
+    tweets
+        .iter()
+        .filter(|tweet| !tweet.is_superseded(&ids))
+        .collect()
+}
+
+/// Selects which kinds of tweets are indexed, based on the fields of their `Tweet`.
+///
+/// Note tweets do not hold these fields themselves, so the filter applies to the tweet a note
+/// tweet is matched to. Note tweets indexed without a tweets archive are always kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TweetFilter {
+    /// Whether to index retweets, excluded by default
+    pub include_retweets: bool,
+    /// Whether to index replies, excluded by default
+    pub include_replies: bool,
+}
+
+impl TweetFilter {
+    /// Checks whether the tweet passes the filter.
+    pub fn accepts(&self, tweet: &Tweet) -> bool {
+        (self.include_retweets || !tweet.is_retweet())
+            && (self.include_replies || !tweet.is_reply())
+    }
+}
+
 pub mod types {
+    use std::collections::HashSet;
+
     use serde::{Deserialize, Serialize};
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -57,6 +89,78 @@ pub mod types {
         pub in_reply_to_user_id_str: Option<String>,
     }
 
+    impl Tweet {
+        /// Checks whether the tweet is a retweet.
+        ///
+        /// Archives usually leave `retweeted` unset on retweets, so their `RT @` text prefix
+        /// is checked as well.
+        pub fn is_retweet(&self) -> bool {
+            self.retweeted || self.full_text.starts_with("RT @")
+        }
+
+        /// Checks whether the tweet is a reply to another tweet.
+        pub fn is_reply(&self) -> bool {
+            self.in_reply_to_status_id.is_some()
+        }
+
+        /// Returns the ids of the versions of the tweet, oldest first, as listed by the
+        /// `editTweetIds` of its `edit_info`.
+        ///
+        /// The initial version lists them in `initial`, and later versions in `edit`. Archives
+        /// without edit info yield no ids.
+        pub fn edit_tweet_ids(&self) -> &[String] {
+            let control = self.edit_info.initial.as_ref().or(self
+                .edit_info
+                .edit
+                .as_ref()
+                .map(|edit| &edit.edit_control_initial));
+            control.map_or(&[], |control| control.edit_tweet_ids.as_slice())
+        }
+
+        /// Returns the id of the initial version of the tweet, its own id if it was never edited.
+        pub fn initial_id(&self) -> &str {
+            self.edit_tweet_ids()
+                .first()
+                .map_or(self.id_str.as_str(), String::as_str)
+        }
+
+        /// Checks whether a later version of the tweet is among `ids`.
+        pub fn is_superseded(&self, ids: &HashSet<String>) -> bool {
+            let versions = self.edit_tweet_ids();
+            versions
+                .iter()
+                .position(|id| *id == self.id_str)
+                .is_some_and(|position| versions[position + 1..].iter().any(|id| ids.contains(id)))
+        }
+
+        /// Returns the part of `full_text` displayed as the tweet, as marked by `display_text_range`.
+        ///
+        /// The displayed text leaves out the leading mentions of replies and the trailing
+        /// media links. The range holds the indices of its first and past-the-end characters;
+        /// the full text is returned if the range is malformed, e.g. holds no numbers or ends
+        /// past the text.
+        pub fn display_text(&self) -> &str {
+            let range = match self.display_text_range.as_slice() {
+                [start, end] => start.parse::<usize>().ok().zip(end.parse::<usize>().ok()),
+                _ => None,
+            };
+            let Some((start, end)) = range.filter(|(start, end)| start <= end) else {
+                return &self.full_text;
+            };
+            // NOTE: The indices count characters, which are mapped to byte offsets
+            let offsets: Vec<usize> = self
+                .full_text
+                .char_indices()
+                .map(|(offset, _)| offset)
+                .chain(std::iter::once(self.full_text.len()))
+                .collect();
+            match (offsets.get(start), offsets.get(end)) {
+                (Some(&start), Some(&end)) => &self.full_text[start..end],
+                _ => &self.full_text,
+            }
+        }
+    }
+
     #[derive(Debug, Serialize, Deserialize)]
     pub struct EditInfo {
         pub edit: Option<Edit>,
@@ -99,8 +203,8 @@ pub mod types {
 
     #[derive(Debug, Serialize, Deserialize)]
     pub struct Symbol {
-        text: String,
-        indices: Vec<String>,
+        pub text: String,
+        pub indices: Vec<String>,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
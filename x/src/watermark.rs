@@ -0,0 +1,166 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+use crate::note_tweet::types::NoteTweet;
+
+/// The latest note tweet embedded by a previous run, persisted in a state file so that
+/// subsequent runs only embed newer note tweets.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watermark {
+    /// Creation date of the note tweet, as found in the archive
+    pub created_at: String,
+    /// Id of the note tweet, breaking ties between note tweets created at the same time
+    pub note_tweet_id: String,
+}
+
+impl Watermark {
+    /// Builds the watermark of a note tweet.
+    pub fn of(note_tweet: &NoteTweet) -> Self {
+        Self {
+            created_at: note_tweet.created_at.clone(),
+            note_tweet_id: note_tweet.note_tweet_id.clone(),
+        }
+    }
+
+    /// Returns the watermark of the latest of the given note tweets, if any.
+    ///
+    /// Note tweets whose creation date cannot be parsed are ignored.
+    pub fn latest(note_tweets: &[NoteTweet]) -> Option<Self> {
+        note_tweets
+            .iter()
+            .map(Self::of)
+            .filter_map(|watermark| watermark.key().map(|key| (key, watermark)))
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, watermark)| watermark)
+    }
+
+    /// Checks whether a note tweet is newer than the watermark.
+    ///
+    /// Note tweets whose creation date cannot be parsed are always considered newer, so they
+    /// are embedded rather than silently skipped.
+    pub fn is_older_than(&self, note_tweet: &NoteTweet) -> bool {
+        match (self.key(), Self::of(note_tweet).key()) {
+            (Some(watermark), Some(note_tweet)) => note_tweet > watermark,
+            _ => true,
+        }
+    }
+
+    /// Orders watermarks by creation date, then numerically by id.
+    fn key(&self) -> Option<(DateTime<FixedOffset>, u64)> {
+        let created_at = DateTime::parse_from_rfc3339(&self.created_at).ok()?;
+        let note_tweet_id = self.note_tweet_id.parse().unwrap_or_default();
+        Some((created_at, note_tweet_id))
+    }
+
+    /// Loads the watermark from a state file, or `None` if the file does not exist yet.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be read, or is not a valid
+    /// watermark.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Saves the watermark to a state file, atomically.
+    ///
+    /// The watermark is written to a temporary file next to the state file, which is then
+    /// renamed over it, so an interrupted run never leaves a truncated state file behind.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the temporary file cannot be written or renamed.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, serde_json::to_string(self)?)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Keeps the note tweets newer than the watermark, or all of them without a watermark.
+pub fn filter_new(note_tweets: Vec<NoteTweet>, watermark: Option<&Watermark>) -> Vec<NoteTweet> {
+    match watermark {
+        Some(watermark) => note_tweets
+            .into_iter()
+            .filter(|note_tweet| watermark.is_older_than(note_tweet))
+            .collect(),
+        None => note_tweets,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_tweet(note_tweet_id: &str, created_at: &str) -> NoteTweet {
+        serde_json::from_value(serde_json::json!({
+            "noteTweetId": note_tweet_id,
+            "updatedAt": created_at,
+            "lifecycle": {
+                "value": "0",
+                "name": "Initial",
+                "originalName": "Initial",
+                "annotations": {}
+            },
+            "createdAt": created_at,
+            "core": {
+                "styletags": null,
+                "urls": [],
+                "text": format!("Note tweet {}", note_tweet_id),
+                "mentions": [],
+                "cashtags": [],
+                "hashtags": []
+            }
+        }))
+        .unwrap()
+    }
+
+    fn archive() -> Vec<NoteTweet> {
+        vec![
+            note_tweet("9", "2024-11-01T12:00:00.000Z"),
+            note_tweet("10", "2024-11-01T12:00:00.000Z"),
+            note_tweet("8", "2024-10-30T08:00:00.000Z"),
+        ]
+    }
+
+    #[test]
+    fn test_second_run_only_embeds_new_note_tweet() {
+        let path = std::env::temp_dir().join(format!("x-watermark-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        // First run, without a state file: everything is embedded
+        let watermark = Watermark::load(&path).unwrap();
+        assert!(watermark.is_none());
+        let embedded = filter_new(archive(), watermark.as_ref());
+        assert_eq!(embedded.len(), 3);
+        Watermark::latest(&embedded).unwrap().save(&path).unwrap();
+
+        // Second run, over the same archive plus one note tweet
+        let watermark = Watermark::load(&path).unwrap().unwrap();
+        assert_eq!(watermark.note_tweet_id, "10");
+        let mut archive = archive();
+        archive.push(note_tweet("11", "2024-11-02T09:30:00.000Z"));
+        let embedded = filter_new(archive, Some(&watermark));
+        let ids: Vec<&str> = embedded.iter().map(|n| n.note_tweet_id.as_str()).collect();
+        assert_eq!(ids, vec!["11"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unparsable_dates_are_never_skipped() {
+        let watermark = Watermark::of(&note_tweet("10", "2024-11-01T12:00:00.000Z"));
+        assert!(watermark.is_older_than(&note_tweet("1", "not a date")));
+        assert!(!watermark.is_older_than(&note_tweet("9", "2024-11-01T12:00:00.000Z")));
+        assert!(Watermark::latest(&[note_tweet("1", "not a date")]).is_none());
+    }
+}